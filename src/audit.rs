@@ -0,0 +1,28 @@
+//! `--audit-log <file>`, a line for every link we make or remove, to tell our changes to the graph from the others.
+//! The file is only ever appended to, one tab separated line per change
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+pub struct AuditLog {
+    file: File,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> std::io::Result<AuditLog> {
+        OpenOptions::new().create(true).append(true).open(path).map(|file| AuditLog { file })
+    }
+
+    /// Writes the fields after the local time, a whole line at once so instances sharing the file do not mix them
+    pub fn write(&self, fields: &[&str]) {
+        let fields = fields.iter().map(|a| if a.is_empty() { "-".to_string() } else { a.replace('\t', " ") }).collect::<Vec<String>>();
+        let line = format!("{}\t{}\n", crate::config::local_timestamp(crate::config::unix_time()), fields.join("\t"));
+
+        if let Err(e) = (&self.file).write_all(line.as_bytes()) {
+            println!("Could not write the audit log: {}", e);
+        }
+    }
+}
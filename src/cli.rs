@@ -0,0 +1,607 @@
+//! The command line: the arguments, with their defaults and the usage errors, and the help
+
+use std::{fmt, time::Duration};
+
+use crate::config::{parse_tags, TagFilter, SCHEDULE_INTERVAL, SYSTEM_CONFIG};
+use crate::graph::NodeFilter;
+use crate::report::Format;
+use crate::watchdog::EXIT_STALLED;
+use crate::webhook;
+
+pub fn help() {
+    println!("Usage: \n");
+    println!("pw-autoconnect [options] [filename], $XDG_CONFIG_HOME/pw-autoconnect.conf when it exists and no file is given \n");
+    println!("pw-autoconnect [options] <command> <filename> \n");
+    println!("Commands:");
+    println!("  init                 pick the ports to link from the graph and write them to the file");
+    println!("  suggest              print rules for the links to and from the devices in the graph, no file needed");
+    println!("                       with --append <file> they are added to the end of the file instead");
+    println!("  discover             print every node in the graph with its ports written like in the rules, no file needed");
+    println!("  expand               replace the stereo, channels and map rules with a rule for every link they make in the graph");
+    println!("  cleanup              remove the links made by the rules in the file, or created by pw-autoconnect");
+    println!("  dry-run              show the links that would be added (+), removed (-) or are already there (=),");
+    println!("                       using the running instance if there is one");
+    println!("  check [--live]       load the file and count the rules, with --live check every rule against the graph");
+    println!("  list [--unmatched]   show the rules with the closest nodes and ports to the ones missing from the graph,");
+    println!("                       without a file the nodes and ports in the graph");
+    println!("  import --from <fmt>  print the rules for a file of another tool, supported formats: qjackctl, aj-snapshot, pw-link");
+    println!("                       with --append <file> they are added to the end of the file instead");
+    println!("  export --to <fmt>    save the current links to the file, supported formats: aj-snapshot, graph");
+    println!("  test <graph>         show what the rules would do to a graph saved with export --to graph, given after the file");
+    println!("  diff <before> <after> show the nodes, ports and links added (+) or removed (-) between two saved graphs");
+    println!("  reload               load the rules of the running instance again");
+    println!("  status               show what the running instance is doing");
+    println!("  stats                show how many links every rule of the running instance made and when it last did");
+    println!("  health               show if the running instance is connected and its @required rules are linked, fails when not");
+    println!("  profile switch <name> load only the rules tagged name in the running instance, removing the links of the others");
+    println!("  enable <rule>        turn a rule of the running instance on, by its number or @name, to link a @manual rule");
+    println!("  disable <rule>       turn a rule of the running instance off and remove its links");
+    println!("  replay <events>      run the rules against the registry events saved with --record, without changing the graph\n");
+    println!("Options:");
+    println!("  -h, --help           print this help");
+    println!("  --no-linger          remove the created links when pw-autoconnect exits");
+    println!("  --backend <backend>  graph to connect to: pipewire (default), jack, pulse, or sim <graph> to run against");
+    println!("                       a graph saved with export --to graph, exiting once the rules are done");
+    println!("  --events <file>      registry events saved with --record, played against the graph of the sim backend");
+    println!("  --socket <socket>    PipeWire socket name or path, defaults to $PIPEWIRE_REMOTE or pipewire-0");
+    println!("  --runtime-dir <dir>  directory the socket name is looked up in, defaults to $PIPEWIRE_RUNTIME_DIR or $XDG_RUNTIME_DIR");
+    println!("  --connect-retries <n> times to try connecting again, a second apart, while PipeWire is not there yet");
+    println!("  --connect-timeout <seconds> how long to keep trying to connect on start, defaults to {}", CONNECT_TIMEOUT.as_secs());
+    println!("  --factory <name>     factory used to create links, defaults to the link factory found in the registry");
+    println!("  --rate-limit <n>     links made per second at most, defaults to {}", RATE_LIMIT);
+    println!("  --once               exit once every rule is linked, with an error when a @required rule is not");
+    println!("  --timeout <seconds>  how long --once waits for the rules, defaults to {}", ONCE_TIMEOUT.as_secs());
+    println!("  --watchdog <seconds> exit with {} when the main loop stalls for this long, defaults to {}, 0 turns it off", EXIT_STALLED, WATCHDOG_TIMEOUT.as_secs());
+    println!("  --record <file>      save the registry events to the file, to reproduce problems with replay");
+    println!("  --audit-log <file>   append every link made or removed to the file, with the time, ids and rule");
+    println!("  --class <class>      only list the nodes of the media class, like Audio/Sink or Midi/*");
+    println!("  --name <pattern>     only list the nodes named like the pattern, * matches any text, --node works too");
+    println!("  --follow             keep discover running and print the ports of the nodes as they appear");
+    println!("  --format <format>    output of list, status and stats: table (default), json or csv");
+    println!("  --web <address>      serve a page to see the graph and turn rules on and off, like 0.0.0.0:8080");
+    println!("  --api <address>      serve the HTTP API for scripts, like 127.0.0.1:8081");
+    println!("  --api-token <file>   token the API requests need, made when missing, defaults to $XDG_RUNTIME_DIR/pw-autoconnect.token");
+    println!("  --webhook <url>      post the links made, failed and removed as JSON to the http:// URL");
+    println!("  --mqtt <broker>      publish the events to the MQTT broker and take commands from it, [user:password@]host:port");
+    println!("  --mqtt-topic <topic> prefix of the MQTT topics, defaults to pw-autoconnect");
+    println!("  --trace-registry     print every global added or removed with all its properties");
+    println!("  --system             run for the system wide PipeWire, the file defaults to {}", SYSTEM_CONFIG);
+    println!("  --write-default-config write a commented example rules file listing the devices to the file, or the default one");
+    println!("  --daemon             run in the background, logging to --log-file and writing the pid to --pid-file");
+    println!("  --pid-file <file>    pid file of --daemon, defaults to $XDG_RUNTIME_DIR/pw-autoconnect.pid");
+    println!("  --log-file <file>    log of --daemon, defaults to $XDG_RUNTIME_DIR/pw-autoconnect.log");
+    println!("  --strict             fail instead of skipping the lines that cannot be used, duplicate rules or rules a never rule removes");
+    println!("  --only-tags <tags>   only load rules with one of the comma separated tags");
+    println!("  --skip-tags <tags>   do not load rules with any of the comma separated tags\n");
+    println!("Scheduled rules are checked every {} seconds\n", SCHEDULE_INTERVAL.as_secs())
+}
+
+/// The arguments miss a value or do not go together
+#[derive(Debug)]
+pub struct UsageError(pub String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+pub const COMMANDS: [&str; 20] = [
+    "init", "suggest", "discover", "expand", "cleanup", "dry-run", "check", "list", "import", "export", "replay", "test", "diff", "reload", "status", "stats", "health",
+    "enable", "disable", "profile",
+];
+
+/// How long to wait for PipeWire on start by default, see --connect-timeout
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Links made per second by default, see --rate-limit
+pub const RATE_LIMIT: u32 = 50;
+
+/// How long --once waits for the rules to be linked by default
+pub const ONCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the main loop may stall before the watchdog exits, see --watchdog
+pub const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Everything given on the command line, the options not given have their defaults
+#[derive(Debug)]
+pub struct Args {
+    // --help, prints the help and nothing else
+    pub help: bool,
+    // -f, prints the names of the nodes as they are found
+    pub find_names: bool,
+    pub linger: bool,
+    pub backend: String,
+    pub filter: TagFilter,
+    // The rules file, or the first file the command takes
+    pub file_name: Option<String>,
+    // One of `COMMANDS`, before the file
+    pub command: Option<String>,
+    pub import_from: Option<String>,
+    pub append: Option<String>,
+    pub export_to: Option<String>,
+    pub record: Option<String>,
+    // --events, or the file replay takes
+    pub events_file: Option<String>,
+    // The graph of --backend sim and test, or the second graph of diff
+    pub graph_file: Option<String>,
+    // The profile of `profile switch`
+    pub profile_name: Option<String>,
+    pub daemon: bool,
+    pub unmatched: bool,
+    pub follow: bool,
+    pub format: Format,
+    pub node_filter: NodeFilter,
+    pub live: bool,
+    pub socket: Option<String>,
+    pub system: bool,
+    pub factory: Option<String>,
+    pub rate_limit: u32,
+    pub watchdog: Option<Duration>,
+    pub once: bool,
+    pub timeout: Duration,
+    pub trace_registry: bool,
+    pub write_config: bool,
+    pub strict: bool,
+    pub runtime_dir: Option<String>,
+    pub connect_retries: u32,
+    pub connect_timeout: Duration,
+    pub web: Option<String>,
+    pub api: Option<String>,
+    pub api_token: Option<std::path::PathBuf>,
+    pub mqtt: Option<String>,
+    pub webhook: Option<webhook::Url>,
+    pub audit_log: Option<String>,
+    pub mqtt_topic: String,
+    pub pid_file: Option<std::path::PathBuf>,
+    pub log_file: Option<std::path::PathBuf>,
+}
+
+/// Reads the arguments, without the name of the binary
+pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Args, Box<dyn std::error::Error>> {
+    let mut help = false;
+    let mut find_names = false;
+    let mut linger = true;
+    let mut backend = String::from("pipewire");
+    let mut filter = TagFilter::default();
+
+    let mut file_name = None;
+    let mut command = None;
+    let mut import_from = None;
+    let mut append = None;
+    let mut export_to = None;
+    let mut record = None;
+    let mut events_file = None;
+    let mut graph_file = None;
+    let mut profile_name = None;
+    let mut daemon = false;
+    let mut unmatched = false;
+    let mut follow = false;
+    let mut format = Format::Table;
+    let mut node_filter = NodeFilter::default();
+    let mut live = false;
+    let mut socket = None;
+    let mut system = false;
+    let mut factory = None;
+    let mut rate_limit = RATE_LIMIT;
+    let mut watchdog = Some(WATCHDOG_TIMEOUT);
+    let mut once = false;
+    let mut timeout = ONCE_TIMEOUT;
+    let mut trace_registry = false;
+    let mut write_config = false;
+    let mut strict = false;
+    let mut runtime_dir = None;
+    let mut connect_retries = 0;
+    let mut connect_timeout = CONNECT_TIMEOUT;
+    let mut web = None;
+    let mut api = None;
+    let mut api_token = None;
+    let mut mqtt = None;
+    let mut webhook = None;
+    let mut audit_log = None;
+    let mut mqtt_topic = String::from("pw-autoconnect");
+    let mut pid_file = None;
+    let mut log_file = None;
+
+    while let Some(a) = args.next() {
+        if COMMANDS.contains(&a.as_str()) && command.is_none() && file_name.is_none() {
+            command = Some(a);
+            continue;
+        }
+
+        if a.eq("--help") || a.eq("-h") {
+            help = true;
+            continue;
+        }
+
+        if a.eq("-f") {
+            println!("-f is deprecated, `pw-autoconnect discover` lists the nodes and ports of the graph as rules");
+            find_names  = true;
+            continue;
+        }
+
+        if a.eq("--from") {
+            match args.next() {
+                Some(format) => import_from = Some(format),
+                None => {
+                    return Err(UsageError("--from requires a format, qjackctl, aj-snapshot or pw-link".to_string()).into());
+                }
+            }
+            continue;
+        }
+
+        if a.eq("--append") {
+            match args.next() {
+                Some(file) => append = Some(file),
+                None => {
+                    return Err(UsageError("--append requires the rules file to add to".to_string()).into());
+                }
+            }
+            continue;
+        }
+
+        if a.eq("--to") {
+            match args.next() {
+                Some(format) => export_to = Some(format),
+                None => {
+                    return Err(UsageError("--to requires a format, aj-snapshot or graph".to_string()).into());
+                }
+            }
+            continue;
+        }
+
+        if a.eq("--backend") {
+            match args.next() {
+                Some(name) => backend = name,
+                None => {
+                    return Err(UsageError("--backend requires a backend name".to_string()).into());
+                }
+            }
+
+            // The simulated graph comes right after the backend
+            if backend.eq("sim") {
+                match args.next() {
+                    Some(file) => graph_file = Some(file),
+                    None => {
+                        return Err(UsageError("--backend sim requires a graph saved with export --to graph".to_string()).into());
+                    }
+                }
+            }
+            continue;
+        }
+
+        if a.eq("--events") {
+            match args.next() {
+                Some(file) => events_file = Some(file),
+                None => {
+                    return Err(UsageError("--events requires a file of registry events".to_string()).into());
+                }
+            }
+            continue;
+        }
+
+        if a.eq("--audit-log") {
+            match args.next() {
+                Some(file) => audit_log = Some(file),
+                None => {
+                    return Err(UsageError("--audit-log requires a file name".to_string()).into());
+                }
+            }
+            continue;
+        }
+
+        if a.eq("--record") {
+            match args.next() {
+                Some(file) => record = Some(file),
+                None => {
+                    return Err(UsageError("--record requires a file name".to_string()).into());
+                }
+            }
+            continue;
+        }
+
+        if a.eq("--socket") {
+            match args.next() {
+                Some(name) => socket = Some(name),
+                None => {
+                    return Err(UsageError("--socket requires a socket name or path".to_string()).into());
+                }
+            }
+            continue;
+        }
+
+        if a.eq("--runtime-dir") {
+            match args.next() {
+                Some(dir) => runtime_dir = Some(dir),
+                None => {
+                    return Err(UsageError("--runtime-dir requires a directory".to_string()).into());
+                }
+            }
+            continue;
+        }
+
+        if a.eq("--connect-timeout") {
+            match args.next().map(|a| a.parse::<u64>()) {
+                Some(Ok(seconds)) => connect_timeout = Duration::from_secs(seconds),
+                _ => {
+                    return Err(UsageError("--connect-timeout requires a number of seconds".to_string()).into());
+                }
+            }
+            continue;
+        }
+
+        if a.eq("--connect-retries") {
+            match args.next().map(|a| a.parse::<u32>()) {
+                Some(Ok(n)) => connect_retries = n,
+                _ => {
+                    return Err(UsageError("--connect-retries requires a number".to_string()).into());
+                }
+            }
+            continue;
+        }
+
+        if a.eq("--factory") {
+            match args.next() {
+                Some(name) => factory = Some(name),
+                None => {
+                    return Err(UsageError("--factory requires a factory name".to_string()).into());
+                }
+            }
+            continue;
+        }
+
+        if a.eq("--rate-limit") {
+            match args.next().map(|a| a.parse::<u32>()) {
+                Some(Ok(n)) if n > 0 => rate_limit = n,
+                _ => {
+                    return Err(UsageError("--rate-limit requires a number of links per second above 0".to_string()).into());
+                }
+            }
+            continue;
+        }
+
+        if a.eq("--once") {
+            once = true;
+            continue;
+        }
+
+        if a.eq("--watchdog") {
+            match args.next().map(|a| a.parse::<u64>()) {
+                Some(Ok(0)) => watchdog = None,
+                Some(Ok(seconds)) => watchdog = Some(Duration::from_secs(seconds)),
+                _ => {
+                    return Err(UsageError("--watchdog requires a number of seconds, 0 to turn it off".to_string()).into());
+                }
+            }
+            continue;
+        }
+
+        if a.eq("--timeout") {
+            match args.next().map(|a| a.parse::<u64>()) {
+                Some(Ok(seconds)) => timeout = Duration::from_secs(seconds),
+                _ => {
+                    return Err(UsageError("--timeout requires a number of seconds".to_string()).into());
+                }
+            }
+            continue;
+        }
+
+        if a.eq("--strict") {
+            strict = true;
+            continue;
+        }
+
+        if a.eq("--write-default-config") {
+            write_config = true;
+            continue;
+        }
+
+        if a.eq("--trace-registry") {
+            trace_registry = true;
+            continue;
+        }
+
+        if a.eq("--api") || a.eq("--api-token") {
+            match args.next() {
+                Some(value) if a.eq("--api") => api = Some(value),
+                Some(value) => api_token = Some(std::path::PathBuf::from(value)),
+                None => {
+                    return Err(UsageError(format!("{} requires {}", a, if a.eq("--api") { "an address, like 127.0.0.1:8081" } else { "a file" })).into());
+                }
+            }
+            continue;
+        }
+
+        if a.eq("--webhook") {
+            match args.next().map(|a| webhook::Url::parse(&a)) {
+                Some(Ok(url)) => webhook = Some(url),
+                Some(Err(e)) => {
+                    return Err(UsageError(format!("invalid --webhook: {}", e)).into());
+                }
+                None => {
+                    return Err(UsageError("--webhook requires a URL, like http://localhost:8080/events".to_string()).into());
+                }
+            }
+            continue;
+        }
+
+        if a.eq("--mqtt") || a.eq("--mqtt-topic") {
+            match args.next() {
+                Some(value) if a.eq("--mqtt") => mqtt = Some(value),
+                Some(value) => mqtt_topic = value,
+                None => {
+                    return Err(UsageError(format!("{} requires {}", a, if a.eq("--mqtt") { "a broker, like localhost:1883" } else { "a topic" })).into());
+                }
+            }
+            continue;
+        }
+
+        if a.eq("--web") {
+            match args.next() {
+                Some(address) => web = Some(address),
+                None => {
+                    return Err(UsageError("--web requires an address, like 0.0.0.0:8080".to_string()).into());
+                }
+            }
+            continue;
+        }
+
+        if a.eq("--system") {
+            system = true;
+            continue;
+        }
+
+        if a.eq("--daemon") {
+            daemon = true;
+            continue;
+        }
+
+        if a.eq("--pid-file") || a.eq("--log-file") {
+            let file = match args.next() {
+                Some(file) => std::path::PathBuf::from(file),
+                None => {
+                    return Err(UsageError(format!("{} requires a file name", a)).into());
+                }
+            };
+
+            if a.eq("--pid-file") {
+                pid_file = Some(file);
+            } else {
+                log_file = Some(file);
+            }
+            continue;
+        }
+
+        if a.eq("--live") {
+            live = true;
+            continue;
+        }
+
+        if a.eq("--format") {
+            match args.next().as_deref().and_then(Format::parse) {
+                Some(a) => format = a,
+                None => {
+                    return Err(UsageError("--format requires table, json or csv".to_string()).into());
+                }
+            }
+            continue;
+        }
+
+        if a.eq("--class") || a.eq("--name") || a.eq("--node") {
+            match args.next() {
+                Some(pattern) if a.eq("--class") => node_filter.classes.push(pattern),
+                Some(pattern) => node_filter.names.push(pattern),
+                None => {
+                    return Err(UsageError(format!("{} requires a pattern", a)).into());
+                }
+            }
+            continue;
+        }
+
+        if a.eq("--unmatched") {
+            unmatched = true;
+            continue;
+        }
+
+        if a.eq("--follow") {
+            follow = true;
+            continue;
+        }
+
+        if a.eq("--no-linger") {
+            linger = false;
+            continue;
+        }
+
+        if a.eq("--only-tags") || a.eq("--skip-tags") {
+            let tags = match args.next() {
+                Some(tags) => parse_tags(&[tags]),
+                None => {
+                    return Err(UsageError(format!("{} requires a list of tags", a)).into());
+                }
+            };
+
+            if a.eq("--only-tags") {
+                filter.only.extend(tags);
+            } else {
+                filter.skip.extend(tags);
+            }
+            continue;
+        }
+
+        // replay takes the events before the rules
+        if command.as_deref() == Some("replay") && events_file.is_none() {
+            events_file = Some(a);
+            continue;
+        }
+
+        // test takes the graph after the rules, and diff the second graph after the first
+        if matches!(command.as_deref(), Some("test" | "diff")) && file_name.is_some() && graph_file.is_none() {
+            graph_file = Some(a);
+            continue;
+        }
+
+        // profile takes switch, then the profile
+        if command.as_deref() == Some("profile") && file_name.is_some() && profile_name.is_none() {
+            profile_name = Some(a);
+            continue;
+        }
+
+        if a.starts_with("--") {
+            return Err(UsageError(format!("unknown option {}, see --help", a)).into());
+        }
+
+        if file_name.is_some() {
+            return Err(UsageError(format!("only one rules file can be given, {} is one too many", a)).into());
+        } else {
+            file_name = Some(a);
+        }
+    }
+
+    Ok(Args {
+        help,
+        find_names,
+        linger,
+        backend,
+        filter,
+        file_name,
+        command,
+        import_from,
+        append,
+        export_to,
+        record,
+        events_file,
+        graph_file,
+        profile_name,
+        daemon,
+        unmatched,
+        follow,
+        format,
+        node_filter,
+        live,
+        socket,
+        system,
+        factory,
+        rate_limit,
+        watchdog,
+        once,
+        timeout,
+        trace_registry,
+        write_config,
+        strict,
+        runtime_dir,
+        connect_retries,
+        connect_timeout,
+        web,
+        api,
+        api_token,
+        mqtt,
+        webhook,
+        audit_log,
+        mqtt_topic,
+        pid_file,
+        log_file,
+    })
+}
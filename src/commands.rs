@@ -0,0 +1,215 @@
+//! The commands the running instance takes from the control socket, the HTTP API, the web page and MQTT
+
+use std::rc::Rc;
+
+use regex::Regex;
+
+use crate::config::{parse_tags, Config};
+use crate::engine::{reload, switch_profile, AppState};
+use crate::graph::{Direction, Graph, GraphBackend, GraphLink, GraphPort, LinkHandle, Node, Port, RegistryEvent};
+#[cfg(feature = "mqtt")]
+use crate::graph::{LINK_TYPE, NODE_TYPE};
+use crate::json;
+use crate::report::{compare, health, stats, status, Format};
+#[cfg(feature = "web")]
+use crate::{report::web_state, web};
+
+/// Runs a command sent to the control socket and returns the answer
+pub fn control_command(
+    command: &str,
+    state: &mut AppState,
+    config: &Config,
+    globals: &[RegistryEvent],
+    backend: &dyn GraphBackend,
+) -> String {
+    let (command, arg) = command.split_once(' ').unwrap_or((command, ""));
+
+    match command {
+        "status" => status(state, config, &Graph::from_globals(globals), Format::parse(arg).unwrap_or(Format::Table)),
+        "stats" => stats(state, Format::parse(arg).unwrap_or(Format::Table)),
+        "health" => health(state, Format::parse(arg).unwrap_or(Format::Table)),
+        "reload" => reload(state, config, globals, backend).unwrap_or_else(|e| format!("Could not reload: {}\n", e)),
+        "connect" | "disconnect" => {
+            connect_ports(arg, command == "connect", &Graph::from_globals(globals), state, backend).unwrap_or_else(|e| format!("Could not {}: {}\n", command, e))
+        }
+        "snapshot" => {
+            let mut out = String::new();
+            json::write(&Graph::from_globals(globals).to_json(), &mut out);
+            out.push('\n');
+            out
+        }
+        "profile" => {
+            let profile = match arg {
+                "" => None,
+                tags => Some(parse_tags(&[tags.to_string()])),
+            };
+
+            switch_profile(state, config, profile, globals, backend).unwrap_or_else(|e| format!("Could not switch the profile: {}\n", e))
+        }
+        "enable" | "disable" => match state.rule_indexes(arg).as_slice() {
+            [] if arg.is_empty() => format!("{} requires the number or the @name of a rule\n", command),
+            [] => format!("Could not {}: no rule named {}\n", command, arg),
+            indexes => indexes
+                .iter()
+                .map(|index| state.set_rule_enabled(*index, command == "enable", backend).unwrap_or_else(|e| format!("Could not {}: {}\n", command, e)))
+                .collect(),
+        },
+        "dry-run" => {
+            let config = Config {
+                path: std::path::PathBuf::from(arg),
+                stdin_rules: None,
+                ..config.clone()
+            };
+
+            match AppState::load(&config, &state.defaults) {
+                Ok(mut rules) => compare(&mut rules, &Graph::from_globals(globals)).0,
+                Err(e) => format!("Could not load {}: {}\n", arg, e),
+            }
+        }
+        _ => format!("Unknown command {}\n", command),
+    }
+}
+
+/// Links or unlinks two ports given like a rule, `[A](out) -> [B](in)`, the link stays after we exit like one made with pw-link
+fn connect_ports(line: &str, connect: bool, graph: &Graph, state: &AppState, backend: &dyn GraphBackend) -> Result<String, String> {
+    lazy_static! {
+        static ref LINK_RE: Regex = Regex::new("^\\s*\\[(?P<node_out>[^\\]]*)\\]\\((?P<port_out>[^)]*)\\)\\s*->\\s*\\[(?P<node_in>[^\\]]*)\\]\\((?P<port_in>[^)]*)\\)\\s*$").unwrap();
+    }
+
+    let caps = LINK_RE.captures(line).ok_or_else(|| format!("expected [node](port) -> [node](port) but got '{}'", line))?;
+
+    let find = |node: &str, port: &str, direction: Direction| {
+        graph.get_port(node, port, direction).ok_or_else(|| graph.find_port(node, port, direction).unwrap_or_else(|| format!("no port {} on [{}]", port, node)))
+    };
+
+    let port_out = find(&caps["node_out"], &caps["port_out"], Direction::Out)?;
+    let port_in = find(&caps["node_in"], &caps["port_in"], Direction::In)?;
+
+    let existing = graph.links.iter().filter(|a| a.port_out == port_out.id && a.port_in == port_in.id).collect::<Vec<&GraphLink>>();
+
+    if !connect {
+        if existing.is_empty() {
+            return Err(format!("{} is not linked", line.trim()));
+        }
+        for link in existing {
+            backend.destroy_link(LinkHandle::Global(link.id))?;
+            state.audit("destroy", Some(link.id), (link.port_out, link.port_in), None, "disconnect command");
+        }
+        return Ok(format!("Disconnected {}\n", line.trim()));
+    }
+
+    if !existing.is_empty() {
+        return Ok(format!("{} is already linked\n", line.trim()));
+    }
+
+    let port = |port: &GraphPort| {
+        let node = graph.nodes.iter().find(|a| a.id == port.node);
+        Port {
+            id: port.id,
+            name: port.name.clone(),
+            node: Rc::new(Node {
+                id: port.node,
+                name: node.map_or_else(String::new, |a| a.name.clone()),
+                kind: node.and_then(|a| a.kind),
+            }),
+        }
+    };
+
+    backend.create_link(&port(port_out), &port(port_in), &[("object.linger".to_string(), "1".to_string())])?;
+    state.audit("create", None, (port_out.id, port_in.id), None, "connect command");
+    Ok(format!("Connected {}\n", line.trim()))
+}
+
+/// The control command of a request to the HTTP API, None when there is no such endpoint
+#[cfg(feature = "web")]
+pub fn api_command(request: &web::Request, config: &Config) -> Option<String> {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/api/status") => Some("status json".to_string()),
+        ("GET", "/api/stats") => Some("stats json".to_string()),
+        ("POST", "/api/reload") => Some("reload".to_string()),
+        ("GET", "/api/dry-run") => Some(format!("dry-run {}", config.path.display())),
+        ("POST", "/api/connect") => Some(format!("connect {}", request.body.trim())),
+        ("POST", "/api/disconnect") => Some(format!("disconnect {}", request.body.trim())),
+        ("GET", "/api/snapshot") => Some("snapshot".to_string()),
+        ("POST", path) => match path.strip_prefix("/api/rules/").and_then(|a| a.split_once('/')) {
+            Some((index, action @ ("enable" | "disable"))) => Some(format!("{} {}", action, index)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// What the events topic gets for a node or link added or removed, as JSON, None for the other globals
+#[cfg(feature = "mqtt")]
+pub fn mqtt_event(event: &RegistryEvent, added: bool, globals: &[RegistryEvent]) -> Option<String> {
+    let (id, kind, props) = match event {
+        RegistryEvent::Global { id, kind, props: Some(props) } => (*id, kind, props),
+        _ => return None,
+    };
+
+    let get = |id: &str| {
+        globals.iter().find_map(|a| match a {
+            RegistryEvent::Global { id: global, props: Some(props), .. } if global.to_string() == id => Some(props),
+            _ => None,
+        })
+    };
+    let port_name = |key: &str| {
+        let port = get(props.get(key)?)?;
+        let node = get(port.get("node.id")?)?;
+        Some(format!("[{}]{}", node.get("node.name")?, port.get("port.name")?))
+    };
+
+    let (kind, name) = if kind.eq(NODE_TYPE) {
+        ("node", props.get("node.name")?.clone())
+    } else if kind.eq(LINK_TYPE) {
+        let name = match (port_name("link.output.port"), port_name("link.input.port")) {
+            (Some(port_out), Some(port_in)) => format!("{} -> {}", port_out, port_in),
+            _ => format!("link {}", id),
+        };
+        ("link", name)
+    } else {
+        return None;
+    };
+
+    let mut out = String::new();
+    json::write(
+        &json::Value::Object(vec![
+            ("event".to_string(), json::Value::String(if added { "added" } else { "removed" }.to_string())),
+            ("type".to_string(), json::Value::String(kind.to_string())),
+            ("id".to_string(), json::Value::Number(id as f64)),
+            ("name".to_string(), json::Value::String(name)),
+        ]),
+        &mut out,
+    );
+    Some(out)
+}
+
+/// Answers the requests of the web page, turning rules on and off goes through the control commands
+#[cfg(feature = "web")]
+pub fn web_request(
+    request: &web::Request,
+    state: &mut AppState,
+    config: &Config,
+    globals: &[RegistryEvent],
+    backend: &dyn GraphBackend,
+) -> (&'static str, &'static str, String) {
+    let not_found = ("404 Not Found", "text/plain; charset=utf-8", "Not found\n".to_string());
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/") => ("200 OK", "text/html; charset=utf-8", web::PAGE.to_string()),
+        ("GET", "/api/graph") => ("200 OK", "application/json", web_state(state, &Graph::from_globals(globals))),
+        ("POST", _) if !request.is_same_origin() => ("403 Forbidden", "text/plain; charset=utf-8", "Forbidden\n".to_string()),
+        ("POST", path) => match path.strip_prefix("/api/rules/").and_then(|a| a.split_once('/')) {
+            Some((index, action @ ("enable" | "disable"))) => {
+                let answer = control_command(&format!("{} {}", action, index), state, config, globals, backend);
+                ("200 OK", "text/plain; charset=utf-8", answer)
+            }
+            _ => not_found,
+        },
+        _ => not_found,
+    }
+}
+
+/// The control commands the MQTT broker can run, anyone allowed to publish to it can send them
+#[cfg(feature = "mqtt")]
+pub const MQTT_COMMANDS: &[&str] = &["profile", "enable", "disable", "reload", "status"];
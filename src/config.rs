@@ -0,0 +1,1625 @@
+//! The rules file: the rules and their options, and the parser that reads them into `Rules`
+
+use std::{cell::Cell, collections::HashMap, env, fmt, fs, io::BufRead, rc::Rc, time::Duration};
+
+use regex::{Regex, RegexSet};
+
+use crate::graph::{Direction, Latency, NodeKind, PortProps};
+use crate::matcher::{glob_matches, glob_regex, PortPattern};
+use crate::{filter_chain, webhook};
+
+/// Placeholders for the default devices and the keys of the `default` metadata they follow
+pub const DEFAULTS: [(&str, &str); 2] = [("@DEFAULT_SINK@", "default.audio.sink"), ("@DEFAULT_SOURCE@", "default.audio.source")];
+
+/// Port name that stands for the left and right ports of a node
+pub const STEREO: &str = "stereo";
+/// Port name that stands for every port of a node, paired by `audio.channel`
+pub const CHANNELS: &str = "channels";
+
+/// Silent source we create for `@keep-alive`, linked to the inputs that should not suspend
+pub const KEEP_ALIVE_NODE: &str = "pw-autoconnect.keep-alive";
+pub const KEEP_ALIVE_PORT: &str = "capture_MONO";
+
+/// How long `@enforce` waits before making a removed link again, when the rule does not say
+pub const ENFORCE_DELAY: Duration = Duration::from_millis(1000);
+/// How often the `@schedule` rules are checked
+pub const SCHEDULE_INTERVAL: Duration = Duration::from_secs(30);
+
+pub const SYSTEM_CONFIG: &str = "/etc/pw-autoconnect.conf";
+pub const SYSTEM_SOCKET: &str = "/run/pipewire/pipewire-0";
+
+#[derive(Debug)]
+pub struct NodeDef {
+    pub name: String,
+}
+
+#[derive(Debug)]
+pub struct PortDef {
+    pub node: Rc<NodeDef>,
+    pub name: String,
+}
+
+impl PortDef {
+    /// Both rules name the same port of the same node
+    pub fn is(&self, other: &PortDef) -> bool {
+        self.name.eq(&other.name) && self.node.name.eq(&other.node.name)
+    }
+}
+
+#[derive(Debug)]
+pub struct LinkDef {
+    pub port_in: Rc<PortDef>,
+    pub port_out: Rc<PortDef>,
+    pub options: RuleOptions,
+    pub active: Cell<bool>,
+    // Failed attempts in a row, the rule is paused when it reaches `@max-attempts`
+    pub failures: Cell<u32>,
+    // The stereo, channels or map rule this link was expanded from
+    pub expanded_from: Option<String>,
+    // Turned off with the `disable` control command, until the rules are loaded again
+    pub disabled: Cell<bool>,
+}
+
+impl LinkDef {
+    /// The links of the rule have to be kept to remove them later
+    pub fn is_tracked(&self, linger: bool) -> bool {
+        // Without linger the link only lives as long as its proxy
+        !linger || self.options.schedule.is_some() || self.options.priority.is_some() || self.options.follows_default || self.options.enforce.is_some()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.options.max_attempts.is_some_and(|a| self.failures.get() >= a)
+    }
+
+    pub fn label(&self) -> String {
+        self.options.label(self)
+    }
+}
+
+/// Options given to a rule with `@option value...`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RuleOptions {
+    // Given with @name, shown with the rule in the output and set on its links
+    pub name: Option<String>,
+    pub tags: Vec<String>,
+    pub schedule: Option<Schedule>,
+    // Rules with a priority want exclusive use of their input port
+    pub priority: Option<i32>,
+    // Extra properties for the created links, these override the defaults
+    pub props: Vec<(String, String)>,
+    // Only link once both nodes are done being created
+    pub wait_ready: bool,
+    // Keep the input node from suspending, with a link from our silent source
+    pub keep_alive: bool,
+    // Written with @DEFAULT_SINK@ or @DEFAULT_SOURCE@, its links move when the default changes
+    pub follows_default: bool,
+    // Only link once both nodes have been there this long, for devices that drop and come back
+    pub grace_period: Option<Duration>,
+    // Failed attempts in a row after which the rule stops trying
+    pub max_attempts: Option<u32>,
+    // Link even when the audio would come back to where it came from
+    pub allow_cycle: bool,
+    // Remove the other links into the input port before linking
+    pub exclusive_input: bool,
+    // Remove the links of the output port to other nodes before linking, to move a stream
+    pub move_output: bool,
+    // Only link when the output and input nodes are of this kind
+    pub from: Option<NodeKind>,
+    pub to: Option<NodeKind>,
+    // Stereo, channels and map rules leave out the monitor ports
+    pub ignore_monitors: bool,
+    // Commands run when a link of the rule shows up in the graph and when it goes away
+    pub on_link: Option<String>,
+    pub on_unlink: Option<String>,
+    // Quantum the graph is forced to while a link of the rule is there
+    pub latency: Option<Latency>,
+    // Make the links again after this long when someone else removes them
+    pub enforce: Option<Duration>,
+    // Stereo, channels and map rules only take the ports going these ways with `port.physical` or `port.terminal`
+    pub physical: Vec<Direction>,
+    pub terminal: Vec<Direction>,
+    // With --once, exit with an error when the rule is not linked in time
+    pub required: bool,
+    // Never linked on its own, only once turned on with `enable`
+    pub manual: bool,
+}
+
+impl RuleOptions {
+    pub fn parse(opts: &str) -> Result<RuleOptions, String> {
+        let mut options = RuleOptions::default();
+
+        for (name, values) in parse_options(opts)? {
+            match name.as_str() {
+                "name" => match values.as_slice() {
+                    [name] => options.name = Some(name.clone()),
+                    _ => return Err("@name expects one word, like mic-to-daw".to_string()),
+                },
+                "tag" => options.tags.extend(parse_tags(&values)),
+                "schedule" => options.schedule = Some(Schedule::parse(&values)?),
+                "priority" => match values.first().map(|a| a.parse::<i32>()) {
+                    Some(Ok(p)) => options.priority = Some(p),
+                    _ => return Err("@priority expects a number".to_string()),
+                },
+                "prop" => {
+                    for value in values {
+                        match value.split_once('=') {
+                            Some((key, _)) if key.starts_with("link.output.") || key.starts_with("link.input.") => {
+                                return Err(format!("{} is set by the rule itself", key))
+                            }
+                            Some((key, value)) if !key.is_empty() => options.props.push((key.to_string(), value.to_string())),
+                            _ => return Err(format!("@prop expects key=value but got '{}'", value)),
+                        }
+                    }
+                }
+                "wait-ready" => options.wait_ready = true,
+                "max-attempts" => match values.first().map(|a| a.parse::<u32>()) {
+                    Some(Ok(n)) if n > 0 => options.max_attempts = Some(n),
+                    _ => return Err("@max-attempts expects a number above 0".to_string()),
+                },
+                "grace-period" => match values.first().map(|a| a.parse::<u64>()) {
+                    Some(Ok(ms)) => options.grace_period = Some(Duration::from_millis(ms)),
+                    _ => return Err("@grace-period expects a number of milliseconds".to_string()),
+                },
+                "keep-alive" => options.keep_alive = true,
+                "allow-cycle" => options.allow_cycle = true,
+                "exclusive-input" => options.exclusive_input = true,
+                "move" => options.move_output = true,
+                "ignore-monitors" => options.ignore_monitors = true,
+                "required" => options.required = true,
+                "manual" => options.manual = true,
+                "enforce" => match values.first().map(|a| a.parse::<u64>()) {
+                    None => options.enforce = Some(ENFORCE_DELAY),
+                    Some(Ok(ms)) => options.enforce = Some(Duration::from_millis(ms)),
+                    Some(Err(_)) => return Err("@enforce expects a number of milliseconds".to_string()),
+                },
+                "latency" => match values.first().map(|a| Latency::parse(a)) {
+                    Some(Some(latency)) => options.latency = Some(latency),
+                    _ => return Err("@latency expects a quantum, like 128 or 128/48000".to_string()),
+                },
+                "on-link" | "on-unlink" => {
+                    if values.is_empty() {
+                        return Err(format!("@{} expects a command", name));
+                    }
+                    if name == "on-link" {
+                        options.on_link = Some(values.join(" "));
+                    } else {
+                        options.on_unlink = Some(values.join(" "));
+                    }
+                }
+                "physical" | "terminal" => {
+                    let directions = match values.first().map(|a| Direction::parse(a)) {
+                        None => vec![Direction::Out, Direction::In],
+                        Some(Some(direction)) => vec![direction],
+                        Some(None) => return Err(format!("@{} expects out, in or nothing for both", name)),
+                    };
+                    if name == "physical" {
+                        options.physical = directions;
+                    } else {
+                        options.terminal = directions;
+                    }
+                }
+                "from" | "to" => {
+                    let kind = match values.first().and_then(|a| NodeKind::parse(a)) {
+                        Some(kind) => kind,
+                        None => return Err(format!("@{} expects hardware, stream or virtual", name)),
+                    };
+                    if name == "from" {
+                        options.from = Some(kind);
+                    } else {
+                        options.to = Some(kind);
+                    }
+                }
+                _ => return Err(format!("unknown option @{}", name)),
+            }
+        }
+
+        Ok(options)
+    }
+
+    /// The rule followed by its `@name`, for the output
+    pub fn label(&self, rule: impl fmt::Display) -> String {
+        match &self.name {
+            Some(name) => format!("{} ({})", rule, name),
+            None => rule.to_string(),
+        }
+    }
+
+    /// The port passes `@physical` and `@terminal`, a port without a direction is checked against both
+    pub fn allows_port(&self, props: &PortProps) -> bool {
+        let applies = |directions: &[Direction]| directions.iter().any(|a| props.direction.is_none_or(|b| b == *a));
+
+        (props.physical || !applies(&self.physical)) && (props.terminal || !applies(&self.terminal))
+    }
+
+    pub fn is_scheduled_now(&self) -> bool {
+        match &self.schedule {
+            Some(schedule) => {
+                let (day, minute) = local_time();
+                schedule.is_active(day, minute)
+            }
+            None => true,
+        }
+    }
+}
+
+impl fmt::Display for LinkDef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}]{} -> [{}]{}", self.port_out.node.name, self.port_out.name, self.port_in.node.name, self.port_in.name)
+    }
+}
+
+/// Rule connecting every port of two nodes that matches one of the port patterns,
+/// `[Mixer] -> [Recorder] map { out_{n} -> in_{n} }`
+#[derive(Debug)]
+pub struct MapDef {
+    pub node_out: Rc<NodeDef>,
+    pub node_in: Rc<NodeDef>,
+    pub ports: Vec<(PortPattern, PortPattern)>,
+    pub options: RuleOptions,
+}
+
+impl fmt::Display for MapDef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ports = self
+            .ports
+            .iter()
+            .map(|(out, input)| format!("{} -> {}", out.template, input.template))
+            .collect::<Vec<String>>();
+        write!(f, "[{}] -> [{}] map {{ {} }}", self.node_out.name, self.node_in.name, ports.join(", "))
+    }
+}
+
+/// The port patterns of all the map rules in one RegexSet, so a port is checked against every map at once
+pub struct MapMatcher {
+    pub set: RegexSet,
+    // Map and pair of patterns of every regex in the set, true for the output side
+    pub patterns: Vec<(Rc<MapDef>, usize, bool)>,
+}
+
+impl MapMatcher {
+    pub fn new(maps: &[Rc<MapDef>]) -> MapMatcher {
+        let mut regexes = Vec::new();
+        let mut patterns = Vec::new();
+
+        for map in maps {
+            for (i, (out, port_in)) in map.ports.iter().enumerate() {
+                regexes.push(out.regex.as_str().to_string());
+                patterns.push((map.clone(), i, true));
+                regexes.push(port_in.regex.as_str().to_string());
+                patterns.push((map.clone(), i, false));
+            }
+        }
+
+        // Every pattern already compiled on its own
+        MapMatcher { set: RegexSet::new(regexes).unwrap(), patterns }
+    }
+
+    /// Returns the links of the maps the port is one end of, as (map, port_out, port_in)
+    pub fn matches(&self, node: &str, port: &str) -> Vec<(Rc<MapDef>, String, String)> {
+        self.set
+            .matches(port)
+            .into_iter()
+            .filter_map(|i| {
+                let (map, pair, is_out) = &self.patterns[i];
+                let (out, port_in) = &map.ports[*pair];
+
+                if *is_out && map.node_out.name.eq(node) {
+                    out.translate(port, port_in).map(|target| (map.clone(), port.to_string(), target))
+                } else if !*is_out && map.node_in.name.eq(node) {
+                    port_in.translate(port, out).map(|source| (map.clone(), source, port.to_string()))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// Rule linking the left and right ports of two nodes, `[A](stereo) -> [B](stereo)`,
+/// or every port with the same `audio.channel` with `[A](channels) -> [B](channels)`.
+/// `[A] -> [B]` is a channels rule that also pairs the ports without a channel by their name
+#[derive(Debug)]
+pub struct StereoDef {
+    pub node_out: Rc<NodeDef>,
+    pub node_in: Rc<NodeDef>,
+    pub surround: bool,
+    pub by_name: bool,
+    pub options: RuleOptions,
+}
+
+impl fmt::Display for StereoDef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.by_name {
+            return write!(f, "[{}] -> [{}]", self.node_out.name, self.node_in.name);
+        }
+
+        let port = if self.surround { CHANNELS } else { STEREO };
+        write!(f, "[{}]{} -> [{}]{}", self.node_out.name, port, self.node_in.name, port)
+    }
+}
+
+/// Links removed as soon as they show up, `never [Mic] -> [Speakers]`, the ports are any when not given.
+/// The names can use `*`
+#[derive(Debug)]
+pub struct NeverDef {
+    pub node_out: String,
+    pub port_out: Option<String>,
+    pub node_in: String,
+    pub port_in: Option<String>,
+    // The names compiled, every link in the graph is checked against them
+    patterns: [Option<Regex>; 4],
+}
+
+impl NeverDef {
+    pub fn new(node_out: String, port_out: Option<String>, node_in: String, port_in: Option<String>) -> Result<NeverDef, regex::Error> {
+        let compile = |a: Option<&String>| a.map(|a| glob_regex(a)).transpose();
+        let patterns = [compile(Some(&node_out))?, compile(port_out.as_ref())?, compile(Some(&node_in))?, compile(port_in.as_ref())?];
+
+        Ok(NeverDef { node_out, port_out, node_in, port_in, patterns })
+    }
+
+    pub fn matches(&self, node_out: &str, port_out: &str, node_in: &str, port_in: &str) -> bool {
+        self.patterns.iter().zip([node_out, port_out, node_in, port_in]).all(|(pattern, name)| pattern.as_ref().is_none_or(|a| a.is_match(name)))
+    }
+}
+
+impl fmt::Display for NeverDef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let port = |a: &Option<String>| a.as_ref().map_or(String::new(), |a| format!("({})", a));
+        write!(f, "never [{}]{} -> [{}]{}", self.node_out, port(&self.port_out), self.node_in, port(&self.port_in))
+    }
+}
+
+/// Profile set on a device when it shows up, `[alsa_card.usb-Focusrite*] profile pro-audio`.
+/// The device is matched by its name or description, which can use `*`
+#[derive(Debug)]
+pub struct ProfileDef {
+    pub device: String,
+    pub profile: String,
+}
+
+impl ProfileDef {
+    pub fn matches(&self, props: &HashMap<String, String>) -> bool {
+        ["device.name", "device.description"]
+            .iter()
+            .any(|key| props.get(*key).is_some_and(|a| glob_matches(&self.device, a)))
+    }
+}
+
+impl fmt::Display for ProfileDef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] profile {}", self.device, self.profile)
+    }
+}
+
+/// Settings of a node applied when it shows up, `[USB Interface] props monitor.channel-volumes=true`
+/// or `[USB Interface] volume 40% unmute`
+#[derive(Debug)]
+pub struct NodeSettings {
+    pub node: Rc<NodeDef>,
+    pub props: Vec<(String, String)>,
+    // In percent, like pavucontrol and wpctl show it
+    pub volume: Option<f32>,
+    pub mute: Option<bool>,
+}
+
+impl NodeSettings {
+    /// The `Props` param in the SPA JSON of `pw-cli set-param`, `channels` is `audio.channels` of the node
+    pub fn pod(&self, channels: Option<usize>) -> String {
+        let mut fields = Vec::new();
+
+        if !self.props.is_empty() {
+            let params = self
+                .props
+                .iter()
+                .map(|(key, value)| format!("{} {}", spa_value(key), spa_value(value)))
+                .collect::<Vec<String>>();
+            fields.push(format!("params = [ {} ]", params.join(" ")));
+        }
+
+        if let Some(volume) = self.volume {
+            // The sliders are cubic, PipeWire takes the linear gain
+            let gain = (volume / 100.0).powi(3);
+            match channels {
+                Some(channels) => fields.push(format!("channelVolumes = [ {} ]", vec![gain.to_string(); channels].join(" "))),
+                None => fields.push(format!("volume = {}", gain)),
+            }
+        }
+
+        if let Some(mute) = self.mute {
+            fields.push(format!("mute = {}", mute));
+        }
+
+        format!("{{ {} }}", fields.join(" "))
+    }
+}
+
+impl fmt::Display for NodeSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}]", self.node.name)?;
+        if !self.props.is_empty() {
+            let props = self.props.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<String>>();
+            write!(f, " props {}", props.join(" "))?;
+        }
+        if let Some(volume) = self.volume {
+            write!(f, " volume {}%", volume)?;
+        }
+        match self.mute {
+            Some(true) => write!(f, " mute"),
+            Some(false) => write!(f, " unmute"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Numbers and booleans as they are, anything else as a quoted string
+pub fn spa_value(value: &str) -> String {
+    if value == "true" || value == "false" || value.parse::<f64>().is_ok() {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// Time window in which a rule is active, set with `@schedule`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    // Minutes since midnight
+    pub start: u32,
+    pub end: u32,
+    // Days of the week, 0 is sunday. Empty means every day
+    pub days: Vec<u32>,
+}
+
+impl Schedule {
+    pub fn parse(values: &[String]) -> Result<Schedule, String> {
+        fn parse_time(time: &str) -> Result<u32, String> {
+            let (hours, minutes) = time
+                .split_once(':')
+                .ok_or_else(|| format!("invalid time '{}', expected HH:MM", time))?;
+
+            match (hours.parse::<u32>(), minutes.parse::<u32>()) {
+                (Ok(hours), Ok(minutes)) if hours <= 24 && minutes < 60 && hours * 60 + minutes <= 24 * 60 => {
+                    Ok(hours * 60 + minutes)
+                }
+                _ => Err(format!("invalid time '{}', expected HH:MM", time)),
+            }
+        }
+
+        const DAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+        let window = values
+            .first()
+            .ok_or_else(|| "@schedule requires a time window like 22:00-07:00".to_string())?;
+
+        let (start, end) = window
+            .split_once('-')
+            .ok_or_else(|| format!("invalid time window '{}', expected HH:MM-HH:MM", window))?;
+
+        let mut days = Vec::new();
+
+        for day in values[1..].iter().flat_map(|a| a.split(',')).filter(|a| !a.is_empty()) {
+            match DAYS.iter().position(|a| a.eq_ignore_ascii_case(day)) {
+                Some(day) => days.push(day as u32),
+                None => return Err(format!("invalid day '{}', expected one of {}", day, DAYS.join(","))),
+            }
+        }
+
+        Ok(Schedule {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+            days,
+        })
+    }
+
+    /// Windows that cross midnight (22:00-07:00) are checked against the current day
+    pub fn is_active(&self, day: u32, minute: u32) -> bool {
+        if !self.days.is_empty() && !self.days.contains(&day) {
+            return false;
+        }
+
+        if self.start <= self.end {
+            minute >= self.start && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/// Returns the current local day of the week (0 is sunday) and minutes since midnight
+pub fn local_time() -> (u32, u32) {
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        (tm.tm_wday as u32, (tm.tm_hour * 60 + tm.tm_min) as u32)
+    }
+}
+
+pub fn unix_time() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |a| a.as_secs())
+}
+
+/// The unix time as a local `2024-05-01 18:30:00`
+pub fn local_timestamp(time: u64) -> String {
+    unsafe {
+        let time = time as libc::time_t;
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&time, &mut tm);
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            tm.tm_year + 1900,
+            tm.tm_mon + 1,
+            tm.tm_mday,
+            tm.tm_hour,
+            tm.tm_min,
+            tm.tm_sec
+        )
+    }
+}
+
+impl LinkDef {
+    pub fn is_scheduled_now(&self) -> bool {
+        self.options.is_scheduled_now()
+    }
+}
+
+/// Which rules to load, based on the tags given with `@tag`
+#[derive(Debug, Default, Clone)]
+pub struct TagFilter {
+    pub only: Vec<String>,
+    pub skip: Vec<String>,
+}
+
+impl TagFilter {
+    pub fn allows(&self, tags: &[String]) -> bool {
+        if !self.only.is_empty() && !tags.iter().any(|t| self.only.contains(t)) {
+            return false;
+        }
+
+        !tags.iter().any(|t| self.skip.contains(t))
+    }
+}
+
+/// Parses the `@option value...` annotations that can follow a rule
+pub fn parse_options(opts: &str) -> Result<Vec<(String, Vec<String>)>, String> {
+    let mut options: Vec<(String, Vec<String>)> = Vec::new();
+
+    for word in opts.split_whitespace() {
+        if let Some(name) = word.strip_prefix('@') {
+            options.push((name.to_string(), Vec::new()));
+        } else if let Some((_, values)) = options.last_mut() {
+            values.push(word.to_string());
+        } else {
+            return Err(format!("expected an @option but got '{}'", word));
+        }
+    }
+
+    Ok(options)
+}
+
+pub fn parse_tags(values: &[String]) -> Vec<String> {
+    values
+        .iter()
+        .flat_map(|a| a.split(','))
+        .filter(|a| !a.is_empty())
+        .map(|a| a.to_string())
+        .collect()
+}
+
+/// `volume 40%`, `mute` and `unmute` in any order
+pub fn parse_volume(settings: &str) -> Result<(Option<f32>, Option<bool>), String> {
+    let (mut volume, mut mute) = (None, None);
+    let mut words = settings.split_whitespace();
+
+    while let Some(word) = words.next() {
+        match word {
+            "volume" => match words.next().and_then(|a| a.strip_suffix('%')).map(|a| a.parse::<f32>()) {
+                Some(Ok(percent)) if percent >= 0.0 => volume = Some(percent),
+                _ => return Err("volume expects a percentage, like 40%".to_string()),
+            },
+            "mute" => mute = Some(true),
+            "unmute" => mute = Some(false),
+            _ => return Err(format!("expected volume, mute or unmute but got '{}'", word)),
+        }
+    }
+
+    Ok((volume, mute))
+}
+
+/// The node name of `[name]`
+pub fn bracketed(text: &str) -> Option<&str> {
+    text.trim().strip_prefix('[')?.strip_suffix(']').filter(|a| !a.is_empty())
+}
+
+pub fn get_node_def(node_def: &mut HashMap<String, Rc<NodeDef>>, name: &str) -> Rc<NodeDef> {
+    node_def
+        .entry(name.to_string())
+        .or_insert_with(|| Rc::new(NodeDef { name: name.to_string() }))
+        .clone()
+}
+
+pub fn get_port_def_entry(
+    node_def: &mut HashMap<String, Rc<NodeDef>>,
+    port_def: &mut HashMap<(String, String), Rc<PortDef>>,
+    node: &str,
+    port: &str,
+) -> Rc<PortDef> {
+    let node = get_node_def(node_def, node);
+
+    port_def
+        .entry((node.name.clone(), port.to_string()))
+        .or_insert_with(|| Rc::new(PortDef { node, name: port.to_string() }))
+        .clone()
+}
+
+/// Adds the rule linking the ports, and its keep alive link
+pub fn push_link_def(
+    node_def: &mut HashMap<String, Rc<NodeDef>>,
+    port_def: &mut HashMap<(String, String), Rc<PortDef>>,
+    link_def: &mut Vec<Rc<LinkDef>>,
+    (node_out, port_out): (&str, &str),
+    (node_in, port_in): (&str, &str),
+    options: RuleOptions,
+) {
+    let port_out = get_port_def_entry(node_def, port_def, node_out, port_out);
+    let port_in = get_port_def_entry(node_def, port_def, node_in, port_in);
+
+    if options.keep_alive {
+        let keep_alive = get_port_def_entry(node_def, port_def, KEEP_ALIVE_NODE, KEEP_ALIVE_PORT);
+
+        // Only the schedule of the rule applies to its keep alive link, and it is off with a @manual rule
+        let manual = options.manual;
+        let options = RuleOptions { schedule: options.schedule.clone(), ..Default::default() };
+
+        let link = LinkDef {
+            port_out: keep_alive,
+            port_in: port_in.clone(),
+            options,
+            active: Cell::new(true),
+            failures: Cell::new(0),
+            expanded_from: None,
+            disabled: Cell::new(manual),
+        };
+        link.active.set(!link.disabled.get() && link.is_scheduled_now());
+        link_def.push(Rc::new(link));
+    }
+
+    let link = LinkDef {
+        port_out,
+        port_in,
+        active: Cell::new(true),
+        failures: Cell::new(0),
+        expanded_from: None,
+        disabled: Cell::new(options.manual),
+        options,
+    };
+    link.active.set(!link.disabled.get() && link.is_scheduled_now());
+    link_def.push(Rc::new(link));
+}
+
+/// How the rules were loaded, to load them again on reload
+#[derive(Clone)]
+pub struct Config {
+    pub path: std::path::PathBuf,
+    pub get_names: bool,
+    pub linger: bool,
+    pub filter: TagFilter,
+    // --socket, takes the place of the one in the file
+    pub socket: Option<String>,
+    // --system, running as a service for the system wide PipeWire
+    pub system: bool,
+    // --factory, instead of the link factory found in the registry
+    pub factory: Option<String>,
+    // --rate-limit, links made per second
+    pub rate_limit: u32,
+    // --trace-registry, print every global added or removed with its properties
+    pub trace_registry: bool,
+    // --web, address of the web page
+    pub web: Option<String>,
+    // --api, address of the HTTP API, with the file of its token given with --api-token
+    pub api: Option<(String, std::path::PathBuf)>,
+    // --mqtt, broker to publish the events to and take commands from, with the topic prefix of --mqtt-topic
+    pub mqtt: Option<(String, String)>,
+    // --webhook, URL the events of the links are posted to
+    pub webhook: Option<webhook::Url>,
+    // --once, exit once every rule is linked or after the --timeout
+    pub once: Option<Duration>,
+    // --watchdog, how long the main loop may stall before we exit, None with 0
+    pub watchdog: Option<Duration>,
+    // The rules read from stdin when the file is `-`, kept to load them again on reload
+    pub stdin_rules: Option<String>,
+    // --strict, a line that cannot be used fails the loading instead of being skipped
+    pub strict: bool,
+    // --connect-retries, times the connection is tried again while PipeWire is not there yet
+    pub connect_retries: u32,
+    // --connect-timeout, how long to keep trying to connect on start
+    pub connect_timeout: Duration,
+}
+
+impl Config {
+    /// The config loading only the rules with one of the tags of the profile
+    pub fn with_profile(&self, profile: Option<&[String]>) -> Config {
+        match profile {
+            Some(tags) => Config {
+                filter: TagFilter { only: tags.to_vec(), skip: self.filter.skip.clone() },
+                ..self.clone()
+            },
+            None => self.clone(),
+        }
+    }
+
+    /// Loads the rules, with the default devices known so far
+    pub fn load(&self, defaults: &HashMap<String, String>) -> Result<Rules, Box<dyn std::error::Error>> {
+        let mut rules = match &self.stdin_rules {
+            Some(rules) => parse_rules(rules.as_bytes(), &self.filter, defaults)?,
+            None => parse_file(self.path.clone(), &self.filter, defaults)?,
+        };
+        if self.strict && !rules.problems.is_empty() {
+            return Err(format!("{} problems in {}, --strict does not skip them", rules.problems.len(), self.path.display()).into());
+        }
+        if self.socket.is_some() {
+            rules.socket = self.socket.clone();
+        } else if rules.socket.is_none() && self.system {
+            rules.socket = system_socket();
+        }
+        Ok(rules)
+    }
+}
+
+/// Without a user session there is no `$XDG_RUNTIME_DIR` to find the socket in, use the one of the system instance
+/// unless PipeWire was told where to look
+pub fn system_socket() -> Option<String> {
+    if env::var_os("PIPEWIRE_REMOTE").is_some() || env::var_os("PIPEWIRE_RUNTIME_DIR").is_some() {
+        return None;
+    }
+
+    Some(SYSTEM_SOCKET.to_string())
+}
+
+/// Everything a rules file declares, before it is applied to a graph
+pub struct Rules {
+    pub node_def: Vec<Rc<NodeDef>>,
+    // A `@keep-alive` rule is followed by the link that keeps its input from suspending
+    pub link_def: Vec<Rc<LinkDef>>,
+    pub port_def: Vec<Rc<PortDef>>,
+    pub map_def: Vec<Rc<MapDef>>,
+    pub stereo_def: Vec<Rc<StereoDef>>,
+    // Names of the sinks of the `combine` rules
+    pub combine_sinks: Vec<String>,
+    pub filters: Vec<filter_chain::FilterDef>,
+    pub node_settings: Vec<Rc<NodeSettings>>,
+    pub never_def: Vec<NeverDef>,
+    pub profile_def: Vec<ProfileDef>,
+    pub policy: Option<(Rc<NodeDef>, RuleOptions)>,
+    pub routes: Vec<(String, Rc<NodeDef>, RuleOptions)>,
+    // `@socket`, or --socket and --system once loaded through `Config`
+    pub socket: Option<String>,
+    pub client_props: Vec<(String, String)>,
+    pub ignore_monitors: bool,
+    // Some rule follows @DEFAULT_SINK@ or @DEFAULT_SOURCE@
+    pub uses_defaults: bool,
+    // Lines that could not be used
+    pub problems: Vec<String>,
+}
+
+pub struct Template {
+    pub params: Vec<String>,
+    pub lines: Vec<String>,
+}
+
+/// Parses `name(A, B)` into the name and its comma separated arguments
+pub fn parse_call(call: &str) -> Result<(String, Vec<String>), String> {
+    let (name, args) = call
+        .trim()
+        .strip_suffix(')')
+        .and_then(|a| a.split_once('('))
+        .ok_or_else(|| format!("expected 'name(...)' but got '{}'", call.trim()))?;
+
+    let args = args.split(',').map(|a| a.trim().to_string()).filter(|a| !a.is_empty()).collect();
+
+    Ok((name.trim().to_string(), args))
+}
+
+/// Replaces the `@template name(PARAMS)` ... `@end` blocks and their `@use name(args)` lines with the
+/// lines of the template, where every parameter is replaced by its argument
+pub fn expand_templates(lines: Vec<String>, problems: &mut Vec<String>) -> Vec<String> {
+    macro_rules! invalid {
+        ($($arg:tt)*) => {{
+            let problem = format!($($arg)*);
+            println!("invalid line: {}", problem);
+            problems.push(problem);
+        }};
+    }
+
+    let mut templates: HashMap<String, Template> = HashMap::new();
+    let mut defining: Option<(String, Template)> = None;
+    let mut out = Vec::new();
+
+    for line in lines {
+        if let Some((_, template)) = defining.as_mut() {
+            if line.trim() == "@end" {
+                let (name, template) = defining.take().unwrap();
+                println!("Found template: {}({}) with {} rules", name, template.params.join(", "), template.lines.len());
+                templates.insert(name, template);
+            } else {
+                template.lines.push(line);
+            }
+            continue;
+        }
+
+        if let Some(call) = line.trim().strip_prefix("@template") {
+            match parse_call(call) {
+                Ok((name, params)) => defining = Some((name, Template { params, lines: Vec::new() })),
+                Err(e) => invalid!("{} ({})", line, e),
+            }
+            continue;
+        }
+
+        let call = match line.trim().strip_prefix("@use") {
+            Some(call) => call,
+            None => {
+                out.push(line);
+                continue;
+            }
+        };
+
+        let (name, args) = match parse_call(call) {
+            Ok(call) => call,
+            Err(e) => {
+                invalid!("{} ({})", line, e);
+                continue;
+            }
+        };
+
+        let template = match templates.get(&name) {
+            Some(template) => template,
+            None => {
+                invalid!("{} (no template named {} before it)", line, name);
+                continue;
+            }
+        };
+
+        if args.len() != template.params.len() {
+            invalid!("{} ({} takes {} arguments)", line, name, template.params.len());
+            continue;
+        }
+
+        if template.params.is_empty() {
+            out.extend(template.lines.iter().cloned());
+            continue;
+        }
+
+        let params = template.params.iter().map(|a| regex::escape(a)).collect::<Vec<String>>().join("|");
+        let params = Regex::new(&format!("\\b({})\\b", params)).unwrap();
+
+        for template_line in &template.lines {
+            let expanded = params.replace_all(template_line, |caps: &regex::Captures| {
+                let i = template.params.iter().position(|a| a.eq(&caps[0])).unwrap();
+                args[i].clone()
+            });
+            out.push(expanded.to_string());
+        }
+    }
+
+    if let Some((name, _)) = defining {
+        println!("template {} has no @end", name);
+        problems.push(format!("template {} has no @end", name));
+    }
+
+    out
+}
+
+/// Replaces the `[A] -> [B]:` blocks with a rule for every `port -> port` line indented under them.
+/// The options after the colon go to every rule of the block, after the options of the line
+pub fn expand_blocks(lines: Vec<String>, problems: &mut Vec<String>) -> Vec<String> {
+    macro_rules! invalid {
+        ($($arg:tt)*) => {{
+            let problem = format!($($arg)*);
+            println!("invalid line: {}", problem);
+            problems.push(problem);
+        }};
+    }
+
+    lazy_static! {
+        static ref BLOCK_RE: Regex = Regex::new("^\\s*\\[(?P<node_out>[^\\]]*)\\]\\s*->\\s*\\[(?P<node_in>[^\\]]*)\\]\\s*:\\s*(?P<opts>.*)$").unwrap();
+        static ref PORTS_RE: Regex = Regex::new("^\\s*(?P<port_out>[^@]*?)\\s*->\\s*(?P<port_in>[^@]*?)\\s*(?P<opts>@.*)?$").unwrap();
+    }
+
+    let mut block: Option<(String, String, String)> = None;
+    let mut out = Vec::new();
+
+    for line in lines {
+        let indented = line.starts_with(char::is_whitespace);
+
+        if let Some((node_out, node_in, opts)) = block.as_ref().filter(|_| indented || line.trim().is_empty()) {
+            // Spacing and comments inside the block
+            if line.trim().is_empty() || line.trim_start().starts_with('#') {
+                continue;
+            }
+
+            match PORTS_RE.captures(&line) {
+                Some(caps) => {
+                    let line_opts = caps.name("opts").map_or("", |a| a.as_str());
+                    out.push(format!("[{}]({}) -> [{}]({}) {} {}", node_out, &caps["port_out"], node_in, &caps["port_in"], line_opts, opts).trim_end().to_string());
+                }
+                None => invalid!("{} (expected port -> port under [{}] -> [{}]:)", line, node_out, node_in),
+            }
+            continue;
+        }
+
+        block = BLOCK_RE
+            .captures(&line)
+            .map(|caps| (caps["node_out"].to_string(), caps["node_in"].to_string(), caps["opts"].trim().to_string()));
+
+        if block.is_none() {
+            out.push(line);
+        }
+    }
+
+    out
+}
+
+/// Replaces the default device placeholders in the rule with the node names, None when a default is not known yet
+pub fn resolve_defaults(line: &str, defaults: &HashMap<String, String>) -> Option<String> {
+    let mut line = line.to_string();
+
+    for (placeholder, _) in DEFAULTS {
+        if line.contains(placeholder) {
+            line = line.replace(placeholder, defaults.get(placeholder)?);
+        }
+    }
+
+    Some(line)
+}
+
+pub fn parse_file(path: std::path::PathBuf, filter: &TagFilter, defaults: &HashMap<String, String>) -> Result<Rules, Box<dyn std::error::Error>> {
+    let file = fs::File::open(path)?;
+    parse_rules(std::io::BufReader::new(file), filter, defaults)
+}
+
+/// Parses the rules from a file or from stdin
+pub fn parse_rules(reader: impl BufRead, filter: &TagFilter, defaults: &HashMap<String, String>) -> Result<Rules, Box<dyn std::error::Error>> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new("^\\s*\\[(?P<node_out>.*)\\]\\((?P<port_out>.*)\\)\\s*->\\s*\\[(?P<node_in>.*)\\]\\((?P<port_in>[^)]*)\\)(?P<opts>.*)").unwrap();
+        // [A](out) -> [B](in), [C](in) with at least two targets
+        static ref FAN_RE: Regex = Regex::new("^\\s*\\[(?P<node_out>[^\\]]*)\\]\\((?P<port_out>[^)]*)\\)\\s*->\\s*(?P<targets>\\[[^\\]]*\\]\\([^)]*\\)(\\s*,\\s*\\[[^\\]]*\\]\\([^)]*\\))+)(?P<opts>.*)").unwrap();
+        static ref TARGET_RE: Regex = Regex::new("\\[(?P<node>[^\\]]*)\\]\\((?P<port>[^)]*)\\)").unwrap();
+        // [Multi Room] combine [Kitchen], [Living Room]
+        static ref COMBINE_RE: Regex = Regex::new("^\\s*\\[(?P<node>[^\\]]*)\\]\\s*combine\\s+(?P<targets>\\[[^\\]]*\\](\\s*,\\s*\\[[^\\]]*\\])*)(?P<opts>.*)").unwrap();
+        // [USB Interface] props monitor.channel-volumes=true channelmix.upmix=false
+        static ref PROPS_RE: Regex = Regex::new("^\\s*\\[(?P<node>[^\\]]*)\\]\\s*props\\s+(?P<props>[^@]*)(?P<opts>.*)").unwrap();
+        // [USB Interface] volume 40% unmute
+        static ref VOLUME_RE: Regex = Regex::new("^\\s*\\[(?P<node>[^\\]]*)\\]\\s*(?P<settings>(volume|mute|unmute)\\b[^@]*)(?P<opts>.*)").unwrap();
+        // [alsa_card.usb-Focusrite*] profile pro-audio
+        static ref PROFILE_RE: Regex = Regex::new("^\\s*\\[(?P<device>[^\\]]*)\\]\\s*profile\\s+(?P<profile>[^@\\s]+)\\s*(?P<opts>.*)").unwrap();
+        // never [Mic](capture_FL) -> [Speakers], the ports are optional
+        static ref NEVER_RE: Regex = Regex::new("^\\s*never\\s+\\[(?P<node_out>[^\\]]*)\\](\\((?P<port_out>[^)]*)\\))?\\s*->\\s*\\[(?P<node_in>[^\\]]*)\\](\\((?P<port_in>[^)]*)\\))?(?P<opts>.*)").unwrap();
+        static ref COMBINE_TARGET_RE: Regex = Regex::new("\\[(?P<node>[^\\]]*)\\]").unwrap();
+        // [Denoised Mic] filter ladspa librnnoise_ladspa noise_suppressor_mono { VAD Threshold (%) = 50 }
+        static ref FILTER_RE: Regex = Regex::new("^\\s*\\[(?P<node>[^\\]]*)\\]\\s*filter\\s+(?P<kind>\\S+)\\s+(?P<plugin>\\S+)\\s+(?P<label>[^\\s{]+)\\s*(\\{(?P<controls>[^}]*)\\})?\\s*$").unwrap();
+        // [Mic] -> [Recorder], every port of the first node to the port of the same channel or name of the second
+        static ref NODES_RE: Regex = Regex::new("^\\s*\\[(?P<node_out>[^\\]]*)\\]\\s*->\\s*\\[(?P<node_in>[^\\]]*)\\]\\s*(?P<opts>(@.*)?)$").unwrap();
+        static ref MAP_RE: Regex = Regex::new("^\\s*\\[(?P<node_out>[^\\]]*)\\]\\s*->\\s*\\[(?P<node_in>[^\\]]*)\\]\\s*map\\s*\\{(?P<map>.*)\\}(?P<opts>[^}]*)").unwrap();
+    }
+
+    let mut node_def: HashMap<String, Rc<NodeDef>>  = HashMap::new();
+    let mut port_def: HashMap<(String, String), Rc<PortDef>>  = HashMap::new();
+    let mut link_def: Vec<Rc<LinkDef>> = Vec::new();
+    let mut map_def: Vec<Rc<MapDef>> = Vec::new();
+    let mut stereo_def: Vec<Rc<StereoDef>> = Vec::new();
+    let mut combine_sinks: Vec<String> = Vec::new();
+    let mut filters: Vec<filter_chain::FilterDef> = Vec::new();
+    let mut node_settings: Vec<Rc<NodeSettings>> = Vec::new();
+    let mut never_def: Vec<NeverDef> = Vec::new();
+    let mut profile_def: Vec<ProfileDef> = Vec::new();
+    let mut socket = None;
+    let mut client_props = Vec::new();
+    let mut ignore_monitors = false;
+    let mut uses_defaults = false;
+    let mut policy = None;
+    let mut routes = Vec::new();
+
+    // Lines that could not be used, only printed unless --strict
+    let mut problems = Vec::new();
+
+    macro_rules! invalid {
+        ($($arg:tt)*) => {{
+            let problem = format!($($arg)*);
+            println!("invalid line: {}", problem);
+            problems.push(problem);
+        }};
+    }
+
+    let lines = expand_templates(reader.lines().collect::<Result<Vec<String>, std::io::Error>>()?, &mut problems);
+    let lines = expand_blocks(lines, &mut problems);
+
+    for line in lines {
+        if line.trim_start().starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+
+        let follows_default = DEFAULTS.iter().any(|(a, _)| line.contains(a));
+
+        uses_defaults |= follows_default;
+
+        let line = if follows_default {
+            match resolve_defaults(&line, defaults) {
+                Some(line) => line,
+                None => {
+                    println!("Waiting for the default device: {}", line);
+                    continue;
+                }
+            }
+        } else {
+            line
+        };
+
+        let parse_rule_options = |opts: &str| {
+            RuleOptions::parse(opts).map(|options| RuleOptions { follows_default, ..options })
+        };
+
+        // Settings of the whole file
+        if let Some(setting) = line.trim().strip_prefix("@socket") {
+            match setting.trim() {
+                "" => invalid!("{} (@socket requires a socket name or path)", line),
+                name => socket = Some(name.to_string()),
+            }
+            continue;
+        }
+
+        if let Some(setting) = line.trim().strip_prefix("@policy") {
+            match bracketed(setting) {
+                Some(name) => {
+                    println!("Found policy: streams go to [{}]", name);
+                    policy = Some((get_node_def(&mut node_def, name), RuleOptions { follows_default, ..RuleOptions::default() }));
+                }
+                _ => invalid!("{} (@policy requires a sink, like @policy [Speakers])", line),
+            }
+            continue;
+        }
+
+        if let Some(setting) = line.trim().strip_prefix("@route") {
+            match setting.trim().split_once(char::is_whitespace).and_then(|(binary, sink)| Some((binary, bracketed(sink)?))) {
+                Some((binary, sink)) => {
+                    println!("Found route: {} streams go to [{}]", binary, sink);
+                    routes.push((binary.to_string(), get_node_def(&mut node_def, sink), RuleOptions { follows_default, ..RuleOptions::default() }));
+                }
+                _ => invalid!("{} (@route requires a binary and a sink, like @route discord [Voice Chat])", line),
+            }
+            continue;
+        }
+
+        if line.trim() == "@ignore-monitors" {
+            ignore_monitors = true;
+            continue;
+        }
+
+        if let Some(setting) = line.trim().strip_prefix("@client-prop") {
+            for value in setting.split_whitespace() {
+                match value.split_once('=') {
+                    Some((key, value)) if !key.is_empty() => client_props.push((key.to_string(), value.to_string())),
+                    _ => invalid!("{} (@client-prop expects key=value but got '{}')", line, value),
+                }
+            }
+            continue;
+        }
+
+        if let Some(caps) = NEVER_RE.captures(&line) {
+            let options = match parse_rule_options(&caps["opts"]) {
+                Ok(options) => options,
+                Err(e) => {
+                    invalid!("{} ({})", line, e);
+                    continue;
+                }
+            };
+
+            if (RuleOptions { tags: Vec::new(), follows_default: false, ..options.clone() }) != RuleOptions::default() {
+                invalid!("{} (never only takes @tag)", line);
+                continue;
+            }
+
+            let never = NeverDef::new(
+                caps["node_out"].to_string(),
+                caps.name("port_out").map(|a| a.as_str().to_string()),
+                caps["node_in"].to_string(),
+                caps.name("port_in").map(|a| a.as_str().to_string()),
+            );
+
+            let never = match never {
+                Ok(never) => never,
+                Err(e) => {
+                    invalid!("{} ({})", line, e);
+                    continue;
+                }
+            };
+
+            if !filter.allows(&options.tags) {
+                println!("Skipping {} (tags: {})", never, options.tags.join(","));
+                continue;
+            }
+
+            println!("Found {}", never);
+
+            never_def.push(never);
+        } else if let Some(caps) = PROFILE_RE.captures(&line) {
+            let options = match parse_rule_options(&caps["opts"]) {
+                Ok(options) => options,
+                Err(e) => {
+                    invalid!("{} ({})", line, e);
+                    continue;
+                }
+            };
+
+            if (RuleOptions { tags: Vec::new(), follows_default: false, ..options.clone() }) != RuleOptions::default() {
+                invalid!("{} (profile only takes @tag)", line);
+                continue;
+            }
+
+            let profile = ProfileDef {
+                device: caps["device"].to_string(),
+                profile: caps["profile"].to_string(),
+            };
+
+            if !filter.allows(&options.tags) {
+                println!("Skipping {} (tags: {})", profile, options.tags.join(","));
+                continue;
+            }
+
+            println!("Found {}", profile);
+
+            profile_def.push(profile);
+        } else if let Some(caps) = FILTER_RE.captures(&line) {
+            let controls = match filter_chain::parse_controls(caps.name("controls").map_or("", |a| a.as_str())) {
+                Ok(controls) => controls,
+                Err(e) => {
+                    invalid!("{} ({})", line, e);
+                    continue;
+                }
+            };
+
+            if filters.iter().any(|a| a.name.eq(&caps["node"])) {
+                invalid!("{} ([{}] is already a filter)", line, &caps["node"]);
+                continue;
+            }
+
+            println!("Found filter: [{}] {} {} with {} controls", &caps["node"], &caps["plugin"], &caps["label"], controls.len());
+
+            filters.push(filter_chain::FilterDef {
+                name: caps["node"].to_string(),
+                kind: caps["kind"].to_string(),
+                plugin: caps["plugin"].to_string(),
+                label: caps["label"].to_string(),
+                controls,
+            });
+        } else if let Some(caps) = PROPS_RE.captures(&line) {
+            let options = match parse_rule_options(&caps["opts"]) {
+                Ok(options) => options,
+                Err(e) => {
+                    invalid!("{} ({})", line, e);
+                    continue;
+                }
+            };
+
+            let props = caps["props"]
+                .split_whitespace()
+                .map(|a| match a.split_once('=') {
+                    Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+                    _ => Err(format!("props expects key=value but got '{}'", a)),
+                })
+                .collect::<Result<Vec<(String, String)>, String>>();
+
+            let props = match props {
+                Ok(props) => props,
+                Err(e) => {
+                    invalid!("{} ({})", line, e);
+                    continue;
+                }
+            };
+
+            if (RuleOptions { tags: Vec::new(), follows_default: false, ..options.clone() }) != RuleOptions::default() {
+                invalid!("{} (props only takes @tag)", line);
+                continue;
+            }
+
+            if !filter.allows(&options.tags) {
+                println!("Skipping props: [{}] (tags: {})", &caps["node"], options.tags.join(","));
+                continue;
+            }
+
+            let settings = NodeSettings {
+                node: get_node_def(&mut node_def, &caps["node"]),
+                props,
+                volume: None,
+                mute: None,
+            };
+
+            println!("Found props: {}", settings);
+
+            node_settings.push(Rc::new(settings));
+        } else if let Some(caps) = VOLUME_RE.captures(&line) {
+            let options = match parse_rule_options(&caps["opts"]) {
+                Ok(options) => options,
+                Err(e) => {
+                    invalid!("{} ({})", line, e);
+                    continue;
+                }
+            };
+
+            let (volume, mute) = match parse_volume(&caps["settings"]) {
+                Ok(settings) => settings,
+                Err(e) => {
+                    invalid!("{} ({})", line, e);
+                    continue;
+                }
+            };
+
+            if (RuleOptions { tags: Vec::new(), follows_default: false, ..options.clone() }) != RuleOptions::default() {
+                invalid!("{} (volume only takes @tag)", line);
+                continue;
+            }
+
+            if !filter.allows(&options.tags) {
+                println!("Skipping volume: [{}] (tags: {})", &caps["node"], options.tags.join(","));
+                continue;
+            }
+
+            let settings = NodeSettings {
+                node: get_node_def(&mut node_def, &caps["node"]),
+                props: Vec::new(),
+                volume,
+                mute,
+            };
+
+            println!("Found volume: {}", settings);
+
+            node_settings.push(Rc::new(settings));
+        } else if let Some(caps) = COMBINE_RE.captures(&line) {
+            let options = match parse_rule_options(&caps["opts"]) {
+                Ok(options) => options,
+                Err(e) => {
+                    invalid!("{} ({})", line, e);
+                    continue;
+                }
+            };
+
+            if options.keep_alive {
+                invalid!("{} (@keep-alive only works on link rules)", line);
+                continue;
+            }
+
+            let targets = COMBINE_TARGET_RE.captures_iter(&caps["targets"]).collect::<Vec<regex::Captures>>();
+
+            if !filter.allows(&options.tags) {
+                println!("Skipping combined sink: [{}] -> {} sinks (tags: {})", &caps["node"], targets.len(), options.tags.join(","));
+                continue;
+            }
+
+            if combine_sinks.iter().any(|a| a.eq(&caps["node"])) {
+                invalid!("{} ([{}] is already a combined sink)", line, &caps["node"]);
+                continue;
+            }
+
+            println!("Found combined sink: [{}] -> {} sinks", &caps["node"], targets.len());
+
+            combine_sinks.push(caps["node"].to_string());
+
+            // What is played to the sink comes out of its monitor ports, which go to every real sink
+            for target in targets {
+                stereo_def.push(Rc::new(StereoDef {
+                    node_out: get_node_def(&mut node_def, &caps["node"]),
+                    node_in: get_node_def(&mut node_def, &target["node"]),
+                    surround: false,
+                    by_name: false,
+                    options: options.clone(),
+                }));
+            }
+        } else if let Some(caps) = MAP_RE.captures(&line) {
+            let options = match parse_rule_options(&caps["opts"]) {
+                Ok(options) => options,
+                Err(e) => {
+                    invalid!("{} ({})", line, e);
+                    continue;
+                }
+            };
+
+            let ports = caps["map"].split([',', ';']).filter(|a| !a.trim().is_empty()).map(|pair| match pair.split_once("->") {
+                Some((out, port_in)) => PortPattern::parse(out.trim()).and_then(|out| {
+                    let port_in = PortPattern::parse(port_in.trim())?;
+                    if out.placeholders() != port_in.placeholders() {
+                        return Err(format!("both sides of '{}' must use the same placeholders", pair.trim()));
+                    }
+                    Ok((out, port_in))
+                }),
+                None => Err(format!("expected 'out -> in' but got '{}'", pair.trim())),
+            }).collect::<Result<Vec<_>, String>>();
+
+            let ports = match ports {
+                Ok(ports) => ports,
+                Err(e) => {
+                    invalid!("{} ({})", line, e);
+                    continue;
+                }
+            };
+
+            if options.keep_alive {
+                invalid!("{} (@keep-alive only works on link rules)", line);
+                continue;
+            }
+
+            if !filter.allows(&options.tags) {
+                println!("Skipping map: [{}] -> [{}] (tags: {})", &caps["node_out"], &caps["node_in"], options.tags.join(","));
+                continue;
+            }
+
+            println!("Found map: [{}] -> [{}] with {} port patterns", &caps["node_out"], &caps["node_in"], ports.len());
+
+            map_def.push(Rc::new(MapDef {
+                node_out: get_node_def(&mut node_def, &caps["node_out"]),
+                node_in: get_node_def(&mut node_def, &caps["node_in"]),
+                ports,
+                options,
+            }));
+        } else if let Some(caps) = NODES_RE.captures(&line) {
+            let options = match parse_rule_options(&caps["opts"]) {
+                Ok(options) => options,
+                Err(e) => {
+                    invalid!("{} ({})", line, e);
+                    continue;
+                }
+            };
+
+            if !filter.allows(&options.tags) {
+                println!("Skipping nodes link: [{}] -> [{}] (tags: {})", &caps["node_out"], &caps["node_in"], options.tags.join(","));
+                continue;
+            }
+
+            if options.keep_alive {
+                invalid!("{} (@keep-alive only works on link rules)", line);
+                continue;
+            }
+
+            println!("Found nodes link: [{}] -> [{}]", &caps["node_out"], &caps["node_in"]);
+
+            stereo_def.push(Rc::new(StereoDef {
+                node_out: get_node_def(&mut node_def, &caps["node_out"]),
+                node_in: get_node_def(&mut node_def, &caps["node_in"]),
+                surround: true,
+                by_name: true,
+                options,
+            }));
+        } else if let Some(caps) = FAN_RE.captures(&line) {
+            let options = match parse_rule_options(&caps["opts"]) {
+                Ok(options) => options,
+                Err(e) => {
+                    invalid!("{} ({})", line, e);
+                    continue;
+                }
+            };
+
+            let targets = TARGET_RE.captures_iter(&caps["targets"]).collect::<Vec<regex::Captures>>();
+
+            if !filter.allows(&options.tags) {
+                println!("Skipping fan-out: [{}]{} -> {} targets (tags: {})", &caps["node_out"], &caps["port_out"], targets.len(), options.tags.join(","));
+                continue;
+            }
+
+            println!("Found fan-out: [{}]{} -> {} targets", &caps["node_out"], &caps["port_out"], targets.len());
+
+            // Every leg is its own rule, so each one comes back with its device
+            for target in targets {
+                push_link_def(
+                    &mut node_def,
+                    &mut port_def,
+                    &mut link_def,
+                    (&caps["node_out"], &caps["port_out"]),
+                    (&target["node"], &target["port"]),
+                    options.clone(),
+                );
+            }
+        } else if let Some(caps) = RE.captures(&line) {
+            let options = match parse_rule_options(&caps["opts"]) {
+                Ok(options) => options,
+                Err(e) => {
+                    invalid!("{} ({})", line, e);
+                    continue;
+                }
+            };
+
+            if !filter.allows(&options.tags) {
+                println!("Skipping link: [{}]{} -> [{}]{} (tags: {})", &caps["node_out"], &caps["port_out"],  &caps["node_in"], &caps["port_in"], options.tags.join(","));
+                continue;
+            }
+
+            let is_channels = |port: &str| port == STEREO || port == CHANNELS;
+
+            match (is_channels(&caps["port_out"]), is_channels(&caps["port_in"])) {
+                (true, true) if caps["port_out"] == caps["port_in"] => {
+                    let surround = &caps["port_out"] == CHANNELS;
+                    println!("Found {} link: [{}] -> [{}]", &caps["port_out"], &caps["node_out"], &caps["node_in"]);
+
+                    if options.keep_alive {
+                        invalid!("{} (@keep-alive only works on link rules)", line);
+                        continue;
+                    }
+
+                    stereo_def.push(Rc::new(StereoDef {
+                        node_out: get_node_def(&mut node_def, &caps["node_out"]),
+                        node_in: get_node_def(&mut node_def, &caps["node_in"]),
+                        surround,
+                        by_name: false,
+                        options,
+                    }));
+                    continue;
+                }
+                (false, false) => (),
+                _ => {
+                    invalid!("{} (both sides must be ({}) or ({}))", line, STEREO, CHANNELS);
+                    continue;
+                }
+            }
+
+            // [A](L,R) -> [B](L,R) is a rule for every pair of ports
+            let ports = |ports: &str| {
+                if ports.contains(',') {
+                    ports.split(',').map(|a| a.trim().to_string()).collect::<Vec<String>>()
+                } else {
+                    vec![ports.to_string()]
+                }
+            };
+            let (ports_out, ports_in) = (ports(&caps["port_out"]), ports(&caps["port_in"]));
+
+            if ports_out.len() != ports_in.len() {
+                invalid!("{} (both sides must list as many ports)", line);
+                continue;
+            }
+
+            for (port_out, port_in) in ports_out.iter().zip(&ports_in) {
+                println!("Found link: [{}]{} -> [{}]{}", &caps["node_out"], port_out, &caps["node_in"], port_in);
+
+                push_link_def(
+                    &mut node_def,
+                    &mut port_def,
+                    &mut link_def,
+                    (&caps["node_out"], port_out),
+                    (&caps["node_in"], port_in),
+                    options.clone(),
+                );
+            }
+        } else {
+            invalid!("{}", line);
+        }
+    }
+
+    let never = never_def.iter().map(|a| a.to_string()).collect::<Vec<String>>();
+    let mut seen = Vec::new();
+
+    for link in link_def.iter().filter(|a| a.port_out.node.name.ne(KEEP_ALIVE_NODE)) {
+        let rule = link.to_string();
+
+        if seen.contains(&rule) {
+            println!("duplicate rule: {}", rule);
+            problems.push(format!("{} (duplicate)", rule));
+        }
+
+        // Whether it is linked depends on the order the rules are applied
+        let (out, input) = (&link.port_out, &link.port_in);
+        if let Some(i) = never_def.iter().position(|a| a.matches(&out.node.name, &out.name, &input.node.name, &input.name)) {
+            println!("ambiguous rule: {} (also matches {})", rule, never[i]);
+            problems.push(format!("{} (also matches {})", rule, never[i]));
+        }
+
+        seen.push(rule);
+    }
+
+    let node_def = node_def.values().cloned().collect::<Vec<Rc<NodeDef>>>();
+    let port_def = port_def.values().cloned().collect::<Vec<Rc<PortDef>>>();
+
+    Ok(Rules {
+        node_def,
+        link_def,
+        port_def,
+        map_def,
+        stereo_def,
+        combine_sinks,
+        filters,
+        node_settings,
+        never_def,
+        profile_def,
+        policy,
+        routes,
+        socket,
+        client_props,
+        ignore_monitors,
+        uses_defaults,
+        problems,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(rules: &str) -> Rules {
+        parse_rules(rules.as_bytes(), &TagFilter::default(), &HashMap::new()).unwrap()
+    }
+
+    #[test]
+    fn rule_options() {
+        let options = RuleOptions::parse("@name mic-live @priority 5 @prop link.passive=true @exclusive-input @grace-period 250 @from hardware @tag live,studio").unwrap();
+
+        assert_eq!(options.name.as_deref(), Some("mic-live"));
+        assert_eq!(options.priority, Some(5));
+        assert_eq!(options.props, [("link.passive".to_string(), "true".to_string())]);
+        assert!(options.exclusive_input);
+        assert_eq!(options.grace_period, Some(Duration::from_millis(250)));
+        assert_eq!(options.from, Some(NodeKind::Hardware));
+        assert_eq!(options.tags, ["live", "studio"]);
+        assert!(!options.manual && !options.keep_alive);
+
+        assert_eq!(RuleOptions::parse("").unwrap(), RuleOptions::default());
+        assert_eq!(RuleOptions::parse("@enforce").unwrap().enforce, Some(ENFORCE_DELAY));
+        assert_eq!(RuleOptions::parse("@physical in").unwrap().physical, [Direction::In]);
+    }
+
+    #[test]
+    fn rule_options_errors() {
+        assert!(RuleOptions::parse("@priority high").is_err());
+        assert!(RuleOptions::parse("@prop link.output.port=3").is_err());
+        assert!(RuleOptions::parse("@max-attempts 0").is_err());
+        assert!(RuleOptions::parse("@from speakers").is_err());
+        assert!(RuleOptions::parse("@name two words").is_err());
+        assert!(RuleOptions::parse("@colour red").is_err());
+    }
+
+    #[test]
+    fn schedule() {
+        let night = Schedule::parse(&["22:00-07:00".to_string()]).unwrap();
+        assert!(night.is_active(3, 23 * 60));
+        assert!(night.is_active(3, 6 * 60));
+        assert!(!night.is_active(3, 12 * 60));
+
+        let weekend = Schedule::parse(&["10:00-12:00".to_string(), "sat,sun".to_string()]).unwrap();
+        assert!(weekend.is_active(0, 11 * 60));
+        assert!(!weekend.is_active(1, 11 * 60));
+
+        assert!(Schedule::parse(&["25:00-07:00".to_string()]).is_err());
+        assert!(Schedule::parse(&["22:00-07:00".to_string(), "someday".to_string()]).is_err());
+    }
+
+    #[test]
+    fn comments_are_skipped() {
+        let state = parse("# [Firefox](stereo) -> [alsa_output.*](stereo)\n  #[A](out) -> [B](in)\n\n   \n[C](out) -> [D](in)\n");
+
+        assert_eq!(state.link_def.len(), 1);
+        assert_eq!(state.link_def[0].to_string(), "[C]out -> [D]in");
+        assert!(state.stereo_def.is_empty());
+        assert!(state.problems.is_empty());
+    }
+
+    #[test]
+    fn broken_map_is_skipped() {
+        let state = parse("[A] -> [B] map { out_{n} -> in_{n}, bogus }\n[A] -> [B] map { out_{n} -> in_{n} }\n");
+
+        assert_eq!(state.map_def.len(), 1);
+        assert_eq!(state.problems.len(), 1);
+    }
+
+    #[test]
+    fn never() {
+        let state = parse("never [Mic*] -> [Speakers](playback_F*)\n");
+        let never = &state.never_def[0];
+
+        assert!(never.matches("Mic 2", "capture_FL", "Speakers", "playback_FL"));
+        assert!(!never.matches("Mic 2", "capture_FL", "Speakers", "playback_RL"));
+        assert!(!never.matches("Webcam", "capture_FL", "Speakers", "playback_FL"));
+    }
+
+    #[test]
+    fn manual_keep_alive() {
+        let state = parse("[Mic](capture_FL) -> [Stream](input_FL) @manual @keep-alive\n");
+
+        assert_eq!(state.link_def.len(), 2);
+        assert!(state.link_def.iter().all(|a| a.disabled.get() && !a.active.get()));
+    }
+}
@@ -0,0 +1,64 @@
+//! Control socket of a running instance, so the commands run against it instead of connecting to PipeWire again
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    time::Duration,
+};
+
+pub fn socket_path(system: bool) -> PathBuf {
+    crate::daemon::runtime_dir(system).join("pw-autoconnect.sock")
+}
+
+/// Sends the command to the running instance and returns its answer, None when nothing is running
+pub fn send(command: &str, system: bool) -> Option<String> {
+    let mut stream = UnixStream::connect(socket_path(system)).ok()?;
+
+    writeln!(stream, "{}", command).ok()?;
+
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer).ok()?;
+
+    Some(answer)
+}
+
+/// Listens on the control socket, replacing the one left behind by an instance that is gone
+pub fn listen(system: bool) -> Result<UnixListener, String> {
+    let path = socket_path(system);
+
+    if UnixStream::connect(&path).is_ok() {
+        return Err(format!("another instance is already listening on {}", path.display()));
+    }
+
+    let _ = std::fs::remove_file(&path);
+
+    if let Some(dir) = path.parent() {
+        crate::daemon::create_runtime_dir(dir).map_err(|e| format!("could not create {}: {}", dir.display(), e))?;
+    }
+
+    let listener = UnixListener::bind(&path).map_err(|e| format!("could not listen on {}: {}", path.display(), e))?;
+    // Anyone who can connect can run commands, so only the owner may connect
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))
+        .map_err(|e| format!("could not restrict {}: {}", path.display(), e))?;
+    listener
+        .set_nonblocking(true)
+        .map_err(|e| format!("could not listen on {}: {}", path.display(), e))?;
+
+    Ok(listener)
+}
+
+/// Reads the command sent by a client, a single line
+pub fn read_command(stream: &UnixStream) -> std::io::Result<String> {
+    // Do not let a client that never writes block the main loop
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+
+    Ok(line.trim().to_string())
+}
@@ -0,0 +1,120 @@
+//! Writes a crash report when we panic, with the rules, the nodes and ports we knew and the last registry events,
+//! so a crash after days of running can be looked into from the report attached to an issue
+
+use std::{
+    collections::VecDeque,
+    fs,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config::{local_timestamp, unix_time};
+use crate::engine::AppState;
+use crate::graph::RegistryEvent;
+
+/// Registry events kept for the report
+const EVENTS: usize = 200;
+/// How often the rules, nodes and ports of the report are taken again, the events in between are in the report too
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct Report {
+    // Where the reports go, None until install
+    dir: Option<PathBuf>,
+    // The rules, nodes and ports, and when they were taken
+    snapshot: String,
+    taken: Option<Instant>,
+    // The last registry events, the oldest first
+    events: VecDeque<(Instant, String)>,
+}
+
+lazy_static! {
+    // The state itself is borrowed by whatever panicked, the report keeps its own copy
+    static ref REPORT: Mutex<Report> = Mutex::new(Report::default());
+}
+
+/// Writes a report to the directory when we panic, after the message of the default hook
+pub fn install(dir: PathBuf) {
+    if let Ok(mut report) = REPORT.lock() {
+        report.dir = Some(dir);
+    }
+
+    let default = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default(info);
+
+        // A panic while the report is written must not lock us up
+        let report = match REPORT.try_lock() {
+            Ok(report) => report,
+            Err(_) => return,
+        };
+        let dir = match &report.dir {
+            Some(dir) => dir,
+            None => return,
+        };
+
+        let mut out = format!("pw-autoconnect {} {}\n", env!("CARGO_PKG_VERSION"), info);
+        out.push_str(&format!("Time: {}\n", local_timestamp(unix_time())));
+
+        match report.taken {
+            Some(taken) => out.push_str(&format!("\nTaken {}s before the crash:\n{}", taken.elapsed().as_secs(), report.snapshot)),
+            None => out.push_str("\nNo rules, nodes or ports yet\n"),
+        }
+
+        out.push_str(&format!("\nLast {} registry events:\n", report.events.len()));
+        for (time, event) in &report.events {
+            out.push_str(&format!("  {:.3}s ago {}\n", time.elapsed().as_secs_f64(), event));
+        }
+
+        let path = dir.join(format!("pw-autoconnect-crash-{}.txt", unix_time()));
+        match crate::daemon::create_runtime_dir(dir).and_then(|_| fs::write(&path, out)) {
+            Ok(()) => eprintln!("Crash report written to {}, please attach it to the issue", path.display()),
+            Err(e) => eprintln!("Could not write the crash report to {}: {}", path.display(), e),
+        }
+    }));
+}
+
+/// Keeps the event for the report, with the name of its node or port
+pub fn record(event: &RegistryEvent) {
+    let mut line = event.to_string();
+    if let RegistryEvent::Global { props: Some(props), .. } = event {
+        if let Some(name) = props.get("port.name").or_else(|| props.get("node.name")) {
+            line.push_str(&format!(" {}", name));
+        }
+    }
+
+    if let Ok(mut report) = REPORT.lock() {
+        if report.events.len() == EVENTS {
+            report.events.pop_front();
+        }
+        report.events.push_back((Instant::now(), line));
+    }
+}
+
+/// Takes the rules, nodes and ports again when the last ones are older than the interval
+pub fn snapshot(state: &AppState) {
+    let mut report = match REPORT.lock() {
+        Ok(report) => report,
+        Err(_) => return,
+    };
+    if report.taken.is_some_and(|a| a.elapsed() < SNAPSHOT_INTERVAL) {
+        return;
+    }
+
+    let mut out = format!("Rules ({}):\n", state.link_def.len());
+    for rule in &state.link_def {
+        out.push_str(&format!("  {}{}\n", rule, if rule.disabled.get() { " (disabled)" } else { "" }));
+    }
+    out.push_str(&format!("Nodes ({}):\n", state.nodes.len()));
+    for node in &state.nodes {
+        out.push_str(&format!("  {} {}\n", node.id, node.name));
+    }
+    out.push_str(&format!("Ports ({}):\n", state.ports.len()));
+    for port in &state.ports {
+        out.push_str(&format!("  {} [{}]{}\n", port.id, port.node.name, port.name));
+    }
+
+    report.snapshot = out;
+    report.taken = Some(Instant::now());
+}
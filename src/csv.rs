@@ -0,0 +1,19 @@
+//! Just enough CSV to print the listings for spreadsheets and scripts
+
+/// Writes one line, quoting the fields that need it
+pub fn write_row(fields: &[&str], out: &mut String) {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+
+        if field.contains([',', '"', '\n', '\r']) {
+            out.push('"');
+            out.push_str(&field.replace('"', "\"\""));
+            out.push('"');
+        } else {
+            out.push_str(field);
+        }
+    }
+    out.push('\n');
+}
@@ -0,0 +1,182 @@
+//! Detaches from the terminal for `--daemon`, for sessions started without systemd
+
+use std::{
+    env,
+    ffi::CString,
+    fs,
+    io::Write,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{DirBuilderExt, MetadataExt, OpenOptionsExt},
+        io::AsRawFd,
+    },
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+/// Where the pid file and the control socket go, `$XDG_RUNTIME_DIR` for a user, or a directory of their own in `/tmp`
+/// without it, and `/run/pw-autoconnect` with --system, where there is no user session
+pub fn runtime_dir(system: bool) -> PathBuf {
+    if system {
+        return PathBuf::from("/run/pw-autoconnect");
+    }
+
+    env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(tmp_dir)
+}
+
+/// `/tmp/pw-autoconnect-<uid>`, for sessions without `$XDG_RUNTIME_DIR`
+fn tmp_dir() -> PathBuf {
+    PathBuf::from(format!("/tmp/pw-autoconnect-{}", unsafe { libc::getuid() }))
+}
+
+/// Creates the directory of a file in `runtime_dir`. Anyone can make the one in `/tmp` before us,
+/// so it is only used when it is a directory of ours that nobody else can open
+pub fn create_runtime_dir(dir: &Path) -> std::io::Result<()> {
+    if dir != tmp_dir() {
+        return fs::create_dir_all(dir);
+    }
+
+    match fs::DirBuilder::new().mode(0o700).create(dir) {
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => (),
+        result => return result,
+    }
+
+    // A link could point anywhere, it is not followed
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() || metadata.uid() != unsafe { libc::getuid() } || metadata.mode() & 0o077 != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{} is not a directory of ours closed to the others, remove it or set $XDG_RUNTIME_DIR", dir.display()),
+        ));
+    }
+
+    Ok(())
+}
+
+pub fn default_pid_file(system: bool) -> PathBuf {
+    runtime_dir(system).join("pw-autoconnect.pid")
+}
+
+pub fn default_log_file(system: bool) -> PathBuf {
+    if system {
+        return PathBuf::from("/var/log/pw-autoconnect.log");
+    }
+
+    runtime_dir(system).join("pw-autoconnect.log")
+}
+
+/// Returns the pid in the file if that process is still running
+fn running_pid(pid_file: &Path) -> Option<i32> {
+    let pid = fs::read_to_string(pid_file).ok()?.trim().parse::<i32>().ok()?;
+
+    // Signal 0 only checks that the process exists
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        Some(pid)
+    } else {
+        None
+    }
+}
+
+fn fork() -> Result<libc::pid_t, String> {
+    match unsafe { libc::fork() } {
+        -1 => Err(format!("could not fork: {}", std::io::Error::last_os_error())),
+        pid => Ok(pid),
+    }
+}
+
+/// The pid file of the daemon, removed when it is dropped or we are stopped with SIGTERM or SIGINT
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// The path for the signal handler, which cannot allocate
+static PID_FILE: OnceLock<CString> = OnceLock::new();
+
+extern "C" fn remove_pid_file(signal: libc::c_int) {
+    if let Some(path) = PID_FILE.get() {
+        unsafe { libc::unlink(path.as_ptr()) };
+    }
+
+    // Stopped by the signal like before, for the service manager
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+/// Forks into the background, only the detached child returns.
+/// Must be called before connecting to PipeWire, the connection does not survive a fork
+pub fn daemonize(pid_file: &Path, log_file: &Path) -> Result<PidFile, String> {
+    if let Some(pid) = running_pid(pid_file) {
+        return Err(format!("already running with pid {} ({})", pid, pid_file.display()));
+    }
+
+    if let Some(dir) = pid_file.parent() {
+        create_runtime_dir(dir).map_err(|e| format!("could not create {}: {}", dir.display(), e))?;
+    }
+
+    // Left behind by an instance that is gone, a new one is made below so a link put there is not followed
+    match fs::remove_file(pid_file) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(format!("could not remove {}: {}", pid_file.display(), e)),
+        _ => (),
+    }
+
+    // Open them while errors can still be shown in the terminal
+    let log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file)
+        .map_err(|e| format!("could not open the log {}: {}", log_file.display(), e))?;
+    let mut pid = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .mode(0o644)
+        .open(pid_file)
+        .map_err(|e| format!("could not create {}: {}", pid_file.display(), e))?;
+    let null = fs::File::open("/dev/null").map_err(|e| format!("could not open /dev/null: {}", e))?;
+
+    if fork()? != 0 {
+        std::process::exit(0);
+    }
+
+    // New session so closing the terminal does not kill us, then fork again so we never get a terminal back
+    unsafe { libc::setsid() };
+
+    let child = fork()?;
+    if child != 0 {
+        println!("Started in the background with pid {}, logging to {}", child, log_file.display());
+        std::process::exit(0);
+    }
+
+    writeln!(pid, "{}", std::process::id()).map_err(|e| format!("could not write {}: {}", pid_file.display(), e))?;
+
+    // Relative paths would break once we change directory
+    let path = pid_file.canonicalize().unwrap_or_else(|_| pid_file.to_path_buf());
+    if let Ok(path) = CString::new(path.as_os_str().as_bytes()) {
+        let _ = PID_FILE.set(path);
+        unsafe {
+            libc::signal(libc::SIGTERM, remove_pid_file as *const () as libc::sighandler_t);
+            libc::signal(libc::SIGINT, remove_pid_file as *const () as libc::sighandler_t);
+        }
+    }
+
+    unsafe {
+        libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO);
+        libc::dup2(log.as_raw_fd(), libc::STDOUT_FILENO);
+        libc::dup2(log.as_raw_fd(), libc::STDERR_FILENO);
+    }
+
+    // Do not keep the directory we were started from busy
+    env::set_current_dir("/").map_err(|e| format!("could not change to /: {}", e))?;
+
+    Ok(PidFile { path })
+}
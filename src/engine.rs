@@ -0,0 +1,2100 @@
+//! Applies the rules to the graph as the registry events come in, the links they want are reconciled with ours
+
+use std::{cell::{Cell, RefCell}, collections::{HashMap, HashSet}, fmt, rc::Rc, time::Instant};
+
+use crate::config::{
+    unix_time, Config, LinkDef, MapDef, MapMatcher, NeverDef, NodeDef, NodeSettings, PortDef, ProfileDef, RuleOptions, Rules, StereoDef,
+    KEEP_ALIVE_NODE,
+};
+use crate::graph::{
+    bus_path, Channel, Direction, Graph, GraphBackend, GraphLink, GraphNode, GraphPort, LinkHandle, Latency, MemoryBackend, Node, NodeKind, Port,
+    PortProps, RegistryEvent, DEVICE_TYPE, LINK_TYPE, NODE_TYPE, OWNER, OWNER_KEY, PORT_TYPE, RULE_KEY, RULE_NAME_KEY,
+};
+use crate::matcher::{glob_matches, is_near_miss, is_pinned, BLUETOOTH_NODE};
+use crate::{audit, crash, filter_chain, json, webhook};
+
+/// What a rule did since we started, for `stats`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RuleStats {
+    // Times both ports were there and not linked yet
+    pub matched: u32,
+    pub created: u32,
+    pub failed: u32,
+    // Unix time of the last of them
+    pub last: Option<u64>,
+}
+
+/// A port of a node used by a stereo or channels rule, kept until its other end shows up
+#[derive(Debug, Clone)]
+pub struct StereoPort {
+    pub id: u32,
+    pub node: Rc<Node>,
+    pub name: String,
+    pub channel: Option<Channel>,
+    pub props: PortProps,
+}
+
+impl StereoPort {
+    /// The channel the rule pairs the ports by, or the name of a port without one for `[A] -> [B]`
+    pub fn key(&self, def: &StereoDef) -> Option<String> {
+        if def.surround {
+            self.props.channel.clone().or_else(|| def.by_name.then(|| self.name.clone()))
+        } else {
+            self.channel.map(|a| format!("{:?}", a))
+        }
+    }
+}
+
+/// Token bucket, lets through a burst of `rate` links and then `rate` links per second
+#[derive(Debug)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub tokens: f64,
+    pub last: Instant,
+}
+
+impl RateLimit {
+    pub fn new(rate: u32) -> RateLimit {
+        RateLimit {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn take(&mut self) -> bool {
+        self.tokens = (self.tokens + self.last.elapsed().as_secs_f64() * self.rate).min(self.rate);
+        self.last = Instant::now();
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+pub struct TrackedLink {
+    pub def: Rc<LinkDef>,
+    pub port_out: u32,
+    pub port_in: u32,
+    pub handle: LinkHandle,
+}
+
+pub struct AppState {
+    pub ports: Vec<Rc<Port>>,
+    pub nodes: Vec<Rc<Node>>,
+
+    // Links created for scheduled or prioritized rules, so they can be removed later
+    pub tracked_links: Vec<TrackedLink>,
+
+    pub get_names: bool,
+    // When false links are owned by us and go away when we exit
+    pub linger: bool,
+    // PipeWire instance to connect to, from `@socket` or --socket
+    pub socket: Option<String>,
+    // Properties of our client, from `@client-prop`
+    pub client_props: Vec<(String, String)>,
+    // `@ignore-monitors` for every stereo, channels and map rule
+    pub ignore_monitors: bool,
+    // Nodes that are usable for `@wait-ready` rules, None when the backend does not know the node states
+    pub ready_nodes: Option<Vec<u32>>,
+    // When the nodes showed up, for `@grace-period` rules. None when the graph is read at once and there is nothing to wait for
+    pub node_times: Option<HashMap<u32, Instant>>,
+    // Links of `@grace-period` rules whose nodes are not there for long enough yet
+    pub waiting_grace: Vec<Rc<LinkDef>>,
+    // Links made per second, None when nothing is linked for real
+    pub rate_limit: Option<RateLimit>,
+    // Links delayed by the rate limit
+    pub waiting_rate: Vec<Rc<LinkDef>>,
+    // Links of `@enforce` rules removed by someone else, made again once their delay is over
+    pub waiting_enforce: Vec<(Rc<LinkDef>, Instant)>,
+    // Last event of every global, to skip the ones announced again unchanged
+    pub seen_globals: HashMap<u32, RegistryEvent>,
+    // Globals seen before a reload and announced again by its replay, their node settings and device profiles were applied already
+    pub replayed: HashSet<u32>,
+    // Ports of the links in the graph, made by anyone
+    pub graph_links: HashMap<u32, (u32, u32)>,
+    // Links in the graph made by our rules, for the hooks and the webhook, kept on reload so the hooks only run once
+    pub rule_links: HashMap<u32, Rc<LinkDef>>,
+    // The hooks only run against the real graph, not for a dry-run or a replay
+    pub run_hooks: bool,
+    // --webhook, told about the links made, failed and removed
+    pub webhook: Option<webhook::Webhook>,
+    // --audit-log, every link we make or remove
+    pub audit: Option<audit::AuditLog>,
+    // Quantum forced for the `@latency` rules with a link in the graph, None when the graph picks it
+    pub forced_latency: Option<Latency>,
+    // Node names of @DEFAULT_SINK@ and @DEFAULT_SOURCE@ the rules were loaded with
+    pub defaults: HashMap<String, String>,
+    // Some rule uses them, so the rules are loaded again when a default changes
+    pub uses_defaults: bool,
+
+    pub node_def: Vec<Rc<NodeDef>>,
+    // The rules of the file come first, the ones added for the ports of stereo and map rules after them
+    pub link_def: Vec<Rc<LinkDef>>,
+    pub file_rules: usize,
+    pub port_def: Vec<Rc<PortDef>>,
+    pub map_def: Vec<Rc<MapDef>>,
+    pub stereo_def: Vec<Rc<StereoDef>>,
+    // The rules looked up by the ports at both of their ends
+    pub links_by_port: HashMap<(String, String), Vec<Rc<LinkDef>>>,
+    pub map_matcher: MapMatcher,
+    // Names of the sinks we create for `combine` rules
+    pub combine_sinks: Vec<String>,
+    // Filter chains we run for `filter` rules
+    pub filters: Vec<filter_chain::FilterDef>,
+    // `props` rules, set on their node when it shows up
+    pub node_settings: Vec<Rc<NodeSettings>>,
+    // `never` rules, their links are removed whoever makes them
+    pub never_def: Vec<NeverDef>,
+    // `profile` rules, set on their device when it shows up
+    pub profile_def: Vec<ProfileDef>,
+    // Sink of `@policy`, the streams of applications no rule names are played to it
+    pub policy: Option<(Rc<NodeDef>, RuleOptions)>,
+    // `@route`, the streams of the applications whose binary matches the pattern are played to the sink instead
+    pub routes: Vec<(String, Rc<NodeDef>, RuleOptions)>,
+
+    pub stereo_ports: Vec<StereoPort>,
+    // Did-you-mean hints already printed, the name in the rule and the one in the graph
+    pub hints: Vec<(String, String)>,
+    // Tags of the rules loaded instead of --only-tags, set with the `profile` command and kept on reload
+    pub profile: Option<Vec<String>>,
+    // Profile of every device, from the last of its nodes that showed up, to notice when it is switched
+    pub device_profiles: HashMap<u32, String>,
+    // What every rule did, by its text so they are kept on reload
+    pub stats: HashMap<String, RuleStats>,
+    // Whether the backend is still connected to the graph, for `health`
+    pub connected: bool,
+    // When the graph last told us something, for `health`
+    pub last_event: Option<Instant>,
+    // The state of every link of the graph by id, `active`, `paused` or `error: <message>`..., for `status`
+    pub link_states: HashMap<u32, String>,
+}
+
+pub fn index_link(index: &mut HashMap<(String, String), Vec<Rc<LinkDef>>>, link: &Rc<LinkDef>) {
+    for port in [&link.port_out, &link.port_in] {
+        index.entry((port.node.name.clone(), port.name.clone())).or_default().push(link.clone());
+    }
+}
+
+pub fn search<T, P>(v: &[Rc<T>], f: P) -> Option<Rc<T>>
+where
+    T: Sized,
+    P: FnMut(&&Rc<T>) -> bool,
+{
+    let n = v.iter().filter(f).cloned().collect::<Vec<Rc<T>>>();
+
+    if n.len() != 1 {
+        return None;
+    }
+
+    Some(n[0].clone())
+}
+
+impl AppState {
+    pub fn new(rules: Rules, get_names: bool) -> AppState {
+        let mut links_by_port = HashMap::new();
+        for link in &rules.link_def {
+            index_link(&mut links_by_port, link);
+        }
+
+        AppState {
+            map_matcher: MapMatcher::new(&rules.map_def),
+            links_by_port,
+            node_def: rules.node_def,
+            file_rules: rules.link_def.len(),
+            link_def: rules.link_def,
+            port_def: rules.port_def,
+            map_def: rules.map_def,
+            get_names,
+            linger: true,
+            socket: rules.socket,
+            client_props: rules.client_props,
+            ignore_monitors: rules.ignore_monitors,
+            hints: Vec::new(),
+            profile: None,
+            ready_nodes: None,
+            node_times: None,
+            waiting_grace: Vec::new(),
+            rate_limit: None,
+            waiting_rate: Vec::new(),
+            waiting_enforce: Vec::new(),
+            seen_globals: HashMap::new(),
+            replayed: HashSet::new(),
+            graph_links: HashMap::new(),
+            rule_links: HashMap::new(),
+            run_hooks: false,
+            webhook: None,
+            audit: None,
+            forced_latency: None,
+            device_profiles: HashMap::new(),
+            stats: HashMap::new(),
+            connected: false,
+            last_event: None,
+            link_states: HashMap::new(),
+            defaults: HashMap::new(),
+            uses_defaults: rules.uses_defaults,
+            stereo_def: rules.stereo_def,
+            combine_sinks: rules.combine_sinks,
+            filters: rules.filters,
+            node_settings: rules.node_settings,
+            never_def: rules.never_def,
+            profile_def: rules.profile_def,
+            policy: rules.policy,
+            routes: rules.routes,
+            stereo_ports: Vec::new(),
+            ports: Vec::new(),
+            nodes: Vec::new(),
+            tracked_links: Vec::new(),
+        }
+    }
+
+    /// Loads the rules of the file, with the default devices known so far
+    pub fn load(config: &Config, defaults: &HashMap<String, String>) -> Result<AppState, Box<dyn std::error::Error>> {
+        let mut state = AppState::new(config.load(defaults)?, config.get_names);
+        state.linger = config.linger;
+        state.defaults = defaults.clone();
+        Ok(state)
+    }
+
+    pub fn try_add_node(&mut self, def: Node) -> bool {
+        if !self.node_def.iter().any(|a| a.name.eq(&def.name)) {
+            return false;
+        };
+
+        let mut nodes = self
+            .nodes
+            .iter()
+            .filter(|a| !a.name.eq(&def.name))
+            .cloned()
+            .collect::<Vec<Rc<Node>>>();
+
+        // Kept when the rules are loaded again, the node did not come back
+        if let Some(times) = self.node_times.as_mut() {
+            times.entry(def.id).or_insert_with(Instant::now);
+        }
+
+        nodes.push(Rc::new(def));
+
+        self.nodes = nodes;
+
+        true
+    }
+
+    pub fn get_node(&self, id: u32) -> Option<Rc<Node>> {
+        search(&self.nodes, |a| a.id == id)
+    }
+
+    pub fn get_port(&self, def: &PortDef) -> Option<Rc<Port>> {
+        search(&self.ports, |a| a.name.eq(&def.name) && a.node.name.eq(&def.node.name))
+    }
+
+    /// Adds the link rules of the map rules that match the port
+    pub fn expand_maps(&mut self, node: &str, port: &str, props: &PortProps) {
+        for (map, port_out, port_in) in self.map_matcher.matches(node, port) {
+            if (props.monitor && self.ignores_monitors(&map.options)) || !map.options.allows_port(props) {
+                continue;
+            }
+
+            if self.has_link_def(&map.node_out.name, &port_out, &map.node_in.name, &port_in) {
+                continue;
+            }
+
+            let link = LinkDef {
+                port_out: self.get_port_def(&map.node_out, port_out),
+                port_in: self.get_port_def(&map.node_in, port_in),
+                options: map.options.clone(),
+                active: Cell::new(true),
+                failures: Cell::new(0),
+                expanded_from: Some(map.to_string()),
+                disabled: Cell::new(map.options.manual),
+            };
+            link.active.set(!link.disabled.get() && link.is_scheduled_now());
+
+            println!("Mapped link: {}", link.label());
+
+            self.add_link_def(link);
+        }
+    }
+
+    pub fn ignores_monitors(&self, options: &RuleOptions) -> bool {
+        self.ignore_monitors || options.ignore_monitors
+    }
+
+    pub fn has_link_def(&self, node_out: &str, port_out: &str, node_in: &str, port_in: &str) -> bool {
+        self.links_by_port
+            .get(&(node_out.to_string(), port_out.to_string()))
+            .is_some_and(|links| links.iter().any(|a| a.port_in.node.name.eq(node_in) && a.port_in.name.eq(port_in)))
+    }
+
+    pub fn add_link_def(&mut self, link: LinkDef) {
+        let link = Rc::new(link);
+        index_link(&mut self.links_by_port, &link);
+        self.link_def.push(link);
+    }
+
+    /// Adds the link rules of the stereo and channels rules between this port and the ports of the same channel on the other node
+    pub fn expand_stereo(&mut self, id: u32, node: &Rc<Node>, name: &str, props: &PortProps) {
+        let channel = Channel::of_port(name);
+
+        if channel.is_none() && props.channel.is_none() && !self.stereo_def.iter().any(|a| a.by_name) {
+            return;
+        }
+
+        let defs = self
+            .stereo_def
+            .iter()
+            .filter(|a| a.node_out.name.eq(&node.name) || a.node_in.name.eq(&node.name))
+            .filter(|a| !(props.monitor && self.ignores_monitors(&a.options)) && a.options.allows_port(props))
+            .cloned()
+            .collect::<Vec<Rc<StereoDef>>>();
+
+        if defs.is_empty() {
+            return;
+        }
+
+        let port = StereoPort {
+            id,
+            node: node.clone(),
+            name: name.to_string(),
+            channel,
+            props: props.clone(),
+        };
+
+        self.stereo_ports.retain(|a| a.id != id);
+        self.stereo_ports.push(port.clone());
+
+        for def in defs {
+            let key = match port.key(&def) {
+                Some(key) => key,
+                None => continue,
+            };
+
+            let can_be = |port: &StereoPort, node: &NodeDef, direction: Direction| {
+                port.node.name.eq(&node.name) && port.props.direction != Some(if direction == Direction::In { Direction::Out } else { Direction::In })
+            };
+
+            let found = self
+                .stereo_ports
+                .iter()
+                .filter(|other| other.key(&def).is_some_and(|a| a.eq(&key)))
+                .filter(|other| !(other.props.monitor && self.ignores_monitors(&def.options)) && def.options.allows_port(&other.props))
+                .filter_map(|other| {
+                    if can_be(&port, &def.node_out, Direction::Out) && can_be(other, &def.node_in, Direction::In) {
+                        Some((port.clone(), other.clone()))
+                    } else if can_be(other, &def.node_out, Direction::Out) && can_be(&port, &def.node_in, Direction::In) {
+                        Some((other.clone(), port.clone()))
+                    } else {
+                        None
+                    }
+                })
+                .collect::<Vec<(StereoPort, StereoPort)>>();
+
+            // Devices do not agree on the order of the channels, so a missing one is only noticed by its name
+            if def.surround && found.is_empty() {
+                let other = if can_be(&port, &def.node_out, Direction::Out) { &def.node_in } else { &def.node_out };
+
+                if self.stereo_ports.iter().any(|a| a.node.name.eq(&other.name) && a.key(&def).is_some()) {
+                    // Every port of the node is tried, most of the ones without a channel have nothing to pair with
+                    if !def.by_name {
+                        println!("Warning: channel {} of [{}]{} has no counterpart on [{}] yet", key, node.name, name, other.name);
+                    } else if self.get_names {
+                        println!("No port of [{}] pairs with [{}]{} yet", other.name, node.name, name);
+                    }
+                }
+            }
+
+            for (port_out, port_in) in found {
+                if !self.has_link_def(&def.node_out.name, &port_out.name, &def.node_in.name, &port_in.name) {
+                    let link = LinkDef {
+                        port_out: self.get_port_def(&def.node_out, port_out.name.clone()),
+                        port_in: self.get_port_def(&def.node_in, port_in.name.clone()),
+                        options: def.options.clone(),
+                        active: Cell::new(true),
+                        failures: Cell::new(0),
+                        expanded_from: Some(def.to_string()),
+                        disabled: Cell::new(def.options.manual),
+                    };
+                    link.active.set(!link.disabled.get() && link.is_scheduled_now());
+
+                    println!("Stereo link: {}", link.label());
+
+                    self.add_link_def(link);
+                }
+
+                // The other end showed up first, when it had no rule yet
+                for other in [port_out, port_in] {
+                    if other.id != id && !self.ports.iter().any(|a| a.id == other.id) {
+                        self.ports.push(Rc::new(Port { id: other.id, name: other.name, node: other.node }));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Keeps the active profile of a device, PipeWire tells it when the device is bound and every time it changes.
+    /// A new profile (e.g. A2DP to HSP) gives the device a whole new set of nodes and ports, so the rules paused by the links
+    /// that failed meanwhile are tried again, and the rules of the nodes of the device are applied again
+    pub fn update_device_profile(&mut self, device: u32, profile: &str, backend: &dyn GraphBackend) {
+        match self.device_profiles.insert(device, profile.to_string()) {
+            Some(old) if old.ne(profile) => println!("Device {} switched from profile {} to {}", self.device_name(device), old, profile),
+            _ => return,
+        }
+
+        for link in self.link_def.iter().filter(|a| a.is_paused()) {
+            link.failures.set(0);
+        }
+
+        let of_device = |id: u32| match self.seen_globals.get(&id) {
+            Some(RegistryEvent::Global { props: Some(props), .. }) => props.get("device.id").is_some_and(|a| a.eq(&device.to_string())),
+            _ => false,
+        };
+        let nodes = self.nodes.iter().filter(|a| of_device(a.id)).map(|a| a.name.clone()).collect::<Vec<String>>();
+        let rules = self
+            .link_def
+            .iter()
+            .filter(|a| nodes.contains(&a.port_out.node.name) || nodes.contains(&a.port_in.node.name))
+            .cloned()
+            .collect::<Vec<Rc<LinkDef>>>();
+
+        self.reconcile(&rules, backend);
+    }
+
+    /// Description of a device for the messages, its id when it was not seen
+    pub fn device_name(&self, id: u32) -> String {
+        match self.seen_globals.get(&id) {
+            Some(RegistryEvent::Global { props: Some(props), .. }) => {
+                props.get("device.description").or_else(|| props.get("device.name")).cloned().unwrap_or_else(|| id.to_string())
+            }
+            _ => id.to_string(),
+        }
+    }
+
+    /// Applies the `profile` rule of a device that just showed up, before it makes its nodes and ports
+    pub fn apply_profile(&self, id: u32, props: &HashMap<String, String>, backend: &dyn GraphBackend) {
+        if let Some(profile) = self.profile_def.iter().find(|a| a.matches(props)) {
+            println!("Setting the profile of device {} to {}", self.device_name(id), profile.profile);
+
+            if let Err(e) = backend.set_device_profile(id, &profile.profile) {
+                println!("Failed to set the profile of device {}: {}", self.device_name(id), e);
+            }
+        }
+    }
+
+    /// Applies the `props` and `volume` rules of a node that just showed up
+    pub fn apply_node_settings(&self, id: u32, props: &HashMap<String, String>, backend: &dyn GraphBackend) {
+        let node = match self.get_node(id) {
+            Some(node) => node,
+            None => return,
+        };
+        let channels = props.get("audio.channels").and_then(|a| a.parse::<usize>().ok()).filter(|a| *a > 0);
+
+        for settings in self.node_settings.iter().filter(|a| a.node.name.eq(&node.name)) {
+            let pod = settings.pod(channels);
+            println!("Setting the props of [{}]: {}", node.name, pod);
+
+            if let Err(e) = backend.set_node_props(id, &pod) {
+                println!("Failed to set the props of [{}]: {}", node.name, e);
+            }
+        }
+    }
+
+    /// Adds a channels rule from a new stream of an application to the sink of the `@route` of its binary,
+    /// or to the `@policy` sink, unless a rule names the stream
+    pub fn claim_stream(&mut self, name: &str, props: &HashMap<String, String>) {
+        if props.get("media.class").is_none_or(|a| a != "Stream/Output/Audio") || self.node_def.iter().any(|a| a.name.eq(name)) {
+            return;
+        }
+
+        let binary = props.get("application.process.binary");
+        let route = self.routes.iter().find(|(pattern, _, _)| binary.is_some_and(|a| glob_matches(pattern, a)));
+
+        let (sink, options) = match route.map(|(_, sink, options)| (sink, options)).or(self.policy.as_ref().map(|(sink, options)| (sink, options))) {
+            Some((sink, options)) => (sink.clone(), options.clone()),
+            None => return,
+        };
+
+        let node = Rc::new(NodeDef { name: name.to_string() });
+        let def = StereoDef {
+            node_out: node.clone(),
+            node_in: sink,
+            surround: true,
+            by_name: false,
+            options,
+        };
+
+        println!("Policy link: {}", def);
+
+        self.node_def.push(node);
+        self.stereo_def.push(Rc::new(def));
+    }
+
+    /// Returns true if a link between these ports would be made by one of the rules
+    pub fn matches_rule(&self, node_out: &str, port_out: &str, node_in: &str, port_in: &str) -> bool {
+        let is_port = |def: &PortDef, node: &str, port: &str| def.node.name.eq(node) && def.name.eq(port);
+
+        if self.link_def.iter().any(|a| is_port(&a.port_out, node_out, port_out) && is_port(&a.port_in, node_in, port_in)) {
+            return true;
+        }
+
+        let channel = |node: &str, port: &str| {
+            self.stereo_ports
+                .iter()
+                .find(|a| a.node.name.eq(node) && a.name.eq(port))
+                .and_then(|a| a.props.channel.clone())
+        };
+
+        let stereo = self.stereo_def.iter().any(|stereo| {
+            stereo.node_out.name.eq(node_out)
+                && stereo.node_in.name.eq(node_in)
+                && if stereo.surround {
+                    match (channel(node_out, port_out), channel(node_in, port_in)) {
+                        (Some(a), Some(b)) => a == b,
+                        (None, None) => stereo.by_name && port_out == port_in,
+                        _ => false,
+                    }
+                } else {
+                    Channel::of_port(port_out).is_some_and(|a| Channel::of_port(port_in) == Some(a))
+                }
+        });
+
+        stereo
+            || self.map_def.iter().any(|map| {
+                map.node_out.name.eq(node_out)
+                    && map.node_in.name.eq(node_in)
+                    && map.ports.iter().any(|(out, input)| out.translate(port_out, input).is_some_and(|a| a.eq(port_in)))
+            })
+    }
+
+    pub fn get_port_def(&mut self, node: &Rc<NodeDef>, name: String) -> Rc<PortDef> {
+        if let Some(port) = search(&self.port_def, |a| a.name.eq(&name) && a.node.name.eq(&node.name)) {
+            return port;
+        }
+
+        let port = Rc::new(PortDef { node: node.clone(), name });
+        self.port_def.push(port.clone());
+        port
+    }
+
+    pub fn try_add_port(&mut self, id: u32, name: String, node_id: u32, props: &PortProps) -> bool {
+        let node = self.get_node(node_id);
+
+        if node.is_none() {
+            return false;
+        }
+
+        let node = node.unwrap();
+
+        self.expand_maps(&node.name, &name, props);
+        self.expand_stereo(id, &node, &name, props);
+
+        let name = self
+            .index_name(&node.name, &name, props)
+            .or_else(|| self.channel_name(&node, &name, props))
+            .unwrap_or(name);
+
+        if self
+            .port_def
+            .iter()
+            .filter(|a| a.name.eq(&name) && a.node.name.eq(&node.name))
+            .count()
+            != 1
+        {
+            if self.get_names && node.id == node_id {
+                println!("Port from node {} -> {}: {}", &node.name, id, name);
+            }
+
+            let missing = self
+                .port_def
+                .iter()
+                .filter(|a| a.node.name.eq(&node.name) && !self.ports.iter().any(|b| b.node.name.eq(&node.name) && b.name.eq(&a.name)))
+                .map(|a| a.name.clone())
+                .collect::<Vec<String>>();
+            self.hint(&missing, &name, |a| format!("[{}] has no port {}", node.name, a));
+            return false;
+        }
+
+        let mut ports = self
+            .ports
+            .iter()
+            .filter(|a| !(a.name.eq(&name) && a.node.name.eq(&node.name)))
+            .cloned()
+            .collect::<Vec<Rc<Port>>>();
+
+        ports.push(Rc::new(Port { id, name, node }));
+
+        self.ports = ports;
+
+        true
+    }
+
+    /// `#3` when the port is named by its index in a rule, `[Node](#3)`, and not by its name
+    pub fn index_name(&self, node: &str, name: &str, props: &PortProps) -> Option<String> {
+        if self.port_def.iter().any(|a| a.name.eq(name) && a.node.name.eq(node)) {
+            return None;
+        }
+
+        let alias = format!("#{}", props.index?);
+        let is_alias = |port: &PortDef| port.node.name.eq(node) && port.name.eq(&alias);
+
+        // Inputs and outputs are counted apart, so the side of the rule tells which one it is
+        self.links_by_port
+            .get(&(node.to_string(), alias.clone()))?
+            .iter()
+            .any(|a| match props.direction {
+                Some(Direction::Out) => is_alias(&a.port_out),
+                Some(Direction::In) => is_alias(&a.port_in),
+                None => true,
+            })
+            .then_some(alias)
+    }
+
+    /// `L` or `R` when the port is named by its channel in a rule, `[Node](L)`, and not by its name
+    pub fn channel_name(&self, node: &Node, name: &str, props: &PortProps) -> Option<String> {
+        if self.port_def.iter().any(|a| a.name.eq(name) && a.node.name.eq(&node.name)) {
+            return None;
+        }
+
+        let alias = props.channel_alias(name)?;
+
+        // The first port of the channel, the node may have more than one going the same way
+        if self.ports.iter().any(|a| a.node.id == node.id && a.name.eq(alias)) {
+            return None;
+        }
+
+        let is_alias = |port: &PortDef| port.node.name.eq(&node.name) && port.name.eq(alias);
+
+        self.links_by_port
+            .get(&(node.name.clone(), alias.to_string()))?
+            .iter()
+            .any(|a| match props.direction {
+                Some(Direction::Out) => is_alias(&a.port_out),
+                Some(Direction::In) => is_alias(&a.port_in),
+                None => true,
+            })
+            .then(|| alias.to_string())
+    }
+
+    /// Removes the node or port with the given id, returns true if it was one of ours
+    pub fn remove_global(&mut self, id: u32) -> bool {
+        let (nodes, ports) = (self.nodes.len(), self.ports.len());
+
+        self.nodes.retain(|a| a.id != id);
+        self.ports.retain(|a| a.id != id && a.node.id != id);
+        if let Some(times) = self.node_times.as_mut() {
+            times.remove(&id);
+        }
+        self.stereo_ports.retain(|a| a.id != id && a.node.id != id);
+
+        if nodes == self.nodes.len() && ports == self.ports.len() {
+            return false;
+        }
+
+        // PipeWire removes the links together with the ports
+        let ports = &self.ports;
+        self.tracked_links.retain(|a| {
+            ports.iter().any(|p| p.id == a.port_out) && ports.iter().any(|p| p.id == a.port_in)
+        });
+
+        true
+    }
+
+    pub fn create_links(&mut self, port: Rc<Port>, backend: &dyn GraphBackend) {
+        let links = self
+            .links_by_port
+            .get(&(port.node.name.clone(), port.name.clone()))
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect::<Vec<Rc<LinkDef>>>();
+
+        self.reconcile(&links, backend);
+    }
+
+    /// The rules of `rules` that want their link in the graph now, the active ones with their nodes and ports there.
+    /// Of the prioritized rules of an input only the highest one whose nodes have settled is wanted,
+    /// the ones still settling are returned apart
+    pub fn desired_links(&self, rules: &[Rc<LinkDef>]) -> (Vec<Rc<LinkDef>>, Vec<Rc<LinkDef>>) {
+        let present = |link: &LinkDef| link.active.get() && self.get_port(&link.port_in).is_some() && self.get_port(&link.port_out).is_some();
+
+        let mut desired = rules
+            .iter()
+            .filter(|link| link.options.priority.is_none() && present(link))
+            .cloned()
+            .collect::<Vec<Rc<LinkDef>>>();
+        let mut unsettled = Vec::new();
+
+        let mut inputs: Vec<&PortDef> = Vec::new();
+        for link in rules.iter().filter(|link| link.options.priority.is_some()) {
+            if !inputs.iter().any(|a| a.is(&link.port_in)) {
+                inputs.push(&link.port_in);
+            }
+        }
+
+        for input in inputs {
+            // The links of the others are only torn down once the device of the rule stays
+            let (candidates, waiting) = self
+                .link_def
+                .iter()
+                .filter(|link| link.options.priority.is_some() && link.port_in.is(input) && present(link))
+                .cloned()
+                .partition::<Vec<Rc<LinkDef>>, _>(|link| self.is_settled(link));
+
+            unsettled.extend(waiting);
+            desired.extend(candidates.into_iter().max_by_key(|link| link.options.priority));
+        }
+
+        (desired, unsettled)
+    }
+
+    /// Brings the graph to what `rules` want: our links of them that are not wanted anymore are removed
+    /// and the wanted ones that are not there are made. The prioritized rules of their inputs decide together
+    pub fn reconcile(&mut self, rules: &[Rc<LinkDef>], backend: &dyn GraphBackend) {
+        let (desired, unsettled) = self.desired_links(rules);
+
+        for link in unsettled {
+            self.wait_grace(link);
+        }
+
+        let in_scope = |def: &LinkDef| {
+            rules.iter().any(|a| std::ptr::eq(a.as_ref(), def))
+                || def.options.priority.is_some() && rules.iter().any(|a| a.options.priority.is_some() && a.port_in.is(&def.port_in))
+        };
+
+        let (remove, keep) = std::mem::take(&mut self.tracked_links)
+            .into_iter()
+            .partition::<Vec<TrackedLink>, _>(|a| in_scope(&a.def) && !desired.iter().any(|b| Rc::ptr_eq(b, &a.def)));
+        self.tracked_links = keep;
+
+        for link in remove {
+            let reason = if link.def.disabled.get() {
+                "of a disabled rule"
+            } else if !link.def.active.get() {
+                "schedule ended"
+            } else {
+                "preempted"
+            };
+
+            println!("Removing link, {}: {}", reason, link.def.label());
+            self.audit("destroy", None, (link.port_out, link.port_in), Some(&link.def), reason);
+            if let Err(e) = backend.destroy_link(link.handle) {
+                println!("Failed to remove link: {}", e);
+            }
+        }
+
+        for link in desired {
+            if !self.tracked_links.iter().any(|a| Rc::ptr_eq(&a.def, &link)) {
+                self.create_link(link, backend);
+            }
+        }
+    }
+
+    pub fn create_link(&mut self, link: Rc<LinkDef>, backend: &dyn GraphBackend) {
+        let (port_in, port_out) = match (self.get_port(&link.port_in), self.get_port(&link.port_out)) {
+            (Some(port_in), Some(port_out)) => (port_in, port_out),
+            _ => return,
+        };
+
+        let is_kind = |node: &Node, kind: Option<NodeKind>| kind.is_none() || node.kind.is_none() || node.kind == kind;
+        if !is_kind(&port_out.node, link.options.from) || !is_kind(&port_in.node, link.options.to) {
+            if self.get_names {
+                println!("Not linking {}: the nodes are not of the kind of the rule", link.label());
+            }
+            return;
+        }
+
+        if link.options.wait_ready && !(self.is_ready(port_out.node.id) && self.is_ready(port_in.node.id)) {
+            println!("Waiting for the nodes to be ready: {}", link.label());
+            return;
+        }
+
+        if !self.is_settled(&link) {
+            self.wait_grace(link);
+            return;
+        }
+
+        if link.is_paused() {
+            return;
+        }
+
+        // Already made, by a rule before a reload
+        if self.tracked_links.iter().any(|a| a.port_out == port_out.id && a.port_in == port_in.id) {
+            return;
+        }
+
+        // Already in the graph, made by an instance before us or by someone else
+        if self.graph_links.values().any(|a| *a == (port_out.id, port_in.id)) {
+            return;
+        }
+
+        self.count(&link, |a| a.matched += 1);
+
+        if let Some(never) = self.never_def.iter().find(|a| a.matches(&port_out.node.name, &port_out.name, &port_in.node.name, &port_in.name)) {
+            println!("Not linking {}: {} blocks it", link.label(), never);
+            return;
+        }
+
+        if !link.options.allow_cycle && self.closes_cycle(port_out.node.id, port_in.node.id) {
+            println!("Not linking {}: it would feed the audio back into [{}], add @allow-cycle to link anyway", link.label(), port_out.node.name);
+            return;
+        }
+
+        // Only the links we are about to make use up the limit
+        if !self.rate_limit.as_mut().is_none_or(|a| a.take()) {
+            if self.waiting_rate.is_empty() {
+                println!("Too many links at once, delaying the rest");
+            }
+            if !self.waiting_rate.iter().any(|a| Rc::ptr_eq(a, &link)) {
+                self.waiting_rate.push(link);
+            }
+            return;
+        }
+
+        match &link.options.name {
+            Some(name) => println!("Try to created link: [{}]{} -> [{}]{} ({})", port_out.node.name, port_out.name, port_in.node.name, port_in.name, name),
+            None => println!("Try to created link: [{}]{} -> [{}]{}", port_out.node.name, port_out.name, port_in.node.name, port_in.name),
+        }
+
+        let mut props = vec![
+            ("object.linger".to_string(), if self.linger { "1" } else { "0" }.to_string()),
+            (OWNER_KEY.to_string(), OWNER.to_string()),
+            (RULE_KEY.to_string(), link.to_string()),
+        ];
+        if let Some(name) = &link.options.name {
+            props.push((RULE_NAME_KEY.to_string(), name.clone()));
+        }
+        props.extend(link.options.props.iter().cloned());
+
+        // The inputs of other nodes the output goes to now, moved away from once the new link is made
+        let moved = if link.options.move_output {
+            self.graph_links
+                .values()
+                .copied()
+                .chain(self.tracked_links.iter().map(|a| (a.port_out, a.port_in)))
+                .filter(|a| a.0 == port_out.id && self.port_node(a.1).is_some_and(|node| node != port_in.node.id))
+                .map(|a| a.1)
+                .collect::<Vec<u32>>()
+        } else {
+            Vec::new()
+        };
+
+        // Try to create the link
+        match backend.create_link(&port_out, &port_in, &props) {
+            Ok(handle) => {
+                // Only now, a link that fails must not leave the ports with nothing linked
+                if !moved.is_empty() {
+                    self.remove_links(|out, input| out == port_out.id && moved.contains(&input), "to move the output", backend);
+                }
+                if link.options.exclusive_input {
+                    self.remove_links(|out, input| input == port_in.id && out != port_out.id, "into an exclusive input", backend);
+                }
+
+                link.failures.set(0);
+                self.notify("created", &link, None);
+                self.count(&link, |a| a.created += 1);
+                self.audit("create", None, (port_out.id, port_in.id), Some(&link), "");
+
+                if link.is_tracked(self.linger) {
+                    self.tracked_links.push(TrackedLink {
+                        def: link,
+                        port_out: port_out.id,
+                        port_in: port_in.id,
+                        handle,
+                    });
+                }
+            }
+            Err(e) => {
+                println!("Failed to create link: {}", e);
+                self.notify("failed", &link, Some(&e));
+                self.count(&link, |a| a.failed += 1);
+                self.audit("failed", None, (port_out.id, port_in.id), Some(&link), &e);
+
+                link.failures.set(link.failures.get() + 1);
+                if link.is_paused() {
+                    println!("Rule paused after {} failed attempts, reload to try again: {}", link.failures.get(), link.label());
+                }
+            }
+        }
+    }
+
+    /// Removes our links and the ones in the graph between the ports `remove` says, for `@exclusive-input` and `@move`
+    pub fn remove_links<F: Fn(u32, u32) -> bool>(&mut self, remove: F, reason: &str, backend: &dyn GraphBackend) {
+        let (removed, keep) = std::mem::take(&mut self.tracked_links)
+            .into_iter()
+            .partition::<Vec<TrackedLink>, _>(|a| remove(a.port_out, a.port_in));
+        self.tracked_links = keep;
+
+        let mut ports = Vec::new();
+
+        for link in removed {
+            println!("Removing link {}: {}", reason, link.def.label());
+            self.audit("destroy", None, (link.port_out, link.port_in), Some(&link.def), reason);
+            ports.push((link.port_out, link.port_in));
+            if let Err(e) = backend.destroy_link(link.handle) {
+                println!("Failed to remove link: {}", e);
+            }
+        }
+
+        // Made by someone else, or by our rules before a restart
+        let others = self
+            .graph_links
+            .iter()
+            .filter(|(_, a)| remove(a.0, a.1) && !ports.contains(a))
+            .map(|(id, _)| *id)
+            .collect::<Vec<u32>>();
+
+        for id in others {
+            println!("Removing link {} {}", id, reason);
+            if let Some(ports) = self.graph_links.remove(&id) {
+                self.audit("destroy", Some(id), ports, None, reason);
+            }
+            if let Err(e) = backend.destroy_link(LinkHandle::Global(id)) {
+                println!("Failed to remove link {}: {}", id, e);
+            }
+        }
+    }
+
+    /// Bus path of the device of the node, from its properties in the registry
+    pub fn device_path(&self, props: &HashMap<String, String>) -> Option<String> {
+        bus_path(props, |device| match self.seen_globals.get(&device)? {
+            RegistryEvent::Global { props: Some(props), .. } => props.get("device.bus-path").cloned(),
+            _ => None,
+        })
+    }
+
+    /// Prints a hint when `found` is almost the name of one of the missing nodes or ports of the rules, once for every pair
+    pub fn hint<F: Fn(&str) -> String>(&mut self, missing: &[String], found: &str, describe: F) {
+        for name in missing.iter().filter(|a| is_near_miss(a, found)) {
+            let pair = (name.clone(), found.to_string());
+            if !self.hints.contains(&pair) {
+                println!("Hint: {} yet, did you mean `{}`?", describe(name), found);
+                self.hints.push(pair);
+            }
+        }
+    }
+
+    /// The `never` rule a link of the graph breaks, by the names of its ports and nodes in the registry
+    pub fn blocking_rule(&self, port_out: u32, port_in: u32) -> Option<&NeverDef> {
+        if self.never_def.is_empty() {
+            return None;
+        }
+
+        let names = |port: u32| {
+            let props = |id: u32| match self.seen_globals.get(&id)? {
+                RegistryEvent::Global { props: Some(props), .. } => Some(props),
+                _ => None,
+            };
+            let port = props(port)?;
+            let node = props(port.get("node.id")?.parse().ok()?)?;
+            Some((node.get("node.name")?.clone(), port.get("port.name")?.clone()))
+        };
+
+        let ((node_out, port_out), (node_in, port_in)) = (names(port_out)?, names(port_in)?);
+        self.never_def.iter().find(|a| a.matches(&node_out, &port_out, &node_in, &port_in))
+    }
+
+    /// Node of the port, from its properties in the registry
+    pub fn port_node(&self, port: u32) -> Option<u32> {
+        if let Some(port) = self.ports.iter().find(|a| a.id == port) {
+            return Some(port.node.id);
+        }
+
+        match self.seen_globals.get(&port)? {
+            RegistryEvent::Global { props: Some(props), .. } => props.get("node.id")?.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// Returns true when the audio of `node_in` already reaches `node_out` through the links in the graph,
+    /// so linking them would make a feedback loop
+    pub fn closes_cycle(&self, node_out: u32, node_in: u32) -> bool {
+        let edges = self
+            .graph_links
+            .values()
+            .copied()
+            .chain(self.tracked_links.iter().map(|a| (a.port_out, a.port_in)))
+            .filter_map(|(port_out, port_in)| Some((self.port_node(port_out)?, self.port_node(port_in)?)))
+            .collect::<Vec<(u32, u32)>>();
+
+        let mut next = vec![node_in];
+        let mut seen = Vec::new();
+
+        while let Some(node) = next.pop() {
+            if node == node_out {
+                return true;
+            }
+            if seen.contains(&node) {
+                continue;
+            }
+            seen.push(node);
+            next.extend(edges.iter().filter(|a| a.0 == node).map(|a| a.1));
+        }
+
+        false
+    }
+
+    /// Returns false while a node of a `@grace-period` rule has not been there for long enough
+    pub fn is_settled(&self, link: &LinkDef) -> bool {
+        let (grace, times) = match (link.options.grace_period, self.node_times.as_ref()) {
+            (Some(grace), Some(times)) => (grace, times),
+            _ => return true,
+        };
+
+        [&link.port_out, &link.port_in]
+            .iter()
+            .filter_map(|a| self.get_port(a))
+            .all(|port| times.get(&port.node.id).is_none_or(|a| a.elapsed() >= grace))
+    }
+
+    pub fn wait_grace(&mut self, link: Rc<LinkDef>) {
+        if !self.waiting_grace.iter().any(|a| Rc::ptr_eq(a, &link)) {
+            println!("Waiting for the nodes to settle: {}", link.label());
+            self.waiting_grace.push(link);
+        }
+    }
+
+    /// Makes the links of the `@grace-period` rules whose nodes have been there for long enough,
+    /// and the ones delayed by the rate limit
+    pub fn apply_waiting(&mut self, backend: &dyn GraphBackend) {
+        if !self.waiting_grace.is_empty() {
+            let (settled, waiting) = std::mem::take(&mut self.waiting_grace)
+                .into_iter()
+                .partition::<Vec<Rc<LinkDef>>, _>(|link| self.is_settled(link));
+            self.waiting_grace = waiting;
+
+            self.reconcile(&settled, backend);
+        }
+
+        let delayed = std::mem::take(&mut self.waiting_rate);
+        self.reconcile(&delayed, backend);
+
+        if !self.waiting_enforce.is_empty() {
+            let now = Instant::now();
+            let (due, waiting) = std::mem::take(&mut self.waiting_enforce)
+                .into_iter()
+                .partition::<Vec<(Rc<LinkDef>, Instant)>, _>(|a| a.1 <= now);
+            self.waiting_enforce = waiting;
+
+            let due = due.into_iter().map(|a| a.0).filter(|a| a.active.get() && !a.disabled.get()).collect::<Vec<Rc<LinkDef>>>();
+            for link in &due {
+                println!("Making the enforced link again: {}", link.label());
+            }
+            self.reconcile(&due, backend);
+        }
+    }
+
+    /// A link in the graph went away, when we still track it someone else removed it,
+    /// and the link of an `@enforce` rule is made again after its delay
+    pub fn enforce_link(&mut self, ports: (u32, u32)) {
+        let index = match self.tracked_links.iter().position(|a| (a.port_out, a.port_in) == ports) {
+            Some(index) => index,
+            None => return,
+        };
+
+        let delay = match self.tracked_links[index].def.options.enforce {
+            Some(delay) => delay,
+            None => return,
+        };
+
+        let link = self.tracked_links.remove(index);
+        println!("Link removed by someone else, making it again in {}ms: {}", delay.as_millis(), link.def.label());
+        self.waiting_enforce.push((link.def, Instant::now() + delay));
+    }
+
+    /// Tracks a link made by one of our rules before, by an instance that exited, so it is not made again
+    pub fn adopt_link(&mut self, id: u32, props: &HashMap<String, String>) {
+        let get_id = |key: &str| props.get(key).and_then(|a| a.parse::<u32>().ok());
+
+        let (port_out, port_in, rule) = match (get_id("link.output.port"), get_id("link.input.port"), props.get(RULE_KEY)) {
+            (Some(port_out), Some(port_in), Some(rule)) => (port_out, port_in, rule),
+            _ => return,
+        };
+
+        let def = match self.link_def.iter().find(|a| a.to_string().eq(rule) && a.is_tracked(self.linger)) {
+            Some(def) => def.clone(),
+            None => return,
+        };
+
+        if self.tracked_links.iter().any(|a| a.port_out == port_out && a.port_in == port_in) {
+            return;
+        }
+
+        println!("Adopted link {}: {}", id, def.label());
+
+        self.tracked_links.push(TrackedLink { def, port_out, port_in, handle: LinkHandle::Global(id) });
+    }
+
+    /// Runs the `@on-link` hook of the rule that made a link showing up in the graph
+    pub fn link_added(&mut self, id: u32, props: &HashMap<String, String>, backend: &dyn GraphBackend) {
+        if !self.run_hooks || self.rule_links.contains_key(&id) || props.get(OWNER_KEY).is_none_or(|a| a != OWNER) {
+            return;
+        }
+
+        let def = match props.get(RULE_KEY).and_then(|rule| self.link_def.iter().find(|a| a.to_string().eq(rule))) {
+            Some(def) => def.clone(),
+            None => return,
+        };
+
+        if let Some(command) = &def.options.on_link {
+            run_hook(command, "link", id, self.graph_links.get(&id).copied(), &def);
+        }
+        self.rule_links.insert(id, def);
+        self.update_latency(backend);
+    }
+
+    /// Runs the `@on-unlink` hook of the rule that made a link gone from the graph
+    pub fn link_removed(&mut self, id: u32, ports: Option<(u32, u32)>, backend: &dyn GraphBackend) {
+        if let Some(def) = self.rule_links.remove(&id) {
+            if let Some(command) = &def.options.on_unlink {
+                run_hook(command, "unlink", id, ports, &def);
+            }
+            self.notify("removed", &def, None);
+            self.update_latency(backend);
+        }
+    }
+
+    /// Forces the smallest quantum of the `@latency` rules with a link in the graph, or lets it go when there is none
+    pub fn update_latency(&mut self, backend: &dyn GraphBackend) {
+        let latency = self.rule_links.values().filter_map(|a| a.options.latency).min_by_key(|a| a.quantum);
+
+        if latency == self.forced_latency {
+            return;
+        }
+
+        match latency {
+            Some(latency) => println!("Forcing the quantum to {}", latency),
+            None => println!("No @latency rule is linked, the quantum is not forced anymore"),
+        }
+
+        // Tried again on the next link when it fails
+        match backend.force_latency(latency) {
+            Ok(()) => self.forced_latency = latency,
+            Err(e) => println!("Failed to force the quantum: {}", e),
+        }
+    }
+
+    /// Updates the `stats` of the rule
+    pub fn count(&mut self, link: &LinkDef, update: impl FnOnce(&mut RuleStats)) {
+        let stats = self.stats.entry(link.to_string()).or_default();
+        update(stats);
+        stats.last = Some(unix_time());
+    }
+
+    /// Both ports of the rule are there and the graph has a link between them, made by anyone
+    pub fn is_linked(&self, link: &LinkDef) -> bool {
+        match (self.get_port(&link.port_out), self.get_port(&link.port_in)) {
+            (Some(port_out), Some(port_in)) => self.graph_links.values().any(|a| *a == (port_out.id, port_in.id)),
+            _ => false,
+        }
+    }
+
+    /// The rules of the file that are not linked, only the `@required` ones with `required`.
+    /// A stereo, channels or map rule is linked once it found ports to link and all of its links are there
+    pub fn unlinked_rules(&self, required: bool) -> Vec<String> {
+        let wanted = |options: &RuleOptions| !required || options.required;
+        let mut unlinked = self.link_def[..self.file_rules]
+            .iter()
+            .filter(|a| wanted(&a.options) && a.active.get() && !a.disabled.get() && a.port_out.node.name.ne(KEEP_ALIVE_NODE))
+            .filter(|a| !self.is_linked(a))
+            .map(|a| a.to_string())
+            .collect::<Vec<String>>();
+
+        let expanded = self
+            .stereo_def
+            .iter()
+            .filter(|a| wanted(&a.options))
+            .map(|a| a.to_string())
+            .chain(self.map_def.iter().filter(|a| wanted(&a.options)).map(|a| a.to_string()));
+
+        for rule in expanded {
+            let mut links = self.link_def[self.file_rules..].iter().filter(|a| a.expanded_from.as_deref() == Some(rule.as_str())).peekable();
+            if links.peek().is_none() || links.any(|a| a.active.get() && !a.disabled.get() && !self.is_linked(a)) {
+                unlinked.push(rule);
+            }
+        }
+
+        unlinked
+    }
+
+    /// Writes a link we made, failed to make or removed to the `--audit-log`, the id of the link is not known when it is made
+    pub fn audit(&self, action: &str, id: Option<u32>, ports: (u32, u32), rule: Option<&LinkDef>, reason: &str) {
+        if let Some(audit) = &self.audit {
+            let id = id.map_or_else(String::new, |a| a.to_string());
+            let rule = rule.map_or_else(String::new, |a| a.label());
+            audit.write(&[action, &id, &ports.0.to_string(), &ports.1.to_string(), &rule, reason]);
+        }
+    }
+
+    /// Tells the webhook about a link of a rule
+    pub fn notify(&self, event: &str, link: &LinkDef, error: Option<&str>) {
+        let webhook = match &self.webhook {
+            Some(webhook) => webhook,
+            None => return,
+        };
+
+        let time = unix_time();
+        let string = |a: &str| json::Value::String(a.to_string());
+        let mut fields = vec![
+            ("event".to_string(), string(event)),
+            ("time".to_string(), json::Value::Number(time as f64)),
+            ("rule".to_string(), string(&link.to_string())),
+            ("name".to_string(), link.options.name.as_deref().map_or(json::Value::Null, string)),
+            ("node_out".to_string(), string(&link.port_out.node.name)),
+            ("port_out".to_string(), string(&link.port_out.name)),
+            ("node_in".to_string(), string(&link.port_in.node.name)),
+            ("port_in".to_string(), string(&link.port_in.name)),
+        ];
+        if let Some(error) = error {
+            fields.push(("error".to_string(), string(error)));
+        }
+
+        let mut out = String::new();
+        json::write(&json::Value::Object(fields), &mut out);
+        webhook.send(out);
+    }
+
+    pub fn is_ready(&self, node: u32) -> bool {
+        self.ready_nodes.as_ref().is_none_or(|a| a.contains(&node))
+    }
+
+    /// Updates the state of the node and makes the `@wait-ready` links that were waiting for it
+    pub fn set_node_ready(&mut self, id: u32, ready: bool, backend: &dyn GraphBackend) {
+        let nodes = match self.ready_nodes.as_mut() {
+            Some(nodes) => nodes,
+            None => return,
+        };
+
+        if nodes.contains(&id) == ready {
+            return;
+        }
+
+        if !ready {
+            nodes.retain(|a| *a != id);
+            return;
+        }
+
+        nodes.push(id);
+
+        let node = match self.get_node(id) {
+            Some(node) => node,
+            None => return,
+        };
+
+        println!("Node ready: {}", node.name);
+
+        let is_node = |def: &PortDef| def.node.name.eq(&node.name);
+
+        let links = self
+            .link_def
+            .iter()
+            .filter(|link| link.active.get() && link.options.wait_ready)
+            .filter(|link| is_node(&link.port_out) || is_node(&link.port_in))
+            .cloned()
+            .collect::<Vec<Rc<LinkDef>>>();
+
+        self.reconcile(&links, backend);
+    }
+
+    /// Tries every rule again, for when PipeWire denied the links before
+    pub fn apply_all(&mut self, backend: &dyn GraphBackend) {
+        let links = self.link_def.clone();
+        self.reconcile(&links, backend);
+    }
+
+    /// Re-applies the prioritized rules after a node or port went away
+    pub fn reconcile_priorities(&mut self, backend: &dyn GraphBackend) {
+        let links = self.link_def.iter().filter(|link| link.options.priority.is_some()).cloned().collect::<Vec<Rc<LinkDef>>>();
+        self.reconcile(&links, backend);
+    }
+
+    /// The indexes in `link_def` of the rule given by its number or its `@name`, every link of a named stereo or map rule
+    pub fn rule_indexes(&self, rule: &str) -> Vec<usize> {
+        if let Ok(index) = rule.parse::<usize>() {
+            return vec![index];
+        }
+
+        self.link_def.iter().enumerate().filter(|(_, a)| a.options.name.as_deref() == Some(rule)).map(|(i, _)| i).collect()
+    }
+
+    /// Turns the rule at `index` of `link_def` on or off together with its keep alive link, removing their links when it is turned off
+    pub fn set_rule_enabled(&mut self, index: usize, enabled: bool, backend: &dyn GraphBackend) -> Result<String, String> {
+        let link = self.link_def.get(index).cloned().ok_or_else(|| format!("no rule {}", index))?;
+
+        if link.disabled.get() != enabled {
+            return Ok(format!("{} is already {}\n", link.label(), if enabled { "enabled" } else { "disabled" }));
+        }
+
+        // The keep alive link of a rule is added right before it
+        let keep_alive = index
+            .checked_sub(1)
+            .and_then(|i| self.link_def.get(i))
+            .filter(|a| link.options.keep_alive && a.port_out.node.name.eq(KEEP_ALIVE_NODE) && Rc::ptr_eq(&a.port_in, &link.port_in))
+            .cloned();
+        let links = std::iter::once(link.clone()).chain(keep_alive).collect::<Vec<Rc<LinkDef>>>();
+
+        for link in &links {
+            link.disabled.set(!enabled);
+            link.active.set(enabled && link.is_scheduled_now());
+        }
+
+        if !enabled {
+            for link in &links {
+                if let (Some(port_out), Some(port_in)) = (self.get_port(&link.port_out), self.get_port(&link.port_in)) {
+                    self.remove_links(|a, b| a == port_out.id && b == port_in.id, "of a disabled rule", backend);
+                }
+            }
+        }
+
+        self.reconcile(&links, backend);
+
+        Ok(format!("{} {}\n", if enabled { "Enabled" } else { "Disabled" }, link.label()))
+    }
+
+    /// Activates the scheduled rules that entered their window and removes the links of the ones that left it
+    pub fn update_schedules(&mut self, backend: &dyn GraphBackend) {
+        let changed = self
+            .link_def
+            .iter()
+            .filter(|link| !link.disabled.get() && link.options.schedule.is_some() && link.is_scheduled_now() != link.active.get())
+            .cloned()
+            .collect::<Vec<Rc<LinkDef>>>();
+
+        for link in changed {
+            link.active.set(!link.active.get());
+
+            if link.active.get() {
+                println!("Schedule started for: {}", link.label());
+            } else {
+                println!("Schedule ended for: {}", link.label());
+            }
+
+            // The links of the rule are removed when it is not active anymore
+            self.reconcile(&[link], backend);
+        }
+    }
+}
+
+pub fn add_node(state: &mut AppState, id: u32, name: &str, nick: &str, kind: Option<NodeKind>) {
+    if state.get_names {
+        println!("Got Audio device {}: {}({})", id, name, nick);
+    }
+
+    if state.try_add_node(Node {
+        id,
+        name: name.to_string(),
+        kind,
+    }) {
+        println!("Got {}: {}({})", id, name, nick);
+    } else {
+        let missing = state
+            .node_def
+            .iter()
+            .filter(|a| !state.nodes.iter().any(|b| b.name.eq(&a.name)))
+            .map(|a| a.name.clone())
+            .collect::<Vec<String>>();
+        state.hint(&missing, name, |a| format!("no node [{}]", a));
+    }
+}
+
+pub fn add_port(state: &mut AppState, id: u32, name: &str, node_id: u32, props: PortProps, backend: &dyn GraphBackend) {
+    if state.try_add_port(id, name.to_string(), node_id, &props) {
+        let port = search(&state.ports, |a| a.id == id).unwrap();
+        println!("Got port {} for {}", name, port.node.name);
+        state.create_links(port, backend)
+    }
+}
+
+/// Devices (`Audio/Sink`, ...) and the streams of applications (`Stream/Output/Audio`, ...)
+pub fn is_audio(class: &str) -> bool {
+    class.starts_with("Audio") || (class.starts_with("Stream/") && class.ends_with("/Audio"))
+}
+
+/// Bluetooth nodes are made again with another suffix when the codec or profile changes,
+/// so they can be named in the rules without it, by their address
+pub fn bluetooth_name(state: &AppState, props: &HashMap<String, String>) -> Option<String> {
+    if props.get("device.api").is_none_or(|a| a != "bluez5") {
+        return None;
+    }
+
+    let name = props.get("node.name")?;
+    let stable = BLUETOOTH_NODE.captures(name)?.name("name")?.as_str();
+
+    if state.node_def.iter().any(|a| a.name.eq(name)) || !state.node_def.iter().any(|a| a.name.eq(stable)) {
+        return None;
+    }
+
+    Some(stable.to_string())
+}
+
+/// The rule name of a node pinned by its bus path
+pub fn pinned_name(state: &AppState, name: &str, path: Option<&str>) -> Option<String> {
+    if state.node_def.iter().any(|a| a.name.eq(name)) {
+        return None;
+    }
+
+    state.node_def.iter().find(|a| is_pinned(&a.name, name, path)).map(|a| a.name.clone())
+}
+
+pub fn deal_with_node(id: u32, props: &HashMap<String, String>, state: &mut AppState) {
+    if let (Some(class), Some(name)) = (props.get("media.class"), props.get("node.name")) {
+        let name = if let Some(stable) = bluetooth_name(state, props) {
+            println!("Bluetooth node {} matched as {}", name, stable);
+            stable
+        } else if let Some(pinned) = pinned_name(state, name, state.device_path(props).as_deref()) {
+            println!("Node {} matched as {}", name, pinned);
+            pinned
+        } else {
+            name.clone()
+        };
+
+        state.claim_stream(&name, props);
+
+        if is_audio(class) {
+            add_node(state, id, &name, props.get("node.nick").map_or("<no nick>", |a| a.as_str()), Some(NodeKind::from_props(props)));
+        }
+    }
+}
+
+pub fn deal_with_port(id: u32, props: &HashMap<String, String>, state: &mut AppState, backend: &dyn GraphBackend) {
+    if let (Some(name), Some(node_id)) = (props.get("port.name"), props.get("node.id")) {
+        if let Ok(node_id) = node_id.parse::<u32>() {
+            add_port(state, id, name, node_id, PortProps::from_props(props), backend);
+        } else {
+            println!("Clould not parse {}'s node.id({})", name, node_id)
+        }
+    }
+}
+
+pub fn handle_event(state: &mut AppState, event: &RegistryEvent, backend: &dyn GraphBackend) {
+    // A reload announces the globals again, they are not new events from PipeWire and in the ring buffer already
+    if !matches!(event, RegistryEvent::Global { id, .. } if state.replayed.contains(id)) {
+        state.last_event = Some(Instant::now());
+        crash::record(event);
+    }
+
+    match event {
+        RegistryEvent::Global { id, kind, props } => {
+            // Reconnects and re-enumerations announce the same global again
+            if state.seen_globals.get(id) == Some(event) {
+                if state.get_names {
+                    println!("Skipping the same global again: {}", id);
+                }
+                return;
+            }
+            state.seen_globals.insert(*id, event.clone());
+
+            if kind.eq(LINK_TYPE) {
+                let get_id = |key: &str| props.as_ref()?.get(key)?.parse::<u32>().ok();
+                if let (Some(port_out), Some(port_in)) = (get_id("link.output.port"), get_id("link.input.port")) {
+                    if let Some(never) = state.blocking_rule(port_out, port_in) {
+                        println!("Removing link {}: {} blocks it", id, never);
+                        state.audit("destroy", Some(*id), (port_out, port_in), None, &format!("blocked by {}", never));
+                        if let Err(e) = backend.destroy_link(LinkHandle::Global(*id)) {
+                            println!("Failed to remove link {}: {}", id, e);
+                        }
+                        return;
+                    }
+                    state.graph_links.insert(*id, (port_out, port_in));
+                }
+                if let Some(props) = props {
+                    state.link_added(*id, props, backend);
+                }
+                return;
+            }
+
+            if kind.eq(DEVICE_TYPE) {
+                if let Some(props) = props.as_ref().filter(|_| !state.replayed.contains(id)) {
+                    state.apply_profile(*id, props, backend);
+                }
+                return;
+            }
+
+            let is_node = kind.eq(NODE_TYPE);
+            if !is_node && !kind.eq(PORT_TYPE) {
+                return;
+            }
+
+            match props {
+                Some(props) if is_node => {
+                    deal_with_node(*id, props, state);
+                    if !state.replayed.contains(id) {
+                        state.apply_node_settings(*id, props, backend);
+                    }
+                }
+                Some(props) => deal_with_port(*id, props, state, backend),
+                None => println!("No props! Skiping id: {}", id),
+            }
+        }
+        RegistryEvent::Remove { id } => {
+            state.seen_globals.remove(id);
+            state.link_states.remove(id);
+            state.device_profiles.remove(id);
+            let ports = state.graph_links.remove(id);
+            if let Some(ports) = ports {
+                state.enforce_link(ports);
+            }
+            state.link_removed(*id, ports, backend);
+
+            if state.remove_global(*id) {
+                println!("Removed {}", id);
+                state.reconcile_priorities(backend);
+            }
+        }
+    }
+}
+
+/// Runs the command of a hook with `sh -c` without waiting for it, with the link and its rule in the environment
+pub fn run_hook(command: &str, event: &str, id: u32, ports: Option<(u32, u32)>, link: &LinkDef) {
+    println!("Running the {} hook of {}: {}", event, link.label(), command);
+
+    let (port_out, port_in) = ports.map_or((String::new(), String::new()), |(a, b)| (a.to_string(), b.to_string()));
+    let child = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("PW_AUTOCONNECT_EVENT", event)
+        .env("PW_AUTOCONNECT_RULE", link.to_string())
+        .env("PW_AUTOCONNECT_RULE_NAME", link.options.name.as_deref().unwrap_or_default())
+        .env("PW_AUTOCONNECT_LINK_ID", id.to_string())
+        .env("PW_AUTOCONNECT_NODE_OUT", &link.port_out.node.name)
+        .env("PW_AUTOCONNECT_PORT_OUT", &link.port_out.name)
+        .env("PW_AUTOCONNECT_PORT_OUT_ID", port_out)
+        .env("PW_AUTOCONNECT_NODE_IN", &link.port_in.node.name)
+        .env("PW_AUTOCONNECT_PORT_IN", &link.port_in.name)
+        .env("PW_AUTOCONNECT_PORT_IN_ID", port_in)
+        .stdin(std::process::Stdio::null())
+        .spawn();
+
+    match child {
+        // Waited for on its own thread, so it does not stay a zombie
+        Ok(mut child) => {
+            std::thread::spawn(move || child.wait());
+        }
+        Err(e) => println!("Failed to run the {} hook of {}: {}", event, link.label(), e),
+    }
+}
+
+/// Loads the rules again and applies them to the globals that are there
+pub fn reload(state: &mut AppState, config: &Config, globals: &[RegistryEvent], backend: &dyn GraphBackend) -> Result<String, String> {
+    let mut new = AppState::load(&config.with_profile(state.profile.as_deref()), &state.defaults).map_err(|e| e.to_string())?;
+    new.profile = state.profile.clone();
+    new.ready_nodes = state.ready_nodes.take();
+    new.node_times = state.node_times.take();
+    new.rate_limit = state.rate_limit.take();
+    new.rule_links = std::mem::take(&mut state.rule_links);
+    new.run_hooks = state.run_hooks;
+    new.webhook = state.webhook.take();
+    new.audit = state.audit.take();
+    new.forced_latency = state.forced_latency;
+    new.device_profiles = std::mem::take(&mut state.device_profiles);
+    new.stats = std::mem::take(&mut state.stats);
+    new.connected = state.connected;
+    new.last_event = state.last_event;
+    new.link_states = std::mem::take(&mut state.link_states);
+    new.replayed = state.seen_globals.keys().copied().collect();
+
+    // Rules turned on or off with enable and disable stay that way, unless @manual was added or removed
+    for link in &new.link_def {
+        if let Some(old) = state.link_def.iter().find(|a| a.to_string() == link.to_string() && a.options.manual == link.options.manual) {
+            link.disabled.set(old.disabled.get());
+            link.active.set(!link.disabled.get() && link.is_scheduled_now());
+        }
+    }
+
+    let mut report = format!("Reloaded {}: {} rules, {} map rules\n", config.path.display(), new.link_def.len(), new.map_def.len());
+    let (old_rules, new_rules) = (rule_options(state), rule_options(&new));
+    let mut changes = 0;
+
+    for (rule, options) in &new_rules {
+        match old_rules.iter().find(|a| a.0.eq(rule)) {
+            None => report.push_str(&format!("+ rule {}\n", rule)),
+            Some(old) if old.1 != *options => report.push_str(&format!("~ rule {} (options changed)\n", rule)),
+            Some(_) => continue,
+        }
+        changes += 1;
+    }
+
+    for (rule, _) in old_rules.iter().filter(|a| !new_rules.iter().any(|b| b.0.eq(&a.0))) {
+        report.push_str(&format!("- rule {}\n", rule));
+        changes += 1;
+    }
+
+    // Keep the links of the rules that are still there, the others are left alone
+    // unless they went to the old default device
+    for mut link in std::mem::take(&mut state.tracked_links) {
+        if let Some(def) = new.link_def.iter().find(|a| a.to_string() == link.def.to_string()) {
+            link.def = def.clone();
+            new.tracked_links.push(link);
+        } else if link.def.options.follows_default {
+            println!("Default changed, removing link: {}", link.def.label());
+            new.audit("destroy", None, (link.port_out, link.port_in), Some(&link.def), "default changed");
+            report.push_str(&format!("- link {}\n", link.def));
+            changes += 1;
+            if let Err(e) = backend.destroy_link(link.handle) {
+                println!("Failed to remove link: {}", e);
+            }
+        }
+    }
+
+    let kept = new.tracked_links.iter().map(|a| a.def.to_string()).collect::<Vec<String>>();
+    let reporting = ReportingBackend {
+        backend,
+        created: RefCell::new(Vec::new()),
+    };
+
+    for global in globals {
+        handle_event(&mut new, global, &reporting);
+    }
+    new.replayed.clear();
+
+    // Taken over by a higher priority rule
+    for rule in kept.iter().filter(|a| !new.tracked_links.iter().any(|b| b.def.to_string().eq(*a))) {
+        report.push_str(&format!("- link {}\n", rule));
+        changes += 1;
+    }
+
+    for link in reporting.created.into_inner() {
+        report.push_str(&format!("+ link {}\n", link));
+        changes += 1;
+    }
+
+    if changes == 0 {
+        report.push_str("No changes\n");
+    }
+
+    *state = new;
+
+    Ok(report)
+}
+
+/// Loads only the rules with one of the tags of `profile`, all of them without one, and removes our links that only
+/// the rules of the old profile wanted, after the new ones are made. The links both want stay, and nothing changes
+/// when the rules cannot be loaded or no rule has the tags
+pub fn switch_profile(
+    state: &mut AppState,
+    config: &Config,
+    profile: Option<Vec<String>>,
+    globals: &[RegistryEvent],
+    backend: &dyn GraphBackend,
+) -> Result<String, String> {
+    let old = state.link_def.iter().map(|a| a.to_string()).collect::<Vec<String>>();
+    let previous = std::mem::replace(&mut state.profile, profile);
+
+    let mut report = match reload(state, config, globals, backend) {
+        Ok(report) => report,
+        Err(e) => {
+            state.profile = previous;
+            return Err(e);
+        }
+    };
+
+    // A typo would remove every link, go back to the rules we had
+    let empty = state.link_def.is_empty() && state.stereo_def.is_empty() && state.map_def.is_empty();
+    if let (true, Some(tags)) = (empty, state.profile.clone()) {
+        state.profile = previous;
+        reload(state, config, globals, backend)?;
+        return Err(format!("no rule has the tag {}", tags.join(" or ")));
+    }
+
+    let graph = Graph::from_globals(globals);
+    for link in &graph.links {
+        let rule = match link.props.get(RULE_KEY) {
+            Some(rule) if link.props.get(OWNER_KEY).is_some_and(|a| a.eq(OWNER)) => rule,
+            _ => continue,
+        };
+        if !old.contains(rule) || state.link_def.iter().any(|a| a.to_string().eq(rule)) {
+            continue;
+        }
+
+        println!("Removing link {}, its rule is not in the profile: {}", link.id, rule);
+        state.audit("destroy", Some(link.id), (link.port_out, link.port_in), None, "profile switch");
+        match backend.destroy_link(LinkHandle::Global(link.id)) {
+            Ok(()) => report.push_str(&format!("- link {}\n", graph.describe_link(link))),
+            Err(e) => println!("Failed to remove link {}: {}", link.id, e),
+        }
+    }
+
+    Ok(report)
+}
+
+/// Every rule with its options, to tell what a reload changed
+pub fn rule_options(state: &AppState) -> Vec<(String, &RuleOptions)> {
+    state.link_def[..state.file_rules]
+        .iter()
+        .filter(|a| a.port_out.node.name.ne(KEEP_ALIVE_NODE))
+        .map(|a| (a.to_string(), &a.options))
+        .chain(state.stereo_def.iter().map(|a| (a.to_string(), &a.options)))
+        .chain(state.map_def.iter().map(|a| (a.to_string(), &a.options)))
+        .collect()
+}
+
+/// Passes the links on to the backend and keeps the ones it made, for the report of a reload
+pub struct ReportingBackend<'a> {
+    pub backend: &'a dyn GraphBackend,
+    pub created: RefCell<Vec<String>>,
+}
+
+impl GraphBackend for ReportingBackend<'_> {
+    fn create_link(&self, port_out: &Port, port_in: &Port, props: &[(String, String)]) -> Result<LinkHandle, String> {
+        let handle = self.backend.create_link(port_out, port_in, props)?;
+        self.created
+            .borrow_mut()
+            .push(format!("[{}]{} -> [{}]{}", port_out.node.name, port_out.name, port_in.node.name, port_in.name));
+        Ok(handle)
+    }
+
+    fn destroy_link(&self, link: LinkHandle) -> Result<(), String> {
+        self.backend.destroy_link(link)
+    }
+
+    fn set_node_props(&self, node: u32, props: &str) -> Result<(), String> {
+        self.backend.set_node_props(node, props)
+    }
+
+    fn set_device_profile(&self, device: u32, profile: &str) -> Result<(), String> {
+        self.backend.set_device_profile(device, profile)
+    }
+
+    fn force_latency(&self, latency: Option<Latency>) -> Result<(), String> {
+        self.backend.force_latency(latency)
+    }
+}
+
+/// Feeds the nodes and ports of the graph to the rules, like the registry would
+pub fn simulate(state: &mut AppState, graph: &Graph) -> MemoryBackend {
+    let backend = MemoryBackend::default();
+
+    for node in &graph.nodes {
+        let name = pinned_name(state, &node.name, node.path.as_deref()).unwrap_or_else(|| node.name.clone());
+        state.claim_stream(&name, &node.properties);
+        add_node(state, node.id, &name, "<no nick>", node.kind);
+        state.apply_node_settings(node.id, &node.properties, &backend);
+    }
+
+    for port in &graph.ports {
+        add_port(state, port.id, &port.name, port.node, port.props.clone(), &backend);
+    }
+
+    backend
+}
+
+/// A rule with the nodes and ports of the graph it names, and what is missing of it
+pub struct RuleMatch<'a> {
+    pub rule: String,
+    pub name: Option<String>,
+    pub problems: Vec<String>,
+    pub nodes: Vec<&'a GraphNode>,
+    pub ports: Vec<&'a GraphPort>,
+    // The links already in the graph between its ports, or the ports of its nodes
+    pub links: Vec<&'a GraphLink>,
+}
+
+impl RuleMatch<'_> {
+    /// The rule followed by its `@name`, for the output
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{} ({})", self.rule, name),
+            None => self.rule.clone(),
+        }
+    }
+}
+
+pub fn rule_matches<'a>(state: &AppState, graph: &'a Graph) -> Vec<RuleMatch<'a>> {
+    let nodes = |names: [&str; 2]| graph.nodes.iter().filter(|a| names.iter().any(|b| a.is_named(b))).collect::<Vec<&GraphNode>>();
+
+    let between = |outs: &[&GraphPort], ins: &[&GraphPort]| {
+        graph
+            .links
+            .iter()
+            .filter(|a| outs.iter().any(|b| b.id == a.port_out) && ins.iter().any(|b| b.id == a.port_in))
+            .collect::<Vec<&GraphLink>>()
+    };
+
+    let links = state.link_def.iter().filter(|a| a.port_out.node.name.ne(KEEP_ALIVE_NODE)).map(|a| {
+        let (port_out, port_in) = (
+            graph.get_port(&a.port_out.node.name, &a.port_out.name, Direction::Out),
+            graph.get_port(&a.port_in.node.name, &a.port_in.name, Direction::In),
+        );
+
+        RuleMatch {
+            rule: a.to_string(),
+            name: a.options.name.clone(),
+            problems: [
+                graph.find_port(&a.port_out.node.name, &a.port_out.name, Direction::Out),
+                graph.find_port(&a.port_in.node.name, &a.port_in.name, Direction::In),
+            ]
+            .into_iter()
+            .flatten()
+            .collect(),
+            nodes: nodes([&a.port_out.node.name, &a.port_in.node.name]),
+            ports: [port_out, port_in].into_iter().flatten().collect(),
+            links: between(&Vec::from_iter(port_out), &Vec::from_iter(port_in)),
+        }
+    });
+
+    let node_rules = state
+        .stereo_def
+        .iter()
+        .map(|a| (a.to_string(), &a.options, &a.node_out, &a.node_in))
+        .chain(state.map_def.iter().map(|a| (a.to_string(), &a.options, &a.node_out, &a.node_in)))
+        .map(|(rule, options, node_out, node_in)| RuleMatch {
+            rule,
+            name: options.name.clone(),
+            problems: [graph.find_node(&node_out.name), graph.find_node(&node_in.name)].into_iter().flatten().collect(),
+            nodes: nodes([&node_out.name, &node_in.name]),
+            ports: Vec::new(),
+            links: between(&graph.node_ports(&node_out.name), &graph.node_ports(&node_in.name)),
+        });
+
+    links.chain(node_rules).collect()
+}
+
+/// The one-shot commands fail with `Unsatisfied` when some rules are not linked in the graph
+pub fn satisfied(missing: usize) -> Result<(), Box<dyn std::error::Error>> {
+    match missing {
+        0 => Ok(()),
+        _ => Err(Box::new(Unsatisfied(missing))),
+    }
+}
+
+/// A one-shot command found this many rules that are not linked
+#[derive(Debug)]
+pub struct Unsatisfied(pub usize);
+
+impl fmt::Display for Unsatisfied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} rules are not satisfied", self.0)
+    }
+}
+
+impl std::error::Error for Unsatisfied {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{local_time, parse_rules, TagFilter};
+
+    const GRAPH: &str = r#"{
+        "nodes": [
+            {"id": 1, "name": "Music", "kind": "stream"},
+            {"id": 2, "name": "Speakers", "kind": "hardware"},
+            {"id": 3, "name": "Alerts", "kind": "stream"},
+            {"id": 4, "name": "pw-autoconnect.keep-alive", "kind": "virtual"}
+        ],
+        "ports": [
+            {"id": 10, "node": 1, "name": "output_FL", "direction": "out"},
+            {"id": 11, "node": 1, "name": "output_FR", "direction": "out"},
+            {"id": 20, "node": 2, "name": "playback_FL", "direction": "in"},
+            {"id": 21, "node": 2, "name": "playback_FR", "direction": "in"},
+            {"id": 30, "node": 3, "name": "output_FL", "direction": "out"},
+            {"id": 40, "node": 4, "name": "capture_MONO", "direction": "out"}
+        ]
+    }"#;
+
+    const MATCH_GRAPH: &str = r#"{
+        "nodes": [
+            {"id": 1, "name": "Music", "kind": "stream"},
+            {"id": 2, "name": "Speakers", "kind": "hardware"}
+        ],
+        "ports": [
+            {"id": 10, "node": 1, "name": "output_FL", "direction": "out"},
+            {"id": 11, "node": 1, "name": "output_FR", "direction": "out"},
+            {"id": 20, "node": 2, "name": "playback_FL", "direction": "in"},
+            {"id": 21, "node": 2, "name": "playback_FR", "direction": "in"}
+        ],
+        "links": [
+            {"id": 30, "port_out": 10, "port_in": 20}
+        ]
+    }"#;
+
+    fn state(rules: &str) -> AppState {
+        AppState::new(parse_rules(rules.as_bytes(), &TagFilter::default(), &HashMap::new()).unwrap(), false)
+    }
+
+    /// The globals of the graph announced to the rules one by one, like the registry does
+    fn announce(state: &mut AppState, backend: &MemoryBackend) -> Vec<RegistryEvent> {
+        let events = Graph::from_json(&json::parse(GRAPH).unwrap()).unwrap().to_events();
+        for event in &events {
+            handle_event(state, event, backend);
+        }
+        events
+    }
+
+    /// PipeWire removes the links of a port together with it
+    fn remove(state: &mut AppState, id: u32, backend: &MemoryBackend) {
+        backend.links.borrow_mut().retain(|a| a.port_out != id && a.port_in != id);
+        handle_event(state, &RegistryEvent::Remove { id }, backend);
+    }
+
+    /// Makes no links once told to fail, like PipeWire without a link factory
+    #[derive(Default)]
+    struct FailingBackend {
+        backend: MemoryBackend,
+        fail: Cell<bool>,
+    }
+
+    impl GraphBackend for FailingBackend {
+        fn create_link(&self, port_out: &Port, port_in: &Port, props: &[(String, String)]) -> Result<LinkHandle, String> {
+            if self.fail.get() {
+                return Err("no link factory".to_string());
+            }
+            self.backend.create_link(port_out, port_in, props)
+        }
+
+        fn destroy_link(&self, link: LinkHandle) -> Result<(), String> {
+            self.backend.destroy_link(link)
+        }
+
+        fn set_node_props(&self, node: u32, props: &str) -> Result<(), String> {
+            self.backend.set_node_props(node, props)
+        }
+
+        fn set_device_profile(&self, device: u32, profile: &str) -> Result<(), String> {
+            self.backend.set_device_profile(device, profile)
+        }
+
+        fn force_latency(&self, latency: Option<Latency>) -> Result<(), String> {
+            self.backend.force_latency(latency)
+        }
+    }
+
+    fn links(backend: &MemoryBackend) -> Vec<(u32, u32)> {
+        let mut links = backend.links.borrow().iter().map(|a| (a.port_out, a.port_in)).collect::<Vec<(u32, u32)>>();
+        links.sort();
+        links
+    }
+
+    #[test]
+    fn links_the_rules() {
+        let mut state = state("[Music](output_FL) -> [Speakers](playback_FL) @name left\n[Music](output_FR) -> [Speakers](playback_FR)\n");
+        let backend = MemoryBackend::default();
+        announce(&mut state, &backend);
+
+        assert_eq!(links(&backend), [(10, 20), (11, 21)]);
+
+        let link = &backend.links.borrow()[0];
+        assert!(link.props.contains(&(OWNER_KEY.to_string(), OWNER.to_string())));
+        assert!(link.props.contains(&(RULE_NAME_KEY.to_string(), "left".to_string())));
+    }
+
+    #[test]
+    fn removed_port() {
+        let mut state = state("[Music](output_FL) -> [Speakers](playback_FL)\n");
+        let backend = MemoryBackend::default();
+        let events = announce(&mut state, &backend);
+        assert_eq!(links(&backend), [(10, 20)]);
+
+        remove(&mut state, 20, &backend);
+        assert!(links(&backend).is_empty());
+        assert!(state.ports.iter().all(|a| a.id != 20));
+        assert!(state.tracked_links.is_empty());
+
+        // The port comes back, with the same props
+        let port = events.iter().find(|a| matches!(a, RegistryEvent::Global { id: 20, .. })).unwrap();
+        handle_event(&mut state, port, &backend);
+        assert_eq!(links(&backend), [(10, 20)]);
+    }
+
+    #[test]
+    fn priorities() {
+        let mut state = state("[Music](output_FL) -> [Speakers](playback_FL) @priority 1\n[Alerts](output_FL) -> [Speakers](playback_FL) @priority 2\n");
+        let backend = MemoryBackend::default();
+        announce(&mut state, &backend);
+
+        assert_eq!(links(&backend), [(30, 20)]);
+
+        // The higher priority node goes away, the input goes back to the other rule
+        remove(&mut state, 30, &backend);
+        remove(&mut state, 3, &backend);
+        assert_eq!(links(&backend), [(10, 20)]);
+    }
+
+    #[test]
+    fn exclusive_input() {
+        let rules = "[Music](output_FL) -> [Speakers](playback_FL)\n[Alerts](output_FL) -> [Speakers](playback_FL) @exclusive-input\n";
+        let events = Graph::from_json(&json::parse(GRAPH).unwrap()).unwrap().to_events();
+        let (alerts, others) = events.iter().partition::<Vec<&RegistryEvent>, _>(|a| matches!(a, RegistryEvent::Global { id: 30, .. }));
+
+        // Like --no-linger, the links are ours to remove without their globals in the graph
+        // The new link fails, the one already into the input stays
+        let mut failing = state(rules);
+        failing.linger = false;
+        let backend = FailingBackend::default();
+        for event in &others {
+            handle_event(&mut failing, event, &backend);
+        }
+        assert_eq!(links(&backend.backend), [(10, 20)]);
+
+        backend.fail.set(true);
+        handle_event(&mut failing, alerts[0], &backend);
+        assert_eq!(links(&backend.backend), [(10, 20)]);
+        assert_eq!(failing.tracked_links.len(), 1);
+
+        // The new link is made, and takes the input for itself
+        let mut state = state(rules);
+        state.linger = false;
+        let backend = MemoryBackend::default();
+        announce(&mut state, &backend);
+        assert_eq!(links(&backend), [(30, 20)]);
+    }
+
+    #[test]
+    fn schedules() {
+        const DAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+        let (today, _) = local_time();
+        let tomorrow = DAYS[(today as usize + 1) % 7];
+
+        let mut state = state(&format!(
+            "[Music](output_FL) -> [Speakers](playback_FL) @schedule 00:00-24:00\n[Music](output_FR) -> [Speakers](playback_FR) @schedule 00:00-24:00 {}\n",
+            tomorrow
+        ));
+        let backend = MemoryBackend::default();
+        announce(&mut state, &backend);
+
+        assert_eq!(links(&backend), [(10, 20)]);
+        assert!(state.link_def.iter().any(|a| !a.active.get()));
+    }
+
+    #[test]
+    fn manual_rules() {
+        let mut state = state("[Music](output_FL) -> [Speakers](playback_FL) @manual @keep-alive @name live\n");
+        // Like --no-linger, the links are ours to remove without their globals in the graph
+        state.linger = false;
+        let backend = MemoryBackend::default();
+        announce(&mut state, &backend);
+
+        assert!(links(&backend).is_empty());
+
+        // Turned on together with its keep alive link, and off again
+        let index = state.rule_indexes("live")[0];
+        state.set_rule_enabled(index, true, &backend).unwrap();
+        assert_eq!(links(&backend), [(10, 20), (40, 20)]);
+
+        state.set_rule_enabled(index, false, &backend).unwrap();
+        assert!(links(&backend).is_empty());
+    }
+
+    #[test]
+    fn matches() {
+        let rules = "[Music](output_FL) -> [Speakers](playback_FL) @name left\n[Music](output_FR) -> [Speaker](playback_FR)\n";
+        let state = state(rules);
+        let graph = Graph::from_json(&json::parse(MATCH_GRAPH).unwrap()).unwrap();
+
+        let matches = rule_matches(&state, &graph);
+        assert_eq!(matches.len(), 2);
+
+        let left = &matches[0];
+        assert_eq!(left.label(), "[Music]output_FL -> [Speakers]playback_FL (left)");
+        assert!(left.problems.is_empty());
+        assert_eq!(left.ports.iter().map(|a| a.id).collect::<Vec<u32>>(), [10, 20]);
+        assert_eq!(left.links.iter().map(|a| a.id).collect::<Vec<u32>>(), [30]);
+
+        // The typo in the node name is a problem, the ports of the node that is there are still found
+        let right = &matches[1];
+        assert!(!right.problems.is_empty());
+        assert_eq!(right.nodes.iter().map(|a| a.id).collect::<Vec<u32>>(), [1]);
+        assert_eq!(right.ports.iter().map(|a| a.id).collect::<Vec<u32>>(), [11]);
+        assert!(right.links.is_empty());
+    }
+}
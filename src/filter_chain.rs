@@ -0,0 +1,142 @@
+//! Filter chains declared in the rules, run by a `pipewire -c` process of their own like the
+//! filter-chain service of PipeWire, since modules can only be loaded in the process that uses them
+
+use std::{
+    fs,
+    os::unix::process::CommandExt,
+    process::{Child, Command},
+};
+
+use crate::json::{self, Value};
+
+/// `[Denoised Mic] filter ladspa librnnoise_ladspa noise_suppressor_mono { VAD Threshold (%) = 50 }`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterDef {
+    pub name: String,
+    /// Plugin type of filter-chain, ladspa, lv2 or builtin
+    pub kind: String,
+    pub plugin: String,
+    pub label: String,
+    pub controls: Vec<(String, String)>,
+}
+
+impl FilterDef {
+    /// The sink the audio to filter is played to, the filtered audio comes out of the source named like the filter
+    pub fn input_name(&self) -> String {
+        format!("{} input", self.name)
+    }
+
+    fn object(fields: Vec<(&str, Value)>) -> Value {
+        Value::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+
+    fn string(text: &str) -> Value {
+        Value::String(text.to_string())
+    }
+
+    /// The config of the pipewire process, SPA-JSON takes JSON values
+    pub fn config(&self) -> String {
+        let controls = self
+            .controls
+            .iter()
+            .map(|(k, v)| (k.clone(), v.parse::<f64>().map_or_else(|_| Value::String(v.clone()), Value::Number)))
+            .collect();
+
+        let node = FilterDef::object(vec![
+            ("type", FilterDef::string(&self.kind)),
+            ("name", FilterDef::string("filter")),
+            ("plugin", FilterDef::string(&self.plugin)),
+            ("label", FilterDef::string(&self.label)),
+            ("control", Value::Object(controls)),
+        ]);
+
+        let args = FilterDef::object(vec![
+            ("node.description", FilterDef::string(&self.name)),
+            ("media.name", FilterDef::string(&self.name)),
+            ("filter.graph", FilterDef::object(vec![("nodes", Value::Array(vec![node]))])),
+            (
+                "capture.props",
+                FilterDef::object(vec![
+                    ("node.name", FilterDef::string(&self.input_name())),
+                    ("media.class", FilterDef::string("Audio/Sink")),
+                ]),
+            ),
+            (
+                "playback.props",
+                FilterDef::object(vec![
+                    ("node.name", FilterDef::string(&self.name)),
+                    ("media.class", FilterDef::string("Audio/Source")),
+                ]),
+            ),
+        ]);
+
+        let module = |name: &str| FilterDef::object(vec![("name", FilterDef::string(name))]);
+
+        let mut filter = module("libpipewire-module-filter-chain");
+        if let Value::Object(fields) = &mut filter {
+            fields.push(("args".to_string(), args));
+        }
+
+        let modules = Value::Array(vec![
+            module("libpipewire-module-protocol-native"),
+            module("libpipewire-module-client-node"),
+            module("libpipewire-module-adapter"),
+            filter,
+        ]);
+
+        let libs = FilterDef::object(vec![
+            ("audio.convert.*", FilterDef::string("audioconvert/libspa-audioconvert")),
+            ("support.*", FilterDef::string("support/libspa-support")),
+        ]);
+
+        let mut out = format!("# Filter {} of pw-autoconnect\n", self.name);
+        out.push_str("context.spa-libs = ");
+        json::write(&libs, &mut out);
+        out.push_str("\ncontext.modules = ");
+        json::write(&modules, &mut out);
+        out.push('\n');
+        out
+    }
+
+    /// Starts the pipewire process running the filter, it is stopped when we exit
+    pub fn spawn(&self, socket: Option<&str>, system: bool) -> Result<Child, String> {
+        let path = crate::daemon::runtime_dir(system).join(format!("pw-autoconnect-filter-{}.conf", file_name(&self.name)));
+
+        fs::write(&path, self.config()).map_err(|e| format!("could not write {}: {}", path.display(), e))?;
+
+        let mut command = Command::new("pipewire");
+        command.arg("-c").arg(&path);
+
+        if let Some(socket) = socket {
+            command.env("PIPEWIRE_REMOTE", socket);
+        }
+
+        // Killed together with us, even when we crash
+        unsafe {
+            command.pre_exec(|| {
+                libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM);
+                Ok(())
+            });
+        }
+
+        command.spawn().map_err(|e| format!("could not run pipewire: {}", e))
+    }
+}
+
+fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|a| if a.is_ascii_alphanumeric() { a.to_ascii_lowercase() } else { '-' })
+        .collect()
+}
+
+/// Parses the controls of the filter, `name = value` separated by commas
+pub fn parse_controls(controls: &str) -> Result<Vec<(String, String)>, String> {
+    controls
+        .split(',')
+        .filter(|a| !a.trim().is_empty())
+        .map(|a| match a.split_once('=') {
+            Some((k, v)) if !k.trim().is_empty() => Ok((k.trim().to_string(), v.trim().to_string())),
+            _ => Err(format!("expected 'control = value' but got '{}'", a.trim())),
+        })
+        .collect()
+}
@@ -0,0 +1,821 @@
+//! The audio graph the rules are applied to: its nodes, ports and links, the registry events that change it
+//! and the backends that make and remove the links
+
+use std::{cell::{Cell, RefCell}, collections::HashMap, fmt, rc::Rc, time::Duration};
+
+use pipewire as pw;
+
+use crate::json;
+use crate::matcher::{closest, glob_matches, is_pinned, unpinned_name};
+
+/// The types of the globals in the registry events, named like PipeWire names them
+pub const NODE_TYPE: &str = "PipeWire:Interface:Node";
+pub const PORT_TYPE: &str = "PipeWire:Interface:Port";
+pub const LINK_TYPE: &str = "PipeWire:Interface:Link";
+pub const CLIENT_TYPE: &str = "PipeWire:Interface:Client";
+pub const DEVICE_TYPE: &str = "PipeWire:Interface:Device";
+
+/// How often the links waiting for their `@grace-period` or the rate limit are checked
+pub const WAIT_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub struct Port {
+    pub id: u32,
+    pub name: String,
+    pub node: Rc<Node>,
+}
+
+#[derive(Debug)]
+pub struct Node {
+    pub id: u32,
+    pub name: String,
+    // None when the backend cannot tell, JACK clients
+    pub kind: Option<NodeKind>,
+}
+
+/// What a node is, for `@from` and `@to`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NodeKind {
+    // A device, it has `device.api`
+    Hardware,
+    // The stream of an application, `media.class` is `Stream/...`
+    Stream,
+    // Anything else, virtual sinks and filters
+    Virtual,
+}
+
+impl NodeKind {
+    pub fn from_props(props: &HashMap<String, String>) -> NodeKind {
+        if props.contains_key("device.api") {
+            NodeKind::Hardware
+        } else if props.get("media.class").is_some_and(|a| a.starts_with("Stream/")) {
+            NodeKind::Stream
+        } else {
+            NodeKind::Virtual
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<NodeKind> {
+        match kind {
+            "hardware" => Some(NodeKind::Hardware),
+            "stream" => Some(NodeKind::Stream),
+            "virtual" => Some(NodeKind::Virtual),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            NodeKind::Hardware => "hardware",
+            NodeKind::Stream => "stream",
+            NodeKind::Virtual => "virtual",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+impl Direction {
+    /// Parses `port.direction`
+    pub fn parse(direction: &str) -> Option<Direction> {
+        match direction {
+            "in" => Some(Direction::In),
+            "out" => Some(Direction::Out),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channel {
+    Left,
+    Right,
+}
+
+impl Channel {
+    /// Finds the channel from the end of the port name, FL/FR, L/R or 1/2
+    pub fn of_port(name: &str) -> Option<Channel> {
+        let suffix = name.rsplit(['_', '-', ' ', ':']).next().unwrap_or(name);
+
+        match suffix.to_ascii_uppercase().as_str() {
+            "FL" | "L" | "1" | "LEFT" => Some(Channel::Left),
+            "FR" | "R" | "2" | "RIGHT" => Some(Channel::Right),
+            _ => None,
+        }
+    }
+}
+
+/// The properties of a port the rules look at besides its name
+#[derive(Debug, Clone, Default)]
+pub struct PortProps {
+    pub direction: Option<Direction>,
+    /// `audio.channel`, FL, FR, FC, LFE, SL, ...
+    pub channel: Option<String>,
+    /// `port.id`, the position of the port among the ports of the node going the same way
+    pub index: Option<u32>,
+    /// `port.monitor`, the output of a sink with what is played to it
+    pub monitor: bool,
+    /// `port.physical` and `port.terminal`, a port of the hardware itself where the audio starts or ends
+    pub physical: bool,
+    pub terminal: bool,
+}
+
+impl PortProps {
+    pub fn from_props(props: &HashMap<String, String>) -> PortProps {
+        PortProps {
+            direction: props.get("port.direction").and_then(|a| Direction::parse(a)),
+            channel: props.get("audio.channel").cloned(),
+            index: props.get("port.id").and_then(|a| a.parse().ok()),
+            monitor: props.get("port.monitor").is_some_and(|a| a == "true"),
+            physical: props.get("port.physical").is_some_and(|a| a == "true"),
+            terminal: props.get("port.terminal").is_some_and(|a| a == "true"),
+        }
+    }
+
+    /// `L` or `R`, the name of the port in a rule naming it by its channel. The channel comes from
+    /// `audio.channel` or else the end of the name, `playback_FL`, `playback_1`, `out_l`...
+    pub fn channel_alias(&self, name: &str) -> Option<&'static str> {
+        if self.monitor {
+            return None;
+        }
+
+        let channel = match self.channel.as_deref() {
+            Some("FL") => Channel::Left,
+            Some("FR") => Channel::Right,
+            Some(_) => return None,
+            None => Channel::of_port(name)?,
+        };
+
+        match channel {
+            Channel::Left => Some("L"),
+            Channel::Right => Some("R"),
+        }
+    }
+}
+
+/// Buffer size asked for with `@latency <quantum>[/<rate>]`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Latency {
+    pub quantum: u32,
+    pub rate: Option<u32>,
+}
+
+impl Latency {
+    pub fn parse(latency: &str) -> Option<Latency> {
+        let (quantum, rate) = match latency.split_once('/') {
+            Some((quantum, rate)) => (quantum, Some(rate.parse::<u32>().ok().filter(|a| *a > 0)?)),
+            None => (latency, None),
+        };
+
+        Some(Latency {
+            quantum: quantum.parse::<u32>().ok().filter(|a| *a > 0)?,
+            rate,
+        })
+    }
+}
+
+impl fmt::Display for Latency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.rate {
+            Some(rate) => write!(f, "{}/{}", self.quantum, rate),
+            None => write!(f, "{}", self.quantum),
+        }
+    }
+}
+
+/// A link made by a backend, dropping it does not remove the link
+pub enum LinkHandle {
+    PipeWire(pw::link::Link),
+    // A link made before, found in the registry
+    Global(u32),
+    #[cfg(feature = "jack")]
+    Jack(String, String),
+    #[cfg(feature = "pulse")]
+    Pulse(String, String),
+    Memory(u32),
+}
+
+/// The audio graph the rules are applied to
+pub trait GraphBackend {
+    /// Links the ports, `props` are set on the link if the backend supports link properties
+    fn create_link(&self, port_out: &Port, port_in: &Port, props: &[(String, String)]) -> Result<LinkHandle, String>;
+    fn destroy_link(&self, link: LinkHandle) -> Result<(), String>;
+    /// Sets the `Props` param of the node, given in the SPA JSON of `pw-cli set-param`
+    fn set_node_props(&self, node: u32, props: &str) -> Result<(), String>;
+    /// Switches the device to the profile with this name, like `pro-audio` or `a2dp-sink`
+    fn set_device_profile(&self, device: u32, profile: &str) -> Result<(), String>;
+    /// Forces the quantum, and the rate when given, of the whole graph; None lets the graph pick it again
+    fn force_latency(&self, latency: Option<Latency>) -> Result<(), String>;
+}
+
+pub struct MemoryLink {
+    pub id: u32,
+    pub port_out: u32,
+    pub port_in: u32,
+    pub props: Vec<(String, String)>,
+}
+
+/// Keeps the links in memory instead of making them, used to run the rules against a graph without changing it
+#[derive(Default)]
+pub struct MemoryBackend {
+    pub links: RefCell<Vec<MemoryLink>>,
+    pub next_id: Cell<u32>,
+}
+
+impl GraphBackend for MemoryBackend {
+    fn create_link(&self, port_out: &Port, port_in: &Port, props: &[(String, String)]) -> Result<LinkHandle, String> {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+
+        self.links.borrow_mut().push(MemoryLink {
+            id,
+            port_out: port_out.id,
+            port_in: port_in.id,
+            props: props.to_vec(),
+        });
+
+        Ok(LinkHandle::Memory(id))
+    }
+
+    fn destroy_link(&self, link: LinkHandle) -> Result<(), String> {
+        let id = match link {
+            LinkHandle::Memory(id) => id,
+            // A link of the graph we started from, nothing to keep track of
+            LinkHandle::Global(_) => return Ok(()),
+            _ => return Err("not a memory link".to_string()),
+        };
+
+        let mut links = self.links.borrow_mut();
+        let index = links.iter().position(|a| a.id == id).ok_or_else(|| format!("no link {}", id))?;
+        links.remove(index);
+
+        Ok(())
+    }
+
+    fn set_node_props(&self, _node: u32, _props: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn set_device_profile(&self, _device: u32, _profile: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn force_latency(&self, _latency: Option<Latency>) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Property set on the links we create, so they can be told apart from the others
+pub const OWNER_KEY: &str = "autoconnect.owner";
+pub const OWNER: &str = "pipewire-autoconnect";
+/// Property with the rule that made the link
+pub const RULE_KEY: &str = "autoconnect.rule";
+pub const RULE_NAME_KEY: &str = "autoconnect.rule.name";
+
+#[derive(Debug)]
+pub struct GraphNode {
+    pub id: u32,
+    pub name: String,
+    pub kind: Option<NodeKind>,
+    // `device.bus-path` of its device
+    pub path: Option<String>,
+    // Every property of the global
+    pub properties: HashMap<String, String>,
+}
+
+impl GraphNode {
+    /// The node is the one the rules call `name`, by its name or pinned by its bus path
+    pub fn is_named(&self, name: &str) -> bool {
+        self.name.eq(name) || is_pinned(name, &self.name, self.path.as_deref())
+    }
+}
+
+/// The nodes `list` and `discover` show, by `--class` and `--name`, where `*` matches any text
+#[derive(Debug, Default, Clone)]
+pub struct NodeFilter {
+    pub classes: Vec<String>,
+    pub names: Vec<String>,
+}
+
+impl NodeFilter {
+    pub fn is_empty(&self) -> bool {
+        self.classes.is_empty() && self.names.is_empty()
+    }
+
+    pub fn allows(&self, node: &GraphNode) -> bool {
+        let class = node.properties.get("media.class").map_or("", |a| a.as_str());
+        let description = node.properties.get("node.description").map_or("", |a| a.as_str());
+
+        (self.classes.is_empty() || self.classes.iter().any(|a| glob_matches(a, class)))
+            && (self.names.is_empty() || self.names.iter().any(|a| glob_matches(a, &node.name) || glob_matches(a, description)))
+    }
+}
+
+/// `device.bus-path` of the device of the node, `pci-0000:00:14.0-usb-0:2:1.0`
+pub fn bus_path(props: &HashMap<String, String>, device: impl Fn(u32) -> Option<String>) -> Option<String> {
+    props
+        .get("device.bus-path")
+        .cloned()
+        .or_else(|| device(props.get("device.id")?.parse().ok()?))
+}
+
+#[derive(Debug)]
+pub struct GraphPort {
+    pub id: u32,
+    pub node: u32,
+    pub name: String,
+    pub props: PortProps,
+    // Every property of the global
+    pub properties: HashMap<String, String>,
+}
+
+#[derive(Debug)]
+pub struct GraphLink {
+    pub id: u32,
+    pub port_out: u32,
+    pub port_in: u32,
+    pub props: HashMap<String, String>,
+}
+
+/// Who made a link in the graph
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkOwner {
+    /// Has our `autoconnect.owner` property
+    Ours,
+    /// Made by a client of the session manager
+    SessionManager,
+    /// Made by anything else, a patchbay or `pw-link`
+    Manual,
+}
+
+impl LinkOwner {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LinkOwner::Ours => "ours",
+            LinkOwner::SessionManager => "session manager",
+            LinkOwner::Manual => "manual",
+        }
+    }
+}
+
+/// `application.name` of the session managers
+pub const SESSION_MANAGERS: [&str; 2] = ["WirePlumber", "pipewire-media-session"];
+
+/// Snapshot of the PipeWire graph, used by the commands that look at the graph once and exit
+#[derive(Debug, Default)]
+pub struct Graph {
+    pub nodes: Vec<GraphNode>,
+    pub ports: Vec<GraphPort>,
+    pub links: Vec<GraphLink>,
+    // `application.name` of every client, to tell who made the links
+    pub clients: HashMap<u32, String>,
+}
+
+impl Graph {
+    /// Ours by the owner property, otherwise by the client that made the link
+    pub fn link_owner(&self, link: &GraphLink) -> LinkOwner {
+        if link.props.get(OWNER_KEY).is_some_and(|a| a.eq(OWNER)) {
+            return LinkOwner::Ours;
+        }
+
+        let client = link.props.get("client.id").and_then(|a| a.parse::<u32>().ok()).and_then(|a| self.clients.get(&a));
+
+        match client {
+            Some(name) if SESSION_MANAGERS.iter().any(|a| a.eq_ignore_ascii_case(name)) => LinkOwner::SessionManager,
+            _ => LinkOwner::Manual,
+        }
+    }
+
+    /// `[A]out -> [B]in`, or the id of the link when one of its ports is not in the graph
+    pub fn describe_link(&self, link: &GraphLink) -> String {
+        match self.link_names(link) {
+            Some((node_out, port_out, node_in, port_in)) => format!("[{}]{} -> [{}]{}", node_out, port_out, node_in, port_in),
+            None => format!("link {}", link.id),
+        }
+    }
+
+    /// The links of the port, as the other end of each and who made it
+    pub fn port_links(&self, port: u32) -> Vec<(String, LinkOwner)> {
+        self.links
+            .iter()
+            .filter_map(|a| {
+                let (peer, arrow) = match (a.port_out == port, a.port_in == port) {
+                    (true, _) => (a.port_in, "->"),
+                    (_, true) => (a.port_out, "<-"),
+                    _ => return None,
+                };
+                let peer = self.ports.iter().find(|b| b.id == peer)?;
+                let node = self.nodes.iter().find(|b| b.id == peer.node)?;
+                Some((format!("{} [{}]({})", arrow, node.name, peer.name), self.link_owner(a)))
+            })
+            .collect()
+    }
+
+    /// Returns the node and port names of both ends of the link
+    pub fn link_names(&self, link: &GraphLink) -> Option<(&str, &str, &str, &str)> {
+        let port_out = self.ports.iter().find(|a| a.id == link.port_out)?;
+        let port_in = self.ports.iter().find(|a| a.id == link.port_in)?;
+        let node_out = self.nodes.iter().find(|a| a.id == port_out.node)?;
+        let node_in = self.nodes.iter().find(|a| a.id == port_in.node)?;
+
+        Some((&node_out.name, &port_out.name, &node_in.name, &port_in.name))
+    }
+
+    /// Returns what is missing for the node, with the closest node name
+    pub fn find_node(&self, name: &str) -> Option<String> {
+        if self.nodes.iter().any(|a| a.is_named(name)) {
+            return None;
+        }
+
+        let node = closest(name, self.nodes.iter().map(|a| a.name.as_str())).and_then(|a| self.nodes.iter().find(|b| b.name.eq(a)));
+
+        Some(match node {
+            // The path to pin it with, when there are identical devices
+            Some(GraphNode { name: node, path: Some(path), .. }) => {
+                format!("no node [{}], closest is [{}] at [{}@{}]", name, node, unpinned_name(node), path)
+            }
+            Some(node) => format!("no node [{}], closest is [{}]", name, node.name),
+            None => format!("no node [{}]", name),
+        })
+    }
+
+    /// Returns what is missing for the port, with the closest port name on the node
+    pub fn find_port(&self, node: &str, port: &str, direction: Direction) -> Option<String> {
+        if let Some(problem) = self.find_node(node) {
+            return Some(problem);
+        }
+
+        if self.get_port(node, port, direction).is_some() {
+            return None;
+        }
+
+        Some(match closest(port, self.node_ports(node).into_iter().map(|a| a.name.as_str())) {
+            Some(name) => format!("no port {} on [{}], closest is {}", port, node, name),
+            None => format!("no port {} on [{}], it has no ports", port, node),
+        })
+    }
+
+    pub fn node_ports(&self, node: &str) -> Vec<&GraphPort> {
+        self.ports
+            .iter()
+            .filter(|a| self.nodes.iter().any(|b| b.id == a.node && b.is_named(node)))
+            .collect()
+    }
+
+    /// The port of the rule, by its name or its number on the side of the rule
+    pub fn get_port(&self, node: &str, port: &str, direction: Direction) -> Option<&GraphPort> {
+        let by_index = |a: &GraphPort| {
+            a.props.index.is_some_and(|i| format!("#{}", i) == port) && a.props.direction.is_none_or(|a| a == direction)
+        };
+        let by_channel = |a: &GraphPort| a.props.channel_alias(&a.name) == Some(port) && a.props.direction.is_none_or(|a| a == direction);
+
+        self.node_ports(node).into_iter().find(|a| a.name.eq(port) || by_index(a) || by_channel(a))
+    }
+
+    pub fn from_globals(globals: &[RegistryEvent]) -> Graph {
+        let mut graph = Graph::default();
+
+        for global in globals {
+            let (id, kind, props) = match global {
+                RegistryEvent::Global { id, kind, props: Some(props) } => (*id, kind, props),
+                _ => continue,
+            };
+
+            let get_id = |key: &str| props.get(key).and_then(|a| a.parse::<u32>().ok());
+
+            if kind.eq(NODE_TYPE) {
+                let device = |device: u32| {
+                    globals.iter().find_map(|a| match a {
+                        RegistryEvent::Global { id, props: Some(props), .. } if *id == device => props.get("device.bus-path").cloned(),
+                        _ => None,
+                    })
+                };
+
+                graph.nodes.push(GraphNode {
+                    id,
+                    name: props.get("node.name").cloned().unwrap_or_default(),
+                    kind: Some(NodeKind::from_props(props)),
+                    path: bus_path(props, device),
+                    properties: props.clone(),
+                });
+            } else if kind.eq(PORT_TYPE) {
+                if let (Some(name), Some(node)) = (props.get("port.name"), get_id("node.id")) {
+                    graph.ports.push(GraphPort {
+                        id,
+                        node,
+                        name: name.clone(),
+                        props: PortProps::from_props(props),
+                        properties: props.clone(),
+                    });
+                }
+            } else if kind.eq(LINK_TYPE) {
+                if let (Some(port_out), Some(port_in)) = (get_id("link.output.port"), get_id("link.input.port")) {
+                    graph.links.push(GraphLink {
+                        id,
+                        port_out,
+                        port_in,
+                        props: props.clone(),
+                    });
+                }
+            } else if kind.eq(CLIENT_TYPE) {
+                if let Some(name) = props.get("application.name") {
+                    graph.clients.insert(id, name.clone());
+                }
+            }
+        }
+
+        graph
+    }
+
+    /// The registry events that announce the graph, for `--backend sim`. What the rules look at is filled in
+    /// from the fields of the nodes and ports when their properties lack it
+    pub fn to_events(&self) -> Vec<RegistryEvent> {
+        let global = |id: u32, kind: &str, props: HashMap<String, String>| RegistryEvent::Global {
+            id,
+            kind: kind.to_string(),
+            props: Some(props),
+        };
+
+        let mut events = Vec::new();
+
+        for (id, name) in &self.clients {
+            events.push(global(*id, CLIENT_TYPE, HashMap::from([("application.name".to_string(), name.clone())])));
+        }
+
+        for node in &self.nodes {
+            let mut props = node.properties.clone();
+            props.entry("node.name".to_string()).or_insert_with(|| node.name.clone());
+            if let Some(path) = &node.path {
+                props.entry("device.bus-path".to_string()).or_insert_with(|| path.clone());
+            }
+            if node.kind == Some(NodeKind::Hardware) {
+                props.entry("device.api".to_string()).or_insert_with(|| "sim".to_string());
+            }
+            let class = if node.kind == Some(NodeKind::Stream) { "Stream/Output/Audio" } else { "Audio/Duplex" };
+            props.entry("media.class".to_string()).or_insert_with(|| class.to_string());
+
+            events.push(global(node.id, NODE_TYPE, props));
+        }
+
+        for port in &self.ports {
+            let mut props = port.properties.clone();
+            let flag = |set: bool| set.then(|| "true".to_string());
+            let fields = [
+                ("port.name", Some(port.name.clone())),
+                ("node.id", Some(port.node.to_string())),
+                ("port.direction", port.props.direction.map(|a| a.as_str().to_string())),
+                ("audio.channel", port.props.channel.clone()),
+                ("port.id", port.props.index.map(|a| a.to_string())),
+                ("port.monitor", flag(port.props.monitor)),
+                ("port.physical", flag(port.props.physical)),
+                ("port.terminal", flag(port.props.terminal)),
+            ];
+            for (key, value) in fields {
+                if let Some(value) = value {
+                    props.entry(key.to_string()).or_insert(value);
+                }
+            }
+
+            events.push(global(port.id, PORT_TYPE, props));
+        }
+
+        for link in &self.links {
+            let mut props = link.props.clone();
+            props.insert("link.output.port".to_string(), link.port_out.to_string());
+            props.insert("link.input.port".to_string(), link.port_in.to_string());
+
+            events.push(global(link.id, LINK_TYPE, props));
+        }
+
+        events
+    }
+
+    pub fn to_json(&self) -> json::Value {
+        let number = |a: u32| json::Value::Number(a as f64);
+        let object = |fields: Vec<(&str, json::Value)>| {
+            json::Value::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+        };
+
+        object(vec![
+            (
+                "nodes",
+                json::Value::Array(
+                    self.nodes
+                        .iter()
+                        .map(|a| {
+                            let kind = a.kind.map_or(json::Value::Null, |a| json::Value::String(a.as_str().to_string()));
+                            let path = a.path.clone().map_or(json::Value::Null, json::Value::String);
+                            object(vec![
+                                ("id", number(a.id)),
+                                ("name", json::Value::String(a.name.clone())),
+                                ("kind", kind),
+                                ("path", path),
+                                ("props", json::Value::from_string_map(&a.properties)),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                "ports",
+                json::Value::Array(
+                    self.ports
+                        .iter()
+                        .map(|a| {
+                            let direction = a.props.direction.map_or(json::Value::Null, |a| json::Value::String(a.as_str().to_string()));
+                            let channel = a.props.channel.clone().map_or(json::Value::Null, json::Value::String);
+                            let index = a.props.index.map_or(json::Value::Null, number);
+                            object(vec![
+                                ("id", number(a.id)),
+                                ("node", number(a.node)),
+                                ("name", json::Value::String(a.name.clone())),
+                                ("direction", direction),
+                                ("channel", channel),
+                                ("index", index),
+                                ("monitor", json::Value::Bool(a.props.monitor)),
+                                ("physical", json::Value::Bool(a.props.physical)),
+                                ("terminal", json::Value::Bool(a.props.terminal)),
+                                ("props", json::Value::from_string_map(&a.properties)),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                "links",
+                json::Value::Array(
+                    self.links
+                        .iter()
+                        .map(|a| {
+                            object(vec![
+                                ("id", number(a.id)),
+                                ("port_out", number(a.port_out)),
+                                ("port_in", number(a.port_in)),
+                                ("props", json::Value::from_string_map(&a.props)),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            ("clients", {
+                let mut clients = self.clients.iter().collect::<Vec<(&u32, &String)>>();
+                clients.sort();
+                json::Value::Array(clients.into_iter().map(|(id, name)| object(vec![("id", number(*id)), ("name", json::Value::String(name.clone()))])).collect())
+            }),
+        ])
+    }
+
+    pub fn from_json(value: &json::Value) -> Result<Graph, String> {
+        let list = |key: &str| value.get(key).and_then(|a| a.as_array()).unwrap_or_default();
+        let id = |value: &json::Value, key: &str| {
+            value
+                .get(key)
+                .and_then(|a| a.as_u64())
+                .map(|a| a as u32)
+                .ok_or_else(|| format!("missing {}", key))
+        };
+        let name = |value: &json::Value| {
+            value
+                .get("name")
+                .and_then(|a| a.as_str())
+                .map(|a| a.to_string())
+                .ok_or_else(|| "missing name".to_string())
+        };
+
+        let mut graph = Graph::default();
+
+        for node in list("nodes") {
+            graph.nodes.push(GraphNode {
+                id: id(node, "id")?,
+                name: name(node)?,
+                kind: node.get("kind").and_then(|a| a.as_str()).and_then(NodeKind::parse),
+                path: node.get("path").and_then(|a| a.as_str()).map(|a| a.to_string()),
+                properties: node.get("props").and_then(|a| a.as_string_map()).unwrap_or_default(),
+            });
+        }
+
+        for port in list("ports") {
+            graph.ports.push(GraphPort {
+                id: id(port, "id")?,
+                node: id(port, "node")?,
+                name: name(port)?,
+                props: PortProps {
+                    direction: port.get("direction").and_then(|a| a.as_str()).and_then(Direction::parse),
+                    channel: port.get("channel").and_then(|a| a.as_str()).map(|a| a.to_string()),
+                    index: port.get("index").and_then(|a| a.as_u64()).map(|a| a as u32),
+                    monitor: port.get("monitor").and_then(|a| a.as_bool()).unwrap_or_default(),
+                    physical: port.get("physical").and_then(|a| a.as_bool()).unwrap_or_default(),
+                    terminal: port.get("terminal").and_then(|a| a.as_bool()).unwrap_or_default(),
+                },
+                properties: port.get("props").and_then(|a| a.as_string_map()).unwrap_or_default(),
+            });
+        }
+
+        for link in list("links") {
+            graph.links.push(GraphLink {
+                id: id(link, "id")?,
+                port_out: id(link, "port_out")?,
+                port_in: id(link, "port_in")?,
+                props: link.get("props").and_then(|a| a.as_string_map()).unwrap_or_default(),
+            });
+        }
+
+        for client in list("clients") {
+            graph.clients.insert(id(client, "id")?, name(client)?);
+        }
+
+        Ok(graph)
+    }
+}
+
+/// An event of the PipeWire registry, kept apart from the PipeWire types so it can be recorded and replayed
+#[derive(Clone, PartialEq)]
+pub enum RegistryEvent {
+    Global {
+        id: u32,
+        kind: String,
+        props: Option<HashMap<String, String>>,
+    },
+    Remove {
+        id: u32,
+    },
+}
+
+impl RegistryEvent {
+    /// The event with all the properties of the global, sorted by key, for --trace-registry
+    pub fn trace(&self) -> String {
+        match self {
+            RegistryEvent::Global { id, kind, props } => {
+                let mut out = format!("+ {} {}\n", id, kind);
+                let mut props = props.iter().flatten().collect::<Vec<(&String, &String)>>();
+                props.sort();
+                for (key, value) in props {
+                    out.push_str(&format!("    {} = {}\n", key, value));
+                }
+                out
+            }
+            RegistryEvent::Remove { id } => format!("- {}\n", id),
+        }
+    }
+
+    pub fn to_json(&self, time: u64) -> json::Value {
+        let mut fields = vec![("time".to_string(), json::Value::Number(time as f64))];
+
+        match self {
+            RegistryEvent::Global { id, kind, props } => {
+                fields.push(("event".to_string(), json::Value::String("global".to_string())));
+                fields.push(("id".to_string(), json::Value::Number(*id as f64)));
+                fields.push(("type".to_string(), json::Value::String(kind.clone())));
+                fields.push(("props".to_string(), props.as_ref().map_or(json::Value::Null, json::Value::from_string_map)));
+            }
+            RegistryEvent::Remove { id } => {
+                fields.push(("event".to_string(), json::Value::String("remove".to_string())));
+                fields.push(("id".to_string(), json::Value::Number(*id as f64)));
+            }
+        }
+
+        json::Value::Object(fields)
+    }
+
+    /// Returns the event and the milliseconds since the recording started
+    pub fn from_json(value: &json::Value) -> Result<(u64, RegistryEvent), String> {
+        let time = value.get("time").and_then(|a| a.as_u64()).ok_or("missing time")?;
+        let id = value.get("id").and_then(|a| a.as_u64()).ok_or("missing id")? as u32;
+
+        let event = match value.get("event").and_then(|a| a.as_str()) {
+            Some("global") => RegistryEvent::Global {
+                id,
+                kind: value.get("type").and_then(|a| a.as_str()).ok_or("missing type")?.to_string(),
+                props: match value.get("props") {
+                    Some(json::Value::Null) | None => None,
+                    Some(props) => Some(props.as_string_map().ok_or("props must be an object of strings")?),
+                },
+            },
+            Some("remove") => RegistryEvent::Remove { id },
+            Some(event) => return Err(format!("unknown event '{}'", event)),
+            None => return Err("missing event".to_string()),
+        };
+
+        Ok((time, event))
+    }
+}
+
+impl fmt::Display for RegistryEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegistryEvent::Global { id, kind, .. } => write!(f, "added {} {}", kind, id),
+            RegistryEvent::Remove { id } => write!(f, "removed {}", id),
+        }
+    }
+}
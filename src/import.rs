@@ -0,0 +1,376 @@
+//! Converts the connection files of other tools from and into rules
+
+use crate::xml;
+
+/// Converts a qjackctl patchbay, every cable links the plugs of its sockets in order
+pub fn from_qjackctl(content: &str) -> Result<String, String> {
+    let root = xml::parse(content)?;
+
+    if !root.name.eq("patchbay") {
+        return Err(format!("expected a <patchbay> but got <{}>", root.name));
+    }
+
+    let sockets = |section: &str| {
+        root.child(section)
+            .map(|a| a.children_named("socket").collect::<Vec<&xml::Element>>())
+            .unwrap_or_default()
+    };
+
+    let outputs = sockets("output-sockets");
+    let inputs = sockets("input-sockets");
+
+    let mut rules = format!(
+        "# Imported from the qjackctl patchbay '{}'\n# JACK client names might differ from the PipeWire node names, list them with pw-autoconnect discover\n",
+        root.attr("name").unwrap_or_default()
+    );
+
+    for cable in root.child("cables").iter().flat_map(|a| a.children_named("cable")) {
+        let (output, input) = (cable.attr("output").unwrap_or_default(), cable.attr("input").unwrap_or_default());
+
+        let (output, input) = match (
+            outputs.iter().find(|a| a.attr("name") == Some(output)),
+            inputs.iter().find(|a| a.attr("name") == Some(input)),
+        ) {
+            (Some(output), Some(input)) => (output, input),
+            _ => {
+                rules.push_str(&format!("# Skipped cable {} -> {}: socket not found\n", output, input));
+                continue;
+            }
+        };
+
+        let kind = cable.attr("type").unwrap_or("jack-audio");
+        if !kind.eq("jack-audio") {
+            rules.push_str(&format!("# Skipped {} cable {} -> {}\n", kind, output.attr("name").unwrap_or_default(), input.attr("name").unwrap_or_default()));
+            continue;
+        }
+
+        let plugs = |socket: &xml::Element| {
+            socket
+                .children_named("plug")
+                .map(|a| a.text.trim().to_string())
+                .collect::<Vec<String>>()
+        };
+
+        let (plugs_out, plugs_in) = (plugs(output), plugs(input));
+
+        if plugs_out.len() != plugs_in.len() {
+            rules.push_str(&format!(
+                "# Cable {} -> {} has {} output and {} input plugs, only the first {} are linked\n",
+                output.attr("name").unwrap_or_default(),
+                input.attr("name").unwrap_or_default(),
+                plugs_out.len(),
+                plugs_in.len(),
+                plugs_out.len().min(plugs_in.len())
+            ));
+        }
+
+        for (plug_out, plug_in) in plugs_out.iter().zip(plugs_in.iter()) {
+            rules.push_str(&format!(
+                "[{}]({}) -> [{}]({})\n",
+                output.attr("client").unwrap_or_default(),
+                plug_out,
+                input.attr("client").unwrap_or_default(),
+                plug_in
+            ));
+        }
+    }
+
+    Ok(rules)
+}
+
+/// Converts the jack section of an aj-snapshot, connections are written as `client:port`
+pub fn from_aj_snapshot(content: &str) -> Result<String, String> {
+    let root = xml::parse(content)?;
+
+    if !root.name.eq("aj-snapshot") {
+        return Err(format!("expected an <aj-snapshot> but got <{}>", root.name));
+    }
+
+    let mut rules = String::from("# Imported from aj-snapshot\n# JACK client names might differ from the PipeWire node names, list them with pw-autoconnect discover\n");
+
+    if root.child("alsa").is_some_and(|a| !a.children.is_empty()) {
+        rules.push_str("# Skipped the alsa connections\n");
+    }
+
+    for client in root.child("jack").iter().flat_map(|a| a.children_named("client")) {
+        for port in client.children_named("port") {
+            for connection in port.children_named("connection") {
+                let target = connection.attr("port").unwrap_or_default();
+
+                match target.split_once(':') {
+                    Some((node, target)) => rules.push_str(&format!(
+                        "[{}]({}) -> [{}]({})\n",
+                        client.attr("name").unwrap_or_default(),
+                        port.attr("name").unwrap_or_default(),
+                        node,
+                        target
+                    )),
+                    None => rules.push_str(&format!("# Skipped connection to '{}': expected client:port\n", target)),
+                }
+            }
+        }
+    }
+
+    Ok(rules)
+}
+
+/// Writes the links, given as (node out, port out, node in, port in), as an aj-snapshot
+pub fn to_aj_snapshot(links: &[(&str, &str, &str, &str)]) -> String {
+    let mut jack = xml::Element {
+        name: "jack".to_string(),
+        ..Default::default()
+    };
+
+    for (node_out, port_out, node_in, port_in) in links {
+        let client = match jack.children.iter().position(|a| a.attr("name") == Some(node_out)) {
+            Some(client) => client,
+            None => {
+                jack.children.push(xml::Element {
+                    name: "client".to_string(),
+                    attrs: vec![("name".to_string(), node_out.to_string())],
+                    ..Default::default()
+                });
+                jack.children.len() - 1
+            }
+        };
+        let client = &mut jack.children[client];
+
+        let port = match client.children.iter().position(|a| a.attr("name") == Some(port_out)) {
+            Some(port) => port,
+            None => {
+                client.children.push(xml::Element {
+                    name: "port".to_string(),
+                    attrs: vec![("name".to_string(), port_out.to_string())],
+                    ..Default::default()
+                });
+                client.children.len() - 1
+            }
+        };
+
+        client.children[port].children.push(xml::Element {
+            name: "connection".to_string(),
+            attrs: vec![("port".to_string(), format!("{}:{}", node_in, port_in))],
+            ..Default::default()
+        });
+    }
+
+    let root = xml::Element {
+        name: "aj-snapshot".to_string(),
+        children: vec![
+            xml::Element {
+                name: "alsa".to_string(),
+                ..Default::default()
+            },
+            jack,
+        ],
+        ..Default::default()
+    };
+
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml::write(&root, 0, &mut out);
+    out
+}
+
+/// Splits a shell command into words, handling quotes and backslashes
+fn shell_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some(a) if a == c => break,
+                        Some('\\') if c == '"' => word.extend(chars.next()),
+                        Some(a) => word.push(a),
+                        None => return Err(format!("unterminated {} quote", c)),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                word.extend(chars.next());
+            }
+            '#' if !in_word => break,
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+
+    if in_word {
+        words.push(word);
+    }
+
+    Ok(words)
+}
+
+/// Converts a script of `pw-link OUT IN` commands, the ports are written as `node:port`
+pub fn from_pw_link(content: &str) -> Result<String, String> {
+    let mut rules = String::from("# Imported from pw-link commands\n");
+
+    for line in content.lines() {
+        let trimmed = line.trim();
+
+        if trimmed.starts_with("#!") || trimmed.is_empty() {
+            continue;
+        }
+
+        if trimmed.starts_with('#') {
+            rules.push_str(trimmed);
+            rules.push('\n');
+            continue;
+        }
+
+        let words = shell_words(trimmed)?;
+
+        if !words.first().is_some_and(|a| a.eq("pw-link") || a.ends_with("/pw-link")) {
+            rules.push_str(&format!("# Skipped: {}\n", trimmed));
+            continue;
+        }
+
+        if words.iter().any(|a| a.eq("-d") || a.eq("--disconnect")) {
+            rules.push_str(&format!("# Skipped disconnect: {}\n", trimmed));
+            continue;
+        }
+
+        let ports = words[1..].iter().filter(|a| !a.starts_with('-')).collect::<Vec<&String>>();
+
+        let link = match ports[..] {
+            [out, input] => out.split_once(':').zip(input.split_once(':')),
+            _ => None,
+        };
+
+        match link {
+            Some(((node_out, port_out), (node_in, port_in))) => {
+                rules.push_str(&format!("[{}]({}) -> [{}]({})\n", node_out, port_out, node_in, port_in))
+            }
+            None => rules.push_str(&format!("# Skipped, expected pw-link node:port node:port: {}\n", trimmed)),
+        }
+    }
+
+    Ok(rules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The rule lines of the output, without the comments
+    fn rules(out: &str) -> Vec<&str> {
+        out.lines().filter(|a| !a.starts_with('#')).collect()
+    }
+
+    #[test]
+    fn qjackctl() {
+        let patchbay = r#"<!DOCTYPE patchbay>
+<patchbay version="0.9.6" name="studio">
+ <output-sockets>
+  <socket exclusive="off" client="Synth" type="jack-audio" name="Synth">
+   <plug>out_1</plug>
+   <plug>out_2</plug>
+  </socket>
+  <socket exclusive="off" client="Keys" type="jack-midi" name="Keys">
+   <plug>midi_out</plug>
+  </socket>
+ </output-sockets>
+ <input-sockets>
+  <socket exclusive="off" client="system" type="jack-audio" name="system">
+   <plug>playback_1</plug>
+  </socket>
+  <socket exclusive="off" client="Sampler" type="jack-midi" name="Sampler">
+   <plug>midi_in</plug>
+  </socket>
+ </input-sockets>
+ <cables>
+  <cable output="Synth" input="system" type="jack-audio"/>
+  <cable output="Keys" input="Sampler" type="jack-midi"/>
+  <cable output="Gone" input="system" type="jack-audio"/>
+ </cables>
+</patchbay>
+"#;
+        let out = from_qjackctl(patchbay).unwrap();
+
+        // Only as many links as the socket with fewer plugs has
+        assert_eq!(rules(&out), ["[Synth](out_1) -> [system](playback_1)"]);
+        assert!(out.contains("'studio'"));
+        assert!(out.contains("# Cable Synth -> system has 2 output and 1 input plugs"));
+        assert!(out.contains("# Skipped jack-midi cable Keys -> Sampler"));
+        assert!(out.contains("# Skipped cable Gone -> system: socket not found"));
+
+        assert!(from_qjackctl("<aj-snapshot/>").unwrap_err().contains("<patchbay>"));
+        assert!(from_qjackctl("<patchbay><cables></patchbay>").is_err());
+    }
+
+    #[test]
+    fn aj_snapshot() {
+        let links = [("Synth", "out_1", "system", "playback_1"), ("Synth", "out_1", "system", "playback_2"), ("Drums", "out", "system", "playback_1")];
+        let snapshot = to_aj_snapshot(&links);
+        assert!(snapshot.starts_with("<?xml"));
+
+        // The links come back grouped by their client and port
+        let out = from_aj_snapshot(&snapshot).unwrap();
+        assert_eq!(
+            rules(&out),
+            [
+                "[Synth](out_1) -> [system](playback_1)",
+                "[Synth](out_1) -> [system](playback_2)",
+                "[Drums](out) -> [system](playback_1)"
+            ]
+        );
+
+        let snapshot = r#"<aj-snapshot>
+<alsa><client name="System"/></alsa>
+<jack>
+  <client name="Synth">
+    <port name="out_1">
+      <connection port="no-colon"/>
+    </port>
+  </client>
+</jack>
+</aj-snapshot>"#;
+        let out = from_aj_snapshot(snapshot).unwrap();
+        assert!(rules(&out).is_empty());
+        assert!(out.contains("# Skipped the alsa connections"));
+        assert!(out.contains("# Skipped connection to 'no-colon': expected client:port"));
+
+        assert!(from_aj_snapshot("<patchbay/>").unwrap_err().contains("<aj-snapshot>"));
+        assert!(from_aj_snapshot("<aj-snapshot>").is_err());
+    }
+
+    #[test]
+    fn pw_link() {
+        let script = r#"#!/bin/sh
+# Studio
+pw-link "Synth Out:out 1" 'system:playback_1'
+/usr/bin/pw-link -L Synth\ Out:out_2 system:playback_2 # the right side
+pw-link -d Synth:out_1 system:playback_1
+pw-link Synth:out_1
+echo done
+"#;
+        let out = from_pw_link(script).unwrap();
+
+        assert_eq!(
+            rules(&out),
+            ["[Synth Out](out 1) -> [system](playback_1)", "[Synth Out](out_2) -> [system](playback_2)"]
+        );
+        assert!(out.contains("# Studio\n"));
+        assert!(!out.contains("#!/bin/sh"));
+        assert!(out.contains("# Skipped disconnect: pw-link -d"));
+        assert!(out.contains("# Skipped, expected pw-link node:port node:port: pw-link Synth:out_1"));
+        assert!(out.contains("# Skipped: echo done"));
+
+        assert_eq!(from_pw_link("pw-link 'Synth:out_1 system:playback_1\n").unwrap_err(), "unterminated ' quote");
+    }
+}
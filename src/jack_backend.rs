@@ -0,0 +1,192 @@
+//! Applies the rules to a plain JACK server, used with `--backend jack`
+
+use std::{cell::RefCell, collections::HashMap, rc::Rc, sync::mpsc, sync::Mutex, time::Instant};
+
+use crate::config::SCHEDULE_INTERVAL;
+use crate::engine::{add_node, add_port, AppState};
+use crate::graph::{Direction, GraphBackend, Latency, LinkHandle, Port, PortProps, WAIT_INTERVAL};
+use crate::crash;
+
+enum Event {
+    PortAdded(String),
+    PortRemoved(String),
+    Shutdown(String),
+}
+
+/// JACK does not allow connecting ports from its callbacks, so the events are sent to the main thread
+struct Notifications {
+    events: Mutex<mpsc::Sender<Event>>,
+}
+
+impl Notifications {
+    fn send(&self, event: Event) {
+        if let Ok(events) = self.events.lock() {
+            let _ = events.send(event);
+        }
+    }
+}
+
+impl jack::NotificationHandler for Notifications {
+    fn port_registration(&mut self, client: &jack::Client, port_id: jack::PortId, is_registered: bool) {
+        let port = match client.port_by_id(port_id) {
+            Some(port) => port,
+            None => return,
+        };
+
+        if !port.port_type().is_ok_and(|a| a.contains("audio")) {
+            return;
+        }
+
+        if let Ok(name) = port.name() {
+            self.send(if is_registered { Event::PortAdded(name) } else { Event::PortRemoved(name) });
+        }
+    }
+
+    unsafe fn shutdown(&mut self, _status: jack::ClientStatus, reason: &str) {
+        self.send(Event::Shutdown(reason.to_string()));
+    }
+}
+
+struct JackBackend<'a> {
+    client: &'a jack::Client,
+}
+
+fn full_name(port: &Port) -> String {
+    format!("{}:{}", port.node.name, port.name)
+}
+
+impl GraphBackend for JackBackend<'_> {
+    // JACK connections have no properties, and stay after we exit
+    fn create_link(&self, port_out: &Port, port_in: &Port, _props: &[(String, String)]) -> Result<LinkHandle, String> {
+        let (port_out, port_in) = (full_name(port_out), full_name(port_in));
+
+        self.client
+            .connect_ports_by_name(&port_out, &port_in)
+            .map_err(|e| e.to_string())?;
+
+        Ok(LinkHandle::Jack(port_out, port_in))
+    }
+
+    fn destroy_link(&self, link: LinkHandle) -> Result<(), String> {
+        match link {
+            LinkHandle::Jack(port_out, port_in) => self
+                .client
+                .disconnect_ports_by_name(&port_out, &port_in)
+                .map_err(|e| e.to_string()),
+            _ => Err("not a JACK link".to_string()),
+        }
+    }
+
+    fn set_node_props(&self, _node: u32, _props: &str) -> Result<(), String> {
+        Err("JACK clients have no properties".to_string())
+    }
+
+    fn set_device_profile(&self, _device: u32, _profile: &str) -> Result<(), String> {
+        Err("JACK has no devices".to_string())
+    }
+
+    fn force_latency(&self, _latency: Option<Latency>) -> Result<(), String> {
+        Err("the buffer size of JACK is set when starting the server".to_string())
+    }
+}
+
+/// JACK has no object ids, the clients (nodes) and ports get one the first time they are seen
+fn get_id(ids: &mut HashMap<String, u32>, name: &str) -> u32 {
+    let next = ids.len() as u32 + 1;
+    *ids.entry(name.to_string()).or_insert(next)
+}
+
+fn port_added(state: &mut AppState, ids: &mut HashMap<String, u32>, name: &str, backend: &JackBackend) {
+    let flags = backend.client.port_by_name(name).map(|a| a.flags());
+    let direction = flags.and_then(|flags| {
+        if flags.contains(jack::PortFlags::IS_OUTPUT) {
+            Some(Direction::Out)
+        } else if flags.contains(jack::PortFlags::IS_INPUT) {
+            Some(Direction::In)
+        } else {
+            None
+        }
+    });
+    let has = |flag: jack::PortFlags| flags.is_some_and(|a| a.contains(flag));
+
+    let (node, port) = match name.split_once(':') {
+        Some(a) => a,
+        None => return,
+    };
+
+    let node_id = get_id(ids, node);
+
+    if state.get_node(node_id).is_none() {
+        add_node(state, node_id, node, "<jack client>", None);
+    }
+
+    let props = PortProps {
+        direction,
+        channel: None,
+        index: None,
+        monitor: false,
+        physical: has(jack::PortFlags::IS_PHYSICAL),
+        terminal: has(jack::PortFlags::IS_TERMINAL),
+    };
+
+    add_port(state, get_id(ids, name), port, node_id, props, backend);
+}
+
+pub fn run(state: Rc<RefCell<AppState>>) -> Result<(), Box<dyn std::error::Error>> {
+    let (client, _status) = jack::Client::new("pw-autoconnect", jack::ClientOptions::NO_START_SERVER)?;
+
+    if !state.borrow().linger {
+        println!("--no-linger is not supported by the JACK backend, links will stay after exit");
+    }
+
+    state.borrow_mut().node_times = Some(HashMap::new());
+
+    let (sender, events) = mpsc::channel();
+
+    // The ports that exist before we are activated are not announced
+    let existing = client.ports(None, Some("audio"), jack::PortFlags::empty());
+
+    let client = client.activate_async(Notifications { events: Mutex::new(sender) }, ())?;
+    let backend = JackBackend {
+        client: client.as_client(),
+    };
+
+    let mut ids: HashMap<String, u32> = HashMap::new();
+
+    for name in existing {
+        port_added(&mut state.borrow_mut(), &mut ids, &name, &backend);
+    }
+
+    let mut last_schedule = Instant::now();
+
+    loop {
+        match events.recv_timeout(WAIT_INTERVAL) {
+            Ok(Event::PortAdded(name)) => port_added(&mut state.borrow_mut(), &mut ids, &name, &backend),
+            Ok(Event::PortRemoved(name)) => {
+                let mut state = state.borrow_mut();
+                if let Some(id) = ids.get(&name) {
+                    if state.remove_global(*id) {
+                        println!("Removed {}", name);
+                        state.reconcile_priorities(&backend);
+                    }
+                }
+            }
+            Ok(Event::Shutdown(reason)) => {
+                println!("JACK server shut down: {}", reason);
+                break;
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+
+        state.borrow_mut().apply_waiting(&backend);
+        crash::snapshot(&state.borrow());
+
+        if last_schedule.elapsed() >= SCHEDULE_INTERVAL {
+            state.borrow_mut().update_schedules(&backend);
+            last_schedule = Instant::now();
+        }
+    }
+
+    Ok(())
+}
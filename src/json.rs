@@ -0,0 +1,306 @@
+//! Just enough JSON to save and load recordings and graph snapshots
+
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|a| a.0.eq(key)).map(|a| &a.1),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(a) => Some(*a),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Number(a) if *a >= 0.0 && a.fract() == 0.0 => Some(*a as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    /// Returns the fields of an object whose values are all strings
+    pub fn as_string_map(&self) -> Option<HashMap<String, String>> {
+        match self {
+            Value::Object(fields) => fields
+                .iter()
+                .map(|(k, v)| v.as_str().map(|v| (k.clone(), v.to_string())))
+                .collect(),
+            _ => None,
+        }
+    }
+
+    pub fn from_string_map(map: &HashMap<String, String>) -> Value {
+        let mut fields = map
+            .iter()
+            .map(|(k, v)| (k.clone(), Value::String(v.clone())))
+            .collect::<Vec<(String, Value)>>();
+        // Keep the output stable, HashMap has no order
+        fields.sort_by(|a, b| a.0.cmp(&b.0));
+        Value::Object(fields)
+    }
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|a| a.is_whitespace()).is_some() {}
+    }
+
+    fn expect(&mut self, word: &str) -> Result<(), String> {
+        for c in word.chars() {
+            if self.chars.next() != Some(c) {
+                return Err(format!("expected '{}'", word));
+            }
+        }
+        Ok(())
+    }
+
+    /// The four hex digits after `\u`
+    fn code_unit(&mut self) -> Result<u32, String> {
+        let code = (0..4).filter_map(|_| self.chars.next()).collect::<String>();
+        u32::from_str_radix(&code, 16).map_err(|_| format!("invalid escape \\u{}", code))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect("\"")?;
+
+        let mut out = String::new();
+
+        loop {
+            match self.chars.next().ok_or("unterminated string")? {
+                '"' => return Ok(out),
+                '\\' => match self.chars.next().ok_or("unterminated string")? {
+                    'n' => out.push('\n'),
+                    't' => out.push('\t'),
+                    'r' => out.push('\r'),
+                    'b' => out.push('\u{8}'),
+                    'f' => out.push('\u{c}'),
+                    'u' => {
+                        let mut code = self.code_unit()?;
+                        // Characters outside the BMP come as a pair of surrogates, like Python writes them
+                        if (0xd800..0xdc00).contains(&code) && self.chars.clone().take(2).eq(['\\', 'u']) {
+                            self.chars.nth(1);
+                            let low = self.code_unit()?;
+                            code = match low {
+                                0xdc00..0xe000 => 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00),
+                                _ => low,
+                            };
+                        }
+                        out.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                    }
+                    c => out.push(c),
+                },
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+
+        match self.chars.peek().ok_or("unexpected end")? {
+            '{' => {
+                self.chars.next();
+                let mut fields = Vec::new();
+                loop {
+                    self.skip_whitespace();
+                    if self.chars.next_if_eq(&'}').is_some() {
+                        return Ok(Value::Object(fields));
+                    }
+                    if !fields.is_empty() {
+                        self.expect(",")?;
+                        self.skip_whitespace();
+                    }
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    self.expect(":")?;
+                    fields.push((key, self.value()?));
+                }
+            }
+            '[' => {
+                self.chars.next();
+                let mut values = Vec::new();
+                loop {
+                    self.skip_whitespace();
+                    if self.chars.next_if_eq(&']').is_some() {
+                        return Ok(Value::Array(values));
+                    }
+                    if !values.is_empty() {
+                        self.expect(",")?;
+                    }
+                    values.push(self.value()?);
+                }
+            }
+            '"' => self.string().map(Value::String),
+            't' => self.expect("true").map(|_| Value::Bool(true)),
+            'f' => self.expect("false").map(|_| Value::Bool(false)),
+            'n' => self.expect("null").map(|_| Value::Null),
+            _ => {
+                let mut number = String::new();
+                while let Some(c) = self.chars.next_if(|a| a.is_ascii_digit() || "+-.eE".contains(*a)) {
+                    number.push(c);
+                }
+                number
+                    .parse::<f64>()
+                    .map(Value::Number)
+                    .map_err(|_| format!("invalid value '{}'", number))
+            }
+        }
+    }
+}
+
+pub fn parse(content: &str) -> Result<Value, String> {
+    let mut parser = Parser { chars: content.chars().peekable() };
+
+    let value = parser.value()?;
+    parser.skip_whitespace();
+
+    match parser.chars.next() {
+        Some(c) => Err(format!("unexpected '{}' after the value", c)),
+        None => Ok(value),
+    }
+}
+
+fn write_string(text: &str, out: &mut String) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Writes the value in a single line
+pub fn write(value: &Value, out: &mut String) {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(a) => out.push_str(if *a { "true" } else { "false" }),
+        Value::Number(a) => out.push_str(&a.to_string()),
+        Value::String(a) => write_string(a, out),
+        Value::Array(values) => {
+            out.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write(value, out);
+            }
+            out.push(']');
+        }
+        Value::Object(fields) => {
+            out.push('{');
+            for (i, (key, value)) in fields.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_string(key, out);
+                out.push_str(": ");
+                write(value, out);
+            }
+            out.push('}');
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(value: &Value) -> Value {
+        let mut out = String::new();
+        write(value, &mut out);
+        parse(&out).unwrap()
+    }
+
+    #[test]
+    fn values() {
+        let value = Value::Object(vec![
+            ("null".to_string(), Value::Null),
+            ("yes".to_string(), Value::Bool(true)),
+            ("numbers".to_string(), Value::Array(vec![Value::Number(0.0), Value::Number(-12.5), Value::Number(1e20)])),
+            ("empty".to_string(), Value::Array(Vec::new())),
+            ("nested".to_string(), Value::Object(vec![("name".to_string(), Value::String("Music".to_string()))])),
+        ]);
+        assert_eq!(round_trip(&value), value);
+
+        let parsed = parse(" {\n\t\"id\" : 42 , \"tags\" : [ \"a\" ,\"b\" ] }\n").unwrap();
+        assert_eq!(parsed.get("id").and_then(Value::as_u64), Some(42));
+        assert_eq!(parsed.get("tags").and_then(Value::as_array).map(|a| a.len()), Some(2));
+        assert_eq!(Value::Number(-1.0).as_u64(), None);
+        assert_eq!(Value::Number(1.5).as_u64(), None);
+
+        let map = HashMap::from([("b".to_string(), "2".to_string()), ("a".to_string(), "1".to_string())]);
+        let mut out = String::new();
+        write(&Value::from_string_map(&map), &mut out);
+        assert_eq!(out, r#"{"a": "1", "b": "2"}"#);
+        assert_eq!(parse(&out).unwrap().as_string_map(), Some(map));
+    }
+
+    #[test]
+    fn escaping() {
+        let text = "say \"hi\" \\ back\n\tthen\r\u{1}\u{1f} Café ♫ 🎵";
+
+        let mut out = String::new();
+        write(&Value::String(text.to_string()), &mut out);
+        assert_eq!(out, "\"say \\\"hi\\\" \\\\ back\\n\\tthen\\r\\u0001\\u001f Café ♫ 🎵\"");
+        assert_eq!(parse(&out).unwrap().as_str(), Some(text));
+
+        // The escapes other writers use for the same characters
+        assert_eq!(parse(r#""Caf\u00e9 \u266b \ud83c\udfb5 \/ \b\f""#).unwrap().as_str(), Some("Café ♫ 🎵 / \u{8}\u{c}"));
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(parse("\"open").unwrap_err(), "unterminated string");
+        assert_eq!(parse("\"\\u12g4\"").unwrap_err(), "invalid escape \\u12g4");
+        assert_eq!(parse("[1, 2").unwrap_err(), "expected ','");
+        assert_eq!(parse("[1 2]").unwrap_err(), "expected ','");
+        assert_eq!(parse("{\"a\" 1}").unwrap_err(), "expected ':'");
+        assert_eq!(parse("{1: 2}").unwrap_err(), "expected '\"'");
+        assert_eq!(parse("tru").unwrap_err(), "expected 'true'");
+        assert_eq!(parse("nope").unwrap_err(), "expected 'null'");
+        assert_eq!(parse("1 2").unwrap_err(), "unexpected '2' after the value");
+        assert_eq!(parse("").unwrap_err(), "unexpected end");
+        assert_eq!(parse("@").unwrap_err(), "invalid value ''");
+    }
+}
@@ -0,0 +1,84 @@
+//! The links we made, saved in the runtime directory so a new instance can adopt them
+//! and `cleanup` can still find them after a crash
+
+use std::{collections::HashMap, fs, path::PathBuf};
+
+use crate::json::{self, Value};
+
+pub struct LinksFile {
+    path: PathBuf,
+    // Global id and properties of every link
+    links: Vec<(u32, HashMap<String, String>)>,
+}
+
+impl LinksFile {
+    pub fn path(system: bool) -> PathBuf {
+        crate::daemon::runtime_dir(system).join("pw-autoconnect.links")
+    }
+
+    /// Reads the file, a missing or broken file has no links
+    pub fn load(path: PathBuf) -> LinksFile {
+        let links = fs::read_to_string(&path)
+            .ok()
+            .and_then(|a| json::parse(&a).ok())
+            .and_then(|a| {
+                a.as_array()?
+                    .iter()
+                    .map(|link| {
+                        let id = link.get("id")?.as_u64()? as u32;
+                        Some((id, link.get("props")?.as_string_map()?))
+                    })
+                    .collect::<Option<Vec<(u32, HashMap<String, String>)>>>()
+            })
+            .unwrap_or_default();
+
+        LinksFile { path, links }
+    }
+
+    pub fn links(&self) -> &[(u32, HashMap<String, String>)] {
+        &self.links
+    }
+
+    pub fn insert(&mut self, id: u32, props: HashMap<String, String>) {
+        self.links.retain(|a| a.0 != id);
+        self.links.push((id, props));
+        self.save();
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        let len = self.links.len();
+        self.links.retain(|a| a.0 != id);
+        if len != self.links.len() {
+            self.save();
+        }
+    }
+
+    pub fn retain<F: FnMut(&(u32, HashMap<String, String>)) -> bool>(&mut self, f: F) {
+        self.links.retain(f);
+        self.save();
+    }
+
+    fn save(&self) {
+        let links = self
+            .links
+            .iter()
+            .map(|(id, props)| {
+                Value::Object(vec![
+                    ("id".to_string(), Value::Number(*id as f64)),
+                    ("props".to_string(), Value::from_string_map(props)),
+                ])
+            })
+            .collect();
+
+        let mut out = String::new();
+        json::write(&Value::Array(links), &mut out);
+
+        if let Some(dir) = self.path.parent() {
+            let _ = crate::daemon::create_runtime_dir(dir);
+        }
+
+        if let Err(e) = fs::write(&self.path, out) {
+            println!("Could not save the links to {}: {}", self.path.display(), e);
+        }
+    }
+}
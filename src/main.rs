@@ -1,382 +1,436 @@
-use std::{cell::RefCell, env, fs, io::BufRead, collections::HashMap};
-use std::vec::Vec;
-use std::{cell::RefMut, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, env, fmt, fs, process::ExitCode, rc::Rc};
 
-use libspa::ReadableDict;
-use pipewire::{types::ObjectType, Context, MainLoop};
 use pipewire as pw;
-use regex::Regex;
 
 #[macro_use]
 extern crate lazy_static;
 
+mod audit;
+mod cli;
+mod commands;
+mod config;
+mod control;
+mod crash;
+mod csv;
+mod daemon;
+mod engine;
+mod filter_chain;
+mod graph;
+mod import;
+#[cfg(feature = "jack")]
+mod jack_backend;
+mod json;
+mod links_file;
+mod matcher;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod oneshot;
+mod pipewire_backend;
+#[cfg(feature = "pulse")]
+mod pulse_backend;
+mod record;
+mod report;
+mod sim_backend;
+mod watchdog;
+#[cfg(feature = "web")]
+mod web;
+mod webhook;
+mod xml;
+
+use cli::UsageError;
+use config::{system_socket, Config};
+use engine::{satisfied, AppState, Unsatisfied};
+use oneshot::{
+    append_rules, check_live, default_config, diff, discover, dry_run, expand, export, init, list, list_graph, missing_config, read_graph_file,
+    replay, suggest, test, write_default_config,
+};
+use pipewire_backend::{cleanup, NotConnected};
+use record::{read_events, Recorder};
+
+/// Exit codes, so scripts and unit files can tell why pw-autoconnect stopped without reading its output
+const EXIT_ERROR: u8 = 1;
+const EXIT_CONFIG: u8 = 2;
+const EXIT_PIPEWIRE: u8 = 3;
+const EXIT_UNSATISFIED: u8 = 4;
+/// The rules file could not be read or has a mistake
 #[derive(Debug)]
-struct Port {
-    id: u32,
-    name: String,
-    node: Rc<Node>,
-}
+struct ConfigError(Box<dyn std::error::Error>);
 
-#[derive(Debug)]
-struct Node {
-    id: u32,
-    name: String,
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
-#[derive(Debug)]
-struct NodeDef {
-    name: String,
-}
+impl std::error::Error for ConfigError {}
 
-#[derive(Debug)]
-struct PortDef {
-    node: Rc<NodeDef>,
-    name: String,
-}
-
-#[derive(Debug)]
-struct LinkDef {
-    port_in: Rc<PortDef>,
-    port_out: Rc<PortDef>,
+fn exit_code(error: &(dyn std::error::Error + 'static)) -> u8 {
+    if error.is::<ConfigError>() {
+        EXIT_CONFIG
+    } else if error.is::<pw::Error>() || error.is::<NotConnected>() {
+        EXIT_PIPEWIRE
+    } else if error.is::<Unsatisfied>() {
+        EXIT_UNSATISFIED
+    } else {
+        EXIT_ERROR
+    }
 }
 
-struct AppState {
-    ports: Vec<Rc<Port>>,
-    nodes: Vec<Rc<Node>>,
-
-    get_names: bool,
-
-    node_def: Vec<Rc<NodeDef>>,
-    link_def: Vec<Rc<LinkDef>>,
-    port_def: Vec<Rc<PortDef>>,
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            // The one-shot commands already printed what is missing
+            if !e.is::<Unsatisfied>() {
+                eprintln!("Error: {}", e);
+            }
+            ExitCode::from(exit_code(e.as_ref()))
+        }
+    }
 }
 
-fn search<T, P>(v: &[Rc<T>], f: P) -> Option<Rc<T>>
-where
-    T: Sized,
-    P: FnMut(&&Rc<T>) -> bool,
-{
-    let n = v.iter().filter(f).cloned().collect::<Vec<Rc<T>>>();
-
-    if n.len() != 1 {
-        return None;
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let cli::Args {
+        help,
+        find_names,
+        linger,
+        backend,
+        filter,
+        mut file_name,
+        command,
+        import_from,
+        append,
+        export_to,
+        record,
+        events_file,
+        graph_file,
+        profile_name,
+        daemon,
+        unmatched,
+        follow,
+        format,
+        node_filter,
+        live,
+        socket,
+        system,
+        factory,
+        rate_limit,
+        watchdog,
+        once,
+        timeout,
+        trace_registry,
+        write_config,
+        strict,
+        runtime_dir,
+        connect_retries,
+        connect_timeout,
+        web,
+        api,
+        api_token,
+        mqtt,
+        webhook,
+        audit_log,
+        mqtt_topic,
+        pid_file,
+        log_file,
+    } = cli::parse(env::args().skip(1))?;
+
+    if help {
+        cli::help();
+        return Ok(());
     }
 
-    Some(n[0].clone())
-}
+    // PipeWire looks the socket name up there, and so do the filters we start
+    if let Some(dir) = runtime_dir {
+        env::set_var("PIPEWIRE_RUNTIME_DIR", dir);
+    }
 
-impl AppState {
-    fn new(
-        node_def: Vec<Rc<NodeDef>>,
-        link_def: Vec<Rc<LinkDef>>,
-        port_def: Vec<Rc<PortDef>>,
-        get_names: bool,
-    ) -> AppState {
-        AppState {
-            node_def,
-            link_def,
-            port_def,
-            get_names,
-            ports: Vec::new(),
-            nodes: Vec::new(),
-        }
+    if command.as_deref() == Some("discover") {
+        let socket = socket.or_else(|| if system { system_socket() } else { None });
+        return discover(socket.as_deref(), &node_filter, follow);
     }
 
-    fn try_add_node(&mut self, def: Node) -> bool {
-        if !self.node_def.iter().any(|a| a.name.eq(&def.name)) {
-            return false;
+    if command.as_deref() == Some("diff") {
+        return match (file_name, graph_file) {
+            (Some(before), Some(after)) => diff(std::path::Path::new(&before), std::path::Path::new(&after)),
+            _ => {
+                Err(UsageError("diff requires two graph files saved with export --to graph".to_string()).into())
+            }
         };
-
-        let mut nodes = self
-            .nodes
-            .iter()
-            .filter(|a| !a.name.eq(&def.name))
-            .cloned()
-            .collect::<Vec<Rc<Node>>>();
-
-        nodes.push(Rc::new(def));
-
-        self.nodes = nodes;
-
-        true
     }
 
-    fn get_node(&self, id: u32) -> Option<Rc<Node>> {
-        search(&self.nodes, |a| a.id == id)
+    if command.as_deref() == Some("suggest") {
+        let socket = socket.or_else(|| if system { system_socket() } else { None });
+        return suggest(socket.as_deref(), append.as_deref().map(std::path::Path::new));
     }
 
-    fn get_port_by_name(&self, name: String) -> Option<Rc<Port>> {
-        search(&self.ports, |a| a.name.eq(&name))
+    if command.as_deref() == Some("list") && file_name.is_none() {
+        let socket = socket.or_else(|| if system { system_socket() } else { None });
+        return list_graph(socket.as_deref(), &node_filter, format);
     }
 
-    fn try_add_port(&mut self, id: u32, name: String, node_id: u32) -> bool {
-        let node = self.get_node(node_id);
-
-        if node.is_none() {
-            return false;
+    if command.as_deref() == Some("profile") {
+        if file_name.as_deref() != Some("switch") {
+            return Err(UsageError("profile requires switch <name>, or switch alone to load every rule".to_string()).into());
         }
 
-        let node = node.unwrap();
-
-        if self
-            .port_def
-            .iter()
-            .filter(|a| a.name.eq(&name) && a.node.name.eq(&node.name))
-            .count()
-            != 1
-        {
-            if self.get_names && node.id == node_id {
-                println!("Port from node {} -> {}: {}", &node.name, id, name);
+        match control::send(&format!("profile {}", profile_name.unwrap_or_default()), system) {
+            Some(answer) if answer.starts_with("Could not") => {
+                print!("{}", answer);
+                return Err("the profile was not switched".into());
             }
-            return false;
+            Some(answer) => print!("{}", answer),
+            None => return Err("pw-autoconnect is not running".into()),
         }
-
-        let mut ports = self
-            .ports
-            .iter()
-            .filter(|a| !(a.name.eq(&name) && a.node.name.eq(&node.name)))
-            .cloned()
-            .collect::<Vec<Rc<Port>>>();
-
-        ports.push(Rc::new(Port { id, name, node }));
-
-        self.ports = ports;
-
-        true
-    }
-
-    fn create_links(&mut self, port_name: String, core: Rc<pw::Core>) {
-        #[derive(Debug)]
-        struct TempLink {
-            port_in: Option<Rc<Port>>,
-            port_out: Option<Rc<Port>>,
-        }
-
-        self.link_def
-            .iter()
-            .filter(|link| (link.port_in.name.eq(&port_name) || link.port_out.name.eq(&port_name)))
-            .map(|a| TempLink {
-                port_in: self.get_port_by_name(a.port_in.name.to_string()),
-                port_out: self.get_port_by_name(a.port_out.name.to_string()),
-            }).filter(|a| a.port_in.is_some() && a.port_out.is_some()).for_each(|a| {
-                let port_in = a.port_in.unwrap();
-                let port_out = a.port_out.unwrap();
-
-                println!("Try to created link: [{}]{} -> [{}]{}", port_out.node.name, port_out.name, port_in.node.name, port_in.name);
-                
-                // Try to create the link
-                if core.create_object::<pw::link::Link, _>(
-                    // The actual name for a link factory might be different for your system,
-                    // you should probably obtain a factory from the registry.
-                    "link-factory",
-                    &pw::properties! {
-                        "link.output.port" => port_out.id.to_string(),
-                        "link.input.port" => port_in.id.to_string(),
-                        "link.output.node" => port_out.node.id.to_string(),
-                        "link.input.node" => port_in.node.id.to_string(),
-                        "object.linger" => "1"
-                    },
-                ).is_err() {
-                    println!("Failed to create link");
-                }
-            });
+        return Ok(());
     }
-}
 
-fn deal_with_node(
-    global_object: &pipewire::registry::GlobalObject<libspa::ForeignDict>,
-    mut state: RefMut<AppState>,
-) {
-    if let Some(props) = &global_object.props {
-        if let (Some(class), Some(name)) = (props.get("media.class"), props.get("node.name")) {
-            if class.starts_with("Audio") {
-                if state.get_names {
-                    println!(
-                        "Got Audio device {}: {}({})",
-                        global_object.id,
-                        name,
-                        props.get("node.nick").unwrap_or("<no nick>")
-                    );
+    if let Some(command @ ("reload" | "status" | "stats" | "health" | "enable" | "disable")) = command.as_deref() {
+        let health = command == "health";
+        let command = match command {
+            "status" | "stats" | "health" => format!("{} {}", command, format.as_str()),
+            "enable" | "disable" => match &file_name {
+                Some(rule) => format!("{} {}", command, rule),
+                None => {
+                    return Err(UsageError(format!("{} requires the number or the @name of a rule", command)).into());
                 }
+            },
+            _ => command.to_string(),
+        };
 
-                if state.try_add_node(Node {
-                    id: global_object.id,
-                    name: name.to_string(),
-                }) {
-                    println!(
-                        "Got {}: {}({})",
-                        global_object.id,
-                        name,
-                        props.get("node.nick").unwrap_or("<no nick>")
-                    );
-                }
+        match control::send(&command, system) {
+            // Monitoring tools only look at the exit code
+            Some(answer) if health && answer.contains("unhealthy") => {
+                print!("{}", answer);
+                return Err("pw-autoconnect is unhealthy".into());
             }
+            Some(answer) => print!("{}", answer),
+            None if health => return Err("pw-autoconnect is not running".into()),
+            None => println!("pw-autoconnect is not running"),
         }
-    } else {
-        println!("No props! Skiping id: {:?}", global_object.id);
+        return Ok(());
     }
-}
 
-fn deal_with_port(
-    port: &pipewire::registry::GlobalObject<libspa::ForeignDict>,
-    mut state: RefMut<AppState>,
-    core: Rc<pw::Core>,
-) {
-    if let Some(props) = &port.props {
-        if let (Some(name), Some(node_id)) = (props.get("port.name"), props.get("node.id")) {
-            if let Ok(node_id) = node_id.parse::<u32>() {
-                if state.try_add_port(port.id, name.to_string(), node_id) {
-                    println!(
-                        "Got port {} for {}",
-                        name,
-                        state.get_node(node_id).unwrap().name
-                    );
-                    state.create_links(name.to_string(), core)
-                }
-            } else {
-                println!("Clould not parse {}'s node.id({})", name, node_id)
+    if write_config {
+        let path = match file_name.map(std::path::PathBuf::from).or_else(|| default_config(system)) {
+            Some(path) => path,
+            None => {
+                return Err(UsageError("--write-default-config requires a file when $HOME is not set".to_string()).into());
             }
-        }
-    } else {
-        println!("No props! Skiping id: {}", port.id);
+        };
+        let socket = socket.or_else(|| if system { system_socket() } else { None });
+        return write_default_config(&path, socket.as_deref());
     }
-}
-
-fn help() {
-    println!("Usage: \n");
-    println!("pw-autoconnect <filename> \n")
-}
 
-fn parse_file(path: std::path::PathBuf, get_names: bool) -> Result<AppState, Box<dyn std::error::Error>> {
-    let file = fs::File::open(path)?;
-    let reader = std::io::BufReader::new(file);
-    
-    lazy_static! {
-        static ref RE: Regex = Regex::new("\\[(?P<node_out>.*)\\]\\((?P<port_out>.*)\\)\\s*->\\s*\\[(?P<node_in>.*)\\]\\((?P<port_in>.*)\\)").unwrap();
+    // The one of --system is always read, the one of a user only once it was written
+    if file_name.is_none() && command.is_none() {
+        file_name = default_config(system).filter(|a| system || a.exists()).map(|a| a.display().to_string());
     }
 
-    let mut node_def: HashMap<String, Rc<NodeDef>>  = HashMap::new();
-    let mut port_def: HashMap<String, Rc<PortDef>>  = HashMap::new();
-    let mut link_def: Vec<Rc<LinkDef>> = Vec::new();
-
-    for line in reader.lines() {
-        let line = line?;
+    let file_name = match file_name {
+        Some(file_name) => file_name,
+        None => return Err(UsageError("no rules file given, see --help".to_string()).into()),
+    };
 
-        if RE.is_match(&line) {
-            let caps = RE.captures(&line).unwrap();
-            println!("Found link: [{}]{} -> [{}]{}", &caps["node_out"], &caps["port_out"],  &caps["node_in"], &caps["port_in"]);
-
-            let node_out = match node_def.get_mut(&caps["node_out"]) {
-                Some(node) => node.to_owned(),
-                None => {
-                    let node = Rc::new(NodeDef {name: caps["node_out"].to_string()});
-                    node_def.insert(caps["node_out"].to_string(), node.clone());
-                    node
-                },
-            };
-
-            let node_in = match node_def.get_mut(&caps["node_in"]) {
-                Some(node) => node.to_owned(),
-                None => {
-                    let node = Rc::new(NodeDef {name: caps["node_in"].to_string()});
-                    node_def.insert(caps["node_in"].to_string(), node.clone());
-                    node
-                },
-            };
-
-            let port_out = match port_def.get_mut(&caps["port_out"]) {
-                Some(port) => port.to_owned(),
-                None => {
-                    let port = Rc::new(PortDef { node: node_out.clone(), name: caps["port_out"].to_string() } );
-                    port_def.insert(caps["port_out"].to_string(), port.clone());
-                    port
-                },
-            };
+    let path = std::path::Path::new(&file_name);
 
-            let port_in = match port_def.get_mut(&caps["port_in"]) {
-                Some(port) => port.to_owned(),
-                None => {
-                    let port = Rc::new(PortDef { node: node_in.clone(), name: caps["port_in"].to_string() } );
-                    port_def.insert(caps["port_in"].to_string(), port.clone());
-                    port
-                },
-            };
+    if command.as_deref() == Some("export") {
+        return match export_to.as_deref() {
+            Some(format @ ("aj-snapshot" | "graph")) => {
+                let socket = socket.or_else(|| if system { system_socket() } else { None });
+                export(path, format, socket.as_deref())
+            }
+            Some(format) => {
+                Err(UsageError(format!("unknown format {}", format)).into())
+            }
+            None => {
+                Err(UsageError("export requires --to <format>".to_string()).into())
+            }
+        };
+    }
 
-            let link = Rc::new(LinkDef { port_out: port_out.clone(), port_in: port_in.clone() });
+    if command.as_deref() == Some("init") {
+        let socket = socket.or_else(|| if system { system_socket() } else { None });
+        return init(path, socket.as_deref());
+    }
 
-            link_def.push(link)
-        } else if !line.starts_with('#') {
-            println!("invalid line: {}", line);
+    // `-` reads the rules from stdin, for rules made by a script
+    let stdin_rules = match file_name.as_str() {
+        "-" if matches!(command.as_deref(), Some("expand" | "import")) => {
+            return Err(UsageError(format!("{} requires a file, the rules cannot be read from stdin", command.unwrap_or_default())).into());
         }
+        "-" => Some(std::io::read_to_string(std::io::stdin()).map_err(|e| ConfigError(format!("could not read the rules from stdin: {}", e).into()))?),
+        _ => None,
+    };
+
+    if stdin_rules.is_none() && (!path.exists() || !path.is_file()) {
+        return Err(Box::new(ConfigError(missing_config(&file_name, system).into())));
     }
 
-    let node_def = node_def.values().cloned().collect::<Vec<Rc<NodeDef>>>();
-    let port_def = port_def.values().cloned().collect::<Vec<Rc<PortDef>>>();
+    if command.as_deref() == Some("import") {
+        let content = fs::read_to_string(path)?;
 
-    Ok(AppState::new(node_def, link_def, port_def, get_names))
-}
+        let rules = match import_from.as_deref() {
+            Some("qjackctl") => import::from_qjackctl(&content),
+            Some("aj-snapshot") => import::from_aj_snapshot(&content),
+            Some("pw-link") => import::from_pw_link(&content),
+            Some(format) => Err(format!("unknown format {}", format)),
+            None => Err("import requires --from <format>".to_string()),
+        };
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+        match (rules, &append) {
+            (Ok(rules), Some(append)) => append_rules(std::path::Path::new(append), &rules, "import")?,
+            (Ok(rules), None) => print!("{}", rules),
+            (Err(e), _) => return Err(UsageError(format!("could not import {}: {}", file_name, e)).into()),
+        }
 
-    println!("Hello, world!");
+        return Ok(());
+    }
 
-    let mut args = env::args();
-    // Skip the current directory
-    args.next();
+    // Create DeSized State
 
-    let mut find_names = false;
+    // Absolute, the daemon changes directory and the running instance has its own
+    let config = Config {
+        path: if stdin_rules.is_some() { path.to_path_buf() } else { path.canonicalize()? },
+        get_names: find_names,
+        linger,
+        filter,
+        socket,
+        system,
+        factory,
+        rate_limit,
+        trace_registry,
+        web,
+        api: api.map(|a| (a, api_token.unwrap_or_else(|| daemon::runtime_dir(system).join("pw-autoconnect.token")))),
+        mqtt: mqtt.map(|a| (a, mqtt_topic)),
+        webhook,
+        once: once.then_some(timeout),
+        watchdog,
+        stdin_rules,
+        strict,
+        connect_retries,
+        connect_timeout,
+    };
+
+    // The running instance cannot read our stdin
+    if command.as_deref() == Some("dry-run") && config.stdin_rules.is_none() {
+        if let Some(answer) = control::send(&format!("dry-run {}", config.path.display()), system) {
+            print!("{}", answer);
+            let summary = answer.lines().last().unwrap_or_default();
+            let count = |a: Option<(&str, &str)>| a.and_then(|a| a.0.rsplit(' ').next()?.parse::<usize>().ok());
+
+            return match (count(summary.split_once(" to add")), count(summary.split_once(" rules missing"))) {
+                (Some(added), Some(missing)) => satisfied(added + missing),
+                // The running instance could not load the file
+                _ => Err(Box::new(ConfigError(format!("{} could not be loaded", file_name).into()))),
+            };
+        }
+    }
 
-    let mut file_name = None;
+    let mut state = AppState::load(&config, &HashMap::new()).map_err(ConfigError)?;
+    // Opened before --daemon changes directory, only for the commands that change the graph
+    let audit = || audit_log.as_deref().map(|a| audit::AuditLog::open(std::path::Path::new(a))).transpose();
 
-    for a in args {
-        if a.eq("-f") {
-            find_names  = true;
-            continue;
+    match command.as_deref() {
+        Some("cleanup") => {
+            state.audit = audit()?;
+            return cleanup(&state, system);
         }
-
-        if file_name.is_some() {
-            println!("File name already exists");
+        Some("dry-run") => return dry_run(&mut state),
+        Some("list") => return list(&state, unmatched, &node_filter, format),
+        Some("expand") => return expand(&mut state, &config.path),
+        Some("check") if live => return check_live(&state),
+        Some("check") => {
+            println!(
+                "{}: {} rules, {} stereo and channels rules, {} map rules",
+                config.path.display(),
+                state.file_rules,
+                state.stereo_def.len(),
+                state.map_def.len()
+            );
             return Ok(());
-        } else {
-            file_name = Some(a);
         }
+        Some("replay") => return replay(&mut state, std::path::Path::new(&events_file.unwrap_or_default())),
+        Some("test") => match graph_file {
+            Some(graph) => return test(&mut state, std::path::Path::new(&graph)),
+            None => {
+                return Err(UsageError("test requires a graph file saved with export --to graph".to_string()).into());
+            }
+        },
+        _ => (),
     }
 
-    if file_name.is_none() {
-        help();
-        return Ok(());
+    #[cfg(not(feature = "web"))]
+    if config.web.is_some() || config.api.is_some() {
+        return Err(UsageError("pw-autoconnect was built without the web page and HTTP API, rebuild it with --features web".to_string()).into());
     }
 
-    let file_name = file_name.unwrap();
+    #[cfg(not(feature = "mqtt"))]
+    if config.mqtt.is_some() {
+        return Err(UsageError("pw-autoconnect was built without MQTT support, rebuild it with --features mqtt".to_string()).into());
+    }
 
-    let path = std::path::Path::new(&file_name);
+    if events_file.is_some() && backend.ne("sim") {
+        return Err(UsageError("--events only works with the sim backend".to_string()).into());
+    }
 
-    if !path.exists() || !path.is_file() {
-        println!("File not found does not exists");
-        return Ok(());
+    if config.once.is_some() && (backend.ne("pipewire") || daemon) {
+        return Err(UsageError("--once only works with the pipewire backend and without --daemon".to_string()).into());
     }
 
-    // Create DeSized State
+    let record = match record {
+        Some(file) if backend.eq("pipewire") => Some(Recorder::create(std::path::Path::new(&file))?),
+        Some(_) => {
+            return Err(UsageError("--record only works with the pipewire backend".to_string()).into());
+        }
+        None => None,
+    };
 
-    let state = RefCell::new(parse_file(path.to_path_buf(), find_names)?);
+    state.audit = audit()?;
 
-    println!("\n\nGot state! Starting up\n\n");
+    // Removed once the backend returns
+    let _pid_file = if daemon {
+        let pid_file = pid_file.unwrap_or_else(|| daemon::default_pid_file(system));
+        let log_file = log_file.unwrap_or_else(|| daemon::default_log_file(system));
+
+        match daemon::daemonize(&pid_file, &log_file) {
+            Ok(pid_file) => Some(pid_file),
+            Err(e) => return Err(format!("could not start in the background: {}", e).into()),
+        }
+    } else {
+        None
+    };
 
-    let mainloop = MainLoop::new()?;
-    let context = Context::new(&mainloop)?;
-    let core = Rc::new(context.connect(None)?);
-    let registry = core.get_registry()?;
+    crash::install(daemon::runtime_dir(system));
 
-    let _listener = registry
-        .add_listener_local()
-        .global(move |global| match global.type_ {
-            ObjectType::Port => deal_with_port(global, state.borrow_mut(), core.clone()),
-            ObjectType::Node => deal_with_node(global, state.borrow_mut()),
-            _ => (),
-        })
-        .register();
+    let state = Rc::new(RefCell::new(state));
 
-    mainloop.run();
+    println!("\n\nGot state! Starting up\n\n");
 
-    Ok(())
+    match backend.as_str() {
+        "pipewire" => pipewire_backend::run(state, record, config),
+        "sim" => {
+            let graph = read_graph_file(std::path::Path::new(&graph_file.unwrap_or_default()))?;
+            let events = match events_file {
+                Some(file) => read_events(std::path::Path::new(&file))?,
+                None => Vec::new(),
+            };
+            sim_backend::run(&mut state.borrow_mut(), &graph, events)
+        }
+        #[cfg(feature = "jack")]
+        "jack" => jack_backend::run(state),
+        #[cfg(not(feature = "jack"))]
+        "jack" => {
+            Err(UsageError("pw-autoconnect was built without JACK support, rebuild it with --features jack".to_string()).into())
+        }
+        #[cfg(feature = "pulse")]
+        "pulse" => pulse_backend::run(state),
+        #[cfg(not(feature = "pulse"))]
+        "pulse" => {
+            Err(UsageError("pw-autoconnect was built without PulseAudio support, rebuild it with --features pulse".to_string()).into())
+        }
+        _ => {
+            Err(UsageError(format!("unknown backend {}", backend)).into())
+        }
+    }
 }
@@ -0,0 +1,153 @@
+//! Matching the names in the rules to the nodes and ports of the graph: port patterns, globs and pinned names
+
+use regex::Regex;
+
+lazy_static! {
+    pub static ref PLACEHOLDER: Regex = Regex::new("\\{(?P<name>\\w+)\\}").unwrap();
+    // `bluez_output.00_1B_66_AA_BB_CC.1`, the address without the profile that comes after it
+    pub static ref BLUETOOTH_NODE: Regex = Regex::new("^(?P<name>bluez_(input|output)\\.[0-9A-Fa-f]{2}(_[0-9A-Fa-f]{2}){5})(\\..*)?$").unwrap();
+}
+
+/// Port name with `{name}` placeholders, `out_{n}` matches `out_1` with `n = 1`
+#[derive(Debug)]
+pub struct PortPattern {
+    pub regex: Regex,
+    pub template: String,
+}
+
+impl PortPattern {
+    pub fn parse(pattern: &str) -> Result<PortPattern, String> {
+        let mut regex = String::from("^");
+        let mut last = 0;
+
+        for caps in PLACEHOLDER.captures_iter(pattern) {
+            let placeholder = caps.get(0).unwrap();
+            regex.push_str(&regex::escape(&pattern[last..placeholder.start()]));
+            regex.push_str(&format!("(?P<{}>.+)", &caps["name"]));
+            last = placeholder.end();
+        }
+
+        regex.push_str(&regex::escape(&pattern[last..]));
+        regex.push('$');
+
+        match Regex::new(&regex) {
+            Ok(regex) => Ok(PortPattern {
+                regex,
+                template: pattern.to_string(),
+            }),
+            Err(_) => Err(format!("invalid port pattern '{}'", pattern)),
+        }
+    }
+
+    pub fn placeholders(&self) -> Vec<String> {
+        let mut names = PLACEHOLDER
+            .captures_iter(&self.template)
+            .map(|a| a["name"].to_string())
+            .collect::<Vec<String>>();
+        names.sort();
+        names
+    }
+
+    /// If `name` matches this pattern, returns the name `other` gives to the same placeholders
+    pub fn translate(&self, name: &str, other: &PortPattern) -> Option<String> {
+        let caps = self.regex.captures(name)?;
+
+        Some(
+            PLACEHOLDER
+                .replace_all(&other.template, |a: &regex::Captures| {
+                    caps.name(&a["name"]).map_or("", |m| m.as_str()).to_string()
+                })
+                .to_string(),
+        )
+    }
+}
+
+/// `Midi/*` matches `Midi/Bridge`, without a `*` the whole text has to be the same
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    glob_regex(pattern).is_ok_and(|a| a.is_match(text))
+}
+
+/// The regex of a name that can use `*`, to compile it once for the rules that match it often
+pub fn glob_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^{}$", regex::escape(pattern).replace("\\*", ".*")))
+}
+
+/// Returns the name with the fewest edits away from `name`, ignoring case
+pub fn closest<'a>(name: &str, names: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    names.min_by_key(|a| edit_distance(&name.to_lowercase(), &a.to_lowercase()))
+}
+
+/// The names differ by a few typos or only by case
+pub fn is_near_miss(rule: &str, found: &str) -> bool {
+    if rule == found || rule.starts_with('#') || rule == "L" || rule == "R" {
+        return false;
+    }
+
+    rule.eq_ignore_ascii_case(found) || edit_distance(rule, found) <= (rule.chars().count() / 10).max(2)
+}
+
+/// Levenshtein distance between the two strings
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<char>>();
+    let mut row = (0..=b.len()).collect::<Vec<usize>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous + usize::from(ca != *cb);
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(previous + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// `[Name@path]` pins a node by the bus path of its device, for identical devices.
+/// PipeWire adds `.2`, `.3`... to the name of the second one, in the order they were found
+pub fn is_pinned(rule: &str, name: &str, path: Option<&str>) -> bool {
+    match rule.rsplit_once('@') {
+        Some((base, pin)) if !base.is_empty() && path == Some(pin) => unpinned_name(name) == base,
+        _ => false,
+    }
+}
+
+/// The node name without the number PipeWire adds to tell identical devices apart
+pub fn unpinned_name(name: &str) -> &str {
+    match name.rsplit_once('.') {
+        Some((base, n)) if !n.is_empty() && n.chars().all(|a| a.is_ascii_digit()) => base,
+        _ => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translate() {
+        let out = PortPattern::parse("out_{n}").unwrap();
+        let port_in = PortPattern::parse("in_{n}").unwrap();
+
+        assert_eq!(out.translate("out_12", &port_in).as_deref(), Some("in_12"));
+        assert_eq!(out.translate("in_12", &port_in), None);
+        assert_eq!(PortPattern::parse("{ch}_{n}").unwrap().placeholders(), ["ch", "n"]);
+
+        // Without placeholders only the exact name matches, the dot is not a wildcard
+        let exact = PortPattern::parse("monitor.FL").unwrap();
+        assert_eq!(exact.translate("monitor.FL", &exact).as_deref(), Some("monitor.FL"));
+        assert_eq!(exact.translate("monitorXFL", &exact), None);
+    }
+
+    #[test]
+    fn near_miss() {
+        assert!(is_near_miss("Speakers", "speakers"));
+        assert!(is_near_miss("Speakers", "Speaker"));
+        assert!(!is_near_miss("Speakers", "Speakers"));
+        assert!(!is_near_miss("Speakers", "Headphones"));
+        assert!(glob_matches("alsa_card.usb-*", "alsa_card.usb-Focusrite"));
+        assert!(!glob_matches("alsa_card.usb-*", "alsa_card.pci-0000"));
+    }
+}
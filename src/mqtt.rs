@@ -0,0 +1,242 @@
+//! Just enough MQTT 3.1.1 to publish the events of the graph and take commands from a topic, `--mqtt <broker>`.
+//! Polled from a timer of the main loop, so it can connect again when the broker goes away
+
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+/// Seconds the broker waits for a packet before it drops us, we ping at half of it
+const KEEP_ALIVE: u16 = 60;
+/// Time between attempts to connect to the broker
+const RETRY: Duration = Duration::from_secs(10);
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82;
+const PINGREQ: u8 = 0xc0;
+
+pub struct Client {
+    // host:port of the broker
+    address: String,
+    user: Option<(String, String)>,
+    // Topics are `<prefix>/events`, `<prefix>/command` and `<prefix>/answer`
+    prefix: String,
+    stream: Option<TcpStream>,
+    buffer: Vec<u8>,
+    last_sent: Instant,
+    retry_at: Instant,
+}
+
+impl Client {
+    /// `broker` is `[user:password@]host:port`
+    pub fn new(broker: &str, prefix: &str) -> Client {
+        let (user, address) = match broker.rsplit_once('@') {
+            Some((user, address)) => (user.split_once(':').map(|(a, b)| (a.to_string(), b.to_string())), address),
+            None => (None, broker),
+        };
+
+        Client {
+            address: address.to_string(),
+            user,
+            prefix: prefix.to_string(),
+            stream: None,
+            buffer: Vec::new(),
+            last_sent: Instant::now(),
+            retry_at: Instant::now(),
+        }
+    }
+
+    fn topic(&self, name: &str) -> String {
+        format!("{}/{}", self.prefix, name)
+    }
+
+    fn connect(&mut self) -> Result<TcpStream, String> {
+        let address = self
+            .address
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut a| a.next())
+            .ok_or_else(|| format!("could not resolve {}", self.address))?;
+
+        let mut stream = TcpStream::connect_timeout(&address, TIMEOUT).map_err(|e| e.to_string())?;
+        stream.set_read_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+
+        // Clean session, with the user and password when given
+        let mut flags = 0x02;
+        let mut payload = Vec::new();
+        write_string(&mut payload, &format!("pw-autoconnect-{}", std::process::id()));
+        if let Some((user, password)) = &self.user {
+            flags |= 0xc0;
+            write_string(&mut payload, user);
+            write_string(&mut payload, password);
+        }
+
+        let mut body = Vec::new();
+        write_string(&mut body, "MQTT");
+        body.push(4);
+        body.push(flags);
+        body.extend_from_slice(&KEEP_ALIVE.to_be_bytes());
+        body.extend(payload);
+        stream.write_all(&packet(CONNECT, &body)).map_err(|e| e.to_string())?;
+
+        let mut connack = [0; 4];
+        stream.read_exact(&mut connack).map_err(|e| e.to_string())?;
+        match connack {
+            [CONNACK, 2, _, 0] => (),
+            [CONNACK, 2, _, code] => return Err(format!("the broker refused the connection with code {}", code)),
+            _ => return Err("not an MQTT broker".to_string()),
+        }
+
+        let mut body = vec![0, 1];
+        write_string(&mut body, &self.topic("command"));
+        body.push(0);
+        stream.write_all(&packet(SUBSCRIBE, &body)).map_err(|e| e.to_string())?;
+
+        stream.set_nonblocking(true).map_err(|e| e.to_string())?;
+        Ok(stream)
+    }
+
+    /// Connects when needed, keeps the connection alive and returns the commands received
+    pub fn poll(&mut self) -> Vec<String> {
+        if self.stream.is_none() {
+            if Instant::now() < self.retry_at {
+                return Vec::new();
+            }
+
+            match self.connect() {
+                Ok(stream) => {
+                    println!("Connected to the MQTT broker {}, commands are read from {}", self.address, self.topic("command"));
+                    self.stream = Some(stream);
+                    self.buffer.clear();
+                    self.last_sent = Instant::now();
+                }
+                Err(e) => {
+                    println!("Could not connect to the MQTT broker {}: {}", self.address, e);
+                    self.retry_at = Instant::now() + RETRY;
+                    return Vec::new();
+                }
+            }
+        }
+
+        if self.last_sent.elapsed() > Duration::from_secs(KEEP_ALIVE as u64 / 2) {
+            self.send(&[PINGREQ, 0]);
+        }
+
+        let mut chunk = [0; 4096];
+        while let Some(stream) = self.stream.as_mut() {
+            match stream.read(&mut chunk) {
+                Ok(0) => self.disconnected("the broker closed the connection".to_string()),
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => self.disconnected(e.to_string()),
+            }
+        }
+
+        let mut commands = Vec::new();
+        while let Some((kind, body)) = self.next_packet() {
+            if kind & 0xf0 == PUBLISH {
+                if let Some((topic, payload)) = parse_publish(kind, &body) {
+                    if topic == self.topic("command") {
+                        commands.push(payload);
+                    }
+                }
+            }
+        }
+        commands
+    }
+
+    /// Publishes to `<prefix>/<name>`, dropped when not connected
+    pub fn publish(&mut self, name: &str, payload: &str) {
+        let mut body = Vec::new();
+        write_string(&mut body, &self.topic(name));
+        body.extend_from_slice(payload.as_bytes());
+        self.send(&packet(PUBLISH, &body));
+    }
+
+    fn send(&mut self, packet: &[u8]) {
+        if let Some(stream) = self.stream.as_mut() {
+            // Small packets, the socket only refuses them when the broker is stuck
+            stream.set_nonblocking(false).ok();
+            let written = stream.write_all(packet);
+            stream.set_nonblocking(true).ok();
+
+            match written {
+                Ok(()) => self.last_sent = Instant::now(),
+                Err(e) => self.disconnected(e.to_string()),
+            }
+        }
+    }
+
+    fn disconnected(&mut self, reason: String) {
+        println!("Lost the MQTT broker {}: {}", self.address, reason);
+        self.stream = None;
+        self.retry_at = Instant::now() + RETRY;
+    }
+
+    /// Takes the first whole packet out of the buffer
+    fn next_packet(&mut self) -> Option<(u8, Vec<u8>)> {
+        let mut length = 0;
+        let mut i = 1;
+
+        loop {
+            let byte = *self.buffer.get(i)?;
+            length |= ((byte & 0x7f) as usize) << (7 * (i - 1));
+            i += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            if i > 4 {
+                // Broken stream, start again on the next connection
+                self.disconnected("invalid packet".to_string());
+                self.buffer.clear();
+                return None;
+            }
+        }
+
+        if self.buffer.len() < i + length {
+            return None;
+        }
+
+        let kind = self.buffer[0];
+        let body = self.buffer[i..i + length].to_vec();
+        self.buffer.drain(..i + length);
+        Some((kind, body))
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, text: &str) {
+    out.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    out.extend_from_slice(text.as_bytes());
+}
+
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![kind];
+    let mut length = body.len();
+
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+
+    out.extend_from_slice(body);
+    out
+}
+
+/// The topic and payload of a PUBLISH packet, the packet id of QoS 1 and 2 is skipped
+fn parse_publish(kind: u8, body: &[u8]) -> Option<(String, String)> {
+    let length = u16::from_be_bytes([*body.first()?, *body.get(1)?]) as usize;
+    let topic = String::from_utf8_lossy(body.get(2..2 + length)?).to_string();
+    let start = if kind & 0x06 == 0 { 2 + length } else { 4 + length };
+    Some((topic, String::from_utf8_lossy(body.get(start..)?).trim().to_string()))
+}
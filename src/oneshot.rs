@@ -0,0 +1,831 @@
+//! The commands that read the graph or a file once and exit, the ones the running instance answers are in `commands`
+
+use std::{cell::RefCell, collections::HashMap, env, fs, io::{BufRead, Write}};
+
+use regex::Regex;
+
+use crate::config::SYSTEM_CONFIG;
+use crate::engine::{handle_event, rule_matches, satisfied, simulate, AppState, RuleMatch};
+use crate::graph::{Direction, Graph, GraphNode, GraphPort, MemoryBackend, NodeFilter, NodeKind, RegistryEvent, PORT_TYPE, RULE_KEY};
+use crate::record::read_events;
+use crate::report::{check_rules, compare, link_json, match_rules, rules_csv, rules_json, Format};
+use crate::{csv, import, json, pipewire_backend};
+
+/// Shows what running the rules would change in the graph, without changing it
+pub fn dry_run(state: &mut AppState) -> Result<(), Box<dyn std::error::Error>> {
+    let graph = pipewire_backend::current_graph(state.socket.as_deref(), &state.client_props)?;
+
+    let (report, added) = compare(state, &graph);
+    print!("{}", report);
+
+    satisfied(added)
+}
+
+/// Checks the rules against a graph saved with `export --to graph`
+pub fn test(state: &mut AppState, path: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
+    let graph = read_graph_file(path)?;
+
+    let (report, added) = compare(state, &graph);
+    print!("{}", report);
+
+    satisfied(added)
+}
+
+/// Prints the nodes, ports and links only in one of two graphs saved with `export --to graph`, and the changed
+/// properties of the nodes in both. They are told apart by name, the ids change when a device comes back
+pub fn diff(before: &std::path::Path, after: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
+    let (before, after) = (read_graph_file(before)?, read_graph_file(after)?);
+    print!("{}", graph_diff(&before, &after));
+
+    Ok(())
+}
+
+/// The output of `diff`
+fn graph_diff(before: &Graph, after: &Graph) -> String {
+    let names = |graph: &Graph| {
+        let node = |id: u32| graph.nodes.iter().find(|a| a.id == id).map_or("?", |a| a.name.as_str());
+
+        let mut names = graph.nodes.iter().map(|a| format!("node [{}]", a.name)).collect::<Vec<String>>();
+        names.sort();
+        let mut ports = graph.ports.iter().map(|a| format!("port [{}]{}", node(a.node), a.name)).collect::<Vec<String>>();
+        ports.sort();
+        let mut links = graph.links.iter().map(|a| format!("link {}", graph.describe_link(a))).collect::<Vec<String>>();
+        links.sort();
+
+        names.extend(ports);
+        names.extend(links);
+        names
+    };
+
+    let mut removed = names(before);
+    let mut added = Vec::new();
+
+    // Identical devices have the same name, every one of them is matched once
+    for name in names(after) {
+        match removed.iter().position(|a| a.eq(&name)) {
+            Some(index) => {
+                removed.remove(index);
+            }
+            None => added.push(name),
+        }
+    }
+
+    let mut out = String::from("\n");
+
+    for name in &removed {
+        out.push_str(&format!("- {}\n", name));
+    }
+    for name in &added {
+        out.push_str(&format!("+ {}\n", name));
+    }
+
+    // Ids and serials change every time, they say nothing about what changed
+    let volatile = |key: &str| key.ends_with(".id") || key.ends_with(".serial");
+    let mut changed = 0;
+
+    for node in &after.nodes {
+        let old = match before.nodes.iter().find(|a| a.name.eq(&node.name)) {
+            Some(old) => old,
+            None => continue,
+        };
+
+        let mut keys = old.properties.keys().chain(node.properties.keys()).filter(|a| !volatile(a)).collect::<Vec<&String>>();
+        keys.sort();
+        keys.dedup();
+
+        for key in keys {
+            let (a, b) = (old.properties.get(key), node.properties.get(key));
+            if a != b {
+                let value = |value: Option<&String>| value.map_or("(none)".to_string(), |a| format!("\"{}\"", a));
+                out.push_str(&format!("~ node [{}] {}: {} -> {}\n", node.name, key, value(a), value(b)));
+                changed += 1;
+            }
+        }
+    }
+
+    out.push_str(&format!("\n{} added, {} removed, {} properties changed\n", added.len(), removed.len(), changed));
+    out
+}
+
+/// Reads a graph saved with `export --to graph`
+pub fn read_graph_file(path: &std::path::Path) -> Result<Graph, Box<dyn std::error::Error>> {
+    let graph = json::parse(&fs::read_to_string(path)?)
+        .and_then(|a| Graph::from_json(&a))
+        .map_err(|e| format!("Could not read the graph {}: {}", path.display(), e))?;
+    Ok(graph)
+}
+
+/// Replaces the stereo, channels and map rules of the file with a rule for every link they make in the current graph
+pub fn expand(state: &mut AppState, path: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
+    let graph = pipewire_backend::current_graph(state.socket.as_deref(), &state.client_props)?;
+
+    let backend = simulate(state, &graph);
+    let made = backend
+        .links
+        .borrow()
+        .iter()
+        .filter_map(|a| a.props.iter().find(|a| a.0.eq(RULE_KEY)).map(|a| a.1.clone()))
+        .collect::<Vec<String>>();
+
+    let (content, expanded) = expand_rules(state, &made, &fs::read_to_string(path)?);
+
+    if expanded == 0 {
+        println!("No rules to expand, stereo, channels and map rules need their nodes in the graph");
+        return Ok(());
+    }
+
+    fs::write(path, content)?;
+    println!("Expanded {} rules in {}", expanded, path.display());
+    Ok(())
+}
+
+/// Returns the file with every node rule that made links commented out and followed by its links,
+/// with the number of rules expanded. The other lines, comments and line endings are kept as they are
+fn expand_rules(state: &AppState, made: &[String], content: &str) -> (String, usize) {
+    lazy_static! {
+        // [A](stereo) -> [B](stereo), [A](channels) -> [B](channels) or [A] -> [B] map { ... }
+        static ref NODE_RULE_RE: Regex = Regex::new("^\\s*\\[(?P<node_out>[^\\]]*)\\]\\s*(\\((?P<port>stereo|channels)\\)\\s*->\\s*\\[(?P<stereo_in>[^\\]]*)\\]\\s*\\((stereo|channels)\\)|->\\s*\\[(?P<map_in>[^\\]]*)\\]\\s*map\\s*\\{(?P<map>.*)\\})(?P<opts>[^}]*)$").unwrap();
+        // [A] -> [B]
+        static ref NODES_RE: Regex = Regex::new("^\\s*\\[(?P<node_out>[^\\]]*)\\]\\s*->\\s*\\[(?P<node_in>[^\\]]*)\\]\\s*(?P<opts>(@.*)?)$").unwrap();
+    }
+
+    let mut out = String::new();
+    let mut expanded = 0;
+
+    for full_line in content.split_inclusive('\n') {
+        let line = full_line.trim_end_matches(['\r', '\n']);
+        let (indent, ending) = (&line[..line.len() - line.trim_start().len()], &full_line[line.len()..]);
+
+        // Written like the Display of the StereoDef or MapDef the links were expanded from
+        let (rule, opts) = if let Some(caps) = NODE_RULE_RE.captures(line) {
+            let rule = match caps.name("stereo_in") {
+                Some(node_in) => format!("[{}]{} -> [{}]{}", &caps["node_out"], &caps["port"], node_in.as_str(), &caps["port"]),
+                None => {
+                    let ports = caps["map"]
+                        .split([',', ';'])
+                        .filter_map(|a| a.split_once("->"))
+                        .map(|(out, port_in)| format!("{} -> {}", out.trim(), port_in.trim()))
+                        .collect::<Vec<String>>();
+                    format!("[{}] -> [{}] map {{ {} }}", &caps["node_out"], &caps["map_in"], ports.join(", "))
+                }
+            };
+            (rule, caps["opts"].trim().to_string())
+        } else if let Some(caps) = NODES_RE.captures(line) {
+            (format!("[{}] -> [{}]", &caps["node_out"], &caps["node_in"]), caps["opts"].trim().to_string())
+        } else {
+            out.push_str(full_line);
+            continue;
+        };
+
+        let mut links = state.link_def[state.file_rules..]
+            .iter()
+            .filter(|a| a.expanded_from.as_ref().is_some_and(|a| a.eq(&rule)) && made.contains(&a.to_string()))
+            .map(|a| format!("[{}]({}) -> [{}]({})", a.port_out.node.name, a.port_out.name, a.port_in.node.name, a.port_in.name))
+            .collect::<Vec<String>>();
+
+        if links.is_empty() {
+            println!("Not expanded: {} (no ports of it in the graph)", line.trim());
+            out.push_str(full_line);
+            continue;
+        }
+
+        links.sort();
+
+        // The last line of a file without a newline at its end gets one, the links follow it
+        let ending = if ending.is_empty() { "\n" } else { ending };
+
+        println!("Expanded: {} into {} links", line.trim(), links.len());
+        out.push_str(&format!("{}# {}{}", indent, line.trim(), ending));
+        for link in links {
+            match opts.as_str() {
+                "" => out.push_str(&format!("{}{}{}", indent, link, ending)),
+                opts => out.push_str(&format!("{}{} {}{}", indent, link, opts, ending)),
+            }
+        }
+        expanded += 1;
+    }
+
+    (out, expanded)
+}
+
+/// Prints every rule with what was found of it in the current graph, `--unmatched` only prints the rules missing a node or port
+pub fn list(state: &AppState, unmatched: bool, filter: &NodeFilter, format: Format) -> Result<(), Box<dyn std::error::Error>> {
+    let graph = pipewire_backend::current_graph(state.socket.as_deref(), &state.client_props)?;
+
+    // A rule is shown when one of the nodes it found is
+    let matches = rule_matches(state, &graph)
+        .into_iter()
+        .filter(|a| filter.is_empty() || a.nodes.iter().any(|b| filter.allows(b)))
+        .collect::<Vec<RuleMatch>>();
+
+    let (report, missing) = match format {
+        Format::Table => match_rules(&graph, &matches, unmatched),
+        Format::Json => (rules_json(&graph, &matches, unmatched), 0),
+        Format::Csv => (rules_csv(&graph, &matches, unmatched), 0),
+    };
+    print!("{}", report);
+
+    satisfied(match format {
+        Format::Table => missing,
+        _ => matches.iter().filter(|a| !a.problems.is_empty()).count(),
+    })
+}
+
+/// Prints the nodes in the graph with their ports, `list` without a file
+pub fn list_graph(socket: Option<&str>, filter: &NodeFilter, format: Format) -> Result<(), Box<dyn std::error::Error>> {
+    let graph = pipewire_backend::current_graph(socket, &[])?;
+
+    let nodes = graph.nodes.iter().filter(|a| filter.allows(a)).collect::<Vec<&GraphNode>>();
+    let class = |node: &GraphNode| node.properties.get("media.class").cloned().unwrap_or_default();
+    let direction = |port: &GraphPort| port.props.direction.map_or(String::new(), |a| a.as_str().to_string());
+
+    let mut out = String::new();
+
+    match format {
+        Format::Table => {
+            for node in &nodes {
+                out.push_str(&format!("[{}] {}\n", node.name, class(node)));
+                for port in graph.ports.iter().filter(|a| a.node == node.id) {
+                    out.push_str(&format!("    ({}) {}\n", port.name, direction(port)));
+                    for (peer, owner) in graph.port_links(port.id) {
+                        out.push_str(&format!("        {} ({})\n", peer, owner.as_str()));
+                    }
+                }
+            }
+            out.push_str(&format!("\n{} nodes\n", nodes.len()));
+        }
+        Format::Json => {
+            let object = |fields: Vec<(&str, json::Value)>| json::Value::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect());
+            let string = |a: &str| json::Value::String(a.to_string());
+            let number = |a: u32| json::Value::Number(a as f64);
+
+            let nodes = nodes
+                .iter()
+                .map(|node| {
+                    let ports = graph
+                        .ports
+                        .iter()
+                        .filter(|a| a.node == node.id)
+                        .map(|a| {
+                            let links = graph
+                                .links
+                                .iter()
+                                .filter(|b| b.port_out == a.id || b.port_in == a.id)
+                                .map(|b| link_json(&graph, b))
+                                .collect();
+
+                            object(vec![
+                                ("id", number(a.id)),
+                                ("name", string(&a.name)),
+                                ("direction", string(&direction(a))),
+                                ("props", json::Value::from_string_map(&a.properties)),
+                                ("links", json::Value::Array(links)),
+                            ])
+                        })
+                        .collect();
+
+                    object(vec![
+                        ("id", number(node.id)),
+                        ("name", string(&node.name)),
+                        ("class", string(&class(node))),
+                        ("props", json::Value::from_string_map(&node.properties)),
+                        ("ports", json::Value::Array(ports)),
+                    ])
+                })
+                .collect();
+
+            json::write(&json::Value::Array(nodes), &mut out);
+            out.push('\n');
+        }
+        Format::Csv => {
+            csv::write_row(&["node", "class", "port", "direction", "links"], &mut out);
+            for node in &nodes {
+                for port in graph.ports.iter().filter(|a| a.node == node.id) {
+                    let links = graph
+                        .port_links(port.id)
+                        .into_iter()
+                        .map(|(peer, owner)| format!("{} ({})", peer, owner.as_str()))
+                        .collect::<Vec<String>>();
+                    csv::write_row(&[&node.name, &class(node), &port.name, &direction(port), &links.join("; ")], &mut out);
+                }
+            }
+        }
+    }
+
+    print!("{}", out);
+    Ok(())
+}
+
+/// Checks every rule against the current graph, `check --live`
+pub fn check_live(state: &AppState) -> Result<(), Box<dyn std::error::Error>> {
+    let graph = pipewire_backend::current_graph(state.socket.as_deref(), &state.client_props)?;
+
+    let (report, unsatisfied) = check_rules(state, &graph);
+    print!("{}", report);
+
+    satisfied(unsatisfied)
+}
+
+/// Saves the links that are currently in the graph as an aj-snapshot, or the whole graph for the test command
+pub fn export(path: &std::path::Path, format: &str, socket: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let graph = pipewire_backend::current_graph(socket, &[])?;
+
+    if format.eq("graph") {
+        let mut out = String::new();
+        json::write(&graph.to_json(), &mut out);
+        out.push('\n');
+        fs::write(path, out)?;
+
+        println!("Saved {} nodes, {} ports and {} links to {}", graph.nodes.len(), graph.ports.len(), graph.links.len(), path.display());
+        return Ok(());
+    }
+
+    let links = graph
+        .links
+        .iter()
+        .filter_map(|a| graph.link_names(a))
+        .collect::<Vec<(&str, &str, &str, &str)>>();
+
+    fs::write(path, import::to_aj_snapshot(&links))?;
+
+    println!("Saved {} links to {}", links.len(), path.display());
+
+    Ok(())
+}
+
+/// Prints rules for the links in the graph that go from or to a device, so links made in a patchbay can be kept,
+/// or adds them to the end of the file of --append
+pub fn suggest(socket: Option<&str>, append: Option<&std::path::Path>) -> Result<(), Box<dyn std::error::Error>> {
+    let graph = pipewire_backend::current_graph(socket, &[])?;
+
+    let is_hardware = |port: u32| {
+        graph
+            .ports
+            .iter()
+            .find(|a| a.id == port)
+            .and_then(|a| graph.nodes.iter().find(|b| b.id == a.node))
+            .is_some_and(|a| a.kind == Some(NodeKind::Hardware))
+    };
+
+    let mut rules = String::from("# Suggested from the links in the graph\n");
+    let mut count = 0;
+
+    for link in graph.links.iter().filter(|a| is_hardware(a.port_out) || is_hardware(a.port_in)) {
+        if let Some((node_out, port_out, node_in, port_in)) = graph.link_names(link) {
+            let line = format!("[{}]({}) -> [{}]({})\n", node_out, port_out, node_in, port_in);
+            if !rules.contains(&line) {
+                rules.push_str(&line);
+                count += 1;
+            }
+        }
+    }
+
+    if count == 0 {
+        eprintln!("No links to or from a device found");
+        return Ok(());
+    }
+
+    match append {
+        Some(path) => append_rules(path, &rules, "suggest"),
+        None => {
+            print!("{}", rules);
+            Ok(())
+        }
+    }
+}
+
+/// Adds the rules that are not in the file yet to its end, the rest of the file is left as it is
+pub fn append_rules(path: &std::path::Path, rules: &str, command: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    // A rule is there already when a line has it, with or without options after it
+    let exists = |rule: &str| {
+        content
+            .lines()
+            .filter_map(|a| a.trim().strip_prefix(rule))
+            .any(|a| a.is_empty() || a.starts_with(char::is_whitespace))
+    };
+    let added = rules
+        .lines()
+        .map(str::trim)
+        .filter(|a| !a.is_empty() && !a.starts_with('#') && !exists(a))
+        .collect::<Vec<&str>>();
+
+    if added.is_empty() {
+        println!("Every rule is in {} already", path.display());
+        return Ok(());
+    }
+
+    if !content.is_empty() {
+        if !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push('\n');
+    }
+    content.push_str(&format!("# Added by pw-autoconnect {}\n", command));
+    for rule in &added {
+        content.push_str(rule);
+        content.push('\n');
+    }
+
+    fs::write(path, content)?;
+    println!("Added {} rules to {}", added.len(), path.display());
+
+    Ok(())
+}
+
+/// Prints every node of the graph with its ports written like in the rules, sorted, to copy them into a rules file.
+/// With `--follow` it keeps running and prints the ports as they appear instead, for devices that are plugged in later
+pub fn discover(socket: Option<&str>, filter: &NodeFilter, follow: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if follow {
+        // The nodes we printed ports of, to tell when they go away
+        let shown = RefCell::new(HashMap::new());
+        let filter = filter.clone();
+
+        return pipewire_backend::follow(socket, move |globals, event| match event {
+            RegistryEvent::Global { id, kind, .. } if kind.eq(PORT_TYPE) => {
+                let graph = Graph::from_globals(globals);
+                let port = graph.ports.iter().find(|a| a.id == *id);
+                let node = port.and_then(|port| graph.nodes.iter().find(|a| a.id == port.node));
+
+                if let (Some(port), Some(node)) = (port, node) {
+                    if !filter.allows(node) {
+                        return;
+                    }
+                    if shown.borrow_mut().insert(node.id, node.name.clone()).is_none() {
+                        print!("{}", node_heading(node));
+                    }
+                    println!("[{}]({})", node.name, port.name);
+                }
+            }
+            RegistryEvent::Remove { id } => {
+                if let Some(name) = shown.borrow_mut().remove(id) {
+                    println!("# [{}] removed", name);
+                }
+            }
+            _ => (),
+        });
+    }
+
+    let graph = pipewire_backend::current_graph(socket, &[])?;
+
+    let nodes = graph.nodes.iter().filter(|a| filter.allows(a)).collect::<Vec<&GraphNode>>();
+
+    print!("{}", node_listing(&graph, &nodes));
+    eprintln!("{} nodes", nodes.len());
+
+    Ok(())
+}
+
+/// The comment naming a node above its ports, its description and media class
+fn node_heading(node: &GraphNode) -> String {
+    let class = node.properties.get("media.class").map_or("", |a| a.as_str());
+    match node.properties.get("node.description") {
+        Some(description) => format!("# {} ({})\n", description, class),
+        None => format!("# {}\n", class),
+    }
+}
+
+/// The nodes sorted by name, each with its ports written like in the rules under its description and media class
+fn node_listing(graph: &Graph, nodes: &[&GraphNode]) -> String {
+    let mut nodes = nodes.to_vec();
+    nodes.sort_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id)));
+
+    let mut out = String::new();
+
+    for node in &nodes {
+        let mut ports = graph.ports.iter().filter(|a| a.node == node.id).collect::<Vec<&GraphPort>>();
+        ports.sort_by(|a, b| a.props.index.cmp(&b.props.index).then(a.name.cmp(&b.name)));
+
+        out.push_str(&node_heading(node));
+
+        for (direction, title) in [(Direction::Out, "outputs"), (Direction::In, "inputs")] {
+            let ports = ports.iter().filter(|a| a.props.direction == Some(direction)).collect::<Vec<&&GraphPort>>();
+            if ports.is_empty() {
+                continue;
+            }
+
+            out.push_str(&format!("#   {}\n", title));
+            for port in ports {
+                out.push_str(&format!("[{}]({})\n", node.name, port.name));
+            }
+        }
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Where the rules are read from when no file is given, the one of --system or `$XDG_CONFIG_HOME/pw-autoconnect.conf`
+pub fn default_config(system: bool) -> Option<std::path::PathBuf> {
+    if system {
+        return Some(std::path::PathBuf::from(SYSTEM_CONFIG));
+    }
+
+    env::var_os("XDG_CONFIG_HOME")
+        .map(std::path::PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|a| std::path::Path::new(&a).join(".config")))
+        .map(|a| a.join("pw-autoconnect.conf"))
+}
+
+/// Why the rules file given cannot be read, with the forms that can be given and the default file when there is one
+pub fn missing_config(file_name: &str, system: bool) -> String {
+    let path = std::path::Path::new(file_name);
+
+    let mut message = if path.is_dir() {
+        format!("{} is a directory, the rules are read from one file", file_name)
+    } else if file_name.contains(['*', '?']) {
+        format!("no file matches {}, the rules are read from one file", file_name)
+    } else {
+        format!("{} does not exist", file_name)
+    };
+
+    if path.is_dir() || file_name.contains(['*', '?']) {
+        message.push_str("\ngive the path of a file, `-` to read the rules from stdin or nothing to read the default file");
+    }
+
+    if let Some(default) = default_config(system).filter(|a| a.is_file() && a.as_path() != path) {
+        message.push_str(&format!("\ndid you mean {}?", default.display()));
+    }
+
+    message
+}
+
+/// Writes a commented example rules file that links nothing, with the devices in the graph listed when PipeWire is running
+pub fn write_default_config(path: &std::path::Path, socket: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    if path.exists() {
+        return Err(format!("{} already exists, --write-default-config does not overwrite it", path.display()).into());
+    }
+
+    let graph = pipewire_backend::current_graph(socket, &[]);
+
+    let mut out = String::from("# Written by pw-autoconnect --write-default-config\n");
+    out.push_str("# Every line links an output port to an input port: [Node](Port) -> [Node](Port)\n");
+    out.push_str("# * matches any text in a name, and the stereo port links the left and right ports at once:\n");
+    out.push_str("#   [Firefox](stereo) -> [alsa_output.*](stereo)\n");
+    out.push_str("# Add options after a rule with @option, e.g. @tag or @schedule, see the README for all of them\n");
+    out.push_str("# Nothing is linked until a rule is uncommented\n");
+
+    match &graph {
+        Ok(graph) => {
+            let devices = graph.nodes.iter().filter(|a| a.kind == Some(NodeKind::Hardware)).collect::<Vec<&GraphNode>>();
+            out.push_str("\n# The devices when this file was written:\n#\n");
+            for line in node_listing(graph, &devices).lines() {
+                // The headers of the listing are comments already
+                match line {
+                    "" => out.push_str("#\n"),
+                    line if line.starts_with('#') => out.push_str(&format!("{}\n", line)),
+                    line => out.push_str(&format!("# {}\n", line)),
+                }
+            }
+        }
+        Err(e) => println!("Could not list the devices, PipeWire is not reachable: {}", e),
+    }
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, out)?;
+
+    println!("Wrote {}, uncomment or add the rules and run them with: pw-autoconnect {}", path.display(), path.display());
+
+    Ok(())
+}
+
+/// Asks which ports in the graph to link and writes them as a rules file
+pub fn init(path: &std::path::Path, socket: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    if path.exists() {
+        return Err(format!("{} already exists, init does not overwrite it", path.display()).into());
+    }
+
+    let graph = pipewire_backend::current_graph(socket, &[])?;
+
+    let ports = |direction: Direction| {
+        graph
+            .ports
+            .iter()
+            .filter(|a| a.props.direction == Some(direction) && !a.props.monitor)
+            .filter_map(|a| Some(format!("[{}]({})", graph.nodes.iter().find(|b| b.id == a.node)?.name, a.name)))
+            .collect::<Vec<String>>()
+    };
+    let (outputs, inputs) = (ports(Direction::Out), ports(Direction::In));
+
+    if outputs.is_empty() || inputs.is_empty() {
+        println!("No ports to link found in the graph, is PipeWire running the devices?");
+        return Ok(());
+    }
+
+    for (title, ports) in [("Outputs", &outputs), ("Inputs", &inputs)] {
+        println!("{}:", title);
+        for (i, port) in ports.iter().enumerate() {
+            println!("  {:>3}) {}", i + 1, port);
+        }
+        println!();
+    }
+
+    let mut rules = Vec::new();
+
+    while let Some(output) = ask("Output to link (number, empty to finish): ", outputs.len())? {
+        let input = match ask(&format!("Input to link {} to: ", outputs[output]), inputs.len())? {
+            Some(input) => input,
+            None => break,
+        };
+
+        let rule = format!("{} -> {}", outputs[output], inputs[input]);
+        println!("Added {}\n", rule);
+        rules.push(rule);
+    }
+
+    if rules.is_empty() {
+        println!("No links chosen, {} was not written", path.display());
+        return Ok(());
+    }
+
+    fs::write(path, init_rules(&rules))?;
+
+    println!("Saved {} rules to {}, run them with: pw-autoconnect {}", rules.len(), path.display(), path.display());
+
+    Ok(())
+}
+
+/// The rules file init writes, the chosen rules under a commented header
+fn init_rules(rules: &[String]) -> String {
+    let mut out = String::from("# Written by pw-autoconnect init\n");
+    out.push_str("# Every line links an output port to an input port: [Node](Port) -> [Node](Port)\n");
+    out.push_str("# Add options after a rule with @option, e.g. @tag or @schedule, see the README for all of them\n\n");
+    for rule in rules {
+        out.push_str(rule);
+        out.push('\n');
+    }
+    out
+}
+
+/// Asks for a number from 1 to `count` until one is given, returns its index or None for an empty answer
+fn ask(question: &str, count: usize) -> std::io::Result<Option<usize>> {
+    let stdin = std::io::stdin();
+
+    loop {
+        print!("{}", question);
+        std::io::stdout().flush()?;
+
+        let mut answer = String::new();
+        if stdin.lock().read_line(&mut answer)? == 0 {
+            return Ok(None);
+        }
+
+        match answer.trim().parse::<usize>() {
+            _ if answer.trim().is_empty() => return Ok(None),
+            Ok(n) if (1..=count).contains(&n) => return Ok(Some(n - 1)),
+            _ => println!("Enter a number from 1 to {}", count),
+        }
+    }
+}
+
+/// Feeds recorded registry events to the rules without changing the graph
+pub fn replay(state: &mut AppState, path: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
+    let events = read_events(path)?;
+    let backend = MemoryBackend::default();
+
+    for (time, event) in events.iter().cloned() {
+        println!("-- {}.{:03}s {}", time / 1000, time % 1000, event);
+
+        // PipeWire removes the links together with their ports
+        if let RegistryEvent::Remove { id } = event {
+            backend.links.borrow_mut().retain(|a| a.port_out != id && a.port_in != id);
+        }
+
+        handle_event(state, &event, &backend);
+    }
+
+    let links = backend.links.borrow();
+
+    println!("\n{} events replayed, {} links at the end:", events.len(), links.len());
+    for link in links.iter() {
+        let rule = link.props.iter().find(|a| a.0.eq(RULE_KEY)).map(|a| a.1.as_str()).unwrap_or_default();
+        println!("  {}", rule);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{parse_rules, Rules, TagFilter};
+
+    const GRAPH: &str = r#"{
+        "nodes": [
+            {"id": 1, "name": "Music", "kind": "stream"},
+            {"id": 2, "name": "Speakers", "kind": "hardware"}
+        ],
+        "ports": [
+            {"id": 10, "node": 1, "name": "output_FL", "direction": "out", "channel": "FL"},
+            {"id": 11, "node": 1, "name": "output_FR", "direction": "out", "channel": "FR"},
+            {"id": 20, "node": 2, "name": "playback_FL", "direction": "in", "channel": "FL"},
+            {"id": 21, "node": 2, "name": "playback_FR", "direction": "in", "channel": "FR"}
+        ]
+    }"#;
+
+    fn parse(rules: &str) -> Rules {
+        parse_rules(rules.as_bytes(), &TagFilter::default(), &HashMap::new()).unwrap()
+    }
+
+    #[test]
+    fn init_file() {
+        let rules = ["[Music](output_FL) -> [Speakers](playback_FL)".to_string(), "[Mic](capture_MONO) -> [Recorder](input_MONO)".to_string()];
+        let parsed = parse(&init_rules(&rules));
+
+        // The header is not a rule
+        let links = parsed.link_def.iter().map(|a| a.to_string()).collect::<Vec<String>>();
+        assert_eq!(links, ["[Music]output_FL -> [Speakers]playback_FL", "[Mic]capture_MONO -> [Recorder]input_MONO"]);
+        assert!(parsed.problems.is_empty());
+    }
+
+    #[test]
+    fn expand_and_parse_again() {
+        let content = "# Music\n[Music](stereo) -> [Speakers](stereo) @tag evening\n";
+        let mut state = AppState::new(parse(content), false);
+        let graph = Graph::from_json(&json::parse(GRAPH).unwrap()).unwrap();
+
+        let made = simulate(&mut state, &graph)
+            .links
+            .borrow()
+            .iter()
+            .filter_map(|a| a.props.iter().find(|a| a.0.eq(RULE_KEY)).map(|a| a.1.clone()))
+            .collect::<Vec<String>>();
+        let (expanded, count) = expand_rules(&state, &made, content);
+        assert_eq!(count, 1);
+        assert!(expanded.contains("# [Music](stereo) -> [Speakers](stereo) @tag evening\n"));
+
+        // The commented out rule is not loaded again, its links take its place
+        let parsed = parse(&expanded);
+        let mut links = parsed.link_def.iter().map(|a| a.to_string()).collect::<Vec<String>>();
+        links.sort();
+        let mut wanted = state.link_def[state.file_rules..].iter().map(|a| a.to_string()).collect::<Vec<String>>();
+        wanted.sort();
+
+        assert_eq!(links, wanted);
+        assert_eq!(links.len(), 2);
+        assert!(parsed.stereo_def.is_empty());
+        assert!(parsed.problems.is_empty());
+    }
+
+    #[test]
+    fn expand_keeps_the_other_lines() {
+        let content = "# Music\r\n\r\n  [Music] -> [Speakers] map { output_{ch} -> playback_{ch} } @tag evening\r\n[Music](output_FL) -> [Speakers](playback_FL)\r\n\r\n# [Music](stereo) -> [Speakers](stereo)\r\n[Radio](stereo) -> [Speakers](stereo)";
+        let mut state = AppState::new(parse(content), false);
+        let graph = Graph::from_json(&json::parse(GRAPH).unwrap()).unwrap();
+
+        let made = simulate(&mut state, &graph)
+            .links
+            .borrow()
+            .iter()
+            .filter_map(|a| a.props.iter().find(|a| a.0.eq(RULE_KEY)).map(|a| a.1.clone()))
+            .collect::<Vec<String>>();
+        let (expanded, count) = expand_rules(&state, &made, content);
+
+        // Only the map rule has its nodes in the graph, and the rule after it already makes its left link.
+        // The comments, blank lines and line endings stay as they were
+        assert_eq!(count, 1);
+        assert_eq!(
+            expanded,
+            "# Music\r\n\r\n  # [Music] -> [Speakers] map { output_{ch} -> playback_{ch} } @tag evening\r\n  [Music](output_FR) -> [Speakers](playback_FR) @tag evening\r\n[Music](output_FL) -> [Speakers](playback_FL)\r\n\r\n# [Music](stereo) -> [Speakers](stereo)\r\n[Radio](stereo) -> [Speakers](stereo)"
+        );
+
+        // Nothing in the graph for any rule, the file is left alone
+        let (unchanged, count) = expand_rules(&state, &[], content);
+        assert_eq!((unchanged.as_str(), count), (content, 0));
+    }
+
+    #[test]
+    fn diff_output() {
+        let before = Graph::from_json(&json::parse(GRAPH).unwrap()).unwrap();
+        let after = r#"{
+            "nodes": [
+                {"id": 5, "name": "Music", "kind": "stream"},
+                {"id": 6, "name": "Speakers", "kind": "hardware", "props": {"node.description": "Speakers", "object.serial": "60"}},
+                {"id": 7, "name": "Mic", "kind": "hardware"}
+            ],
+            "ports": [
+                {"id": 50, "node": 5, "name": "output_FL", "direction": "out"},
+                {"id": 51, "node": 5, "name": "output_FR", "direction": "out"},
+                {"id": 60, "node": 6, "name": "playback_FL", "direction": "in"}
+            ],
+            "links": [
+                {"id": 70, "port_out": 50, "port_in": 60}
+            ]
+        }"#;
+        let after = Graph::from_json(&json::parse(after).unwrap()).unwrap();
+
+        // The new ids are not changes, the serial is not a property that changed
+        assert_eq!(
+            graph_diff(&before, &after),
+            "\n- port [Speakers]playback_FR\n+ node [Mic]\n+ link [Music]output_FL -> [Speakers]playback_FL\n~ node [Speakers] node.description: (none) -> \"Speakers\"\n\n2 added, 1 removed, 1 properties changed\n"
+        );
+        assert_eq!(graph_diff(&after, &after), "\n\n0 added, 0 removed, 0 properties changed\n");
+    }
+}
@@ -0,0 +1,1091 @@
+//! The PipeWire backend: connects to PipeWire, follows its registry for the rules and makes their links
+
+use std::{cell::{Cell, RefCell}, collections::HashMap, env, fmt, io::Write, rc::Rc, time::{Duration, Instant}};
+
+use libspa::{
+    flags::IoFlags,
+    param::ParamType,
+    pod::{deserialize::PodDeserializer, Pod, Value},
+    ReadableDict,
+};
+use pipewire::{prelude::*, types::ObjectType, Context, MainLoop};
+use pipewire as pw;
+
+use crate::commands::control_command;
+#[cfg(feature = "mqtt")]
+use crate::commands::{mqtt_event, MQTT_COMMANDS};
+#[cfg(feature = "web")]
+use crate::commands::{api_command, web_request};
+use crate::config::{spa_value, Config, DEFAULTS, KEEP_ALIVE_NODE, SCHEDULE_INTERVAL};
+use crate::engine::{handle_event, is_audio, reload, satisfied, AppState, RateLimit};
+use crate::graph::{Graph, GraphBackend, Latency, LinkHandle, Port, RegistryEvent, OWNER, OWNER_KEY, WAIT_INTERVAL};
+use crate::record::Recorder;
+#[cfg(feature = "web")]
+use crate::report::{health, Format};
+#[cfg(feature = "mqtt")]
+use crate::mqtt;
+#[cfg(feature = "web")]
+use crate::web;
+use crate::{control, crash, filter_chain, json, links_file, watchdog, webhook};
+
+pub struct PipeWireBackend {
+    pub core: pw::Core,
+    pub registry: Rc<pw::registry::Registry>,
+    // Name of the factory that makes links, found in the registry or given with --factory
+    pub factory: RefCell<Option<String>>,
+    // The socket we are connected to, for pw-cli
+    pub socket: Option<String>,
+    // The `settings` metadata, for `@latency`
+    pub settings: RefCell<Option<pw::metadata::Metadata>>,
+}
+
+impl PipeWireBackend {
+    // pipewire-rs has no set_param for nodes and devices, pw-cli does it for us in the background
+    pub fn set_param(&self, id: u32, param: &'static str, pod: &str) -> Result<(), String> {
+        let mut command = std::process::Command::new("pw-cli");
+        command.arg("set-param").arg(id.to_string()).arg(param).arg(pod);
+
+        if let Some(socket) = &self.socket {
+            command.env("PIPEWIRE_REMOTE", socket);
+        }
+
+        let child = command
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("could not run pw-cli: {}", e))?;
+
+        std::thread::spawn(move || match child.wait_with_output() {
+            Ok(output) if !output.status.success() => {
+                println!("pw-cli could not set the {} of {}: {}", param, id, String::from_utf8_lossy(&output.stderr).trim())
+            }
+            _ => (),
+        });
+
+        Ok(())
+    }
+}
+
+impl GraphBackend for PipeWireBackend {
+    fn create_link(&self, port_out: &Port, port_in: &Port, props: &[(String, String)]) -> Result<LinkHandle, String> {
+        let mut properties = pw::properties! {
+            "link.output.port" => port_out.id.to_string(),
+            "link.input.port" => port_in.id.to_string(),
+            "link.output.node" => port_out.node.id.to_string(),
+            "link.input.node" => port_in.node.id.to_string()
+        };
+
+        for (key, value) in props {
+            properties.insert(key.as_str(), value.as_str());
+        }
+
+        let factory = self.factory.borrow();
+        let factory = factory
+            .as_deref()
+            .ok_or("PipeWire has no link factory, give its name with --factory")?;
+
+        self.core
+            .create_object::<pw::link::Link, _>(factory, &properties)
+            .map(LinkHandle::PipeWire)
+            .map_err(|e| e.to_string())
+    }
+
+    fn destroy_link(&self, link: LinkHandle) -> Result<(), String> {
+        match link {
+            LinkHandle::PipeWire(proxy) => self.core.destroy_object(proxy).map(|_| ()).map_err(|e| e.to_string()),
+            LinkHandle::Global(id) => self.registry.destroy_global(id).into_result().map(|_| ()).map_err(|e| e.to_string()),
+            _ => Err("not a PipeWire link".to_string()),
+        }
+    }
+
+    fn set_node_props(&self, node: u32, props: &str) -> Result<(), String> {
+        self.set_param(node, "Props", props)
+    }
+
+    fn set_device_profile(&self, device: u32, profile: &str) -> Result<(), String> {
+        self.set_param(device, "Profile", &format!("{{ name = {} }}", spa_value(profile)))
+    }
+
+    fn force_latency(&self, latency: Option<Latency>) -> Result<(), String> {
+        let settings = self.settings.borrow();
+        let settings = settings.as_ref().ok_or("PipeWire has no settings metadata yet")?;
+
+        // 0 is not forced
+        let (quantum, rate) = latency.map_or((0, 0), |a| (a.quantum, a.rate.unwrap_or(0)));
+        settings.set_property(0, "clock.force-quantum", None, Some(&quantum.to_string()));
+        settings.set_property(0, "clock.force-rate", None, Some(&rate.to_string()));
+
+        Ok(())
+    }
+}
+
+/// Connects as `pw-autoconnect` to the given PipeWire socket, a name in `$PIPEWIRE_RUNTIME_DIR` (or `$XDG_RUNTIME_DIR`) or an absolute path,
+/// and without one to `$PIPEWIRE_REMOTE` or `pipewire-0`. The name lets us be told apart in pw-top, Helvum and the session manager logs.
+/// `client_props` are the `@client-prop` settings, they can replace the defaults
+fn connect(context: &Context<MainLoop>, socket: Option<&str>, client_props: &[(String, String)]) -> Result<pw::Core, NotConnected> {
+    let mut props = pw::properties! {
+        "application.name" => "pw-autoconnect",
+        "application.id" => "pw-autoconnect",
+        "application.version" => env!("CARGO_PKG_VERSION"),
+        "application.process.binary" => "pw-autoconnect"
+    };
+
+    if let Some(socket) = socket {
+        props.insert("remote.name", socket);
+    }
+
+    for (key, value) in client_props {
+        props.insert(key.as_str(), value.as_str());
+    }
+
+    context.connect(Some(props)).map_err(|error| NotConnected { socket: socket_path(socket), waited: Duration::ZERO, error })
+}
+
+/// Connects like `connect`, trying again while PipeWire is not there yet, like at the start of a session:
+/// `retries` more times, or for as long as `timeout` when that takes longer
+fn connect_retrying(
+    context: &Context<MainLoop>,
+    socket: Option<&str>,
+    client_props: &[(String, String)],
+    retries: u32,
+    timeout: Duration,
+) -> Result<pw::Core, NotConnected> {
+    let started = Instant::now();
+    let mut attempt = 0;
+
+    loop {
+        match connect(context, socket, client_props) {
+            Err(e) if attempt < retries || started.elapsed() + CONNECT_RETRY_DELAY <= timeout => {
+                attempt += 1;
+                if attempt == 1 {
+                    println!("Waiting for PipeWire at {}", e.socket.display());
+                }
+                std::thread::sleep(CONNECT_RETRY_DELAY);
+            }
+            Err(e) => return Err(NotConnected { waited: started.elapsed(), ..e }),
+            result => return result,
+        }
+    }
+}
+
+/// Where PipeWire looks for the socket, for the messages: the path given, or the name in its runtime directory
+fn socket_path(socket: Option<&str>) -> std::path::PathBuf {
+    let name = socket.map(str::to_string).or_else(|| env::var("PIPEWIRE_REMOTE").ok()).unwrap_or_else(|| "pipewire-0".to_string());
+    if name.starts_with('/') {
+        return std::path::PathBuf::from(name);
+    }
+
+    env::var_os("PIPEWIRE_RUNTIME_DIR")
+        .or_else(|| env::var_os("XDG_RUNTIME_DIR"))
+        .map_or_else(|| std::path::PathBuf::from(&name), |a| std::path::Path::new(&a).join(&name))
+}
+
+fn dict_to_map<D: ReadableDict>(dict: &D) -> HashMap<String, String> {
+    dict.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+/// The event of a global the registry announced
+fn registry_event(global: &pw::registry::GlobalObject<libspa::ForeignDict>) -> RegistryEvent {
+    RegistryEvent::Global {
+        id: global.id,
+        kind: global.type_.to_str().to_string(),
+        props: global.props.as_ref().map(dict_to_map),
+    }
+}
+
+/// Runs the main loop until the server has processed everything we sent before
+fn roundtrip(mainloop: &MainLoop, core: &pw::Core) -> Result<(), pw::Error> {
+    let pending = core.sync(0)?;
+
+    let _listener = core
+        .add_listener_local()
+        .done({
+            let mainloop = mainloop.clone();
+            move |id, seq| {
+                if id == pw::PW_ID_CORE && seq == pending {
+                    mainloop.quit();
+                }
+            }
+        })
+        .register();
+
+    mainloop.run();
+
+    Ok(())
+}
+
+/// Globals of the registry whose link properties are filled in as the bound links report them
+type Globals = Rc<RefCell<Vec<RegistryEvent>>>;
+
+/// The registry only has some of the link properties, binds the link to add the rest to its global
+fn bind_link<F, S>(
+    registry: &pw::registry::Registry,
+    global: &pw::registry::GlobalObject<libspa::ForeignDict>,
+    globals: &Globals,
+    on_props: F,
+    on_state: S,
+) -> Option<(pw::link::Link, pw::link::LinkListener)>
+where
+    F: Fn(u32, &HashMap<String, String>) + 'static,
+    S: Fn(u32, String) + 'static,
+{
+    let link = registry.bind::<pw::link::Link, _>(global).ok()?;
+
+    let listener = link
+        .add_listener_local()
+        .info({
+            let globals = globals.clone();
+            move |info| {
+                on_state(info.id(), link_state(&info.state()));
+
+                if let Some(props) = info.props() {
+                    for global in globals.borrow_mut().iter_mut() {
+                        if let RegistryEvent::Global { id, props: Some(global_props), .. } = global {
+                            if *id == info.id() {
+                                global_props.extend(dict_to_map(props));
+                                on_props(*id, global_props);
+                            }
+                        }
+                    }
+                }
+            }
+        })
+        .register();
+
+    Some((link, listener))
+}
+
+/// The state of a link as `status` shows it, with the message of an error
+fn link_state(state: &pw::link::LinkState) -> String {
+    match state {
+        pw::link::LinkState::Error(message) => format!("error: {}", message),
+        pw::link::LinkState::Unlinked => "unlinked".to_string(),
+        pw::link::LinkState::Init => "init".to_string(),
+        pw::link::LinkState::Negotiating => "negotiating".to_string(),
+        pw::link::LinkState::Allocating => "allocating".to_string(),
+        pw::link::LinkState::Paused => "paused".to_string(),
+        pw::link::LinkState::Active => "active".to_string(),
+    }
+}
+
+/// Follows the state of the node, for the `@wait-ready` rules
+fn bind_node(
+    registry: &pw::registry::Registry,
+    global: &pw::registry::GlobalObject<libspa::ForeignDict>,
+    state: &Rc<RefCell<AppState>>,
+    backend: &Rc<PipeWireBackend>,
+) -> Option<(pw::node::Node, pw::node::NodeListener)> {
+    let node = registry.bind::<pw::node::Node, _>(global).ok()?;
+
+    let listener = node
+        .add_listener_local()
+        .info({
+            let state = state.clone();
+            let backend = backend.clone();
+            move |info| {
+                // Nodes nothing plays to suspend, they have to count or they would never be linked
+                let ready = matches!(
+                    info.state(),
+                    pw::node::NodeState::Idle | pw::node::NodeState::Running | pw::node::NodeState::Suspended
+                );
+                state.borrow_mut().set_node_ready(info.id(), ready, backend.as_ref());
+            }
+        })
+        .register();
+
+    Some((node, listener))
+}
+
+/// Follows the active profile of a device, PipeWire sends its `Profile` param once subscribed and again when it changes
+fn bind_device(
+    registry: &pw::registry::Registry,
+    global: &pw::registry::GlobalObject<libspa::ForeignDict>,
+    state: &Rc<RefCell<AppState>>,
+    backend: &Rc<PipeWireBackend>,
+) -> Option<(pw::device::Device, pw::device::DeviceListener)> {
+    let device = registry.bind::<pw::device::Device, _>(global).ok()?;
+    let id = global.id;
+
+    let listener = device
+        .add_listener_local()
+        .param({
+            let state = state.clone();
+            let backend = backend.clone();
+            move |_seq, kind, _index, _next, param| {
+                if kind != ParamType::Profile {
+                    return;
+                }
+                if let Some(profile) = param.and_then(profile_name) {
+                    state.borrow_mut().update_device_profile(id, &profile, backend.as_ref());
+                }
+            }
+        })
+        .register();
+    device.subscribe_params(&[ParamType::Profile]);
+
+    Some((device, listener))
+}
+
+/// The name in a `Profile` param of a device
+fn profile_name(param: &Pod) -> Option<String> {
+    match PodDeserializer::deserialize_any_from(param.as_bytes()).ok()?.1 {
+        Value::Object(object) => object.properties.into_iter().find(|a| a.key == libspa::sys::SPA_PARAM_PROFILE_name).and_then(|a| match a.value {
+            Value::String(name) => Some(name),
+            _ => None,
+        }),
+        _ => None,
+    }
+}
+
+/// Follows the default devices in the `default` metadata, loading the rules again when one of them changes
+fn bind_defaults(
+    registry: &pw::registry::Registry,
+    global: &pw::registry::GlobalObject<libspa::ForeignDict>,
+    state: &Rc<RefCell<AppState>>,
+    config: &Config,
+    globals: &Globals,
+    backend: &Rc<PipeWireBackend>,
+) -> Option<(pw::metadata::Metadata, pw::metadata::MetadataListener)> {
+    let metadata = registry.bind::<pw::metadata::Metadata, _>(global).ok()?;
+
+    let listener = metadata
+        .add_listener_local()
+        .property({
+            let state = state.clone();
+            let config = config.clone();
+            let globals = globals.clone();
+            let backend = backend.clone();
+            move |_subject, key, _type, value| {
+                let placeholder = match DEFAULTS.iter().find(|a| Some(a.1) == key) {
+                    Some(a) => a.0,
+                    None => return 0,
+                };
+
+                // The value is JSON, {"name": "alsa_output..."}
+                let name = value
+                    .and_then(|a| json::parse(a).ok())
+                    .and_then(|a| a.get("name").and_then(|a| a.as_str()).map(|a| a.to_string()));
+
+                let mut state = state.borrow_mut();
+
+                if state.defaults.get(placeholder) == name.as_ref() {
+                    return 0;
+                }
+
+                println!("{} is now {}", placeholder, name.as_deref().unwrap_or("unset"));
+
+                match name {
+                    Some(name) => state.defaults.insert(placeholder.to_string(), name),
+                    None => state.defaults.remove(placeholder),
+                };
+
+                if state.uses_defaults {
+                    match reload(&mut state, &config, &globals.borrow(), backend.as_ref()) {
+                        Ok(answer) => print!("{}", answer),
+                        Err(e) => println!("Could not load the rules again: {}", e),
+                    }
+                }
+
+                0
+            }
+        })
+        .register();
+
+    Some((metadata, listener))
+}
+
+fn read_graph(mainloop: &MainLoop, core: &pw::Core, registry: &Rc<pw::registry::Registry>) -> Result<Graph, pw::Error> {
+    let globals: Globals = Rc::new(RefCell::new(Vec::new()));
+    let links = Rc::new(RefCell::new(Vec::new()));
+
+    let listener = registry
+        .add_listener_local()
+        .global({
+            let globals = globals.clone();
+            let registry = registry.clone();
+            let links = links.clone();
+            move |global| {
+                if global.type_ == ObjectType::Link {
+                    links.borrow_mut().extend(bind_link(&registry, global, &globals, |_, _| (), |_, _| ()));
+                }
+                globals.borrow_mut().push(registry_event(global));
+            }
+        })
+        .register();
+
+    // The first roundtrip gets all the globals, the second the info of the links we bound
+    roundtrip(mainloop, core)?;
+    roundtrip(mainloop, core)?;
+
+    drop(listener);
+    links.borrow_mut().clear();
+
+    let graph = Graph::from_globals(&globals.borrow());
+    Ok(graph)
+}
+
+/// Connects and reads the whole graph once, for the one-shot commands
+pub fn current_graph(socket: Option<&str>, client_props: &[(String, String)]) -> Result<Graph, Box<dyn std::error::Error>> {
+    let mainloop = MainLoop::new()?;
+    let context = Context::new(&mainloop)?;
+    let core = connect(&context, socket, client_props)?;
+    let registry = Rc::new(core.get_registry()?);
+
+    Ok(read_graph(&mainloop, &core, &registry)?)
+}
+
+/// Passes every global added or removed to `on_event` with the globals there so far, until we are stopped.
+/// A removed global is still in them
+pub fn follow<F>(socket: Option<&str>, on_event: F) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: Fn(&[RegistryEvent], &RegistryEvent) + 'static,
+{
+    let mainloop = MainLoop::new()?;
+    let context = Context::new(&mainloop)?;
+    let core = connect(&context, socket, &[])?;
+    let registry = core.get_registry()?;
+
+    let globals: Globals = Rc::new(RefCell::new(Vec::new()));
+    let on_event = Rc::new(on_event);
+
+    let _listener = registry
+        .add_listener_local()
+        .global({
+            let globals = globals.clone();
+            let on_event = on_event.clone();
+            move |global| {
+                let event = registry_event(global);
+                globals.borrow_mut().push(event.clone());
+                on_event(&globals.borrow(), &event);
+            }
+        })
+        .global_remove(move |id| {
+            on_event(&globals.borrow(), &RegistryEvent::Remove { id });
+            globals.borrow_mut().retain(|a| !matches!(a, RegistryEvent::Global { id: global, .. } if *global == id));
+        })
+        .register();
+
+    mainloop.run();
+
+    Ok(())
+}
+
+/// Removes the links that belong to the rules or that were made by us
+pub fn cleanup(state: &AppState, system: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let mainloop = MainLoop::new()?;
+    let context = Context::new(&mainloop)?;
+    let core = connect(&context, state.socket.as_deref(), &state.client_props)?;
+    let registry = Rc::new(core.get_registry()?);
+
+    let graph = read_graph(&mainloop, &core, &registry)?;
+
+    let mut links_file = links_file::LinksFile::load(links_file::LinksFile::path(system));
+    let mut removed = 0;
+    // Kept in the file, so a later cleanup still finds them
+    let mut failed = Vec::new();
+
+    for link in &graph.links {
+        // The id could be another link now, if PipeWire restarted
+        let saved = links_file.links().iter().any(|(id, props)| {
+            *id == link.id
+                && props.get("link.output.port") == Some(&link.port_out.to_string())
+                && props.get("link.input.port") == Some(&link.port_in.to_string())
+        });
+        let owned = saved || link.props.get(OWNER_KEY).is_some_and(|a| a.eq(OWNER));
+
+        match graph.link_names(link) {
+            Some((node_out, port_out, node_in, port_in)) if owned || state.matches_rule(node_out, port_out, node_in, port_in) => {
+                println!("Removing link {}: [{}]{} -> [{}]{}", link.id, node_out, port_out, node_in, port_in)
+            }
+            None if owned => println!("Removing link {}", link.id),
+            _ => continue,
+        }
+
+        if registry.destroy_global(link.id).into_result().is_err() {
+            println!("Failed to remove link {}", link.id);
+            failed.push(link.id);
+        } else {
+            state.audit("destroy", Some(link.id), (link.port_out, link.port_in), None, "cleanup");
+            removed += 1;
+        }
+    }
+
+    roundtrip(&mainloop, &core)?;
+
+    links_file.retain(|a| failed.contains(&a.0));
+
+    println!("Removed {} links", removed);
+
+    Ok(())
+}
+
+/// PipeWire was not there at the socket, after waiting this long for it
+#[derive(Debug)]
+pub struct NotConnected {
+    socket: std::path::PathBuf,
+    waited: Duration,
+    error: pw::Error,
+}
+
+impl fmt::Display for NotConnected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "could not connect to PipeWire at {}", self.socket.display())?;
+        if !self.waited.is_zero() {
+            write!(f, " after waiting {} seconds", self.waited.as_secs())?;
+        }
+        write!(f, " ({}), is it running? --socket and --runtime-dir tell where it is", self.error)
+    }
+}
+
+impl std::error::Error for NotConnected {}
+
+/// Creates a null sink or source that goes away together with us, and so do its links
+fn virtual_node(core: &pw::Core, name: &str, description: &str, class: &str, position: &str) -> Option<pw::node::Node> {
+    let props = pw::properties! {
+        "factory.name" => "support.null-audio-sink",
+        "node.name" => name,
+        "node.description" => description,
+        "media.class" => class,
+        "audio.position" => position,
+        "object.linger" => "false"
+    };
+
+    match core.create_object::<pw::node::Node, _>("adapter", &props) {
+        Ok(node) => Some(node),
+        Err(e) => {
+            println!("Could not create {}: {}", name, e);
+            None
+        }
+    }
+}
+
+/// Creates the sinks of the `combine` rules that are not there yet, and removes the ones no rule declares anymore
+fn sync_combine_sinks(core: &pw::Core, state: &AppState, sinks: &RefCell<HashMap<String, pw::node::Node>>) {
+    let mut sinks = sinks.borrow_mut();
+
+    sinks.retain(|name, _| {
+        let keep = state.combine_sinks.contains(name);
+        if !keep {
+            println!("Removing combined sink {}", name);
+        }
+        keep
+    });
+
+    for name in &state.combine_sinks {
+        if sinks.contains_key(name) {
+            continue;
+        }
+
+        if let Some(node) = virtual_node(core, name, name, "Audio/Sink", "FL,FR") {
+            println!("Created combined sink {}", name);
+            sinks.insert(name.clone(), node);
+        }
+    }
+}
+
+/// Runs the filters of the `filter` rules that are not running yet, and stops the ones that were removed or changed
+fn sync_filters(state: &AppState, system: bool, running: &RefCell<Vec<(filter_chain::FilterDef, std::process::Child)>>) {
+    let mut running = running.borrow_mut();
+
+    for (filter, mut child) in std::mem::take(&mut *running) {
+        if state.filters.contains(&filter) {
+            running.push((filter, child));
+            continue;
+        }
+
+        println!("Stopping filter {}", filter.name);
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+
+    for filter in &state.filters {
+        if running.iter().any(|a| a.0.eq(filter)) {
+            continue;
+        }
+
+        match filter.spawn(state.socket.as_deref(), system) {
+            Ok(child) => {
+                println!("Started filter {} (pid {})", filter.name, child.id());
+                running.push((filter.clone(), child));
+            }
+            Err(e) => println!("Could not start filter {}: {}", filter.name, e),
+        }
+    }
+}
+
+/// Starts and stops the combine sinks and filters after a command that loaded other rules, `reload` or a profile switch
+fn sync_after_command(
+    command: &str,
+    core: &pw::Core,
+    state: &AppState,
+    system: bool,
+    combine_sinks: &RefCell<HashMap<String, pw::node::Node>>,
+    filters: &RefCell<Vec<(filter_chain::FilterDef, std::process::Child)>>,
+) {
+    if command == "reload" || command.starts_with("profile") {
+        sync_combine_sinks(core, state, combine_sinks);
+        sync_filters(state, system, filters);
+    }
+}
+
+pub fn run(state: Rc<RefCell<AppState>>, record: Option<Recorder>, config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    let mainloop = MainLoop::new()?;
+    let context = Context::new(&mainloop)?;
+    let core = connect_retrying(&context, state.borrow().socket.as_deref(), &state.borrow().client_props, config.connect_retries, config.connect_timeout)?;
+    let registry = Rc::new(core.get_registry()?);
+    let backend = Rc::new(PipeWireBackend {
+        core: core.clone(),
+        registry: registry.clone(),
+        factory: RefCell::new(config.factory.clone()),
+        socket: state.borrow().socket.clone(),
+        settings: RefCell::new(None),
+    });
+    let record = Rc::new(record);
+    let once = config.once;
+    let watchdog = config.watchdog;
+
+    // Everything in the graph, for reload and the commands of the control socket
+    let globals: Globals = Rc::new(RefCell::new(Vec::new()));
+    let links = Rc::new(RefCell::new(HashMap::new()));
+    // Our nodes are bound to know their state, the devices to know their profile
+    let nodes = Rc::new(RefCell::new(HashMap::new()));
+    let devices = Rc::new(RefCell::new(HashMap::new()));
+    state.borrow_mut().ready_nodes = Some(Vec::new());
+    state.borrow_mut().node_times = Some(HashMap::new());
+    state.borrow_mut().rate_limit = Some(RateLimit::new(config.rate_limit));
+    state.borrow_mut().run_hooks = true;
+    state.borrow_mut().webhook = config.webhook.clone().map(webhook::Webhook::new);
+    // Our links in the graph, the links of an instance that exited are added again as they are announced.
+    // The ones it saved are adopted even when the link factory dropped our properties
+    let links_file = Rc::new(RefCell::new(links_file::LinksFile::load(links_file::LinksFile::path(config.system))));
+    let saved_links = Rc::new(links_file.borrow().links().to_vec());
+    links_file.borrow_mut().retain(|_| false);
+    // The `default` metadata, for @DEFAULT_SINK@ and @DEFAULT_SOURCE@
+    let metadata = Rc::new(RefCell::new(None));
+    #[cfg(feature = "mqtt")]
+    let mqtt = config.mqtt.as_ref().map(|(broker, topic)| Rc::new(RefCell::new(mqtt::Client::new(broker, topic))));
+
+    let on_event = {
+        let state = state.clone();
+        let backend = backend.clone();
+        let record = record.clone();
+        let trace = config.trace_registry;
+        move |event: RegistryEvent| {
+            if trace {
+                print!("{}", event.trace());
+            }
+            if let Some(record) = record.as_ref() {
+                record.record(&event);
+            }
+            handle_event(&mut state.borrow_mut(), &event, backend.as_ref());
+        }
+    };
+    let on_event = Rc::new(on_event);
+
+    let _listener = registry
+        .add_listener_local()
+        .global({
+            let on_event = on_event.clone();
+            let globals = globals.clone();
+            let registry = registry.clone();
+            let links = links.clone();
+            let backend = backend.clone();
+            let config = config.clone();
+            let state = state.clone();
+            let nodes = nodes.clone();
+            let devices = devices.clone();
+            let links_file = links_file.clone();
+            #[cfg(feature = "mqtt")]
+            let mqtt = mqtt.clone();
+            move |global| {
+                let event = registry_event(global);
+
+                match global.type_ {
+                    // Factories come before the nodes and ports
+                    ObjectType::Factory if config.factory.is_none() => {
+                        let props = global.props.as_ref();
+                        let name = props.and_then(|a| a.get("factory.name"));
+                        if props.and_then(|a| a.get("factory.type.name")) == Some(ObjectType::Link.to_str()) {
+                            if let Some(name) = name {
+                                println!("Using link factory {}", name);
+                                backend.factory.replace(Some(name.to_string()));
+                            }
+                        }
+                    }
+                    ObjectType::Link => {
+                        let on_props = {
+                            let state = state.clone();
+                            let links_file = links_file.clone();
+                            let saved_links = saved_links.clone();
+                            move |id: u32, props: &HashMap<String, String>| {
+                                // The id could be another link now, if PipeWire restarted
+                                let ports = |a: &HashMap<String, String>| (a.get("link.output.port").cloned(), a.get("link.input.port").cloned());
+                                let props = if props.get(OWNER_KEY).is_some_and(|a| a.eq(OWNER)) {
+                                    Some(props.clone())
+                                } else {
+                                    saved_links.iter().find(|(saved, saved_props)| *saved == id && ports(saved_props) == ports(props)).map(|a| a.1.clone())
+                                };
+
+                                if let Some(props) = props {
+                                    links_file.borrow_mut().insert(id, props.clone());
+                                    state.borrow_mut().adopt_link(id, &props);
+                                }
+                            }
+                        };
+
+                        let on_state = {
+                            let state = state.clone();
+                            move |id: u32, link_state: String| {
+                                if link_state.starts_with("error") {
+                                    println!("Link {} is in {}", id, link_state);
+                                }
+                                state.borrow_mut().link_states.insert(id, link_state);
+                            }
+                        };
+
+                        globals.borrow_mut().push(event.clone());
+
+                        if let Some(link) = bind_link(&registry, global, &globals, on_props, on_state) {
+                            links.borrow_mut().insert(global.id, link);
+                        }
+                    }
+                    // Clients tell `status` who made the links, devices their name for a profile switch
+                    ObjectType::Node | ObjectType::Port | ObjectType::Client | ObjectType::Device => globals.borrow_mut().push(event.clone()),
+                    ObjectType::Metadata if global.props.as_ref().and_then(|a| a.get("metadata.name")) == Some("default") => {
+                        metadata.replace(bind_defaults(&registry, global, &state, &config, &globals, &backend));
+                    }
+                    // For `@latency`, the links made before it was there get their quantum now
+                    ObjectType::Metadata if global.props.as_ref().and_then(|a| a.get("metadata.name")) == Some("settings") => {
+                        backend.settings.replace(registry.bind::<pw::metadata::Metadata, _>(global).ok());
+                        state.borrow_mut().update_latency(backend.as_ref());
+                    }
+                    _ => (),
+                }
+
+                #[cfg(feature = "mqtt")]
+                if let (Some(mqtt), Some(payload)) = (&mqtt, mqtt_event(&event, true, &globals.borrow())) {
+                    mqtt.borrow_mut().publish("events", &payload);
+                }
+
+                on_event(event);
+
+                // All the audio nodes, a reload can add rules for any of them
+                let is_audio = global.props.as_ref().and_then(|a| a.get("media.class")).is_some_and(is_audio);
+                if global.type_ == ObjectType::Node && is_audio {
+                    if let Some(node) = bind_node(&registry, global, &state, &backend) {
+                        nodes.borrow_mut().insert(global.id, node);
+                    }
+                }
+
+                // Its profile, the rules of its nodes are applied again when it changes
+                if global.type_ == ObjectType::Device {
+                    if let Some(device) = bind_device(&registry, global, &state, &backend) {
+                        devices.borrow_mut().insert(global.id, device);
+                    }
+                }
+            }
+        })
+        .global_remove({
+            let globals = globals.clone();
+            #[cfg(feature = "mqtt")]
+            let mqtt = mqtt.clone();
+            move |id| {
+                #[cfg(feature = "mqtt")]
+                if let Some(mqtt) = &mqtt {
+                    let globals = globals.borrow();
+                    let removed = globals.iter().find(|a| matches!(a, RegistryEvent::Global { id: global, .. } if *global == id));
+                    if let Some(payload) = removed.and_then(|a| mqtt_event(a, false, &globals)) {
+                        mqtt.borrow_mut().publish("events", &payload);
+                    }
+                }
+
+                globals.borrow_mut().retain(|a| !matches!(a, RegistryEvent::Global { id: global, .. } if *global == id));
+                links.borrow_mut().remove(&id);
+                links_file.borrow_mut().remove(id);
+                nodes.borrow_mut().remove(&id);
+                devices.borrow_mut().remove(&id);
+                on_event(RegistryEvent::Remove { id })
+            }
+        })
+        .register();
+
+    // Goes away together with us, and so do its links
+    let _keep_alive = if state.borrow().link_def.iter().any(|a| a.port_out.node.name.eq(KEEP_ALIVE_NODE)) {
+        virtual_node(&core, KEEP_ALIVE_NODE, "pw-autoconnect keep alive", "Audio/Source/Virtual", "MONO")
+    } else {
+        None
+    };
+
+    let combine_sinks = Rc::new(RefCell::new(HashMap::new()));
+    sync_combine_sinks(&core, &state.borrow(), &combine_sinks);
+
+    let filters = Rc::new(RefCell::new(Vec::new()));
+    sync_filters(&state.borrow(), config.system, &filters);
+
+    // Links are created asynchronously, a denied link only shows up as an error of the core
+    let denied = Rc::new(Cell::new(false));
+    state.borrow_mut().connected = true;
+
+    let _core_listener = core
+        .add_listener_local()
+        .error({
+            let denied = denied.clone();
+            let state = state.clone();
+            move |id, _seq, res, message| {
+                if id == pw::PW_ID_CORE && res == -libc::EPIPE {
+                    println!("Lost the connection to PipeWire: {}", message);
+                    state.borrow_mut().connected = false;
+                } else if res == -libc::EPERM || res == -libc::EACCES {
+                    if !denied.replace(true) {
+                        println!(
+                            "PipeWire denied object {}: {}. The access rules of the PipeWire instance must allow us to create links, trying again in {} seconds",
+                            id,
+                            message,
+                            PERMISSION_RETRY.as_secs()
+                        );
+                    }
+                } else if res != -libc::EEXIST {
+                    println!("PipeWire error on object {}: {} ({})", id, message, res);
+                }
+            }
+        })
+        .register();
+
+    let retry = mainloop.add_timer({
+        let state = state.clone();
+        let backend = backend.clone();
+        move |_| {
+            if denied.replace(false) {
+                println!("Trying the links again");
+                state.borrow_mut().apply_all(backend.as_ref());
+            }
+        }
+    });
+    retry.update_timer(Some(PERMISSION_RETRY), Some(PERMISSION_RETRY));
+
+    #[cfg(feature = "web")]
+    let _web = match config.web.as_deref().map(web::listen) {
+        Some(Ok(listener)) => Some(mainloop.add_io(listener, IoFlags::IN, {
+            let state = state.clone();
+            let backend = backend.clone();
+            let config = config.clone();
+            let globals = globals.clone();
+            move |listener| {
+                while let Ok((mut stream, _)) = listener.accept() {
+                    let request = match web::read_request(&stream) {
+                        Ok(request) => request,
+                        Err(e) => {
+                            println!("Could not read the web request: {}", e);
+                            continue;
+                        }
+                    };
+
+                    let (status, content_type, body) =
+                        web_request(&request, &mut state.borrow_mut(), &config, &globals.borrow(), backend.as_ref());
+
+                    if let Err(e) = web::respond(&mut stream, status, content_type, &body) {
+                        println!("Could not answer the web request: {}", e);
+                    }
+                }
+            }
+        })),
+        Some(Err(e)) => {
+            println!("Web page disabled: {}", e);
+            None
+        }
+        None => None,
+    };
+
+    #[cfg(feature = "web")]
+    let _api = match config.api.as_ref().map(|(address, token)| web::listen(address).and_then(|a| Ok((a, web::token(token)?)))) {
+        Some(Ok((listener, token))) => {
+            if let Some((address, file)) = &config.api {
+                println!("HTTP API on {}, the token is in {}", address, file.display());
+            }
+
+            Some(mainloop.add_io(listener, IoFlags::IN, {
+                let state = state.clone();
+                let backend = backend.clone();
+                let config = config.clone();
+                let globals = globals.clone();
+                let combine_sinks = combine_sinks.clone();
+                let filters = filters.clone();
+                move |listener| {
+                    while let Ok((mut stream, _)) = listener.accept() {
+                        let request = match web::read_request(&stream) {
+                            Ok(request) => request,
+                            Err(e) => {
+                                println!("Could not read the API request: {}", e);
+                                continue;
+                            }
+                        };
+
+                        // Monitoring tools probe it without the token, it tells nothing about the graph
+                        let answer = if request.method == "GET" && request.path == "/healthz" {
+                            let answer = health(&state.borrow(), Format::Json);
+                            let status = if answer.contains("unhealthy") { "503 Service Unavailable" } else { "200 OK" };
+                            web::respond(&mut stream, status, "application/json", &answer)
+                        } else if !request.is_authorized(&token) {
+                            web::respond(&mut stream, "401 Unauthorized", "text/plain; charset=utf-8", "Unauthorized\n")
+                        } else if let Some(command) = api_command(&request, &config) {
+                            println!("API command: {}", command);
+
+                            let answer = control_command(&command, &mut state.borrow_mut(), &config, &globals.borrow(), backend.as_ref());
+
+                            sync_after_command(&command, &backend.core, &state.borrow(), config.system, &combine_sinks, &filters);
+
+                            let content_type = match command.as_str() {
+                                "status json" | "stats json" | "snapshot" => "application/json",
+                                _ => "text/plain; charset=utf-8",
+                            };
+                            web::respond(&mut stream, "200 OK", content_type, &answer)
+                        } else {
+                            web::respond(&mut stream, "404 Not Found", "text/plain; charset=utf-8", "Not found\n")
+                        };
+
+                        if let Err(e) = answer {
+                            println!("Could not answer the API request: {}", e);
+                        }
+                    }
+                }
+            }))
+        }
+        Some(Err(e)) => {
+            println!("HTTP API disabled: {}", e);
+            None
+        }
+        None => None,
+    };
+
+    // Commands from the broker run like the ones of the control socket, the answer is published
+    #[cfg(feature = "mqtt")]
+    let _mqtt = mqtt.map(|mqtt| {
+        let timer = mainloop.add_timer({
+            let state = state.clone();
+            let backend = backend.clone();
+            let config = config.clone();
+            let globals = globals.clone();
+            let combine_sinks = combine_sinks.clone();
+            let filters = filters.clone();
+            move |_| {
+                let commands = mqtt.borrow_mut().poll();
+
+                for command in commands {
+                    println!("MQTT command: {}", command);
+
+                    if !command.split_whitespace().next().is_some_and(|a| MQTT_COMMANDS.contains(&a)) {
+                        println!("Refusing MQTT command: {}", command);
+                        mqtt.borrow_mut().publish("answer", &format!("Not allowed over MQTT: {}\n", command));
+                        continue;
+                    }
+
+                    let answer = control_command(&command, &mut state.borrow_mut(), &config, &globals.borrow(), backend.as_ref());
+
+                    sync_after_command(&command, &backend.core, &state.borrow(), config.system, &combine_sinks, &filters);
+
+                    mqtt.borrow_mut().publish("answer", &answer);
+                }
+            }
+        });
+        timer.update_timer(Some(Duration::from_millis(1)), Some(MQTT_INTERVAL));
+        timer
+    });
+
+    let _control = match control::listen(config.system) {
+        Ok(listener) => Some(mainloop.add_io(listener, IoFlags::IN, {
+            let state = state.clone();
+            let backend = backend.clone();
+            move |listener| {
+                while let Ok((mut stream, _)) = listener.accept() {
+                    let command = match control::read_command(&stream) {
+                        Ok(command) => command,
+                        Err(e) => {
+                            println!("Could not read the control command: {}", e);
+                            continue;
+                        }
+                    };
+
+                    println!("Control command: {}", command);
+
+                    let answer = control_command(&command, &mut state.borrow_mut(), &config, &globals.borrow(), backend.as_ref());
+
+                    sync_after_command(&command, &backend.core, &state.borrow(), config.system, &combine_sinks, &filters);
+
+                    if let Err(e) = stream.write_all(answer.as_bytes()) {
+                        println!("Could not answer the control command: {}", e);
+                    }
+                }
+            }
+        })),
+        Err(e) => {
+            println!("Control socket disabled: {}", e);
+            None
+        }
+    };
+
+    let waiting = mainloop.add_timer({
+        let state = state.clone();
+        let backend = backend.clone();
+        move |_| {
+            state.borrow_mut().apply_waiting(backend.as_ref());
+            crash::snapshot(&state.borrow());
+        }
+    });
+    waiting.update_timer(Some(WAIT_INTERVAL), Some(WAIT_INTERVAL));
+
+    let timer = mainloop.add_timer({
+        let state = state.clone();
+        move |_| state.borrow_mut().update_schedules(backend.as_ref())
+    });
+    timer.update_timer(Some(SCHEDULE_INTERVAL), Some(SCHEDULE_INTERVAL));
+
+    // A main loop that stopped dispatching cannot notice it, a thread does and exits
+    let _watchdog = watchdog.map(|timeout| {
+        let watchdog = watchdog::Watchdog::start(timeout, watchdog::EXIT_STALLED);
+        let timer = mainloop.add_timer(move |_| watchdog.beat());
+        timer.update_timer(Some(WATCHDOG_INTERVAL), Some(WATCHDOG_INTERVAL));
+        timer
+    });
+
+    // --once, the links stay after we exit unless --no-linger is given
+    let _once = once.map(|timeout| {
+        let started = Instant::now();
+        let timer = mainloop.add_timer({
+            let state = state.clone();
+            let mainloop = mainloop.clone();
+            move |_| {
+                if state.borrow().unlinked_rules(false).is_empty() || started.elapsed() >= timeout {
+                    mainloop.quit();
+                }
+            }
+        });
+        timer.update_timer(Some(WAIT_INTERVAL), Some(WAIT_INTERVAL));
+        timer
+    });
+
+    mainloop.run();
+
+    if once.is_some() {
+        let unlinked = state.borrow().unlinked_rules(true);
+        for rule in &unlinked {
+            println!("Required rule not linked: {}", rule);
+        }
+        return satisfied(unlinked.len());
+    }
+
+    Ok(())
+}
+
+/// How long to wait between the tries of --connect-retries and --connect-timeout
+const CONNECT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How long to wait before trying the links again after PipeWire denied them
+const PERMISSION_RETRY: Duration = Duration::from_secs(5);
+
+/// How often the main loop tells the watchdog it runs
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the MQTT broker is read for commands and kept alive
+#[cfg(feature = "mqtt")]
+const MQTT_INTERVAL: Duration = Duration::from_millis(500);
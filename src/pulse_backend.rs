@@ -0,0 +1,453 @@
+//! Applies the rules to a plain PulseAudio server through `pactl`, used with `--backend pulse`.
+//! PulseAudio has no links between ports: a rule from a source or the monitor of a sink to a sink loads
+//! a `module-loopback`, and a rule from an application stream to a sink, or from a source to a recording stream,
+//! moves the stream there. The ports of a rule only pick the nodes, which are linked as a whole
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::{BufRead, BufReader},
+    process::{Command, Stdio},
+    rc::Rc,
+    sync::mpsc,
+    time::Instant,
+};
+
+use crate::config::SCHEDULE_INTERVAL;
+use crate::engine::{add_node, add_port, AppState};
+use crate::graph::{Direction, GraphBackend, Latency, LinkHandle, NodeKind, Port, PortProps, WAIT_INTERVAL};
+use crate::crash;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Sink,
+    Source,
+    SinkInput,
+    SourceOutput,
+}
+
+impl Kind {
+    const ALL: [Kind; 4] = [Kind::Sink, Kind::Source, Kind::SinkInput, Kind::SourceOutput];
+
+    /// The name `pactl list` takes
+    fn list(&self) -> &'static str {
+        match self {
+            Kind::Sink => "sinks",
+            Kind::Source => "sources",
+            Kind::SinkInput => "sink-inputs",
+            Kind::SourceOutput => "source-outputs",
+        }
+    }
+
+    /// The line every object starts with in `pactl list`, before its index
+    fn header(&self) -> &'static str {
+        match self {
+            Kind::Sink => "Sink #",
+            Kind::Source => "Source #",
+            Kind::SinkInput => "Sink Input #",
+            Kind::SourceOutput => "Source Output #",
+        }
+    }
+
+    /// The `media.class` PipeWire would give the node, and the prefix and direction of its ports
+    fn node(&self) -> (&'static str, &'static str, Direction) {
+        match self {
+            Kind::Sink => ("Audio/Sink", "playback", Direction::In),
+            Kind::Source => ("Audio/Source", "capture", Direction::Out),
+            Kind::SinkInput => ("Stream/Output/Audio", "output", Direction::Out),
+            Kind::SourceOutput => ("Stream/Input/Audio", "input", Direction::In),
+        }
+    }
+}
+
+/// A sink, source or stream of `pactl list`
+#[derive(Debug, Clone)]
+struct Object {
+    kind: Kind,
+    index: u32,
+    name: String,
+    // FL, FR, MONO... from the channel map
+    channels: Vec<String>,
+    // The module that made it, the streams of our loopbacks are not nodes
+    module: Option<u32>,
+    props: HashMap<String, String>,
+}
+
+impl Object {
+    fn key(&self) -> String {
+        format!("{}#{}", self.kind.list(), self.index)
+    }
+
+    /// The name the rules call the node by, streams by their application
+    fn node_name(&self) -> String {
+        match self.kind {
+            Kind::Sink | Kind::Source => self.name.clone(),
+            Kind::SinkInput | Kind::SourceOutput => self
+                .props
+                .get("application.name")
+                .or_else(|| self.props.get("media.name"))
+                .cloned()
+                .unwrap_or_else(|| self.key()),
+        }
+    }
+}
+
+/// Runs pactl with the messages in english, so its output can be read
+fn pactl(args: &[&str]) -> Result<String, String> {
+    let output = Command::new("pactl")
+        .args(args)
+        .env("LC_ALL", "C")
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("could not run pactl: {}", e))?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The channel names of PipeWire, front-left is FL
+fn channel_name(position: &str) -> String {
+    match position.trim() {
+        "front-left" => "FL".to_string(),
+        "front-right" => "FR".to_string(),
+        "front-center" => "FC".to_string(),
+        "rear-left" => "RL".to_string(),
+        "rear-right" => "RR".to_string(),
+        "side-left" => "SL".to_string(),
+        "side-right" => "SR".to_string(),
+        "lfe" => "LFE".to_string(),
+        "mono" => "MONO".to_string(),
+        other => other.to_ascii_uppercase(),
+    }
+}
+
+fn list(kind: Kind) -> Result<Vec<Object>, String> {
+    let mut objects: Vec<Object> = Vec::new();
+
+    for line in pactl(&["list", kind.list()])?.lines() {
+        if let Some(index) = line.strip_prefix(kind.header()).and_then(|a| a.trim().parse().ok()) {
+            objects.push(Object {
+                kind,
+                index,
+                name: String::new(),
+                channels: Vec::new(),
+                module: None,
+                props: HashMap::new(),
+            });
+            continue;
+        }
+
+        let object = match objects.last_mut() {
+            Some(object) => object,
+            None => continue,
+        };
+        let line = line.trim();
+
+        if let Some(name) = line.strip_prefix("Name: ") {
+            object.name = name.to_string();
+        } else if let Some(map) = line.strip_prefix("Channel Map: ") {
+            object.channels = map.split(',').map(channel_name).collect();
+        } else if let Some(module) = line.strip_prefix("Owner Module: ") {
+            object.module = module.parse().ok();
+        } else if let Some((key, value)) = line.split_once(" = ") {
+            object.props.insert(key.to_string(), value.trim_matches('"').to_string());
+        }
+    }
+
+    Ok(objects)
+}
+
+/// The loopback modules loaded, by their index, with the source and sink they were given
+fn loopbacks() -> Result<HashMap<u32, (String, String)>, String> {
+    let mut modules = HashMap::new();
+
+    for line in pactl(&["list", "short", "modules"])?.lines() {
+        let fields = line.split('\t').collect::<Vec<&str>>();
+        if fields.len() < 3 || fields[1] != "module-loopback" {
+            continue;
+        }
+
+        let arg = |key: &str| {
+            fields[2]
+                .split_whitespace()
+                .find_map(|a| a.strip_prefix(key)?.strip_prefix('='))
+                .map(|a| a.trim_matches('"').to_string())
+        };
+
+        if let (Ok(index), Some(source), Some(sink)) = (fields[0].parse(), arg("source"), arg("sink")) {
+            modules.insert(index, (source, sink));
+        }
+    }
+
+    Ok(modules)
+}
+
+/// What links a pair of nodes, with how many port links of the rules use it
+struct PulseLink {
+    // The loopback module, None for a moved stream
+    module: Option<u32>,
+    count: usize,
+}
+
+#[derive(Default)]
+struct PulseBackend {
+    // The object of every node, by its id
+    objects: RefCell<HashMap<u32, Object>>,
+    // PulseAudio has no object ids that are unique across kinds, nodes and ports get one the first time they are seen
+    ids: RefCell<HashMap<String, u32>>,
+    // Every pair of nodes linked, by the names pactl knows them by
+    links: RefCell<HashMap<(String, String), PulseLink>>,
+}
+
+impl PulseBackend {
+    fn get_id(&self, key: &str) -> u32 {
+        let mut ids = self.ids.borrow_mut();
+        let next = ids.len() as u32 + 1;
+        *ids.entry(key.to_string()).or_insert(next)
+    }
+
+    fn object(&self, node: u32) -> Result<Object, String> {
+        self.objects.borrow().get(&node).cloned().ok_or_else(|| format!("no PulseAudio object for node {}", node))
+    }
+
+    /// Adds the sinks, sources and streams that are new since the last time and removes the ones that went away
+    fn sync(&self, state: &mut AppState) -> Result<(), String> {
+        let loopbacks = loopbacks()?;
+
+        // Loopbacks made by an instance before us are used again instead of loading another one,
+        // and the ones that went away with their sink are forgotten
+        {
+            let mut links = self.links.borrow_mut();
+            links.retain(|_, link| link.module.is_none_or(|a| loopbacks.contains_key(&a)));
+            for (index, pair) in &loopbacks {
+                links.entry(pair.clone()).or_insert(PulseLink { module: Some(*index), count: 0 });
+            }
+        }
+
+        let mut found = Vec::new();
+        for kind in Kind::ALL {
+            found.extend(list(kind)?.into_iter().filter(|a| {
+                let monitor = a.kind == Kind::Source && a.props.get("device.class").is_some_and(|a| a == "monitor");
+                !monitor && !a.module.is_some_and(|a| loopbacks.contains_key(&a))
+            }));
+        }
+
+        let gone = self
+            .objects
+            .borrow()
+            .iter()
+            .filter(|(_, a)| !found.iter().any(|b| b.key() == a.key()))
+            .map(|(id, _)| *id)
+            .collect::<Vec<u32>>();
+
+        let mut removed = false;
+        for id in gone {
+            if let Some(object) = self.objects.borrow_mut().remove(&id) {
+                self.links.borrow_mut().retain(|pair, link| link.module.is_some() || pair.0 != object.key());
+            }
+            removed |= state.remove_global(id);
+        }
+        if removed {
+            state.reconcile_priorities(self);
+        }
+
+        for object in found {
+            let id = self.get_id(&object.key());
+            if self.objects.borrow().contains_key(&id) {
+                continue;
+            }
+            self.objects.borrow_mut().insert(id, object.clone());
+            self.add(state, id, &object);
+        }
+
+        Ok(())
+    }
+
+    fn add(&self, state: &mut AppState, id: u32, object: &Object) {
+        let (class, prefix, direction) = object.kind.node();
+
+        let mut props = object.props.clone();
+        props.insert("media.class".to_string(), class.to_string());
+
+        let nick = props.get("device.description").cloned().unwrap_or_else(|| "<pulse>".to_string());
+        add_node(state, id, &object.node_name(), &nick, Some(NodeKind::from_props(&props)));
+
+        for (index, channel) in object.channels.iter().enumerate() {
+            let ports = match object.kind {
+                // What is played to a sink comes out of its monitor
+                Kind::Sink => vec![(prefix, direction, false), ("monitor", Direction::Out, true)],
+                _ => vec![(prefix, direction, false)],
+            };
+
+            for (prefix, direction, monitor) in ports {
+                let name = format!("{}_{}", prefix, channel);
+                let props = PortProps {
+                    direction: Some(direction),
+                    channel: Some(channel.clone()),
+                    index: Some(index as u32),
+                    monitor,
+                    physical: false,
+                    terminal: false,
+                };
+
+                add_port(state, self.get_id(&format!("{}:{}", object.key(), name)), &name, id, props, self);
+            }
+        }
+    }
+}
+
+impl GraphBackend for PulseBackend {
+    // A pair of nodes is linked once however many of their ports the rules link
+    fn create_link(&self, port_out: &Port, port_in: &Port, _props: &[(String, String)]) -> Result<LinkHandle, String> {
+        let (out, input) = (self.object(port_out.node.id)?, self.object(port_in.node.id)?);
+
+        let (pair, moves) = match (out.kind, input.kind) {
+            (Kind::SinkInput, Kind::Sink) => ((out.key(), input.name.clone()), Some("move-sink-input")),
+            (Kind::Source, Kind::SourceOutput) => ((input.key(), out.name.clone()), Some("move-source-output")),
+            (Kind::Sink, Kind::Sink) if port_out.name.starts_with("monitor_") => ((format!("{}.monitor", out.name), input.name.clone()), None),
+            (Kind::Source, Kind::Sink) => ((out.name.clone(), input.name.clone()), None),
+            _ => return Err("PulseAudio can only link a source or a monitor to a sink, or move a stream".to_string()),
+        };
+
+        let mut links = self.links.borrow_mut();
+        if let Some(link) = links.get_mut(&pair) {
+            link.count += 1;
+            return Ok(LinkHandle::Pulse(pair.0, pair.1));
+        }
+
+        let module = match moves {
+            Some(command) => {
+                let stream = if out.kind == Kind::SinkInput { &out } else { &input };
+                pactl(&[command, &stream.index.to_string(), &pair.1])?;
+                None
+            }
+            None => {
+                let (source, sink) = (format!("source=\"{}\"", pair.0), format!("sink=\"{}\"", pair.1));
+                let index = pactl(&["load-module", "module-loopback", &source, &sink, "source_dont_move=true", "sink_dont_move=true"])?;
+                Some(index.trim().parse::<u32>().map_err(|_| format!("unexpected answer from pactl: {}", index.trim()))?)
+            }
+        };
+
+        links.insert(pair.clone(), PulseLink { module, count: 1 });
+        Ok(LinkHandle::Pulse(pair.0, pair.1))
+    }
+
+    // A moved stream stays where it is, PulseAudio streams always play somewhere
+    fn destroy_link(&self, link: LinkHandle) -> Result<(), String> {
+        let pair = match link {
+            LinkHandle::Pulse(a, b) => (a, b),
+            _ => return Err("not a PulseAudio link".to_string()),
+        };
+
+        let mut links = self.links.borrow_mut();
+        let link = links.get_mut(&pair).ok_or_else(|| format!("no link from {} to {}", pair.0, pair.1))?;
+
+        link.count = link.count.saturating_sub(1);
+        if link.count > 0 {
+            return Ok(());
+        }
+
+        if let Some(module) = link.module {
+            pactl(&["unload-module", &module.to_string()])?;
+        }
+        links.remove(&pair);
+
+        Ok(())
+    }
+
+    fn set_node_props(&self, _node: u32, _props: &str) -> Result<(), String> {
+        Err("PulseAudio nodes have no props, set them with pactl".to_string())
+    }
+
+    fn set_device_profile(&self, _device: u32, _profile: &str) -> Result<(), String> {
+        Err("PulseAudio cards are not devices of the graph".to_string())
+    }
+
+    fn force_latency(&self, _latency: Option<Latency>) -> Result<(), String> {
+        Err("PulseAudio has no graph wide buffer size".to_string())
+    }
+}
+
+/// Loads a null sink for every `combine` rule missing one, its monitor is looped back to the sinks of the rule
+fn load_combine_sinks(state: &AppState) -> Result<(), String> {
+    let sinks = list(Kind::Sink)?;
+
+    for name in state.combine_sinks.iter().filter(|a| !sinks.iter().any(|b| b.name.eq(*a))) {
+        let (sink, description) = (format!("sink_name=\"{}\"", name), format!("sink_properties=device.description=\"{}\"", name));
+        match pactl(&["load-module", "module-null-sink", &sink, &description]) {
+            Ok(_) => println!("Created combined sink {}", name),
+            Err(e) => println!("Could not create combined sink {}: {}", name, e),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn run(state: Rc<RefCell<AppState>>) -> Result<(), Box<dyn std::error::Error>> {
+    pactl(&["info"]).map_err(|e| format!("Could not reach PulseAudio: {}", e))?;
+
+    if !state.borrow().linger {
+        println!("--no-linger is not supported by the PulseAudio backend, the loopbacks stay loaded after exit");
+    }
+
+    state.borrow_mut().node_times = Some(HashMap::new());
+
+    let backend = PulseBackend::default();
+    load_combine_sinks(&state.borrow())?;
+
+    // Every change is announced on its own line, the graph is listed again when objects come or go
+    let mut subscribe = Command::new("pactl")
+        .arg("subscribe")
+        .env("LC_ALL", "C")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("could not run pactl: {}", e))?;
+
+    let (sender, events) = mpsc::channel();
+    let stdout = subscribe.stdout.take().ok_or("pactl subscribe has no output")?;
+
+    std::thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            let graph = ["sink", "source", "sink-input", "source-output"].iter().any(|a| line.contains(&format!(" on {} #", a)));
+            if graph && (line.contains("'new'") || line.contains("'remove'")) && sender.send(()).is_err() {
+                break;
+            }
+        }
+    });
+
+    backend.sync(&mut state.borrow_mut())?;
+
+    let mut last_schedule = Instant::now();
+
+    loop {
+        match events.recv_timeout(WAIT_INTERVAL) {
+            Ok(()) => {
+                // One listing for every change that came in at once
+                while events.try_recv().is_ok() {}
+                if let Err(e) = backend.sync(&mut state.borrow_mut()) {
+                    println!("Could not list the PulseAudio objects: {}", e);
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                println!("PulseAudio server went away");
+                break;
+            }
+        }
+
+        state.borrow_mut().apply_waiting(&backend);
+        crash::snapshot(&state.borrow());
+
+        if last_schedule.elapsed() >= SCHEDULE_INTERVAL {
+            state.borrow_mut().update_schedules(&backend);
+            last_schedule = Instant::now();
+        }
+    }
+
+    let _ = subscribe.kill();
+    Ok(())
+}
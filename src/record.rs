@@ -0,0 +1,59 @@
+//! `--record` saves the registry events to a file and `replay` or `--events` reads them back
+
+use std::{cell::{Cell, RefCell}, fs, io::Write};
+
+use crate::graph::RegistryEvent;
+use crate::json;
+
+/// Saves the registry events to a file as they happen, so they can be replayed with the replay command
+pub struct Recorder {
+    file: RefCell<fs::File>,
+    start: std::time::Instant,
+    count: Cell<usize>,
+}
+
+impl Recorder {
+    pub fn create(path: &std::path::Path) -> std::io::Result<Recorder> {
+        let mut file = fs::File::create(path)?;
+        file.write_all(b"[")?;
+
+        Ok(Recorder {
+            file: RefCell::new(file),
+            start: std::time::Instant::now(),
+            count: Cell::new(0),
+        })
+    }
+
+    /// Every event is written on its own line as it happens. We are usually stopped with ctrl-c,
+    /// so the list is never closed, replay adds the missing bracket
+    pub fn record(&self, event: &RegistryEvent) {
+        let mut line = String::from(if self.count.get() == 0 { "\n  " } else { ",\n  " });
+        json::write(&event.to_json(self.start.elapsed().as_millis() as u64), &mut line);
+
+        if let Err(e) = self.file.borrow_mut().write_all(line.as_bytes()) {
+            println!("Could not record event: {}", e);
+        }
+        self.count.set(self.count.get() + 1);
+    }
+}
+
+/// Reads the registry events saved with `--record`, with the milliseconds since the recording started
+pub fn read_events(path: &std::path::Path) -> Result<Vec<(u64, RegistryEvent)>, Box<dyn std::error::Error>> {
+    let mut content = fs::read_to_string(path)?;
+
+    // Recordings that were interrupted are missing the closing bracket
+    if content.trim_start().starts_with('[') && !content.trim_end().ends_with(']') {
+        content.push(']');
+    }
+
+    let events = json::parse(&content)
+        .and_then(|a| a.as_array().map(|a| a.to_vec()).ok_or_else(|| "expected a list of events".to_string()))
+        .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+
+    let events = events
+        .iter()
+        .enumerate()
+        .map(|(i, event)| RegistryEvent::from_json(event).map_err(|e| format!("Event {}: {}", i, e)))
+        .collect::<Result<Vec<(u64, RegistryEvent)>, String>>()?;
+    Ok(events)
+}
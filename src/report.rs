@@ -0,0 +1,575 @@
+//! What `dry-run`, `check`, `list`, `status`, `stats` and `health` print, as a table, JSON or CSV
+
+use std::rc::Rc;
+
+use crate::config::{local_timestamp, Config, LinkDef, KEEP_ALIVE_NODE};
+use crate::engine::{rule_matches, simulate, AppState, RuleMatch, RuleStats};
+use crate::graph::{Direction, Graph, GraphLink, LinkOwner, OWNER, OWNER_KEY, RULE_KEY, RULE_NAME_KEY};
+use crate::{csv, json};
+
+/// How `list` and `status` print what they found
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Table,
+    Json,
+    Csv,
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Option<Format> {
+        match format {
+            "table" => Some(Format::Table),
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Format::Table => "table",
+            Format::Json => "json",
+            Format::Csv => "csv",
+        }
+    }
+}
+
+/// Returns the links the rules would add to the graph (+), our links they would remove (-) and the ones already there (=),
+/// with the number of links to add and rules missing their nodes or ports
+pub fn compare(state: &mut AppState, graph: &Graph) -> (String, usize) {
+    let backend = simulate(state, graph);
+    let desired = backend.links.borrow();
+
+    let mut out = String::from("\n");
+
+    let (mut added, mut satisfied, mut removed) = (0, 0, 0);
+
+    for link in desired.iter() {
+        let rule = link.props.iter().find(|a| a.0.eq(RULE_KEY)).map(|a| a.1.as_str()).unwrap_or_default();
+
+        if graph.links.iter().any(|a| a.port_out == link.port_out && a.port_in == link.port_in) {
+            out.push_str(&format!("= {}\n", rule));
+            satisfied += 1;
+        } else {
+            out.push_str(&format!("+ {}\n", rule));
+            added += 1;
+        }
+    }
+
+    // Our own links that the rules would not make anymore
+    for link in &graph.links {
+        if !link.props.get(OWNER_KEY).is_some_and(|a| a.eq(OWNER)) {
+            continue;
+        }
+
+        if desired.iter().any(|a| a.port_out == link.port_out && a.port_in == link.port_in) {
+            continue;
+        }
+
+        out.push_str(&format!("- {}\n", graph.describe_link(link)));
+        removed += 1;
+    }
+
+    let missing = match_rules(graph, &rule_matches(state, graph), true).1;
+
+    out.push_str(&format!(
+        "\n{} to add, {} to remove, {} already linked, {} rules missing nodes or ports\n",
+        added, removed, satisfied, missing
+    ));
+    (out, added + missing)
+}
+
+/// Returns for every rule whether its nodes and ports are there, go the right way and are already linked,
+/// with the number of rules that are not linked
+pub fn check_rules(state: &AppState, graph: &Graph) -> (String, usize) {
+    let mut out = String::new();
+    let (mut linked, mut unlinked, mut problems) = (0, 0, 0);
+
+    let mut report = |status: &str, rule: String, found: Vec<String>| {
+        out.push_str(&format!("{:<11}{}\n", status, rule));
+        for problem in found {
+            out.push_str(&format!("           {}\n", problem));
+        }
+    };
+
+    for link in state.link_def[..state.file_rules].iter().filter(|a| a.port_out.node.name.ne(KEEP_ALIVE_NODE)) {
+        let (out, port_in) = (&link.port_out, &link.port_in);
+        let mut found = [
+            graph.find_port(&out.node.name, &out.name, Direction::Out),
+            graph.find_port(&port_in.node.name, &port_in.name, Direction::In),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<String>>();
+
+        let ports = graph
+            .get_port(&out.node.name, &out.name, Direction::Out)
+            .zip(graph.get_port(&port_in.node.name, &port_in.name, Direction::In));
+
+        if let Some((port_out, port_in)) = ports {
+            if port_out.props.direction == Some(Direction::In) {
+                found.push(format!("{} of [{}] is an input, it cannot be the output of a link", port_out.name, link.port_out.node.name));
+            }
+            if port_in.props.direction == Some(Direction::Out) {
+                found.push(format!("{} of [{}] is an output, it cannot be the input of a link", port_in.name, link.port_in.node.name));
+            }
+        }
+
+        if !found.is_empty() {
+            problems += 1;
+            report("problem", link.to_string(), found);
+        } else if ports.is_some_and(|(a, b)| graph.links.iter().any(|c| c.port_out == a.id && c.port_in == b.id)) {
+            linked += 1;
+            report("linked", link.to_string(), found);
+        } else {
+            unlinked += 1;
+            report("not linked", link.to_string(), found);
+        }
+    }
+
+    // Their links depend on the ports, only the nodes can be checked
+    let nodes = state
+        .stereo_def
+        .iter()
+        .map(|a| (a.to_string(), &a.node_out, &a.node_in))
+        .chain(state.map_def.iter().map(|a| (a.to_string(), &a.node_out, &a.node_in)));
+
+    for (rule, node_out, node_in) in nodes {
+        let found = [graph.find_node(&node_out.name), graph.find_node(&node_in.name)]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<String>>();
+
+        if found.is_empty() {
+            report("found", rule, found);
+        } else {
+            problems += 1;
+            report("problem", rule, found);
+        }
+    }
+
+    out.push_str(&format!("\n{} linked, {} not linked, {} with problems\n", linked, unlinked, problems));
+    (out, unlinked + problems)
+}
+
+/// Returns the rules with the live objects closest to their missing nodes and ports, and the number of rules missing some
+pub fn match_rules(graph: &Graph, matches: &[RuleMatch], unmatched: bool) -> (String, usize) {
+    let mut out = String::new();
+    let mut missing = 0;
+
+    for a in matches {
+        if a.problems.is_empty() {
+            if !unmatched {
+                out.push_str(&format!("ok       {}\n", a.label()));
+                for link in &a.links {
+                    out.push_str(&format!("         linked {} ({})\n", link_label(graph, link), graph.link_owner(link).as_str()));
+                }
+            }
+            continue;
+        }
+
+        missing += 1;
+        out.push_str(&format!("missing  {}\n", a.label()));
+        for problem in &a.problems {
+            out.push_str(&format!("         {}\n", problem));
+        }
+    }
+
+    out.push_str(&format!("\n{} rules with missing nodes or ports\n", missing));
+    (out, missing)
+}
+
+/// The rules as a JSON array, with every property of the nodes and ports found
+pub fn rules_json(graph: &Graph, matches: &[RuleMatch], unmatched: bool) -> String {
+    let object = |fields: Vec<(&str, json::Value)>| json::Value::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect());
+    let string = |a: &str| json::Value::String(a.to_string());
+    let number = |a: u32| json::Value::Number(a as f64);
+
+    let rules = matches
+        .iter()
+        .filter(|a| !unmatched || !a.problems.is_empty())
+        .map(|a| {
+            let nodes = a
+                .nodes
+                .iter()
+                .map(|a| object(vec![("id", number(a.id)), ("name", string(&a.name)), ("props", json::Value::from_string_map(&a.properties))]))
+                .collect();
+
+            let ports = a
+                .ports
+                .iter()
+                .map(|a| {
+                    object(vec![
+                        ("id", number(a.id)),
+                        ("node", number(a.node)),
+                        ("name", string(&a.name)),
+                        ("props", json::Value::from_string_map(&a.properties)),
+                    ])
+                })
+                .collect();
+
+            object(vec![
+                ("rule", string(&a.rule)),
+                ("name", a.name.as_deref().map_or(json::Value::Null, string)),
+                ("status", string(if a.problems.is_empty() { "ok" } else { "missing" })),
+                ("problems", json::Value::Array(a.problems.iter().map(|a| string(a)).collect())),
+                ("nodes", json::Value::Array(nodes)),
+                ("ports", json::Value::Array(ports)),
+                ("links", json::Value::Array(a.links.iter().map(|a| link_json(graph, a)).collect())),
+            ])
+        })
+        .collect();
+
+    let mut out = String::new();
+    json::write(&json::Value::Array(rules), &mut out);
+    out.push('\n');
+    out
+}
+
+/// A link of the graph followed by the `@name` of the rule that made it
+pub fn link_label(graph: &Graph, link: &GraphLink) -> String {
+    match link.props.get(RULE_NAME_KEY) {
+        Some(name) => format!("{} ({})", graph.describe_link(link), name),
+        None => graph.describe_link(link),
+    }
+}
+
+/// A link of the graph with who made it
+pub fn link_json(graph: &Graph, link: &GraphLink) -> json::Value {
+    json::Value::Object(vec![
+        ("id".to_string(), json::Value::Number(link.id as f64)),
+        ("link".to_string(), json::Value::String(graph.describe_link(link))),
+        ("name".to_string(), link.props.get(RULE_NAME_KEY).map_or(json::Value::Null, |a| json::Value::String(a.clone()))),
+        ("owner".to_string(), json::Value::String(graph.link_owner(link).as_str().to_string())),
+    ])
+}
+
+/// The rules as CSV, one line per rule with its problems and links separated by `; `
+pub fn rules_csv(graph: &Graph, matches: &[RuleMatch], unmatched: bool) -> String {
+    let mut out = String::new();
+    csv::write_row(&["status", "rule", "name", "problems", "links"], &mut out);
+
+    for a in matches.iter().filter(|a| !unmatched || !a.problems.is_empty()) {
+        let status = if a.problems.is_empty() { "ok" } else { "missing" };
+        let links = a
+            .links
+            .iter()
+            .map(|a| format!("{} ({})", link_label(graph, a), graph.link_owner(a).as_str()))
+            .collect::<Vec<String>>();
+        csv::write_row(&[status, &a.rule, a.name.as_deref().unwrap_or_default(), &a.problems.join("; "), &links.join("; ")], &mut out);
+    }
+
+    out
+}
+
+pub fn status(state: &AppState, config: &Config, graph: &Graph, format: Format) -> String {
+    let paused = state.link_def.iter().filter(|a| a.is_paused()).collect::<Vec<&Rc<LinkDef>>>();
+    // By their number, for `enable`
+    let manual = state.link_def.iter().enumerate().filter(|(_, a)| a.options.manual).collect::<Vec<(usize, &Rc<LinkDef>)>>();
+    let owned = |owner: LinkOwner| graph.links.iter().filter(|a| graph.link_owner(a) == owner).count();
+    // The owner of the link, with its state once PipeWire told it
+    let describe = |link: &GraphLink| match state.link_states.get(&link.id) {
+        Some(link_state) => format!("{} ({}, {})", link_label(graph, link), graph.link_owner(link).as_str(), link_state),
+        None => format!("{} ({})", link_label(graph, link), graph.link_owner(link).as_str()),
+    };
+    let failed = graph.links.iter().filter(|a| state.link_states.get(&a.id).is_some_and(|a| a.starts_with("error"))).count();
+    let mut devices = state.device_profiles.iter().map(|(id, profile)| (state.device_name(*id), profile.clone())).collect::<Vec<(String, String)>>();
+    devices.sort();
+
+    let fields = [
+        ("pid", std::process::id().to_string()),
+        ("rules_file", config.path.display().to_string()),
+        ("pipewire", state.socket.as_deref().unwrap_or("default").to_string()),
+        ("rules", state.link_def.len().to_string()),
+        ("map_rules", state.map_def.len().to_string()),
+        ("nodes", state.nodes.len().to_string()),
+        ("ports", state.ports.len().to_string()),
+        ("tracked_links", state.tracked_links.len().to_string()),
+        ("profile", state.profile.as_ref().map_or_else(String::new, |a| a.join(","))),
+        ("links_ours", owned(LinkOwner::Ours).to_string()),
+        ("links_session_manager", owned(LinkOwner::SessionManager).to_string()),
+        ("links_manual", owned(LinkOwner::Manual).to_string()),
+        ("links_error", failed.to_string()),
+        ("rules_manual", manual.len().to_string()),
+    ];
+
+    match format {
+        Format::Table => (),
+        Format::Json => {
+            let paused = paused
+                .iter()
+                .map(|a| {
+                    json::Value::Object(vec![
+                        ("rule".to_string(), json::Value::String(a.to_string())),
+                        ("name".to_string(), a.options.name.as_ref().map_or(json::Value::Null, |a| json::Value::String(a.clone()))),
+                        ("failures".to_string(), json::Value::Number(a.failures.get() as f64)),
+                    ])
+                })
+                .collect();
+
+            let mut status = fields
+                .iter()
+                .map(|(key, value)| {
+                    let value = value.parse::<f64>().map_or_else(|_| json::Value::String(value.clone()), json::Value::Number);
+                    (key.to_string(), value)
+                })
+                .collect::<Vec<(String, json::Value)>>();
+            status.push(("paused".to_string(), json::Value::Array(paused)));
+            status.push((
+                "manual".to_string(),
+                json::Value::Array(
+                    manual
+                        .iter()
+                        .map(|(index, a)| {
+                            json::Value::Object(vec![
+                                ("index".to_string(), json::Value::Number(*index as f64)),
+                                ("rule".to_string(), json::Value::String(a.to_string())),
+                                ("name".to_string(), a.options.name.as_ref().map_or(json::Value::Null, |a| json::Value::String(a.clone()))),
+                                ("enabled".to_string(), json::Value::Bool(!a.disabled.get())),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ));
+            status.push((
+                "devices".to_string(),
+                json::Value::Array(
+                    devices
+                        .iter()
+                        .map(|(device, profile)| {
+                            json::Value::Object(vec![
+                                ("device".to_string(), json::Value::String(device.clone())),
+                                ("profile".to_string(), json::Value::String(profile.clone())),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ));
+            let links = graph
+                .links
+                .iter()
+                .map(|a| {
+                    let mut link = link_json(graph, a);
+                    if let json::Value::Object(fields) = &mut link {
+                        let link_state = state.link_states.get(&a.id).map_or(json::Value::Null, |a| json::Value::String(a.clone()));
+                        fields.push(("state".to_string(), link_state));
+                    }
+                    link
+                })
+                .collect();
+            status.push(("links".to_string(), json::Value::Array(links)));
+
+            let mut out = String::new();
+            json::write(&json::Value::Object(status), &mut out);
+            out.push('\n');
+            return out;
+        }
+        Format::Csv => {
+            let mut out = String::new();
+            csv::write_row(&["key", "value"], &mut out);
+            for (key, value) in &fields {
+                csv::write_row(&[key, value], &mut out);
+            }
+            for (device, profile) in &devices {
+                csv::write_row(&["device", &format!("{}: {}", device, profile)], &mut out);
+            }
+            for (index, link) in &manual {
+                csv::write_row(&["manual", &format!("{} {} ({})", index, link.label(), if link.disabled.get() { "off" } else { "on" })], &mut out);
+            }
+            for link in &paused {
+                csv::write_row(&["paused", &format!("{} ({} failed attempts)", link.label(), link.failures.get())], &mut out);
+            }
+            for link in &graph.links {
+                csv::write_row(&["link", &describe(link)], &mut out);
+            }
+            return out;
+        }
+    }
+
+    let mut status = format!(
+        "Running with pid {}\nRules: {}\nPipeWire: {}\n{} rules, {} map rules\n{} nodes and {} ports found\n{} links tracked\n",
+        std::process::id(),
+        config.path.display(),
+        state.socket.as_deref().unwrap_or("default"),
+        state.link_def.len(),
+        state.map_def.len(),
+        state.nodes.len(),
+        state.ports.len(),
+        state.tracked_links.len()
+    );
+
+    if let Some(profile) = &state.profile {
+        status.push_str(&format!("Profile: {}\n", profile.join(",")));
+    }
+
+    for (device, profile) in &devices {
+        status.push_str(&format!("Device {}: profile {}\n", device, profile));
+    }
+
+    for (index, link) in manual {
+        status.push_str(&format!("Manual rule {}: {} ({})\n", index, link.label(), if link.disabled.get() { "off" } else { "on" }));
+    }
+
+    for link in paused {
+        status.push_str(&format!("Paused after {} failed attempts: {}\n", link.failures.get(), link.label()));
+    }
+
+    status.push_str(&format!(
+        "{} links in the graph: {} ours, {} by the session manager, {} manual\n",
+        graph.links.len(),
+        owned(LinkOwner::Ours),
+        owned(LinkOwner::SessionManager),
+        owned(LinkOwner::Manual)
+    ));
+
+    if failed > 0 {
+        status.push_str(&format!("{} links are in error\n", failed));
+    }
+
+    for link in &graph.links {
+        status.push_str(&format!("    {}\n", describe(link)));
+    }
+
+    status
+}
+
+/// What every rule loaded did, the busiest first
+pub fn stats(state: &AppState, format: Format) -> String {
+    let mut rules = state
+        .link_def
+        .iter()
+        .map(|a| (a.to_string(), a.options.name.clone(), state.stats.get(&a.to_string()).copied().unwrap_or_default()))
+        .collect::<Vec<(String, Option<String>, RuleStats)>>();
+    rules.sort_by_key(|a| std::cmp::Reverse(a.2.matched));
+
+    let last = |a: &RuleStats| a.last.map_or_else(|| "never".to_string(), local_timestamp);
+    let mut out = String::new();
+
+    match format {
+        Format::Table => {
+            for (rule, name, stats) in &rules {
+                out.push_str(&format!(
+                    "{}{}\n    matched {}, created {}, failed {}, last {}\n",
+                    rule,
+                    name.as_ref().map_or_else(String::new, |a| format!(" ({})", a)),
+                    stats.matched,
+                    stats.created,
+                    stats.failed,
+                    last(stats)
+                ));
+            }
+        }
+        Format::Json => {
+            let rules = rules
+                .iter()
+                .map(|(rule, name, stats)| {
+                    json::Value::Object(vec![
+                        ("rule".to_string(), json::Value::String(rule.clone())),
+                        ("name".to_string(), name.as_ref().map_or(json::Value::Null, |a| json::Value::String(a.clone()))),
+                        ("matched".to_string(), json::Value::Number(stats.matched as f64)),
+                        ("created".to_string(), json::Value::Number(stats.created as f64)),
+                        ("failed".to_string(), json::Value::Number(stats.failed as f64)),
+                        ("last".to_string(), stats.last.map_or(json::Value::Null, |a| json::Value::Number(a as f64))),
+                    ])
+                })
+                .collect();
+            json::write(&json::Value::Array(rules), &mut out);
+            out.push('\n');
+        }
+        Format::Csv => {
+            csv::write_row(&["rule", "name", "matched", "created", "failed", "last"], &mut out);
+            for (rule, name, stats) in &rules {
+                let last = stats.last.map_or_else(String::new, local_timestamp);
+                csv::write_row(&[rule, name.as_deref().unwrap_or_default(), &stats.matched.to_string(), &stats.created.to_string(), &stats.failed.to_string(), &last], &mut out);
+            }
+        }
+    }
+
+    out
+}
+
+/// Whether we are still connected to PipeWire, how long ago the graph last changed and how many `@required` rules
+/// are not linked, for monitoring tools. It is unhealthy when the connection is lost or a required rule is not linked
+pub fn health(state: &AppState, format: Format) -> String {
+    let unlinked = state.unlinked_rules(true).len();
+    let status = if state.connected && unlinked == 0 { "ok" } else { "unhealthy" };
+    let last_event = state.last_event.map(|a| a.elapsed().as_secs());
+
+    let mut out = String::new();
+
+    match format {
+        Format::Table => {
+            out.push_str(&format!("{}\n", status));
+            out.push_str(&format!("connected: {}\n", if state.connected { "yes" } else { "no" }));
+            out.push_str(&format!("last event: {}\n", last_event.map_or_else(|| "never".to_string(), |a| format!("{}s ago", a))));
+            out.push_str(&format!("required rules not linked: {}\n", unlinked));
+        }
+        Format::Json => {
+            json::write(
+                &json::Value::Object(vec![
+                    ("status".to_string(), json::Value::String(status.to_string())),
+                    ("connected".to_string(), json::Value::Bool(state.connected)),
+                    ("last_event".to_string(), last_event.map_or(json::Value::Null, |a| json::Value::Number(a as f64))),
+                    ("required_not_linked".to_string(), json::Value::Number(unlinked as f64)),
+                ]),
+                &mut out,
+            );
+            out.push('\n');
+        }
+        Format::Csv => {
+            csv::write_row(&["status", "connected", "last_event", "required_not_linked"], &mut out);
+            let last_event = last_event.map_or_else(String::new, |a| a.to_string());
+            csv::write_row(&[status, &state.connected.to_string(), &last_event, &unlinked.to_string()], &mut out);
+        }
+    }
+
+    out
+}
+
+/// The rules, nodes and links shown by the web page, the links made by a rule are marked as configured
+#[cfg(feature = "web")]
+pub fn web_state(state: &AppState, graph: &Graph) -> String {
+    let object = |fields: Vec<(&str, json::Value)>| json::Value::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect());
+    let string = |a: &str| json::Value::String(a.to_string());
+    let number = |a: u32| json::Value::Number(a as f64);
+
+    let rules = state
+        .link_def
+        .iter()
+        .enumerate()
+        .filter(|(_, a)| a.port_out.node.name.ne(KEEP_ALIVE_NODE))
+        .map(|(i, a)| object(vec![("index", number(i as u32)), ("rule", string(&a.to_string())), ("enabled", json::Value::Bool(!a.disabled.get()))]))
+        .collect();
+
+    let nodes = graph
+        .nodes
+        .iter()
+        .map(|a| {
+            let class = a.properties.get("media.class").map_or("", |a| a.as_str());
+            object(vec![("id", number(a.id)), ("name", string(&a.name)), ("class", string(class))])
+        })
+        .collect();
+
+    let links = graph
+        .links
+        .iter()
+        .map(|a| {
+            let node_out = graph.ports.iter().find(|b| b.id == a.port_out).map_or(json::Value::Null, |b| number(b.node));
+            let configured = graph.link_names(a).is_some_and(|(node_out, port_out, node_in, port_in)| state.matches_rule(node_out, port_out, node_in, port_in));
+            object(vec![
+                ("id", number(a.id)),
+                ("node_out", node_out),
+                ("link", string(&graph.describe_link(a))),
+                ("owner", string(graph.link_owner(a).as_str())),
+                ("configured", json::Value::Bool(configured)),
+            ])
+        })
+        .collect();
+
+    let mut out = String::new();
+    json::write(
+        &object(vec![("rules", json::Value::Array(rules)), ("nodes", json::Value::Array(nodes)), ("links", json::Value::Array(links))]),
+        &mut out,
+    );
+    out
+}
@@ -0,0 +1,256 @@
+//! Applies the rules to a graph saved with `export --to graph`, used with `--backend sim <graph.json>`
+//! to try the rules on machines without PipeWire or audio hardware. The links made show up as registry
+//! events like they do in PipeWire, and `--events` plays registry events against the graph as they were recorded
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::config::SCHEDULE_INTERVAL;
+use crate::engine::{handle_event, satisfied, AppState};
+use crate::graph::{Graph, GraphBackend, Latency, LinkHandle, Port, RegistryEvent, LINK_TYPE, PORT_TYPE, WAIT_INTERVAL};
+
+#[derive(Default)]
+struct SimBackend {
+    next_id: Cell<u32>,
+    // Output and input port of every link in the graph
+    links: RefCell<HashMap<u32, (u32, u32)>>,
+    // Node of every port, its links go away with it
+    ports: RefCell<HashMap<u32, u32>>,
+    // What the links made and removed announce, handled after the event that made them
+    pending: RefCell<Vec<RegistryEvent>>,
+}
+
+impl GraphBackend for SimBackend {
+    fn create_link(&self, port_out: &Port, port_in: &Port, props: &[(String, String)]) -> Result<LinkHandle, String> {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+
+        let mut props = props.iter().cloned().collect::<HashMap<String, String>>();
+        props.insert("link.output.port".to_string(), port_out.id.to_string());
+        props.insert("link.input.port".to_string(), port_in.id.to_string());
+
+        self.pending.borrow_mut().push(RegistryEvent::Global {
+            id,
+            kind: LINK_TYPE.to_string(),
+            props: Some(props),
+        });
+
+        Ok(LinkHandle::Global(id))
+    }
+
+    fn destroy_link(&self, link: LinkHandle) -> Result<(), String> {
+        let id = match link {
+            LinkHandle::Global(id) => id,
+            _ => return Err("not a link of the simulated graph".to_string()),
+        };
+
+        if !self.links.borrow().contains_key(&id) {
+            return Err(format!("no link {}", id));
+        }
+
+        self.pending.borrow_mut().push(RegistryEvent::Remove { id });
+        Ok(())
+    }
+
+    fn set_node_props(&self, node: u32, props: &str) -> Result<(), String> {
+        println!("Simulated props of node {}: {}", node, props);
+        Ok(())
+    }
+
+    fn set_device_profile(&self, device: u32, profile: &str) -> Result<(), String> {
+        println!("Simulated profile of device {}: {}", device, profile);
+        Ok(())
+    }
+
+    fn force_latency(&self, latency: Option<Latency>) -> Result<(), String> {
+        match latency {
+            Some(latency) => println!("Simulated latency: {}", latency),
+            None => println!("Simulated latency: back to the default"),
+        }
+        Ok(())
+    }
+}
+
+impl SimBackend {
+    /// Hands the event to the rules, with the links that went away with a removed port or node before it
+    /// and the links the rules made or removed after it
+    fn send(&self, state: &mut AppState, event: RegistryEvent) {
+        let mut events = vec![];
+
+        if let RegistryEvent::Remove { id } = event {
+            let ports = self.ports.borrow();
+            let gone = |port: &u32| *port == id || ports.get(port) == Some(&id);
+
+            events.extend(
+                self.links
+                    .borrow()
+                    .iter()
+                    .filter(|(_, (port_out, port_in))| gone(port_out) || gone(port_in))
+                    .map(|(link, _)| RegistryEvent::Remove { id: *link }),
+            );
+        }
+        events.push(event);
+
+        while !events.is_empty() {
+            for event in events {
+                self.track(&event);
+                handle_event(state, &event, self);
+            }
+            events = std::mem::take(&mut *self.pending.borrow_mut());
+        }
+    }
+
+    /// Hands the links made or removed outside of an event to the rules
+    fn flush(&self, state: &mut AppState) {
+        let pending = self.pending.take();
+        for event in pending {
+            self.send(state, event);
+        }
+    }
+
+    fn track(&self, event: &RegistryEvent) {
+        match event {
+            RegistryEvent::Global { id, kind, props: Some(props) } => {
+                let get_id = |key: &str| props.get(key).and_then(|a| a.parse::<u32>().ok());
+
+                if kind.eq(LINK_TYPE) {
+                    if let (Some(port_out), Some(port_in)) = (get_id("link.output.port"), get_id("link.input.port")) {
+                        self.links.borrow_mut().insert(*id, (port_out, port_in));
+                    }
+                } else if kind.eq(PORT_TYPE) {
+                    if let Some(node) = get_id("node.id") {
+                        self.ports.borrow_mut().insert(*id, node);
+                    }
+                }
+            }
+            RegistryEvent::Remove { id } => {
+                self.links.borrow_mut().remove(id);
+                self.ports.borrow_mut().remove(id);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Runs the rules against the graph and then the events, each at its time since the start,
+/// until the events are over and no rule is waiting anymore. Fails like `--once` when a `@required` rule is not linked
+pub fn run(state: &mut AppState, graph: &Graph, events: Vec<(u64, RegistryEvent)>) -> Result<(), Box<dyn std::error::Error>> {
+    let globals = graph.to_events();
+
+    // The ids of the links we make come after every id of the graph and the events
+    let last = globals
+        .iter()
+        .chain(events.iter().map(|a| &a.1))
+        .map(|a| match a {
+            RegistryEvent::Global { id, .. } | RegistryEvent::Remove { id } => *id,
+        })
+        .max()
+        .unwrap_or_default();
+
+    let backend = SimBackend::default();
+    backend.next_id.set(last + 1);
+
+    state.node_times = Some(HashMap::new());
+
+    for global in globals {
+        backend.send(state, global);
+    }
+
+    let start = Instant::now();
+    let mut events = events.into_iter().peekable();
+    let mut last_schedule = Instant::now();
+
+    loop {
+        while let Some((time, event)) = events.next_if(|a| start.elapsed() >= Duration::from_millis(a.0)) {
+            println!("-- {}.{:03}s {}", time / 1000, time % 1000, event);
+            backend.send(state, event);
+        }
+
+        state.apply_waiting(&backend);
+        backend.flush(state);
+
+        if last_schedule.elapsed() >= SCHEDULE_INTERVAL {
+            state.update_schedules(&backend);
+            backend.flush(state);
+            last_schedule = Instant::now();
+        }
+
+        let idle = state.waiting_grace.is_empty() && state.waiting_rate.is_empty() && state.waiting_enforce.is_empty();
+        if events.peek().is_none() && idle {
+            break;
+        }
+
+        std::thread::sleep(WAIT_INTERVAL);
+    }
+
+    let graph = Graph::from_globals(&state.seen_globals.values().cloned().collect::<Vec<RegistryEvent>>());
+
+    let mut links = graph.links.iter().map(|a| graph.describe_link(a)).collect::<Vec<String>>();
+    links.sort();
+
+    println!("\n{} links at the end:", links.len());
+    for link in links {
+        println!("  {}", link);
+    }
+
+    let unlinked = state.unlinked_rules(true);
+    for rule in &unlinked {
+        println!("Required rule not linked: {}", rule);
+    }
+    satisfied(unlinked.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{parse_rules, TagFilter};
+    use crate::engine::Unsatisfied;
+    use crate::json;
+
+    const GRAPH: &str = r#"{
+        "nodes": [
+            {"id": 1, "name": "Music", "kind": "stream"},
+            {"id": 2, "name": "Speakers", "kind": "hardware"},
+            {"id": 3, "name": "Alerts", "kind": "stream"}
+        ],
+        "ports": [
+            {"id": 10, "node": 1, "name": "output_FL", "direction": "out"},
+            {"id": 20, "node": 2, "name": "playback_FL", "direction": "in"},
+            {"id": 30, "node": 3, "name": "output_FL", "direction": "out"}
+        ]
+    }"#;
+
+    /// The links at the end, and how many rules were not satisfied when it failed
+    fn simulate(rules: &str, events: Vec<(u64, RegistryEvent)>) -> (Vec<(u32, u32)>, Option<usize>) {
+        let mut state = AppState::new(parse_rules(rules.as_bytes(), &TagFilter::default(), &HashMap::new()).unwrap(), false);
+        let graph = Graph::from_json(&json::parse(GRAPH).unwrap()).unwrap();
+
+        let unsatisfied = run(&mut state, &graph, events).err().map(|e| e.downcast_ref::<Unsatisfied>().map_or(0, |a| a.0));
+
+        let graph = Graph::from_globals(&state.seen_globals.values().cloned().collect::<Vec<RegistryEvent>>());
+        let mut links = graph.links.iter().map(|a| (a.port_out, a.port_in)).collect::<Vec<(u32, u32)>>();
+        links.sort();
+        (links, unsatisfied)
+    }
+
+    #[test]
+    fn events_move_the_links() {
+        let rules = "[Music](output_FL) -> [Speakers](playback_FL) @priority 1 @required\n[Alerts](output_FL) -> [Speakers](playback_FL) @priority 2\n";
+        let (links, unsatisfied) = simulate(rules, vec![(0, RegistryEvent::Remove { id: 3 })]);
+
+        assert_eq!(links, [(10, 20)]);
+        assert_eq!(unsatisfied, None);
+    }
+
+    #[test]
+    fn required_rule_not_linked() {
+        let rules = "[Music](output_FL) -> [Speakers](playback_FL)\n[Radio](output_FL) -> [Speakers](playback_FL) @required\n";
+        let (links, unsatisfied) = simulate(rules, Vec::new());
+
+        assert_eq!(links, [(10, 20)]);
+        assert_eq!(unsatisfied, Some(1));
+    }
+}
@@ -0,0 +1,48 @@
+//! Watches the main loop from a thread. A timer on the main loop beats it, when the timer stops running
+//! because the loop is stuck, like in a callback that never returns, we exit so the service manager starts us again
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Exit code once the main loop stalled, next to the ones of `main`
+pub const EXIT_STALLED: u8 = 5;
+
+pub struct Watchdog {
+    started: Instant,
+    // Milliseconds since started of the last time the main loop ran
+    beat: Arc<AtomicU64>,
+}
+
+impl Watchdog {
+    /// Starts the thread, it exits with the code once the main loop did not beat for the timeout
+    pub fn start(timeout: Duration, code: u8) -> Watchdog {
+        let started = Instant::now();
+        let beat = Arc::new(AtomicU64::new(0));
+
+        thread::spawn({
+            let beat = beat.clone();
+            move || loop {
+                thread::sleep(timeout / 4);
+
+                let stalled = started.elapsed().saturating_sub(Duration::from_millis(beat.load(Ordering::Relaxed)));
+                if stalled >= timeout {
+                    println!("The main loop did not run for {} seconds, exiting so we are started again", stalled.as_secs());
+                    std::process::exit(code as i32);
+                }
+            }
+        });
+
+        Watchdog { started, beat }
+    }
+
+    /// Called from the main loop, tells the thread it is still running
+    pub fn beat(&self) {
+        self.beat.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,241 @@
+//! Small web page showing the graph and the rules, to turn rules on and off from a phone, `--web <address>`,
+//! and the HTTP API for scripts, `--api <address>`
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// Requests with a bigger body are refused
+const MAX_BODY: usize = 64 * 1024;
+
+/// Longest request line or header, and most headers, a request can have
+const MAX_LINE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 64;
+
+/// Time a client has to send the whole request, it is read on the main loop
+const REQUEST_DEADLINE: Duration = Duration::from_secs(1);
+
+/// The page, it asks for the graph and the rules with `GET /api/graph` every few seconds
+pub const PAGE: &str = include_str!("web.html");
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Request {
+    /// Header names are case insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|a| a.0.eq_ignore_ascii_case(name)).map(|a| a.1.as_str())
+    }
+
+    /// The request comes from a page of this server, browsers send the `Origin` of a POST,
+    /// so another site open in the browser cannot turn the rules on and off
+    pub fn is_same_origin(&self) -> bool {
+        match (self.header("Origin"), self.header("Host")) {
+            (Some(origin), Some(host)) => origin.strip_prefix("http://").is_some_and(|a| a.eq_ignore_ascii_case(host)),
+            _ => false,
+        }
+    }
+
+    /// The request has `Authorization: Bearer <token>`
+    pub fn is_authorized(&self, token: &str) -> bool {
+        self.header("Authorization").and_then(|a| a.strip_prefix("Bearer ")).is_some_and(|a| a.trim() == token)
+    }
+}
+
+pub fn listen(address: &str) -> Result<TcpListener, String> {
+    let listener = TcpListener::bind(address).map_err(|e| format!("could not listen on {}: {}", address, e))?;
+    listener
+        .set_nonblocking(true)
+        .map_err(|e| format!("could not listen on {}: {}", address, e))?;
+
+    Ok(listener)
+}
+
+/// Reads from the stream until the deadline, the timeout of every read is what is left of it
+struct DeadlineReader<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "request not sent in time"));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+/// Reads the request line, the headers and the body given by `Content-Length`
+pub fn read_request(stream: &TcpStream) -> std::io::Result<Request> {
+    // Do not let a client that never writes, or writes one byte at a time, block the main loop
+    stream.set_nonblocking(false)?;
+
+    let mut reader = BufReader::new(DeadlineReader {
+        stream,
+        deadline: Instant::now() + REQUEST_DEADLINE,
+    });
+    let line = read_line(&mut reader)?;
+
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "not an HTTP request")),
+    };
+
+    let mut headers = Vec::new();
+
+    loop {
+        let header = read_line(&mut reader)?;
+        if header.trim().is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "too many headers"));
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    let mut request = Request { method, path, headers, body: String::new() };
+
+    let length = request.header("Content-Length").and_then(|a| a.parse::<usize>().ok()).unwrap_or_default();
+    if length > MAX_BODY {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "request body too big"));
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    request.body = String::from_utf8_lossy(&body).to_string();
+
+    Ok(request)
+}
+
+/// Reads a line of the request, refusing the ones longer than `MAX_LINE`
+fn read_line<R: BufRead>(reader: &mut R) -> std::io::Result<String> {
+    let mut line = String::new();
+    reader.take(MAX_LINE as u64 + 1).read_line(&mut line)?;
+
+    if line.len() > MAX_LINE {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "request line too long"));
+    }
+    Ok(line)
+}
+
+/// Writes the answer and closes the connection
+pub fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+/// The token of the API, read from the file or written to it when there is none yet, readable only by us
+pub fn token(path: &Path) -> Result<String, String> {
+    if let Ok(token) = fs::read_to_string(path) {
+        if !token.trim().is_empty() {
+            return Ok(token.trim().to_string());
+        }
+    }
+
+    let mut random = [0u8; 16];
+    fs::File::open("/dev/urandom")
+        .and_then(|mut a| a.read_exact(&mut random))
+        .map_err(|e| format!("could not make a token: {}", e))?;
+    let token = random.iter().map(|a| format!("{:02x}", a)).collect::<String>();
+
+    if let Some(dir) = path.parent() {
+        let _ = crate::daemon::create_runtime_dir(dir);
+    }
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut a| writeln!(a, "{}", token))
+        .map_err(|e| format!("could not write the token to {}: {}", path.display(), e))?;
+
+    Ok(token)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: "POST".to_string(),
+            path: "/api/rules/0/disable".to_string(),
+            headers: headers.iter().map(|(a, b)| (a.to_string(), b.to_string())).collect(),
+            body: String::new(),
+        }
+    }
+
+    #[test]
+    fn same_origin() {
+        assert!(request(&[("Host", "music-pc:8080"), ("Origin", "http://music-pc:8080")]).is_same_origin());
+        assert!(!request(&[("Host", "music-pc:8080"), ("Origin", "http://evil.example")]).is_same_origin());
+        assert!(!request(&[("Host", "music-pc:8080")]).is_same_origin());
+    }
+
+    #[test]
+    fn limits() {
+        let read = |request: String| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            client.write_all(request.as_bytes()).unwrap();
+            read_request(&stream)
+        };
+
+        let request = read("POST /api/reload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\n\r\nok".to_string()).unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str(), request.body.as_str()), ("POST", "/api/reload", "ok"));
+        assert_eq!(request.header("content-length"), Some("2"));
+
+        assert!(read(format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE))).is_err());
+        assert!(read(format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: b\r\n".repeat(MAX_HEADERS + 1))).is_err());
+    }
+
+    #[test]
+    fn slow_request_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        // One header line sent in pieces, each before the timeout of a single read
+        let writer = std::thread::spawn(move || {
+            client.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+            for _ in 0..6 {
+                std::thread::sleep(Duration::from_millis(300));
+                if client.write_all(b"X").is_err() {
+                    break;
+                }
+            }
+        });
+
+        let started = Instant::now();
+        assert!(read_request(&stream).is_err());
+        assert!(started.elapsed() < REQUEST_DEADLINE + Duration::from_millis(500));
+        drop(stream);
+        writer.join().unwrap();
+    }
+}
@@ -0,0 +1,113 @@
+//! POSTs the events of the links as JSON to the URL of `--webhook`, for dashboards and alerting.
+//! The requests are made on a thread of their own so a slow server does not hold up the linking
+
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::mpsc,
+    time::Duration,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `http://host[:port][/path]`, there is no TLS
+#[derive(Debug, Clone)]
+pub struct Url {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Url, String> {
+        if url.starts_with("https://") {
+            return Err("https is not supported, use an http:// URL or a local relay".to_string());
+        }
+
+        let rest = url.strip_prefix("http://").ok_or("the URL must start with http://")?;
+        let (address, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse::<u16>().map_err(|_| format!("invalid port '{}'", port))?),
+            None => (address, 80),
+        };
+
+        if host.is_empty() {
+            return Err("the URL has no host".to_string());
+        }
+
+        Ok(Url {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for Url {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+pub struct Webhook {
+    sender: mpsc::Sender<String>,
+}
+
+impl Webhook {
+    pub fn new(url: Url) -> Webhook {
+        let (sender, receiver) = mpsc::channel::<String>();
+
+        // One request at a time, so the server gets the events in order
+        std::thread::spawn(move || {
+            for payload in receiver {
+                if let Err(e) = post(&url, &payload) {
+                    println!("Webhook {} failed: {}", url, e);
+                }
+            }
+        });
+
+        Webhook { sender }
+    }
+
+    pub fn send(&self, payload: String) {
+        self.sender.send(payload).ok();
+    }
+}
+
+fn post(url: &Url, payload: &str) -> Result<(), String> {
+    let address = (url.host.as_str(), url.port)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut a| a.next())
+        .ok_or_else(|| format!("could not resolve {}", url.host))?;
+
+    let mut stream = TcpStream::connect_timeout(&address, TIMEOUT).map_err(|e| e.to_string())?;
+    stream.set_read_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: pw-autoconnect/{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        url.path,
+        url.host,
+        env!("CARGO_PKG_VERSION"),
+        payload.len(),
+        payload
+    )
+    .map_err(|e| e.to_string())?;
+
+    // Only the status line matters
+    let mut answer = [0; 64];
+    let n = stream.read(&mut answer).map_err(|e| e.to_string())?;
+    let status = String::from_utf8_lossy(&answer[..n]);
+
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        Some(code) => Err(format!("the server answered {}", code)),
+        None => Err("the server did not answer".to_string()),
+    }
+}
@@ -0,0 +1,223 @@
+//! Just enough XML to read and write the connection files of other tools
+
+#[derive(Debug, Default)]
+pub struct Element {
+    pub name: String,
+    pub attrs: Vec<(String, String)>,
+    pub children: Vec<Element>,
+    pub text: String,
+}
+
+impl Element {
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.iter().find(|a| a.0.eq(name)).map(|a| a.1.as_str())
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|a| a.name.eq(name))
+    }
+
+    pub fn children_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> {
+        self.children.iter().filter(move |a| a.name.eq(name))
+    }
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn parse_attrs(tag: &str) -> Result<Vec<(String, String)>, String> {
+    let mut attrs = Vec::new();
+    let mut rest = tag.trim();
+
+    while !rest.is_empty() {
+        let (name, value) = rest
+            .split_once('=')
+            .ok_or_else(|| format!("invalid attribute in '{}'", tag))?;
+        let value = value.trim_start();
+
+        let quote = value
+            .chars()
+            .next()
+            .filter(|a| *a == '"' || *a == '\'')
+            .ok_or_else(|| format!("unquoted attribute in '{}'", tag))?;
+        let end = value[1..]
+            .find(quote)
+            .ok_or_else(|| format!("unterminated attribute in '{}'", tag))?;
+
+        attrs.push((name.trim().to_string(), unescape(&value[1..end + 1])));
+        rest = value[end + 2..].trim_start();
+    }
+
+    Ok(attrs)
+}
+
+/// Parses a document and returns its root element
+pub fn parse(content: &str) -> Result<Element, String> {
+    // The root is kept at the bottom of the stack while parsing
+    let mut stack = vec![Element::default()];
+    let mut rest = content;
+
+    while let Some(start) = rest.find('<') {
+        stack.last_mut().unwrap().text.push_str(&unescape(&rest[..start]));
+        rest = &rest[start..];
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            let end = comment.find("-->").ok_or("unterminated comment")?;
+            rest = &comment[end + 3..];
+            continue;
+        }
+
+        let end = rest.find('>').ok_or("unterminated tag")?;
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+
+        if tag.starts_with('?') || tag.starts_with('!') {
+            continue;
+        }
+
+        if let Some(name) = tag.strip_prefix('/') {
+            let element = stack.pop().filter(|_| !stack.is_empty()).ok_or("unexpected closing tag")?;
+
+            if !element.name.eq(name.trim()) {
+                return Err(format!("expected </{}> but got </{}>", element.name, name.trim()));
+            }
+
+            stack.last_mut().unwrap().children.push(element);
+            continue;
+        }
+
+        let (tag, closed) = match tag.strip_suffix('/') {
+            Some(tag) => (tag, true),
+            None => (tag, false),
+        };
+
+        let (name, attrs) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+
+        let element = Element {
+            name: name.to_string(),
+            attrs: parse_attrs(attrs)?,
+            ..Default::default()
+        };
+
+        if closed {
+            stack.last_mut().unwrap().children.push(element);
+        } else {
+            stack.push(element);
+        }
+    }
+
+    if stack.len() != 1 {
+        return Err(format!("missing </{}>", stack.last().unwrap().name));
+    }
+
+    stack
+        .pop()
+        .and_then(|a| a.children.into_iter().next())
+        .ok_or_else(|| "empty document".to_string())
+}
+
+/// Writes the element and its children, indented by `depth`
+pub fn write(element: &Element, depth: usize, out: &mut String) {
+    let indent = " ".repeat(depth * 2);
+
+    out.push_str(&indent);
+    out.push('<');
+    out.push_str(&element.name);
+
+    for (name, value) in &element.attrs {
+        out.push_str(&format!(" {}=\"{}\"", name, escape(value)));
+    }
+
+    if element.children.is_empty() && element.text.trim().is_empty() {
+        out.push_str("/>\n");
+        return;
+    }
+
+    out.push('>');
+
+    if element.children.is_empty() {
+        out.push_str(&escape(element.text.trim()));
+    } else {
+        out.push('\n');
+        for child in &element.children {
+            write(child, depth + 1, out);
+        }
+        out.push_str(&indent);
+    }
+
+    out.push_str(&format!("</{}>\n", element.name));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaping() {
+        let root = Element {
+            name: "patchbay".to_string(),
+            attrs: vec![("name".to_string(), "Tom & \"Jerry's\" <band>".to_string())],
+            children: vec![Element {
+                name: "plug".to_string(),
+                text: "a < b && c > d".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let mut out = String::new();
+        write(&root, 0, &mut out);
+        assert_eq!(
+            out,
+            "<patchbay name=\"Tom &amp; &quot;Jerry's&quot; &lt;band&gt;\">\n  <plug>a &lt; b &amp;&amp; c &gt; d</plug>\n</patchbay>\n"
+        );
+
+        let parsed = parse(&out).unwrap();
+        assert_eq!(parsed.attr("name"), Some("Tom & \"Jerry's\" <band>"));
+        assert_eq!(parsed.child("plug").unwrap().text, "a < b && c > d");
+
+        // An escaped entity is not unescaped twice
+        assert_eq!(parse("<a>&amp;lt; &apos;</a>").unwrap().text, "&lt; '");
+    }
+
+    #[test]
+    fn attributes() {
+        let root = parse("<?xml version=\"1.0\"?>\n<!DOCTYPE patchbay>\n<!-- <not> an element -->\n<socket  name = 'system'\ttype=\"jack-audio\" empty=\"\"/>").unwrap();
+
+        assert_eq!(root.name, "socket");
+        assert_eq!(root.attr("name"), Some("system"));
+        assert_eq!(root.attr("type"), Some("jack-audio"));
+        assert_eq!(root.attr("empty"), Some(""));
+        assert_eq!(root.attr("client"), None);
+        assert!(root.children.is_empty());
+
+        let root = parse("<jack><client name=\"a\"/><port/><client name=\"b\"></client></jack>").unwrap();
+        assert_eq!(root.children_named("client").filter_map(|a| a.attr("name")).collect::<Vec<&str>>(), ["a", "b"]);
+        assert_eq!(root.child("port").map(|a| a.name.as_str()), Some("port"));
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(parse("<a><b></a>").unwrap_err(), "expected </b> but got </a>");
+        assert_eq!(parse("<a><b>").unwrap_err(), "missing </b>");
+        assert_eq!(parse("</a>").unwrap_err(), "unexpected closing tag");
+        assert_eq!(parse("<a").unwrap_err(), "unterminated tag");
+        assert_eq!(parse("<!-- <a/>").unwrap_err(), "unterminated comment");
+        assert_eq!(parse("just text").unwrap_err(), "empty document");
+        assert!(parse("<a name=b/>").unwrap_err().starts_with("unquoted attribute"));
+        assert!(parse("<a name/>").unwrap_err().starts_with("invalid attribute"));
+        assert!(parse("<a name=\"b/>").unwrap_err().starts_with("unterminated attribute"));
+    }
+}